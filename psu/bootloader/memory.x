@@ -0,0 +1,7 @@
+MEMORY
+{
+    /* The last page of the bootloader's 8K, at 0x08001800, holds the image descriptor,
+     * and the application follows at 0x08002000. See `iggie_psu::boot`. */
+    FLASH : ORIGIN = 0x08000000, LENGTH = 6K
+    RAM   : ORIGIN = 0x20000000, LENGTH = 12K
+}
@@ -0,0 +1,188 @@
+//! Serial bootloader for the PSU
+//!
+//! Runs from reset on the 8MHz HSI, checks the application image, and waits briefly
+//! for a hello request on USART1 before starting it. Once a request arrives, or if
+//! there is no bootable image, it stays to carry out update requests until told to
+//! boot. See `iggie_psu::boot` for the flash layout and protocol.
+//!
+//! HRTIM is never clocked and the gate drive pin is held low as a plain output,
+//! so the converter cannot switch while the bootloader runs.
+
+#![no_std]
+#![no_main]
+
+/// Time to wait for a hello request before starting a bootable application (ms).
+const BOOT_WINDOW: u32 = 500;
+
+/// System clock at reset (Hz).
+const F_HSI: u32 = 8_000_000;
+
+use core::panic::PanicInfo;
+use cortex_m::peripheral::{SCB, SYST, syst::SystClkSource};
+use cortex_m_rt::{entry, exception};
+use stm32ral::{gpio, rcc, usart, read_reg, write_reg, modify_reg};
+
+use iggie_psu::{boot, command};
+
+// Flash is driven with the same code as the application.
+#[path = "../../firmware/src/hal/flash.rs"]
+#[allow(dead_code)]
+mod flash;
+
+/// Application flash region, updated through the flash controller.
+struct AppFlash {
+    flash: flash::Flash,
+}
+
+impl boot::Target for AppFlash {
+    fn erase_page(&mut self, address: u32) -> Result<(), boot::FlashError> {
+        self.flash.erase_page(address).map_err(|_| boot::FlashError)
+    }
+
+    fn program(&mut self, address: u32, data: &[u16]) -> Result<(), boot::FlashError> {
+        self.flash.program(address, data).map_err(|_| boot::FlashError)
+    }
+
+    fn read(&self, address: u32, len: usize) -> &[u8] {
+        // UNSAFE: Flash is memory-mapped and only modified through &mut self.
+        unsafe { core::slice::from_raw_parts(address as *const u8, len) }
+    }
+}
+
+/// Polled USART1, on the same pins as the application's telemetry link.
+struct Serial {
+    usart: usart::Instance,
+}
+
+impl Serial {
+    fn setup(&self) {
+        // 8n1 at boot::BAUD from the HSI-clocked APB2
+        write_reg!(stm32ral::usart, self.usart, BRR, F_HSI / boot::BAUD);
+        modify_reg!(stm32ral::usart, self.usart, CR1, TE: Enabled, RE: Enabled, UE: Enabled);
+    }
+
+    fn read(&self) -> Option<u8> {
+        if read_reg!(stm32ral::usart, self.usart, ISR, ORE == 1) {
+            write_reg!(stm32ral::usart, self.usart, ICR, ORECF: Clear);
+        }
+        if read_reg!(stm32ral::usart, self.usart, ISR, RXNE == 1) {
+            Some(read_reg!(stm32ral::usart, self.usart, RDR) as u8)
+        } else {
+            None
+        }
+    }
+
+    /// Transmit `data`, returning once the last byte has left the shift register.
+    fn write(&self, data: &[u8]) {
+        for byte in data {
+            while read_reg!(stm32ral::usart, self.usart, ISR, TXE == 0) {}
+            write_reg!(stm32ral::usart, self.usart, TDR, *byte as u32);
+        }
+        while read_reg!(stm32ral::usart, self.usart, ISR, TC == 0) {}
+    }
+}
+
+#[entry]
+fn main() -> ! {
+    let rcc = rcc::RCC::take().unwrap();
+    let gpioa = gpio::GPIOA::take().unwrap();
+    let gpiob = gpio::GPIOB::take().unwrap();
+    let mut syst = cortex_m::Peripherals::take().unwrap().SYST;
+
+    modify_reg!(stm32ral::rcc, rcc, AHBENR, IOPAEN: Enabled, IOPBEN: Enabled);
+    modify_reg!(stm32ral::rcc, rcc, APB2ENR, USART1EN: Enabled);
+
+    // Hold PA8 GD low
+    modify_reg!(stm32ral::gpio, gpioa, ODR, ODR8: 0);
+    modify_reg!(stm32ral::gpio, gpioa, MODER, MODER8: Output);
+
+    // Set PB6 to USART Tx (AF7) and PB7 to USART Rx (AF7)
+    modify_reg!(stm32ral::gpio, gpiob, AFRL, AFRL6: AF7, AFRL7: AF7);
+    modify_reg!(stm32ral::gpio, gpiob, MODER, MODER6: Alternate, MODER7: Alternate);
+
+    let serial = Serial { usart: usart::USART1::take().unwrap() };
+    serial.setup();
+
+    let mut target = AppFlash { flash: flash::Flash::new(stm32ral::flash::Flash::take().unwrap()) };
+    let mut updater = boot::Updater::new();
+    let mut framer = command::Framer::<{ boot::MAX_PAYLOAD }>::new();
+
+    // Count milliseconds for the boot window
+    syst.set_clock_source(SystClkSource::Core);
+    syst.set_reload(F_HSI / 1000 - 1);
+    syst.clear_current();
+    syst.enable_counter();
+    let mut waiting = boot::image_ok(&target);
+    let mut elapsed = 0;
+
+    loop {
+        if waiting && syst.has_wrapped() {
+            elapsed += 1;
+            if elapsed >= BOOT_WINDOW {
+                start_app(&rcc, &mut syst);
+            }
+        }
+
+        let (id, payload) = match serial.read().and_then(|byte| framer.push(byte)) {
+            Some(frame) => frame,
+            None => continue,
+        };
+        let request = boot::Request::decode(id, payload);
+        let status = match request {
+            Some(request) => {
+                waiting = false;
+                updater.handle(&mut target, request)
+            },
+            None => boot::Status::BadRequest,
+        };
+
+        let hello = boot::hello_payload();
+        let reply = match (request, status) {
+            (Some(boot::Request::Hello), boot::Status::Ok) => &hello[..],
+            _ => &[],
+        };
+        let mut buf = [0; 16];
+        let len = command::encode(status as u8, reply, &mut buf);
+        serial.write(&buf[..len]);
+
+        if let (Some(boot::Request::Boot), boot::Status::Ok) = (request, status) {
+            start_app(&rcc, &mut syst);
+        }
+    }
+}
+
+/// Return USART1 and SysTick to their reset state and jump to the application.
+///
+/// GPIO configuration is left in place, since the application sets up the same pins
+/// and leaving PA8 driven low avoids the gate drive floating during the handover.
+fn start_app(rcc: &rcc::Instance, syst: &mut SYST) -> ! {
+    syst.disable_counter();
+    modify_reg!(stm32ral::rcc, rcc, APB2RSTR, USART1RST: 1);
+    modify_reg!(stm32ral::rcc, rcc, APB2RSTR, USART1RST: 0);
+    modify_reg!(stm32ral::rcc, rcc, APB2ENR, USART1EN: Disabled);
+
+    // UNSAFE: The image has been checked by `boot::image_ok`, so its vector table
+    // holds a stack pointer in RAM and a reset vector inside the application.
+    unsafe {
+        (*SCB::ptr()).vtor.write(boot::APP_START);
+        let sp = core::ptr::read_volatile(boot::APP_START as *const u32);
+        let reset = core::ptr::read_volatile((boot::APP_START + 4) as *const u32);
+        core::arch::asm!("msr msp, {sp}", "bx {reset}",
+                         sp = in(reg) sp, reset = in(reg) reset, options(noreturn));
+    }
+}
+
+#[inline(never)]
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    loop {
+        cortex_m::asm::nop();
+    }
+}
+
+#[exception]
+unsafe fn HardFault(_ef: &cortex_m_rt::ExceptionFrame) -> ! {
+    loop {
+        cortex_m::asm::nop();
+    }
+}
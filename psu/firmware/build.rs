@@ -0,0 +1,42 @@
+//! Provide build information for the firmware hello packet.
+
+use std::env;
+use std::process::Command;
+
+fn git(args: &[&str]) -> Option<String> {
+    let output = Command::new("git").args(args).output().ok()?;
+    if output.status.success() {
+        Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    } else {
+        None
+    }
+}
+
+fn main() {
+    let commit = match git(&["rev-parse", "--short=8", "HEAD"]) {
+        Some(hash) => {
+            let dirty = git(&["status", "--porcelain", "--untracked-files=no"])
+                .map(|s| !s.is_empty()).unwrap_or(false);
+            if dirty { hash + "-dirty" } else { hash }
+        },
+        None => "unknown".to_string(),
+    };
+
+    let mut features: Vec<String> = env::vars()
+        .filter_map(|(k, _)| k.strip_prefix("CARGO_FEATURE_").map(|f| f.to_lowercase()))
+        .collect();
+    features.sort();
+
+    println!("cargo:rustc-env=IGGIE_GIT_COMMIT={}", commit);
+    println!("cargo:rustc-env=IGGIE_BUILD_PROFILE={}", env::var("PROFILE").unwrap());
+    println!("cargo:rustc-env=IGGIE_FEATURES={}", features.join(","));
+
+    // Rebuild when the commit changes, files are staged or committed,
+    // or sources change and so may change the dirty flag
+    if let Some(git_dir) = git(&["rev-parse", "--git-dir"]) {
+        println!("cargo:rerun-if-changed={}/HEAD", git_dir);
+        println!("cargo:rerun-if-changed={}/index", git_dir);
+    }
+    println!("cargo:rerun-if-changed=src");
+    println!("cargo:rerun-if-changed=build.rs");
+}
@@ -0,0 +1,73 @@
+//! Compare the cost of the full and steady-state Kalman filters.
+//!
+//! Runs each filter for a number of steps using the Vout filter parameters,
+//! timing each predict and update pair with the DWT cycle counter, and prints
+//! the mean and maximum cycles per step over semihosting.
+//!
+//! Run with `cargo run --release --example kalman_cycles` under a debugger.
+//! Clocks are left at reset (8MHz HSI, zero flash wait states), so counts at
+//! 70MHz will be slightly higher, but the comparison between the two holds.
+//!
+//! Counts have not yet been recorded on hardware.
+
+#![no_std]
+#![no_main]
+
+use core::hint::black_box;
+use core::panic::PanicInfo;
+use cortex_m::peripheral::DWT;
+use cortex_m_rt::entry;
+use cortex_m_semihosting::hprintln;
+use stm32ral as _;
+
+use iggie_psu::kalman::Kalman;
+
+const STEPS: u32 = 1000;
+
+fn measure(name: &str, kal: &mut Kalman) {
+    let mut total = 0u32;
+    let mut max = 0u32;
+    for i in 0..STEPS {
+        let z = 375.0 + (i % 7) as f32;
+        let t0 = DWT::get_cycle_count();
+        // Keep the filter state in memory at both cycle counter reads, so that
+        // the step cannot be moved outside the timed region
+        let kal = black_box(&mut *kal);
+        kal.predict();
+        kal.update(z);
+        black_box(&*kal);
+        let dt = DWT::get_cycle_count().wrapping_sub(t0);
+        total += dt;
+        if dt > max {
+            max = dt;
+        }
+    }
+    let _ = hprintln!("{}: mean {} cycles, max {} cycles per step",
+                      name, total / STEPS, max);
+    // Ensure the filter output is used so it is not optimised away
+    let _ = hprintln!("  final estimate {:?}", kal.get());
+}
+
+#[entry]
+fn main() -> ! {
+    let mut core = cortex_m::Peripherals::take().unwrap();
+    core.DCB.enable_trace();
+    core.DWT.enable_cycle_counter();
+
+    let mut full = Kalman::new(1e6, 1e0, 1.10857e-5, 375.0);
+    measure("Full", &mut full);
+
+    let mut steady = Kalman::new_steady_state(1e6, 1e0, 1.10857e-5, 375.0);
+    measure("Steady-state", &mut steady);
+
+    loop {
+        cortex_m::asm::bkpt();
+    }
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    loop {
+        cortex_m::asm::nop();
+    }
+}
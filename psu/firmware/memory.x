@@ -1,5 +1,8 @@
 MEMORY
 {
-    FLASH : ORIGIN = 0x08000000, LENGTH = 32K
+    /* The first 8K holds the serial bootloader and its image descriptor, see `boot`.
+     * The last two 2K pages of the 64K flash store presets, at 0x0800F000,
+     * and lifetime counters, at 0x0800F800. */
+    FLASH : ORIGIN = 0x08002000, LENGTH = 52K
     RAM   : ORIGIN = 0x20000000, LENGTH = 12K
 }
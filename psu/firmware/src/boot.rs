@@ -0,0 +1,460 @@
+//! Serial bootloader flash layout and update protocol
+//!
+//! The bootloader occupies the first 8K of flash, with its last page holding the
+//! image descriptor, and the application follows up to the pages it keeps settings in:
+//!
+//!     0x0800_0000  bootloader (6K)
+//!     0x0800_1800  image descriptor
+//!     0x0800_2000  application (52K)
+//!     0x0800_F000  stored presets
+//!     0x0800_F800  lifetime counters
+//!
+//! Images are verified before being committed. Starting an update writes the new
+//! image's length and CRC into a freshly erased descriptor, which stops anything
+//! being booted until the whole image has been written and its CRC checked against
+//! flash, when a commit marker is added. An interrupted update leaves the bootloader
+//! waiting for another attempt.
+//!
+//! An erased descriptor means the application was programmed by a debugger, and
+//! it is booted as long as its vector table looks plausible.
+//!
+//! Requests use the command frame format (see `command`), and each is answered by
+//! a frame whose id is a `Status` code. Only the hello reply has a payload:
+//!
+//!     [ version (u16) | block size (u16) | app start (u32) | app size (u32) ]
+
+use crate::info::Crc32;
+
+/// Bootloader protocol version.
+pub const VERSION: u16 = 1;
+
+/// USART1 baud rate used by the bootloader, which runs from the 8MHz HSI.
+pub const BAUD: u32 = 115_200;
+
+/// Flash page size.
+pub const PAGE_SIZE: u32 = 2048;
+
+/// Image descriptor page.
+pub const DESCRIPTOR_ADDR: u32 = 0x0800_1800;
+
+/// Application flash region, which starts with its vector table.
+pub const APP_START: u32 = 0x0800_2000;
+pub const APP_END: u32 = 0x0800_F000;
+
+/// RAM region, for checking the application's initial stack pointer.
+const RAM_START: u32 = 0x2000_0000;
+const RAM_END: u32 = 0x2000_3000;
+
+/// Largest number of image bytes in one write request.
+pub const BLOCK_SIZE: usize = 128;
+
+/// Longest request payload: a write request's offset and data.
+pub const MAX_PAYLOAD: usize = 4 + BLOCK_SIZE;
+
+/// Descriptor markers written when an update starts and once it has been verified.
+const BEGIN_MAGIC: u32 = 0x6e676562;
+const COMMIT_MAGIC: u32 = 0x6d6d6f63;
+const ERASED: u32 = 0xFFFF_FFFF;
+
+/// Request identifiers, distinct from application command identifiers.
+pub mod id {
+    /// Check the bootloader is listening. No payload.
+    pub const HELLO: u8 = 0x20;
+
+    /// Start an update, erasing the current image. Payload: image length (u32),
+    /// which must be a multiple of 4 bytes, and image CRC-32 (u32).
+    pub const BEGIN: u8 = 0x21;
+
+    /// Write part of the image. Payload: offset into the image (u32), followed by
+    /// an even number of data bytes.
+    pub const WRITE: u8 = 0x22;
+
+    /// Verify the written image against its CRC and mark it bootable. No payload.
+    pub const COMMIT: u8 = 0x23;
+
+    /// Start the application, if it is bootable. No payload.
+    pub const BOOT: u8 = 0x24;
+}
+
+/// A decoded request.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Request<'a> {
+    Hello,
+    Begin { length: u32, crc: u32 },
+    Write { offset: u32, data: &'a [u8] },
+    Commit,
+    Boot,
+}
+
+impl<'a> Request<'a> {
+    pub fn decode(id: u8, payload: &'a [u8]) -> Option<Self> {
+        match (id, payload) {
+            (id::HELLO, &[]) => Some(Request::Hello),
+            (id::BEGIN, &[l0, l1, l2, l3, c0, c1, c2, c3]) => Some(Request::Begin {
+                length: u32::from_le_bytes([l0, l1, l2, l3]),
+                crc: u32::from_le_bytes([c0, c1, c2, c3]),
+            }),
+            (id::WRITE, &[o0, o1, o2, o3, ref data @ ..]) => Some(Request::Write {
+                offset: u32::from_le_bytes([o0, o1, o2, o3]), data,
+            }),
+            (id::COMMIT, &[]) => Some(Request::Commit),
+            (id::BOOT, &[]) => Some(Request::Boot),
+            _ => None,
+        }
+    }
+}
+
+/// Result of a request, sent as the reply frame id.
+#[repr(u8)]
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Status {
+    Ok          = 0,
+    /// Unknown request or malformed payload.
+    BadRequest  = 1,
+    /// Image length is zero, not a multiple of 4 bytes, or too large.
+    BadLength   = 2,
+    /// Write is unaligned or outside the image.
+    BadOffset   = 3,
+    /// Write or commit without a preceding begin.
+    NotStarted  = 4,
+    /// Flash erase or programming failed.
+    Flash       = 5,
+    /// Written image does not match its CRC or has an invalid vector table.
+    Verify      = 6,
+    /// No bootable image to start.
+    NoImage     = 7,
+}
+
+impl Status {
+    pub fn from_u8(x: u8) -> Option<Self> {
+        match x {
+            0 => Some(Status::Ok),
+            1 => Some(Status::BadRequest),
+            2 => Some(Status::BadLength),
+            3 => Some(Status::BadOffset),
+            4 => Some(Status::NotStarted),
+            5 => Some(Status::Flash),
+            6 => Some(Status::Verify),
+            7 => Some(Status::NoImage),
+            _ => None,
+        }
+    }
+}
+
+/// Payload of the reply to a hello request.
+pub fn hello_payload() -> [u8; 12] {
+    let mut out = [0; 12];
+    out[0..2].copy_from_slice(&VERSION.to_le_bytes());
+    out[2..4].copy_from_slice(&(BLOCK_SIZE as u16).to_le_bytes());
+    out[4..8].copy_from_slice(&APP_START.to_le_bytes());
+    out[8..12].copy_from_slice(&(APP_END - APP_START).to_le_bytes());
+    out
+}
+
+/// Flash erase or programming failure.
+#[derive(Copy, Clone, Debug)]
+pub struct FlashError;
+
+/// Flash memory being updated.
+pub trait Target {
+    /// Erase the page starting at `address`.
+    fn erase_page(&mut self, address: u32) -> Result<(), FlashError>;
+
+    /// Program `data` into erased flash starting at `address`.
+    fn program(&mut self, address: u32, data: &[u16]) -> Result<(), FlashError>;
+
+    /// Read `len` bytes of flash starting at `address`.
+    fn read(&self, address: u32, len: usize) -> &[u8];
+}
+
+fn read_u32<T: Target>(target: &T, address: u32) -> u32 {
+    let b = target.read(address, 4);
+    u32::from_le_bytes([b[0], b[1], b[2], b[3]])
+}
+
+fn halfwords(x: u32) -> [u16; 2] {
+    [x as u16, (x >> 16) as u16]
+}
+
+/// Check the application's initial stack pointer and reset vector are plausible.
+fn vectors_ok<T: Target>(target: &T) -> bool {
+    let sp = read_u32(target, APP_START);
+    let reset = read_u32(target, APP_START + 4);
+    (RAM_START..=RAM_END).contains(&sp) && sp.is_multiple_of(4)
+        && reset & 1 == 1 && (APP_START..APP_END).contains(&(reset & !1))
+}
+
+/// Returns true if the application may be booted.
+pub fn image_ok<T: Target>(target: &T) -> bool {
+    let begin = read_u32(target, DESCRIPTOR_ADDR);
+    let length = read_u32(target, DESCRIPTOR_ADDR + 4);
+    let crc = read_u32(target, DESCRIPTOR_ADDR + 8);
+    let commit = read_u32(target, DESCRIPTOR_ADDR + 12);
+    match (begin, commit) {
+        (ERASED, _) => vectors_ok(target),
+        (BEGIN_MAGIC, COMMIT_MAGIC) => {
+            length <= APP_END - APP_START
+                && Crc32::new().bytes(target.read(APP_START, length as usize)).finish() == crc
+                && vectors_ok(target)
+        },
+        _ => false,
+    }
+}
+
+/// Carries out update requests.
+pub struct Updater {
+    /// Length and CRC of the image being written.
+    image: Option<(u32, u32)>,
+}
+
+impl Updater {
+    pub const fn new() -> Self {
+        Updater { image: None }
+    }
+
+    /// Carry out `request`, returning the status to reply with.
+    ///
+    /// A boot request is only checked here; the caller must start the application
+    /// after replying if the status is `Ok`.
+    pub fn handle<T: Target>(&mut self, target: &mut T, request: Request) -> Status {
+        let result = match request {
+            Request::Hello => Ok(Status::Ok),
+            Request::Begin { length, crc } => self.begin(target, length, crc),
+            Request::Write { offset, data } => self.write(target, offset, data),
+            Request::Commit => self.commit(target),
+            Request::Boot if image_ok(target) => Ok(Status::Ok),
+            Request::Boot => Ok(Status::NoImage),
+        };
+        result.unwrap_or(Status::Flash)
+    }
+
+    fn begin<T: Target>(&mut self, target: &mut T, length: u32, crc: u32)
+        -> Result<Status, FlashError>
+    {
+        if length == 0 || !length.is_multiple_of(4) || length > APP_END - APP_START {
+            return Ok(Status::BadLength);
+        }
+        self.image = None;
+        target.erase_page(DESCRIPTOR_ADDR)?;
+        let [b0, b1] = halfwords(BEGIN_MAGIC);
+        let [l0, l1] = halfwords(length);
+        let [c0, c1] = halfwords(crc);
+        target.program(DESCRIPTOR_ADDR, &[b0, b1, l0, l1, c0, c1])?;
+        for page in 0..length.div_ceil(PAGE_SIZE) {
+            target.erase_page(APP_START + page * PAGE_SIZE)?;
+        }
+        self.image = Some((length, crc));
+        Ok(Status::Ok)
+    }
+
+    fn write<T: Target>(&mut self, target: &mut T, offset: u32, data: &[u8])
+        -> Result<Status, FlashError>
+    {
+        let length = match self.image {
+            Some((length, _)) => length,
+            None => return Ok(Status::NotStarted),
+        };
+        let end = offset.checked_add(data.len() as u32);
+        let aligned = offset.is_multiple_of(2) && data.len().is_multiple_of(2);
+        if !aligned || end.is_none_or(|end| end > length) {
+            return Ok(Status::BadOffset);
+        }
+        let mut words = [0u16; BLOCK_SIZE / 2];
+        let words = &mut words[..data.len() / 2];
+        for (w, b) in words.iter_mut().zip(data.chunks(2)) {
+            *w = u16::from_le_bytes([b[0], b[1]]);
+        }
+        target.program(APP_START + offset, words)?;
+        Ok(Status::Ok)
+    }
+
+    fn commit<T: Target>(&mut self, target: &mut T) -> Result<Status, FlashError> {
+        let (length, crc) = match self.image {
+            Some(image) => image,
+            None => return Ok(Status::NotStarted),
+        };
+        let written = Crc32::new().bytes(target.read(APP_START, length as usize)).finish();
+        if written != crc || !vectors_ok(target) {
+            return Ok(Status::Verify);
+        }
+        target.program(DESCRIPTOR_ADDR + 12, &halfwords(COMMIT_MAGIC))?;
+        self.image = None;
+        Ok(Status::Ok)
+    }
+}
+
+impl Default for Updater {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    const FLASH_START: u32 = 0x0800_0000;
+
+    /// Flash which, like the real thing, refuses to program non-erased half-words.
+    struct MockFlash {
+        mem: Vec<u8>,
+        erases: usize,
+    }
+
+    impl MockFlash {
+        fn new() -> Self {
+            MockFlash { mem: std::vec![0xFF; 64 * 1024], erases: 0 }
+        }
+
+        fn offset(address: u32) -> usize {
+            (address - FLASH_START) as usize
+        }
+    }
+
+    impl Target for MockFlash {
+        fn erase_page(&mut self, address: u32) -> Result<(), FlashError> {
+            let start = Self::offset(address);
+            self.mem[start..start + PAGE_SIZE as usize].fill(0xFF);
+            self.erases += 1;
+            Ok(())
+        }
+
+        fn program(&mut self, address: u32, data: &[u16]) -> Result<(), FlashError> {
+            let start = Self::offset(address);
+            for (idx, word) in data.iter().enumerate() {
+                let at = start + 2 * idx;
+                if self.mem[at..at + 2] != [0xFF, 0xFF] {
+                    return Err(FlashError);
+                }
+                self.mem[at..at + 2].copy_from_slice(&word.to_le_bytes());
+            }
+            Ok(())
+        }
+
+        fn read(&self, address: u32, len: usize) -> &[u8] {
+            &self.mem[Self::offset(address)..Self::offset(address) + len]
+        }
+    }
+
+    /// An image with a plausible vector table, padded to `len` bytes.
+    fn image(len: usize) -> Vec<u8> {
+        let mut image: Vec<u8> = (0..len).map(|x| x as u8).collect();
+        image[0..4].copy_from_slice(&0x2000_3000u32.to_le_bytes());
+        image[4..8].copy_from_slice(&(APP_START + 0x401).to_le_bytes());
+        image
+    }
+
+    fn update(updater: &mut Updater, flash: &mut MockFlash, image: &[u8]) -> Status {
+        let crc = Crc32::new().bytes(image).finish();
+        let begin = Request::Begin { length: image.len() as u32, crc };
+        assert_eq!(updater.handle(flash, begin), Status::Ok);
+        for (idx, block) in image.chunks(BLOCK_SIZE).enumerate() {
+            let write = Request::Write { offset: (idx * BLOCK_SIZE) as u32, data: block };
+            assert_eq!(updater.handle(flash, write), Status::Ok);
+        }
+        updater.handle(flash, Request::Commit)
+    }
+
+    #[test]
+    fn decodes_requests() {
+        assert_eq!(Request::decode(id::HELLO, &[]), Some(Request::Hello));
+        assert_eq!(Request::decode(id::BEGIN, &[0, 1, 0, 0, 4, 3, 2, 1]),
+                   Some(Request::Begin { length: 256, crc: 0x0102_0304 }));
+        assert_eq!(Request::decode(id::WRITE, &[2, 0, 0, 0, 9, 8]),
+                   Some(Request::Write { offset: 2, data: &[9, 8] }));
+        assert_eq!(Request::decode(id::WRITE, &[2, 0]), None);
+        assert_eq!(Request::decode(id::BOOT, &[1]), None);
+    }
+
+    #[test]
+    fn updates_and_boots() {
+        let mut flash = MockFlash::new();
+        let mut updater = Updater::new();
+
+        // Blank flash has nothing to boot
+        assert!(!image_ok(&flash));
+        assert_eq!(updater.handle(&mut flash, Request::Boot), Status::NoImage);
+
+        let image = image(5000);
+        assert_eq!(update(&mut updater, &mut flash, &image), Status::Ok);
+        assert_eq!(flash.erases, 1 + 3);
+        assert!(image_ok(&flash));
+        assert_eq!(updater.handle(&mut flash, Request::Boot), Status::Ok);
+
+        // Corruption after commit stops the image booting
+        flash.mem[MockFlash::offset(APP_START) + 100] ^= 1;
+        assert!(!image_ok(&flash));
+    }
+
+    #[test]
+    fn interrupted_update_does_not_boot() {
+        let mut flash = MockFlash::new();
+        let mut updater = Updater::new();
+        let image = image(1024);
+        assert_eq!(update(&mut updater, &mut flash, &image), Status::Ok);
+
+        let crc = Crc32::new().bytes(&image).finish();
+        let begin = Request::Begin { length: 1024, crc };
+        assert_eq!(updater.handle(&mut flash, begin), Status::Ok);
+        let write = Request::Write { offset: 0, data: &image[..BLOCK_SIZE] };
+        assert_eq!(updater.handle(&mut flash, write), Status::Ok);
+        assert!(!image_ok(&flash));
+
+        // Committing an incomplete image fails verification
+        assert_eq!(updater.handle(&mut flash, Request::Commit), Status::Verify);
+        assert!(!image_ok(&flash));
+    }
+
+    #[test]
+    fn rejects_bad_requests() {
+        let mut flash = MockFlash::new();
+        let mut updater = Updater::new();
+        let write = Request::Write { offset: 0, data: &[0, 0] };
+        assert_eq!(updater.handle(&mut flash, write), Status::NotStarted);
+        assert_eq!(updater.handle(&mut flash, Request::Commit), Status::NotStarted);
+        for length in [0, 6, APP_END - APP_START + 4] {
+            let begin = Request::Begin { length, crc: 0 };
+            assert_eq!(updater.handle(&mut flash, begin), Status::BadLength);
+        }
+
+        assert_eq!(updater.handle(&mut flash, Request::Begin { length: 8, crc: 0 }), Status::Ok);
+        for (offset, data) in [(1, &[0, 0][..]), (0, &[0]), (6, &[0, 0, 0, 0]), (u32::MAX, &[])] {
+            let write = Request::Write { offset, data };
+            assert_eq!(updater.handle(&mut flash, write), Status::BadOffset);
+        }
+
+        // Rewriting without erasing is reported as a flash error
+        let write = Request::Write { offset: 0, data: &[0, 0] };
+        assert_eq!(updater.handle(&mut flash, write), Status::Ok);
+        assert_eq!(updater.handle(&mut flash, write), Status::Flash);
+
+        // Images with implausible vector tables are not committed
+        let crc = Crc32::new().bytes(&[0; 8]).finish();
+        assert_eq!(updater.handle(&mut flash, Request::Begin { length: 8, crc }), Status::Ok);
+        for offset in [0, 4] {
+            let write = Request::Write { offset, data: &[0, 0, 0, 0] };
+            assert_eq!(updater.handle(&mut flash, write), Status::Ok);
+        }
+        assert_eq!(updater.handle(&mut flash, Request::Commit), Status::Verify);
+    }
+
+    /// Start and end of the FLASH region in a linker memory.x.
+    fn flash_region(memory_x: &str) -> (u32, u32) {
+        let line = memory_x.lines().find(|l| l.trim_start().starts_with("FLASH")).unwrap();
+        let field = |name: &str| line.split(name).nth(1).unwrap()
+            .trim_start_matches([' ', '=']).split([',', ' ']).next().unwrap();
+        let origin = u32::from_str_radix(field("ORIGIN").trim_start_matches("0x"), 16).unwrap();
+        let length = field("LENGTH").strip_suffix('K').unwrap().parse::<u32>().unwrap() * 1024;
+        (origin, origin + length)
+    }
+
+    #[test]
+    fn linker_regions_match_layout() {
+        let app = flash_region(include_str!("../memory.x"));
+        assert_eq!(app, (APP_START, APP_END));
+        let (start, end) = flash_region(include_str!("../../bootloader/memory.x"));
+        assert_eq!(start, FLASH_START);
+        assert!(end <= DESCRIPTOR_ADDR);
+    }
+}
@@ -0,0 +1,219 @@
+//! Light-load burst mode duty cycle strategies
+//!
+//! At light load the converter is run in HRTIM burst mode, only switching for
+//! part of each burst period, to reduce switching losses. `LightLoad` decides
+//! when burst mode is in use and computes the burst duty cycle from the chosen
+//! `Strategy`. Duty cycles range from 0 (never switching) to 1000 (always switching).
+
+/// A point on a duty cycle against output current curve.
+#[derive(Copy, Clone)]
+pub struct Breakpoint {
+    /// Output current (A)
+    pub i_out: f32,
+    /// Duty cycle at this current, 0 to 1000
+    pub duty: u16,
+}
+
+/// Settings for closed-loop regulation of output ripple.
+#[derive(Copy, Clone)]
+pub struct RippleLoop {
+    /// Target raw Vout peak-to-peak ripple (V)
+    pub target: f32,
+    /// Change in duty per volt of ripple error, each window
+    pub gain: f32,
+    /// Number of control steps per ripple measurement window.
+    /// This should span at least one burst period.
+    pub window: u16,
+    /// Lowest permitted duty cycle
+    pub min_duty: u16,
+}
+
+/// Strategy for setting burst duty cycle under light load.
+#[derive(Copy, Clone)]
+pub enum Strategy {
+    /// Always switch; burst mode is never used.
+    Off,
+    /// Interpolate duty linearly between breakpoints sorted by increasing current,
+    /// holding the end values outside the table.
+    Table(&'static [Breakpoint]),
+    /// Adjust duty to hold raw Vout ripple at a target level. Lower duty cycles
+    /// switch less often but allow more droop between bursts.
+    Ripple(RippleLoop),
+}
+
+/// Light-load burst mode controller.
+///
+/// Burst mode is entered once Vout rises above `enter` times the setpoint, and
+/// left once Vout falls below `exit` times the setpoint; the gap between them
+/// prevents chattering around the boundary. Outside burst mode the duty is 1000.
+pub struct LightLoad {
+    strategy: Strategy,
+    enter: f32,
+    exit: f32,
+    active: bool,
+    ripple_duty: f32,
+    steps: u16,
+    v_min: f32,
+    v_max: f32,
+}
+
+impl LightLoad {
+    pub const fn new(strategy: Strategy, enter: f32, exit: f32) -> Self {
+        LightLoad {
+            strategy, enter, exit, active: false, ripple_duty: 1000.0,
+            steps: 0, v_min: f32::INFINITY, v_max: f32::NEG_INFINITY,
+        }
+    }
+
+    /// Record a raw Vout sample for ripple measurement.
+    ///
+    /// Call this from the ADC ISR with unfiltered readings.
+    pub fn sample(&mut self, v_out: f32) {
+        if v_out < self.v_min {
+            self.v_min = v_out;
+        }
+        if v_out > self.v_max {
+            self.v_max = v_out;
+        }
+    }
+
+    /// Compute the burst duty cycle for the present setpoint and filtered output.
+    ///
+    /// Call this at a regular rate from the control loop.
+    pub fn duty(&mut self, v_set: f32, v_out: f32, i_out: f32) -> u16 {
+        if self.active && v_out < self.exit * v_set {
+            self.active = false;
+        } else if !self.active && v_out > self.enter * v_set {
+            self.active = true;
+            self.restart();
+        }
+
+        if !self.active {
+            return 1000;
+        }
+
+        match self.strategy {
+            Strategy::Off => 1000,
+            Strategy::Table(table) => interpolate(table, i_out),
+            Strategy::Ripple(cfg) => {
+                self.steps += 1;
+                if self.steps >= cfg.window {
+                    let ripple = self.v_max - self.v_min;
+                    let duty = self.ripple_duty + cfg.gain * (ripple - cfg.target);
+                    self.ripple_duty = duty.max(cfg.min_duty as f32).min(1000.0);
+                    self.clear_window();
+                }
+                self.ripple_duty as u16
+            },
+        }
+    }
+
+    /// Leave burst mode, for example when the converter is stopped.
+    pub fn reset(&mut self) {
+        self.active = false;
+        self.restart();
+    }
+
+    /// Returns true if burst mode is currently in use.
+    pub fn active(&self) -> bool {
+        self.active
+    }
+
+    fn restart(&mut self) {
+        self.ripple_duty = 1000.0;
+        self.clear_window();
+    }
+
+    fn clear_window(&mut self) {
+        self.steps = 0;
+        self.v_min = f32::INFINITY;
+        self.v_max = f32::NEG_INFINITY;
+    }
+}
+
+/// Linearly interpolate duty against current from a table of breakpoints.
+fn interpolate(table: &[Breakpoint], i_out: f32) -> u16 {
+    let (first, last) = match (table.first(), table.last()) {
+        (Some(first), Some(last)) => (first, last),
+        _ => return 1000,
+    };
+    if i_out <= first.i_out {
+        return first.duty;
+    }
+    for pair in table.windows(2) {
+        let (a, b) = (pair[0], pair[1]);
+        if i_out < b.i_out {
+            let d0 = a.duty as f32;
+            let d1 = b.duty as f32;
+            return (d0 + (d1 - d0) / (b.i_out - a.i_out) * (i_out - a.i_out)) as u16;
+        }
+    }
+    last.duty
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TABLE: [Breakpoint; 2] = [
+        Breakpoint { i_out: 0.002, duty: 50 },
+        Breakpoint { i_out: 0.020, duty: 1000 },
+    ];
+
+    /// The original hardcoded duty curve.
+    fn original(i_out: f32) -> u16 {
+        if i_out < 0.002 {
+            50
+        } else if i_out < 0.020 {
+            (50.0 + (1000.0-50.0)/(0.020-0.002) * (i_out - 0.002)) as u16
+        } else {
+            1000
+        }
+    }
+
+    #[test]
+    fn table_matches_original_curve() {
+        let mut ll = LightLoad::new(Strategy::Table(&TABLE), 0.95, 0.93);
+        for i in 0..300 {
+            let i_out = i as f32 * 0.0001;
+            assert_eq!(ll.duty(375.0, 370.0, i_out), original(i_out), "i_out={}", i_out);
+        }
+    }
+
+    #[test]
+    fn hysteresis() {
+        let mut ll = LightLoad::new(Strategy::Table(&TABLE), 0.95, 0.93);
+        assert_eq!(ll.duty(375.0, 355.0, 0.0), 1000);
+        assert_eq!(ll.duty(375.0, 357.0, 0.0), 50);
+        // Dipping just below the entry threshold stays in burst mode
+        assert_eq!(ll.duty(375.0, 355.0, 0.0), 50);
+        assert_eq!(ll.duty(375.0, 345.0, 0.0), 1000);
+        assert_eq!(ll.duty(375.0, 355.0, 0.0), 1000);
+        ll.duty(375.0, 375.0, 0.0);
+        ll.reset();
+        assert!(!ll.active());
+    }
+
+    #[test]
+    fn ripple_loop() {
+        let cfg = RippleLoop { target: 2.0, gain: 100.0, window: 10, min_duty: 20 };
+        let mut ll = LightLoad::new(Strategy::Ripple(cfg), 0.95, 0.93);
+        assert_eq!(ll.duty(375.0, 375.0, 0.0), 1000);
+
+        // Low ripple reduces duty each window, down to the minimum
+        for _ in 0..200 {
+            ll.sample(374.5);
+            ll.sample(375.5);
+            ll.duty(375.0, 375.0, 0.0);
+        }
+        assert_eq!(ll.duty(375.0, 375.0, 0.0), 20);
+
+        // High ripple increases duty again
+        for _ in 0..10 {
+            ll.sample(372.0);
+            ll.sample(378.0);
+            ll.duty(375.0, 375.0, 0.0);
+        }
+        assert_eq!(ll.duty(375.0, 375.0, 0.0), 420);
+    }
+}
@@ -0,0 +1,71 @@
+//! Conversions using factory calibration values
+//!
+//! The STM32F334 stores ADC readings of the temperature sensor and internal voltage
+//! reference in system memory, taken during production with VDDA=3.3V.
+//!
+//! The temperature sensor is only connected to ADC1, where it is converted as an
+//! auto-injected channel after every regular sequence rather than on demand, so it
+//! never interrupts a sequence part-way. This costs 181.5 + 12.5 = 194 ADC cycles
+//! (2.8µs) per sequence, lengthening the `DEFAULT` sampling period from 776 to 970
+//! cycles. The internal reference is converted on ADC2, at no cost to ADC1.
+
+/// Nominal analogue supply voltage, at which the calibration values were taken (V).
+pub const VDDA_NOMINAL: f32 = 3.3;
+
+/// Factory calibration values.
+#[derive(Copy, Clone)]
+pub struct FactoryCal {
+    /// Temperature sensor reading at 30°C
+    pub ts_cal1: u16,
+    /// Temperature sensor reading at 110°C
+    pub ts_cal2: u16,
+    /// Internal reference voltage reading at 30°C
+    pub vrefint_cal: u16,
+}
+
+impl FactoryCal {
+    /// Convert a temperature sensor reading to die temperature (°C).
+    pub fn die_temperature(&self, raw: u16) -> f32 {
+        let cal1 = self.ts_cal1 as f32;
+        let cal2 = self.ts_cal2 as f32;
+        30.0 + (raw as f32 - cal1) * (110.0 - 30.0) / (cal2 - cal1)
+    }
+
+    /// Estimate VDDA (V) from an internal reference voltage reading.
+    ///
+    /// Returns None if the reading gives a VDDA outside the 2.0V to 3.6V operating range,
+    /// for example before the first conversion has completed.
+    pub fn vdda(&self, raw: u16) -> Option<f32> {
+        if raw == 0 {
+            return None;
+        }
+        let vdda = VDDA_NOMINAL * self.vrefint_cal as f32 / raw as f32;
+        if (2.0..=3.6).contains(&vdda) {
+            Some(vdda)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn die_temperature() {
+        let cal = FactoryCal { ts_cal1: 1750, ts_cal2: 1330, vrefint_cal: 1520 };
+        assert_eq!(cal.die_temperature(1750), 30.0);
+        assert_eq!(cal.die_temperature(1330), 110.0);
+        assert_eq!(cal.die_temperature(1540), 70.0);
+    }
+
+    #[test]
+    fn vdda() {
+        let cal = FactoryCal { ts_cal1: 1750, ts_cal2: 1330, vrefint_cal: 1520 };
+        assert_eq!(cal.vdda(1520), Some(3.3));
+        assert!((cal.vdda(1672).unwrap() - 3.0).abs() < 1e-6);
+        assert_eq!(cal.vdda(0), None);
+        assert_eq!(cal.vdda(4095), None);
+    }
+}
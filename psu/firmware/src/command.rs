@@ -0,0 +1,325 @@
+//! Commands received over the serial link
+//!
+//! Each command is sent as a frame:
+//!
+//!     [ 0xA5 | id | len | payload (len bytes) | checksum ]
+//!
+//! where checksum is the 8-bit wrapping sum of id, len and the payload bytes.
+//! Frames with an unknown id, bad length or bad checksum are ignored.
+
+use crate::profile::ProfileId;
+use crate::scope;
+use crate::preset::{self, Maxima, Preset};
+
+/// Start of frame marker.
+pub const SYNC: u8 = 0xA5;
+
+/// Longest accepted payload, a slot number followed by a preset.
+pub const MAX_PAYLOAD: usize = 1 + preset::DATA_LEN;
+
+/// Command identifiers.
+pub mod id {
+    /// Select output profile. Payload: profile ID (u8).
+    pub const SET_PROFILE: u8 = 0x01;
+
+    /// Arm waveform capture. Payload: channel mask (u8), trigger kind (u8),
+    /// trigger signal (u8), trigger level (u16), pre-trigger frames (u16).
+    pub const SCOPE_ARM: u8 = 0x02;
+
+    /// Set telemetry rate and fields. Payload: rate in Hz (u16, 0 for poll mode),
+    /// field mask (u16).
+    pub const SET_TELEM: u8 = 0x03;
+
+    /// Send one telemetry packet now. No payload.
+    pub const POLL_TELEM: u8 = 0x04;
+
+    /// Send the telemetry field description packet. No payload.
+    pub const GET_DESCRIPTION: u8 = 0x05;
+
+    /// Send the hello packet with firmware build information. No payload.
+    pub const GET_INFO: u8 = 0x06;
+
+    /// Reset into the serial bootloader, if the converter is not running. No payload.
+    pub const ENTER_BOOTLOADER: u8 = 0x07;
+
+    /// Request the converter runs, once nRUN is also asserted. No payload.
+    pub const RUN: u8 = 0x08;
+
+    /// Withdraw the run request, stopping the converter. No payload.
+    pub const STOP: u8 = 0x09;
+
+    /// Acknowledge and clear a latched fault. No payload.
+    pub const CLEAR_FAULT: u8 = 0x0A;
+
+    /// Send the run status packet. No payload.
+    pub const GET_STATUS: u8 = 0x0B;
+
+    /// Select and apply an operating preset, if the converter is not running.
+    /// Payload: preset slot (u8).
+    pub const SELECT_PRESET: u8 = 0x0C;
+
+    /// Send the preset packet for one slot. Payload: preset slot (u8).
+    pub const GET_PRESET: u8 = 0x0D;
+
+    /// Store a preset into a slot in flash, if the converter is not running.
+    /// Payload: preset slot (u8), preset as laid out in flash without its checksum.
+    pub const STORE_PRESET: u8 = 0x0E;
+}
+
+/// A decoded command.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Command {
+    SetProfile(ProfileId),
+    ScopeArm(scope::Config),
+    SetTelemetry { rate: u16, mask: u16 },
+    PollTelemetry,
+    GetDescription,
+    GetInfo,
+    EnterBootloader,
+    Run,
+    Stop,
+    ClearFault,
+    GetStatus,
+    SelectPreset(u8),
+    GetPreset(u8),
+    StorePreset { slot: u8, preset: Preset },
+}
+
+impl Command {
+    /// Decode a command, rejecting any preset outside `maxima`.
+    fn decode(id: u8, payload: &[u8], maxima: &Maxima) -> Option<Self> {
+        match (id, payload) {
+            (id::SET_PROFILE, &[p]) => ProfileId::from_u8(p).map(Command::SetProfile),
+            (id::SCOPE_ARM, &[channels, kind, signal, l0, l1, p0, p1]) => {
+                let level = u16::from_le_bytes([l0, l1]);
+                let pre_trigger = u16::from_le_bytes([p0, p1]);
+                scope::Trigger::decode(kind, signal, level).map(|trigger|
+                    Command::ScopeArm(scope::Config { channels, trigger, pre_trigger }))
+            },
+            (id::SET_TELEM, &[r0, r1, m0, m1]) => Some(Command::SetTelemetry {
+                rate: u16::from_le_bytes([r0, r1]), mask: u16::from_le_bytes([m0, m1]),
+            }),
+            (id::POLL_TELEM, &[]) => Some(Command::PollTelemetry),
+            (id::GET_DESCRIPTION, &[]) => Some(Command::GetDescription),
+            (id::GET_INFO, &[]) => Some(Command::GetInfo),
+            (id::ENTER_BOOTLOADER, &[]) => Some(Command::EnterBootloader),
+            (id::RUN, &[]) => Some(Command::Run),
+            (id::STOP, &[]) => Some(Command::Stop),
+            (id::CLEAR_FAULT, &[]) => Some(Command::ClearFault),
+            (id::GET_STATUS, &[]) => Some(Command::GetStatus),
+            (id::SELECT_PRESET, &[slot]) => Some(Command::SelectPreset(slot)),
+            (id::GET_PRESET, &[slot]) => Some(Command::GetPreset(slot)),
+            (id::STORE_PRESET, &[slot, ref data @ ..]) => Preset::decode(data, maxima).map(|preset|
+                Command::StorePreset { slot, preset }),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+enum Stage {
+    Sync,
+    Id,
+    Len,
+    Payload,
+    Checksum,
+}
+
+/// Incremental parser for frames with payloads of up to `N` bytes.
+pub struct Framer<const N: usize> {
+    stage: Stage,
+    id: u8,
+    len: u8,
+    idx: usize,
+    sum: u8,
+    payload: [u8; N],
+}
+
+impl<const N: usize> Framer<N> {
+    pub const fn new() -> Self {
+        Framer { stage: Stage::Sync, id: 0, len: 0, idx: 0, sum: 0, payload: [0; N] }
+    }
+
+    /// Process one received byte, returning the id and payload if it completes a valid frame.
+    pub fn push(&mut self, byte: u8) -> Option<(u8, &[u8])> {
+        match self.stage {
+            Stage::Sync => {
+                if byte == SYNC {
+                    self.stage = Stage::Id;
+                }
+            },
+            Stage::Id => {
+                self.id = byte;
+                self.sum = byte;
+                self.stage = Stage::Len;
+            },
+            Stage::Len => {
+                if byte as usize > N {
+                    self.stage = Stage::Sync;
+                } else {
+                    self.len = byte;
+                    self.idx = 0;
+                    self.sum = self.sum.wrapping_add(byte);
+                    self.stage = if byte == 0 { Stage::Checksum } else { Stage::Payload };
+                }
+            },
+            Stage::Payload => {
+                self.payload[self.idx] = byte;
+                self.idx += 1;
+                self.sum = self.sum.wrapping_add(byte);
+                if self.idx == self.len as usize {
+                    self.stage = Stage::Checksum;
+                }
+            },
+            Stage::Checksum => {
+                self.stage = Stage::Sync;
+                if byte == self.sum {
+                    return Some((self.id, &self.payload[..self.len as usize]));
+                }
+            },
+        }
+        None
+    }
+}
+
+impl<const N: usize> Default for Framer<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Write a frame holding `id` and `payload` to the start of `buf`, returning its length.
+///
+/// `buf` must be at least 4 bytes longer than `payload`, which must be at most 255 bytes.
+pub fn encode(id: u8, payload: &[u8], buf: &mut [u8]) -> usize {
+    let len = payload.len();
+    buf[0] = SYNC;
+    buf[1] = id;
+    buf[2] = len as u8;
+    buf[3..3 + len].copy_from_slice(payload);
+    buf[3 + len] = buf[1..3 + len].iter().fold(0u8, |a, b| a.wrapping_add(*b));
+    len + 4
+}
+
+/// Incremental parser for command frames.
+pub struct Parser {
+    framer: Framer<MAX_PAYLOAD>,
+    maxima: Maxima,
+}
+
+impl Parser {
+    /// Create a parser which rejects presets outside `maxima`.
+    pub const fn new(maxima: Maxima) -> Self {
+        Parser { framer: Framer::new(), maxima }
+    }
+
+    /// Process one received byte, returning a command if it completes a valid frame.
+    pub fn push(&mut self, byte: u8) -> Option<Command> {
+        let maxima = &self.maxima;
+        self.framer.push(byte).and_then(|(id, payload)| Command::decode(id, payload, maxima))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(id: u8, payload: &[u8]) -> std::vec::Vec<u8> {
+        let mut f = std::vec![0; payload.len() + 4];
+        encode(id, payload, &mut f);
+        f
+    }
+
+    const MAXIMA: Maxima = Maxima {
+        v_out: 420.0, i_lim: 0.1, i_in_max: 3.5, v_in_min: 10.0, v_in_max: 30.0, temp_max: 85.0,
+    };
+
+    fn parse(bytes: &[u8]) -> std::vec::Vec<Command> {
+        let mut p = Parser::new(MAXIMA);
+        bytes.iter().filter_map(|b| p.push(*b)).collect()
+    }
+
+    #[test]
+    fn set_profile() {
+        let f = frame(id::SET_PROFILE, &[2]);
+        assert_eq!(f, [SYNC, 0x01, 1, 2, 0x04]);
+        assert_eq!(parse(&f), [Command::SetProfile(ProfileId::Hold)]);
+    }
+
+    #[test]
+    fn scope_arm() {
+        let f = frame(id::SCOPE_ARM, &[0b0011, 3, 1, 0x34, 0x12, 0x00, 0x01]);
+        let trigger = scope::Trigger::Rising(crate::sampling::Signal::VOut, 0x1234);
+        let config = scope::Config { channels: 0b0011, trigger, pre_trigger: 256 };
+        assert_eq!(parse(&f), [Command::ScopeArm(config)]);
+    }
+
+    #[test]
+    fn telemetry() {
+        let f = frame(id::SET_TELEM, &[0xE8, 0x03, 0x05, 0x00]);
+        assert_eq!(parse(&f), [Command::SetTelemetry { rate: 1000, mask: 5 }]);
+        assert_eq!(parse(&frame(id::POLL_TELEM, &[])), [Command::PollTelemetry]);
+        assert_eq!(parse(&frame(id::GET_DESCRIPTION, &[])), [Command::GetDescription]);
+        assert_eq!(parse(&frame(id::GET_INFO, &[])), [Command::GetInfo]);
+    }
+
+    #[test]
+    fn enter_bootloader() {
+        let f = frame(id::ENTER_BOOTLOADER, &[]);
+        assert_eq!(parse(&f), [Command::EnterBootloader]);
+        assert!(parse(&frame(id::ENTER_BOOTLOADER, &[0])).is_empty());
+    }
+
+    #[test]
+    fn run_control() {
+        assert_eq!(parse(&frame(id::RUN, &[])), [Command::Run]);
+        assert_eq!(parse(&frame(id::STOP, &[])), [Command::Stop]);
+        assert_eq!(parse(&frame(id::CLEAR_FAULT, &[])), [Command::ClearFault]);
+        assert_eq!(parse(&frame(id::GET_STATUS, &[])), [Command::GetStatus]);
+    }
+
+    #[test]
+    fn presets() {
+        use crate::control::Limits;
+        use crate::pid::Gains;
+        use crate::profile::Profile;
+        use crate::state::ToBytes;
+
+        let profile = Profile { v_set: 50.0, v_lim: 70.0, v_min: 40.0, slew: 500.0 };
+        let limits = Limits {
+            i_lim: 0.02, i_cc: 0.015, cc_time: 0.5, v_in_min: 10.0, v_in_max: 30.0,
+            i_in_derate: 0.8, i_in_max: 1.0, temp_max: 85.0, temp_restart: 70.0,
+        };
+        let gains = Gains { k_p: 20.0, k_i: 120.0, k_d: 20.0 };
+        let preset = Preset::new("bench-lowV", profile, profile, limits, gains, ProfileId::Hold);
+        let mut payload = std::vec![2];
+        payload.extend_from_slice(&preset.to_bytes()[..preset::DATA_LEN]);
+        assert_eq!(payload.len(), MAX_PAYLOAD);
+        assert_eq!(parse(&frame(id::STORE_PRESET, &payload)),
+                   [Command::StorePreset { slot: 2, preset }]);
+        assert!(parse(&frame(id::STORE_PRESET, &payload[..MAX_PAYLOAD - 1])).is_empty());
+        let high = Preset::new("high", Profile { v_lim: 450.0, ..profile }, profile, limits, gains,
+                               ProfileId::Hold);
+        payload.truncate(1);
+        payload.extend_from_slice(&high.to_bytes()[..preset::DATA_LEN]);
+        assert!(parse(&frame(id::STORE_PRESET, &payload)).is_empty());
+        assert_eq!(parse(&frame(id::SELECT_PRESET, &[1])), [Command::SelectPreset(1)]);
+        assert_eq!(parse(&frame(id::GET_PRESET, &[3])), [Command::GetPreset(3)]);
+    }
+
+    #[test]
+    fn rejects_bad_frames() {
+        let mut f = frame(id::SET_PROFILE, &[1]);
+        *f.last_mut().unwrap() ^= 1;
+        assert!(parse(&f).is_empty());
+        assert!(parse(&frame(id::SET_PROFILE, &[7])).is_empty());
+        assert!(parse(&frame(0x7F, &[])).is_empty());
+    }
+
+    #[test]
+    fn resynchronises() {
+        let mut bytes = std::vec![0x00, SYNC, 0xFF, 0xF0];
+        bytes.extend(frame(id::SET_PROFILE, &[0]));
+        assert_eq!(parse(&bytes), [Command::SetProfile(ProfileId::Off)]);
+    }
+}
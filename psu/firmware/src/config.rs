@@ -0,0 +1,373 @@
+//! Firmware configuration
+//!
+//! Every setting the firmware is built with, shared with the simulator so both run
+//! the same profiles, limits, gains and presets, and report the same CONFIG_CRC.
+
+use crate::{pid, burst, profile, sampling, selftest, telemetry, info, timing, control,
+            preset};
+
+/// Strike profile, used to ignite the panels.
+/// Typically 370V. The overvoltage limit has some filtering.
+pub const PROFILE_STRIKE: profile::Profile = profile::Profile {
+    v_set: 375.0, v_lim: 420.0, v_min: 330.0, slew: 2000.0,
+};
+
+/// Hold profile, used to sustain the discharge.
+/// The panels require 210-240V once struck.
+pub const PROFILE_HOLD: profile::Profile = profile::Profile {
+    v_set: 225.0, v_lim: 280.0, v_min: 190.0, slew: 2000.0,
+};
+
+/// Off profile, holding the output at zero while running.
+pub const PROFILE_OFF: profile::Profile = profile::Profile {
+    v_set: 0.0, v_lim: 420.0, v_min: f32::NEG_INFINITY, slew: 2000.0,
+};
+
+/// Select strike or hold profile from the sync input (PB5) from the display driver.
+/// When enabled the sync input also sets the profile at power on.
+pub const PROFILE_SYNC: bool = false;
+
+/// Overcurrent limit before a fault is triggered (A).
+/// This is slightly filtered.
+pub const I_LIM: f32 = 0.100;
+
+/// Output current held by constant-current mode, which lowers the voltage setpoint
+/// so strike transients are regulated rather than tripping I_LIM (A).
+/// Zero disables constant-current mode, which is the default; to enable it, set a
+/// current below I_LIM here or in a preset's limits.
+pub const I_CC: f32 = 0.0;
+
+/// Time the output may stay current limited before folding back (s).
+pub const CC_TIME: f32 = 0.5;
+
+/// Rate at which the constant-current loop lowers the voltage setpoint,
+/// in volts per second for each amp of output current above I_CC.
+/// 50000 removes 2V every 1ms at 40mA over.
+pub const CC_GAIN: f32 = 50_000.0;
+
+/// Fraction of I_CC held once folded back, until the load releases.
+pub const CC_FOLDBACK: f32 = 0.25;
+
+/// Timeout after which VOut must be at least the profile's v_min (system clock cycles).
+pub const V_TIMEOUT: u32 = 500_000_000;
+
+/// Minimum permitted input voltage (V).
+pub const VIN_MIN: f32 = 18.0;
+
+/// Maximum permitted input voltage (V).
+pub const VIN_MAX: f32 = 30.0;
+
+/// Input current above which the current reference ceiling is reduced (A).
+pub const IIN_DERATE: f32 = 3.0;
+
+/// Input current which triggers a fault, should derating fail to hold it below (A).
+pub const IIN_MAX: f32 = 3.5;
+
+/// Rate at which derating reduces the current reference ceiling, as a fraction of
+/// IREF_MAX per second for each amp of input current above IIN_DERATE.
+/// 20 removes 10% of the ceiling every 10ms at 0.5A over.
+pub const DERATE_GAIN: f32 = 20.0;
+
+/// Die temperature above which a fault is triggered (°C).
+pub const TEMP_MAX: f32 = 85.0;
+
+/// Die temperature below which the PSU may be started (°C).
+pub const TEMP_RESTART: f32 = 70.0;
+
+/// Smoothing factor applied to each new VDDA estimate, from 0 (never update) to 1 (no filtering).
+pub const VDDA_ALPHA: f32 = 0.1;
+
+/// DAC level for DCM detection on COMP4.
+/// Measured 1.28V at Vq(adc) node in midpoint of falling edge at DCM.
+/// 1.28/3.30 * 4096 = 1589
+pub const DCM_THRESHOLD: u16 = 1589;
+
+/// Limits which fault the converter while running or prevent it starting.
+pub const LIMITS: control::Limits = control::Limits {
+    i_lim: I_LIM, i_cc: I_CC, cc_time: CC_TIME, v_in_min: VIN_MIN, v_in_max: VIN_MAX,
+    i_in_derate: IIN_DERATE, i_in_max: IIN_MAX, temp_max: TEMP_MAX, temp_restart: TEMP_RESTART,
+};
+
+/// Bench profile, for debugging at a reduced output voltage.
+pub const PROFILE_BENCH: profile::Profile = profile::Profile {
+    v_set: 50.0, v_lim: 70.0, v_min: 40.0, slew: 500.0,
+};
+
+/// Limits for running from a current-limited bench supply, which may be as low as 12V.
+pub const LIMITS_BENCH: control::Limits = control::Limits {
+    i_lim: 0.020, v_in_min: 10.0, v_in_max: 30.0, i_in_derate: 1.2, i_in_max: 1.5,
+    ..LIMITS
+};
+
+/// Limits checked by the self-test before the converter is first started.
+/// The input voltage range is replaced by the active preset's.
+pub const SELFTEST_LIMITS: selftest::Limits = selftest::Limits {
+    v_out_max: 20.0, v_in_min: VIN_MIN, v_in_max: VIN_MAX, dac_tolerance: 50, comp_margin: 200,
+};
+
+/// Software run request at power on. When true the converter starts as soon as nRUN
+/// is asserted; when false it also waits for a run command over the serial link.
+pub const RUN_REQUEST: bool = true;
+
+/// Maximum control signal. Absolute maximum is 4095.
+/// This controls the per-cycle current limit, where 3800=6A.
+pub const IREF_MAX: i16 = 3800;
+
+/// Proportional gain
+pub const K_P: f32 = 20.0;
+
+/// Integral gain
+pub const K_I: f32 = 120.0;
+
+/// Derivative gain
+pub const K_D: f32 = 20.0;
+
+/// PID gains, as used by the built-in presets.
+pub const GAINS: pid::Gains = pid::Gains { k_p: K_P, k_i: K_I, k_d: K_D };
+
+/// Limits on integral gain.
+/// Since we expect the final control signal to be significantly integral based,
+/// set a high limit sufficient to reach the maximum control value.
+pub const I_MAX: f32 = (IREF_MAX as f32) / K_I;
+pub const I_MIN: f32 = -I_MAX;
+
+/// Built-in operating presets, used for each slot with no preset stored in flash.
+/// Each preset also selects the profile in use when it is applied.
+pub const PRESETS: [preset::Preset; preset::COUNT] = [
+    // IGG1 display enclosure
+    preset::Preset::new("IGG1-strike", PROFILE_STRIKE, PROFILE_HOLD, LIMITS, GAINS,
+                        profile::ProfileId::Strike),
+    preset::Preset::new("IGG1-hold", PROFILE_STRIKE, PROFILE_HOLD, LIMITS, GAINS,
+                        profile::ProfileId::Hold),
+    // Bench supply, at reduced or full output voltage
+    preset::Preset::new("bench-lowV", PROFILE_BENCH, PROFILE_BENCH, LIMITS_BENCH, GAINS,
+                        profile::ProfileId::Strike),
+    preset::Preset::new("bench-highV", PROFILE_STRIKE, PROFILE_HOLD, LIMITS_BENCH, GAINS,
+                        profile::ProfileId::Strike),
+];
+
+/// Absolute maxima for any preset, whether built in, stored in flash, or set over
+/// the serial link or Modbus. The input voltage floor is the bench limits', which
+/// run from supplies as low as 12V.
+pub const MAXIMA: preset::Maxima = preset::Maxima {
+    v_out: PROFILE_STRIKE.v_lim, i_lim: I_LIM, i_in_max: IIN_MAX,
+    v_in_min: LIMITS_BENCH.v_in_min, v_in_max: VIN_MAX, temp_max: TEMP_MAX,
+};
+
+/// Preset slot in use at power on.
+pub const PRESET_DEFAULT: u8 = 0;
+
+/// Preset slot in use at power on when the preset strap (PA11 to ground) is fitted.
+pub const PRESET_STRAP: u8 = 2;
+
+/// Flash page holding stored presets, just below the lifetime counters.
+pub const PRESETS_ADDR: u32 = 0x0800_F000;
+
+/// Light-load burst mode strategy.
+/// Burst mode is entered when Vout rises above BURST_ENTER times the setpoint,
+/// and left when Vout falls below BURST_EXIT times the setpoint.
+pub const BURST_STRATEGY: burst::Strategy = burst::Strategy::Table(&[
+    // Set to 5% below 2mA, scaling to 100% at 20mA and above
+    burst::Breakpoint { i_out: 0.002, duty: 50 },
+    burst::Breakpoint { i_out: 0.020, duty: 1000 },
+]);
+pub const BURST_ENTER: f32 = 0.95;
+pub const BURST_EXIT: f32 = 0.93;
+
+/// Burst mode clock prescaler, as a power of two division of f_HRTIM.
+/// 9 gives f_HRTIM/512 = 68kHz.
+pub const BURST_PRESCALER: u8 = 9;
+
+/// Burst mode period in burst clock counts.
+/// 1000 counts at 68kHz gives a 68Hz burst period.
+pub const BURST_PERIOD: u16 = 1000;
+
+/// ADC1 sampling profile. The Kalman filters run at the profile's decimated sample rate.
+pub const SAMPLING: sampling::SamplingProfile = sampling::DEFAULT;
+
+/// Waveform capture buffer length in samples, shared between the selected channels.
+pub const SCOPE_SAMPLES: usize = 2048;
+
+/// Number of state snapshots kept for reporting after a fault.
+pub const HISTORY_LEN: usize = 50;
+
+/// Control loop steps per state snapshot. 20 gives 2ms per snapshot, so 100ms of history.
+pub const HISTORY_DECIMATION: u16 = 20;
+
+/// Time constant for filtering input and output power in efficiency estimates (s).
+pub const ENERGY_TAU: f32 = 1.0;
+
+/// Flash page holding the lifetime counter log: the last page of the 64K flash.
+pub const COUNTERS_ADDR: u32 = 0x0800_F800;
+
+/// Use fixed steady-state gains for the Vout and Iout Kalman filters.
+/// This skips the covariance updates in the ADC ISR; see `kalman::Kalman`.
+pub const KALMAN_STEADY_STATE: bool = true;
+
+/// Process and measurement noise (Q, R) for the Vout Kalman filter.
+pub const VOUT_KALMAN: (f32, f32) = (1e6, 1e0);
+
+/// Process and measurement noise (Q, R) for the Iout Kalman filter.
+pub const IOUT_KALMAN: (f32, f32) = (1e1, 1e-5);
+
+/// Telemetry rate at power on (Hz), or 0 to only send when polled.
+pub const TELEM_RATE: u16 = 10;
+
+/// Telemetry fields at power on. With all fields selected the full state packet is sent.
+pub const TELEM_MASK: u16 = telemetry::ALL;
+
+/// Transmit raw ADC readings from one position in the sampling sequence on every conversion,
+/// instead of telemetry packets.
+pub const TELEM_ADC_DIRECT: bool = false;
+pub const TELEM_ADC_CH: usize = 0;
+
+/// Heartbeats per task profiling report; 50 gives one report a second.
+pub const PROFILE_INTERVAL: u32 = 50;
+
+/// Events queued while the serial link is busy before further events are dropped.
+pub const EVENT_QUEUE_LEN: usize = 16;
+
+/// Serve Modbus RTU on the serial link at timing::MODBUS_BAUD instead of sending
+/// telemetry and receiving commands. See MODBUS.md for the register map.
+pub const MODBUS: bool = false;
+
+/// Modbus slave address, from 1 to 247.
+pub const MODBUS_ADDRESS: u8 = 1;
+
+/// Checksum of the configuration constants above, reported in the hello packet
+/// so boards running the same source with different settings can be told apart.
+pub const CONFIG_CRC: u32 = {
+    let off = PROFILE_OFF;
+    let mut crc = info::Crc32::new().f32(off.v_set).f32(off.v_lim).f32(off.v_min).f32(off.slew);
+    let mut i = 0;
+    while i < PRESETS.len() {
+        let p = &PRESETS[i];
+        let (s, h, l, g) = (p.strike, p.hold, p.limits, p.gains);
+        crc = crc.bytes(&p.name).u32(p.profile as u32)
+                 .f32(s.v_set).f32(s.v_lim).f32(s.v_min).f32(s.slew)
+                 .f32(h.v_set).f32(h.v_lim).f32(h.v_min).f32(h.slew)
+                 .f32(l.i_lim).f32(l.i_cc).f32(l.cc_time).f32(l.v_in_min).f32(l.v_in_max)
+                 .f32(l.i_in_derate).f32(l.i_in_max).f32(l.temp_max).f32(l.temp_restart).f32(g.k_p).f32(g.k_i).f32(g.k_d);
+        i += 1;
+    }
+    crc.u32(PRESET_DEFAULT as u32).u32(PRESET_STRAP as u32).u32(PROFILE_SYNC as u32)
+       .u32(V_TIMEOUT).f32(VDDA_ALPHA).u32(DCM_THRESHOLD as u32).u32(RUN_REQUEST as u32)
+       .u32(IREF_MAX as u32).f32(DERATE_GAIN).f32(CC_GAIN).f32(CC_FOLDBACK)
+       .f32(timing::CTRL_DT).u32(timing::F_SYSCLK)
+       .f32(BURST_ENTER).f32(BURST_EXIT).u32(BURST_PRESCALER as u32).u32(BURST_PERIOD as u32)
+       .u32(SAMPLING.sample_time as u32).u32(SAMPLING.decimation as u32)
+       .u32(SCOPE_SAMPLES as u32).u32(HISTORY_LEN as u32).u32(HISTORY_DECIMATION as u32)
+       .f32(ENERGY_TAU).u32(KALMAN_STEADY_STATE as u32)
+       .f32(VOUT_KALMAN.0).f32(VOUT_KALMAN.1).f32(IOUT_KALMAN.0).f32(IOUT_KALMAN.1)
+       .u32(TELEM_RATE as u32).u32(TELEM_MASK as u32).u32(TELEM_ADC_DIRECT as u32)
+       .u32(EVENT_QUEUE_LEN as u32).u32(MODBUS as u32).u32(MODBUS_ADDRESS as u32)
+       .finish()
+};
+
+/// Telemetry, events and replies to commands are only sent while the serial link
+/// carries packets rather than raw ADC readings or Modbus.
+pub const PACKETS: bool = !TELEM_ADC_DIRECT && !MODBUS;
+
+const _: () = assert!(!(MODBUS && TELEM_ADC_DIRECT));
+const _: () = assert!(MODBUS_ADDRESS >= 1 && MODBUS_ADDRESS <= 247);
+
+// The start timeout is checked with elapsed(), which panics after 2^31 cycles.
+const _: () = assert!(V_TIMEOUT < 1 << 31);
+
+// Scope capture lengths and pre-trigger counts are stored and reported as u16.
+const _: () = assert!(SCOPE_SAMPLES <= u16::MAX as usize);
+
+// Each sequence must convert every feedback signal once, and must not end more
+// often than with the default sample time, as the ADC ISR runs after each one.
+const _: () = assert!(SAMPLING.is_valid());
+const _: () = assert!(SAMPLING.sequence_period() >= sampling::DEFAULT.sequence_period());
+
+// Every built-in preset must be within the absolute maxima.
+const _: () = {
+    let mut i = 0;
+    while i < PRESETS.len() {
+        assert!(MAXIMA.allows(&PRESETS[i]));
+        i += 1;
+    }
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::Cell;
+    use crate::control::{protect, CurrentLimit};
+    use crate::hw::BurstPwm;
+    use crate::state::{CcMode, FaultCode, FaultState, State};
+
+    #[derive(Default)]
+    struct MockPwm {
+        enabled: Cell<bool>,
+    }
+
+    impl BurstPwm for MockPwm {
+        fn enable(&self) {
+            self.enabled.set(true);
+        }
+
+        fn disable(&self) {
+            self.enabled.set(false);
+        }
+
+        fn set_duty(&self, _duty: u16) {}
+    }
+
+    /// Run `protect` once with the default limits and strike profile, after `modify`,
+    /// returning the fault code if the converter was stopped.
+    fn protect_default(modify: fn(&mut State), start_elapsed: bool) -> Option<FaultCode> {
+        let mut profiles = profile::Profiles::new(PROFILE_OFF, PROFILE_STRIKE, PROFILE_HOLD,
+                                                  profile::ProfileId::Strike);
+        profiles.reset();
+        let mut state = State::new();
+        state.v_in = 24.0;
+        state.i_in = 1.0;
+        state.v_out = PROFILE_STRIKE.v_set;
+        state.set_state_running();
+        let pwm = MockPwm::default();
+        pwm.enable();
+
+        modify(&mut state);
+        protect(&mut state, &LIMITS, &profiles, start_elapsed, &pwm);
+        assert_eq!(state.fault_state == FaultState::Fault, !pwm.enabled.get());
+        if pwm.enabled.get() {
+            None
+        } else {
+            Some(state.fault_code)
+        }
+    }
+
+    #[test]
+    fn constant_current_disabled_by_default() {
+        assert_eq!(LIMITS.i_cc, 0.0);
+        assert!(PRESETS.iter().all(|p| p.limits.i_cc == 0.0));
+
+        // However high the output current, the voltage setpoint is left alone
+        let mut current_limit = CurrentLimit::new(CC_GAIN, CC_FOLDBACK);
+        current_limit.configure(LIMITS.i_cc, LIMITS.cc_time);
+        let v_set = PROFILE_STRIKE.v_set;
+        for _ in 0..1000 {
+            assert_eq!(current_limit.step(v_set, 1.0, 1e-3), v_set);
+        }
+        assert_eq!(current_limit.mode(), CcMode::Voltage);
+    }
+
+    #[test]
+    fn default_protection_faults() {
+        // With constant-current mode disabled, I_LIM and v_min trip as fixed limits
+        assert_eq!(protect_default(|_| (), true), None);
+        assert_eq!(protect_default(|s| s.i_out = 0.099, true), None);
+        assert_eq!(protect_default(|s| s.i_out = I_LIM, false), Some(FaultCode::ILim));
+        assert_eq!(protect_default(|s| s.v_out = PROFILE_STRIKE.v_lim, false),
+                   Some(FaultCode::VLim));
+        assert_eq!(protect_default(|s| s.v_in = VIN_MIN, false), Some(FaultCode::VInLow));
+        assert_eq!(protect_default(|s| s.v_in = VIN_MAX, false), Some(FaultCode::VInHigh));
+        assert_eq!(protect_default(|s| s.i_in = IIN_MAX, false), Some(FaultCode::IInHigh));
+        assert_eq!(protect_default(|s| s.v_out = PROFILE_STRIKE.v_min, false), None);
+        assert_eq!(protect_default(|s| s.v_out = PROFILE_STRIKE.v_min, true),
+                   Some(FaultCode::NoVOut));
+    }
+}
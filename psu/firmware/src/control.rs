@@ -0,0 +1,756 @@
+//! Output regulation, protection and the run state machine
+//!
+//! These hold the logic of the firmware's control loop, ADC and heartbeat tasks,
+//! which drive the hardware only through the `hw` traits.
+
+use crate::state::{State, FaultState, FaultCode, CcMode};
+use crate::hw::{CurrentRef, BurstPwm, RunInput, StatusLeds};
+use crate::pid::{PID, Gains};
+use crate::burst::LightLoad;
+use crate::profile::Profiles;
+use crate::run::RunControl;
+
+/// Operating limits which trigger a fault.
+#[repr(C)]
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Limits {
+    /// Filtered output current limit (A), which trips in every constant-current mode.
+    pub i_lim: f32,
+    /// Output current regulated by constant-current mode, below `i_lim`,
+    /// or zero to disable constant-current mode (A).
+    pub i_cc: f32,
+    /// Time the output may stay current limited before folding back (s).
+    pub cc_time: f32,
+    /// Permitted input voltage range (V).
+    pub v_in_min: f32,
+    pub v_in_max: f32,
+    /// Input current above which the current reference is derated (A).
+    pub i_in_derate: f32,
+    /// Input current limit, as a last resort should derating not hold the input
+    /// current below it (A).
+    pub i_in_max: f32,
+    /// Die temperature limit while running (°C).
+    pub temp_max: f32,
+    /// Die temperature below which the converter may start (°C).
+    pub temp_restart: f32,
+}
+
+impl Limits {
+    /// Returns true if the input voltage range is not empty, derating starts below the
+    /// input current limit and any constant-current limit is below the output current limit.
+    pub fn valid(&self) -> bool {
+        self.v_in_min < self.v_in_max && self.i_in_derate < self.i_in_max
+        && (0.0..self.i_lim).contains(&self.i_cc) && self.cc_time >= 0.0
+    }
+}
+
+/// Input current derating.
+///
+/// Integrates the input current's excess over the derating threshold into a scale
+/// factor for the current reference ceiling, which recovers at the same rate once
+/// the input current is back below the threshold. A heavy load or a soft input
+/// supply then reduces the output power instead of tripping the input current limit.
+pub struct Derating {
+    i_in_derate: f32,
+    gain: f32,
+    scale: f32,
+}
+
+impl Derating {
+    /// Create a new derating loop starting at `i_in_derate` amps, where `gain` is the
+    /// change in scale per second for each amp of excess input current.
+    pub const fn new(i_in_derate: f32, gain: f32) -> Self {
+        Derating { i_in_derate, gain, scale: 1.0 }
+    }
+
+    /// Change the input current above which derating begins (A).
+    pub fn set_threshold(&mut self, i_in_derate: f32) {
+        self.i_in_derate = i_in_derate;
+    }
+
+    /// Update from the present input current, returning the new scale factor
+    /// from 0 to 1 for the current reference ceiling.
+    pub fn step(&mut self, i_in: f32, dt: f32) -> f32 {
+        let scale = self.scale + self.gain * (self.i_in_derate - i_in) * dt;
+        // Written so that a NaN reading derates fully
+        self.scale = if scale > 0.0 { scale.min(1.0) } else { 0.0 };
+        self.scale
+    }
+
+    pub fn reset(&mut self) {
+        self.scale = 1.0;
+    }
+}
+
+/// Constant-current limit on the output.
+///
+/// An outer loop which integrates the output current's excess over the limit into
+/// a ceiling on the voltage setpoint, so the voltage loop regulates the output
+/// current whenever the limit rather than the setpoint is the active constraint.
+/// Should the output stay current limited for longer than the time limit, the limit
+/// folds back to a fraction of itself until the load releases and the output
+/// returns to voltage regulation.
+pub struct CurrentLimit {
+    i_cc: f32,
+    t_max: f32,
+    gain: f32,
+    foldback: f32,
+    v_ceiling: f32,
+    t_limiting: f32,
+    folded: bool,
+}
+
+impl CurrentLimit {
+    /// Create a new current limit, disabled until configured, where `gain` is the
+    /// change in voltage ceiling per second for each amp of excess output current
+    /// and `foldback` the fraction of the limit held once folded back.
+    pub const fn new(gain: f32, foldback: f32) -> Self {
+        CurrentLimit {
+            i_cc: 0.0, t_max: 0.0, gain, foldback,
+            v_ceiling: f32::INFINITY, t_limiting: 0.0, folded: false,
+        }
+    }
+
+    /// Change the regulated output current (A), or disable with zero,
+    /// and the time permitted at it before folding back (s).
+    pub fn configure(&mut self, i_cc: f32, t_max: f32) {
+        self.i_cc = i_cc;
+        self.t_max = t_max;
+        self.reset();
+    }
+
+    /// Update from the voltage setpoint and filtered output current, returning
+    /// the voltage reference for the voltage loop.
+    pub fn step(&mut self, v_set: f32, i_out: f32, dt: f32) -> f32 {
+        if self.i_cc <= 0.0 {
+            return v_set;
+        }
+
+        let i_lim = if self.folded { self.i_cc * self.foldback } else { self.i_cc };
+        let v_ceiling = self.v_ceiling + self.gain * (i_lim - i_out) * dt;
+        // Written so that a NaN reading lowers the ceiling fully
+        self.v_ceiling = if v_ceiling > 0.0 { v_ceiling.min(v_set) } else { 0.0 };
+
+        if self.v_ceiling < v_set {
+            self.t_limiting += dt;
+            if self.t_limiting >= self.t_max {
+                self.folded = true;
+            }
+        } else {
+            self.t_limiting = 0.0;
+            self.folded = false;
+        }
+        self.v_ceiling
+    }
+
+    pub fn mode(&self) -> CcMode {
+        if self.folded {
+            CcMode::Foldback
+        } else if self.t_limiting > 0.0 {
+            CcMode::Limiting
+        } else {
+            CcMode::Voltage
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.v_ceiling = f32::INFINITY;
+        self.t_limiting = 0.0;
+        self.folded = false;
+    }
+}
+
+/// Output voltage regulator.
+///
+/// A PID loop on the filtered output voltage sets the peak current reference,
+/// and the light-load controller reduces the burst duty cycle once regulated.
+/// The current reference ceiling is lowered by input current derating, and the
+/// voltage setpoint by the output's constant-current limit.
+pub struct Regulator {
+    pid: PID,
+    light_load: LightLoad,
+    derating: Derating,
+    current_limit: CurrentLimit,
+    iref_max: i16,
+    dt: f32,
+}
+
+impl Regulator {
+    /// Create a new regulator run every `dt` seconds, limiting the current
+    /// reference to `iref_max` DAC counts.
+    pub const fn new(
+        pid: PID, light_load: LightLoad, derating: Derating, current_limit: CurrentLimit,
+        iref_max: i16, dt: f32,
+    ) -> Self {
+        Regulator { pid, light_load, derating, current_limit, iref_max, dt }
+    }
+
+    /// Change the input current above which the current reference is derated (A).
+    pub fn set_derating(&mut self, i_in_derate: f32) {
+        self.derating.set_threshold(i_in_derate);
+    }
+
+    /// Change the constant-current limit (A), or disable it with zero,
+    /// and the time permitted at it before folding back (s).
+    pub fn set_current_limit(&mut self, i_cc: f32, cc_time: f32) {
+        self.current_limit.configure(i_cc, cc_time);
+    }
+
+    /// Change the PID gains, setting the integrator limits so the integral term
+    /// alone can reach the maximum current reference.
+    pub fn set_gains(&mut self, gains: Gains) {
+        let i_max = self.iref_max as f32 / gains.k_i;
+        self.pid.set_gains(gains, -i_max, i_max);
+    }
+
+    /// Record a raw output voltage sample for light-load ripple measurement.
+    pub fn sample(&mut self, v_out: f32) {
+        self.light_load.sample(v_out);
+    }
+
+    /// Run one control loop step, given the filtered output voltage and its derivative.
+    ///
+    /// While running the setpoint slews towards the active profile, is limited by the
+    /// constant-current loop, and the current reference, its derated ceiling and the
+    /// burst duty are updated. Otherwise the controller is reset and the current
+    /// reference held at zero.
+    pub fn step<D: CurrentRef, P: BurstPwm>(
+        &mut self, state: &mut State, profiles: &mut Profiles, v_out: f32, dv_out: f32,
+        dac: &D, pwm: &P,
+    ) {
+        match state.fault_state {
+            FaultState::Running => {
+                let v_set = profiles.step(self.dt, v_out);
+                let v_ref = self.current_limit.step(v_set, state.i_out, self.dt);
+                state.update_cc_mode(self.current_limit.mode());
+                let derate = self.derating.step(state.i_in, self.dt);
+                let ceiling = (self.iref_max as f32 * derate) as i16;
+                let action = (self.pid.control_step(v_ref, v_out, dv_out) as i16)
+                             .clamp(0, ceiling);
+                state.update_derate(derate);
+                dac.set_i_ref(action as u16);
+                state.update_ref_i_q(action as u16);
+
+                // Full duty while charging, then reduced under light load
+                let duty = self.light_load.duty(v_ref, v_out, state.i_out);
+                pwm.set_duty(duty);
+                state.update_duty(duty);
+            },
+
+            FaultState::Stopped | FaultState::Fault => {
+                self.pid.zero();
+                self.light_load.reset();
+                self.derating.reset();
+                self.current_limit.reset();
+                state.update_derate(1.0);
+                state.update_cc_mode(CcMode::Voltage);
+                dac.set_i_ref(0);
+                state.update_ref_i_q(0);
+            },
+        }
+
+        state.update_pid_i(self.pid.get_i());
+        state.update_profile(profiles.active());
+    }
+}
+
+/// Check the filtered outputs and the inputs against their limits while running.
+///
+/// Any violation latches a fault and stops switching. The output must have reached
+/// the profile's minimum voltage once `start_elapsed` is set, after the start timeout,
+/// unless it is held lower by the constant-current limit. The output current limit
+/// trips whether or not the constant-current loop is regulating, which only holds
+/// currents between `i_cc` and `i_lim`.
+pub fn protect<P: BurstPwm>(
+    state: &mut State, limits: &Limits, profiles: &Profiles, start_elapsed: bool, pwm: &P,
+) {
+    if state.fault_state != FaultState::Running {
+        return;
+    }
+
+    let mut fault = false;
+    if state.v_out >= profiles.v_lim() {
+        state.set_fault(FaultCode::VLim);
+        fault = true;
+    }
+    if state.i_out >= limits.i_lim {
+        state.set_fault(FaultCode::ILim);
+        fault = true;
+    }
+    if state.v_in <= limits.v_in_min {
+        state.set_fault(FaultCode::VInLow);
+        fault = true;
+    } else if state.v_in >= limits.v_in_max {
+        state.set_fault(FaultCode::VInHigh);
+        fault = true;
+    }
+    if state.i_in >= limits.i_in_max {
+        state.set_fault(FaultCode::IInHigh);
+        fault = true;
+    }
+    if start_elapsed && state.cc_mode == CcMode::Voltage && state.v_out <= profiles.v_min() {
+        state.set_fault(FaultCode::NoVOut);
+        fault = true;
+    }
+    if fault {
+        state.set_state_fault();
+        pwm.disable();
+    }
+}
+
+/// Record nRUN being released, which has already stopped switching through HRTIM FLT2.
+pub fn nrun_released(state: &mut State) {
+    state.set_fault(FaultCode::NoRun);
+    state.set_state_stopped();
+}
+
+/// Run state machine, stepped by the heartbeat.
+pub struct Supervisor {
+    limits: Limits,
+    led: bool,
+    self_test_passed: bool,
+}
+
+impl Supervisor {
+    pub const fn new(limits: Limits) -> Self {
+        Supervisor { limits, led: false, self_test_passed: false }
+    }
+
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    /// Update the status LEDs, start the converter once permitted, and stop it on
+    /// over-temperature. Returns true if the converter was started.
+    ///
+    /// Before the first start `self_test` is run instead, given the current limits,
+    /// with the start following on the next heartbeat if it passes.
+    pub fn heartbeat<R, L, P, T>(
+        &mut self, state: &mut State, run_control: &RunControl, nrun: &R, leds: &L, pwm: &P,
+        self_test: T,
+    ) -> bool
+        where R: RunInput, L: StatusLeds, P: BurstPwm,
+              T: FnOnce(&State, &Limits) -> Result<(), FaultCode>
+    {
+        self.led = !self.led;
+
+        match state.fault_state {
+            FaultState::Stopped => {
+                leds.set_400v_led(false);
+                leds.set_err_led(false);
+                let run = run_control.may_start(nrun.run());
+                if run && state.temp >= self.limits.temp_restart {
+                    // Remain stopped until cooled down
+                    state.set_fault(FaultCode::OverTemp);
+                } else if run && !self.self_test_passed {
+                    match self_test(state, &self.limits) {
+                        Ok(()) => self.self_test_passed = true,
+                        Err(fault) => {
+                            state.set_fault(fault);
+                            state.set_state_fault();
+                        },
+                    }
+                } else if run {
+                    state.set_fault(FaultCode::NoFault);
+                    state.set_state_running();
+                    pwm.enable();
+                    return true;
+                }
+            },
+            FaultState::Fault => {
+                leds.set_400v_led(false);
+                leds.set_err_led(true);
+            },
+            FaultState::Running => {
+                if state.temp >= self.limits.temp_max {
+                    state.set_fault(FaultCode::OverTemp);
+                    state.set_state_fault();
+                    pwm.disable();
+                }
+                leds.set_400v_led(self.led);
+                leds.set_err_led(false);
+            },
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::Cell;
+    use crate::burst::Strategy;
+    use crate::profile::{Profile, ProfileId};
+
+    #[derive(Default)]
+    struct MockDac {
+        level: Cell<u16>,
+    }
+
+    impl CurrentRef for MockDac {
+        fn set_i_ref(&self, level: u16) {
+            self.level.set(level);
+        }
+    }
+
+    #[derive(Default)]
+    struct MockPwm {
+        enabled: Cell<bool>,
+        duty: Cell<u16>,
+    }
+
+    impl BurstPwm for MockPwm {
+        fn enable(&self) {
+            self.enabled.set(true);
+        }
+
+        fn disable(&self) {
+            self.enabled.set(false);
+        }
+
+        fn set_duty(&self, duty: u16) {
+            self.duty.set(duty);
+        }
+    }
+
+    #[derive(Default)]
+    struct MockBoard {
+        nrun: Cell<bool>,
+        led_400v: Cell<bool>,
+        led_err: Cell<bool>,
+    }
+
+    impl RunInput for MockBoard {
+        fn run(&self) -> bool {
+            self.nrun.get()
+        }
+    }
+
+    impl StatusLeds for MockBoard {
+        fn set_400v_led(&self, on: bool) {
+            self.led_400v.set(on);
+        }
+
+        fn set_err_led(&self, on: bool) {
+            self.led_err.set(on);
+        }
+    }
+
+    const LIMITS: Limits = Limits {
+        i_lim: 0.1, i_cc: 0.05, cc_time: 0.1, v_in_min: 18.0, v_in_max: 30.0,
+        i_in_derate: 3.0, i_in_max: 3.5, temp_max: 85.0, temp_restart: 70.0,
+    };
+
+    const PROFILE: Profile = Profile { v_set: 300.0, v_lim: 400.0, v_min: 100.0, slew: 2000.0 };
+
+    fn profiles() -> Profiles {
+        let mut profiles = Profiles::new(PROFILE, PROFILE, PROFILE, ProfileId::Strike);
+        profiles.reset();
+        profiles
+    }
+
+    fn regulator() -> Regulator {
+        let pid = PID::new(1e-4, 20.0, 120.0, 20.0, -40.0, 40.0);
+        let light_load = LightLoad::new(Strategy::Off, 0.95, 0.93);
+        let (derating, current_limit) = (Derating::new(3.0, 20.0), CurrentLimit::new(5e4, 0.25));
+        Regulator::new(pid, light_load, derating, current_limit, 3800, 1e-4)
+    }
+
+    /// Self-test which always passes.
+    fn pass(_: &State, _: &Limits) -> Result<(), FaultCode> {
+        Ok(())
+    }
+
+    /// A running converter with healthy readings.
+    fn running() -> (State, MockPwm) {
+        let mut state = State::new();
+        state.v_in = 24.0;
+        state.i_in = 1.0;
+        state.v_out = 300.0;
+        state.i_out = 0.01;
+        state.temp = 40.0;
+        state.set_state_running();
+        let pwm = MockPwm::default();
+        pwm.enable();
+        (state, pwm)
+    }
+
+    fn assert_protects(modify: fn(&mut State), start_elapsed: bool, code: FaultCode) {
+        let (mut state, pwm) = running();
+        protect(&mut state, &LIMITS, &profiles(), start_elapsed, &pwm);
+        assert!(state.fault_state == FaultState::Running && pwm.enabled.get());
+
+        modify(&mut state);
+        protect(&mut state, &LIMITS, &profiles(), start_elapsed, &pwm);
+        assert!(state.fault_state == FaultState::Fault);
+        assert_eq!(state.fault_code, code);
+        assert!(!pwm.enabled.get());
+    }
+
+    #[test]
+    fn protection_faults() {
+        assert_protects(|s| s.v_out = 400.0, false, FaultCode::VLim);
+        assert_protects(|s| s.i_out = 0.1, false, FaultCode::ILim);
+        assert_protects(|s| s.v_in = 18.0, false, FaultCode::VInLow);
+        assert_protects(|s| s.v_in = 30.0, false, FaultCode::VInHigh);
+        assert_protects(|s| s.i_in = 3.5, false, FaultCode::IInHigh);
+        assert_protects(|s| s.v_out = 100.0, true, FaultCode::NoVOut);
+    }
+
+    #[test]
+    fn no_vout_waits_for_start_timeout() {
+        let (mut state, pwm) = running();
+        state.v_out = 50.0;
+        protect(&mut state, &LIMITS, &profiles(), false, &pwm);
+        assert!(state.fault_state == FaultState::Running);
+    }
+
+    #[test]
+    fn current_limit_trips_with_constant_current_enabled() {
+        // Currents between i_cc and i_lim are left to the constant-current loop
+        let (mut state, pwm) = running();
+        state.i_out = 0.09;
+        state.cc_mode = CcMode::Limiting;
+        protect(&mut state, &LIMITS, &profiles(), true, &pwm);
+        assert!(state.fault_state == FaultState::Running);
+
+        for &mode in &[CcMode::Voltage, CcMode::Limiting, CcMode::Foldback] {
+            let (mut state, pwm) = running();
+            state.i_out = 0.2;
+            state.cc_mode = mode;
+            protect(&mut state, &LIMITS, &profiles(), true, &pwm);
+            assert!(state.fault_state == FaultState::Fault);
+            assert_eq!(state.fault_code, FaultCode::ILim);
+            assert!(!pwm.enabled.get());
+        }
+    }
+
+    #[test]
+    fn no_vout_while_current_limited() {
+        let (mut state, pwm) = running();
+        state.v_out = 50.0;
+        state.cc_mode = CcMode::Foldback;
+        protect(&mut state, &LIMITS, &profiles(), true, &pwm);
+        assert!(state.fault_state == FaultState::Running);
+    }
+
+    #[test]
+    fn protection_only_while_running() {
+        let (mut state, pwm) = running();
+        state.set_state_stopped();
+        state.v_in = 0.0;
+        protect(&mut state, &LIMITS, &profiles(), true, &pwm);
+        assert!(state.fault_state == FaultState::Stopped);
+        assert!(pwm.enabled.get());
+    }
+
+    #[test]
+    fn nrun_release_stops() {
+        let (mut state, _) = running();
+        nrun_released(&mut state);
+        assert!(state.fault_state == FaultState::Stopped);
+        assert_eq!(state.fault_code, FaultCode::NoRun);
+    }
+
+    #[test]
+    fn regulates_while_running() {
+        let mut regulator = regulator();
+        let mut profiles = profiles();
+        let dac = MockDac::default();
+        let (mut state, pwm) = running();
+
+        // Far below the setpoint the current reference is limited
+        regulator.step(&mut state, &mut profiles, 0.0, 0.0, &dac, &pwm);
+        assert_eq!(dac.level.get(), 3800);
+        assert_eq!(state.ref_i_q, 3800);
+        assert_eq!((pwm.duty.get(), state.duty), (1000, 1000));
+        assert!(state.pid_i > 0.0);
+        assert_eq!(state.derate, 1.0);
+
+        // Once stopped the controller resets and the reference is cleared
+        state.set_state_fault();
+        regulator.step(&mut state, &mut profiles, 0.0, 0.0, &dac, &pwm);
+        assert_eq!(dac.level.get(), 0);
+        assert_eq!(state.ref_i_q, 0);
+        assert_eq!(state.pid_i, 0.0);
+    }
+
+    #[test]
+    fn derates_on_input_current() {
+        let mut regulator = regulator();
+        let mut profiles = profiles();
+        let dac = MockDac::default();
+        let (mut state, pwm) = running();
+
+        // 0.5A over the threshold lowers the ceiling by 10% every 10ms
+        state.i_in = 3.5;
+        for _ in 0..100 {
+            regulator.step(&mut state, &mut profiles, 0.0, 0.0, &dac, &pwm);
+        }
+        assert!((state.derate - 0.9).abs() < 1e-3);
+        assert!((dac.level.get() as i32 - 3420).abs() <= 1);
+        assert!(state.fault_state == FaultState::Running);
+
+        // Excess input current with no output left derates fully
+        for _ in 0..1000 {
+            regulator.step(&mut state, &mut profiles, 0.0, 0.0, &dac, &pwm);
+        }
+        assert_eq!((state.derate, dac.level.get()), (0.0, 0));
+        state.i_in = f32::NAN;
+        regulator.step(&mut state, &mut profiles, 0.0, 0.0, &dac, &pwm);
+        assert_eq!(state.derate, 0.0);
+
+        // Below the threshold the ceiling recovers, but not beyond the maximum
+        state.i_in = 2.0;
+        for _ in 0..600 {
+            regulator.step(&mut state, &mut profiles, 0.0, 0.0, &dac, &pwm);
+        }
+        assert_eq!((state.derate, dac.level.get()), (1.0, 3800));
+
+        // Stopping resets derating
+        state.i_in = 4.0;
+        regulator.step(&mut state, &mut profiles, 0.0, 0.0, &dac, &pwm);
+        assert!(state.derate < 1.0);
+        state.set_state_stopped();
+        regulator.step(&mut state, &mut profiles, 0.0, 0.0, &dac, &pwm);
+        assert_eq!(state.derate, 1.0);
+    }
+
+    #[test]
+    fn limits_output_current() {
+        let mut regulator = regulator();
+        let mut profiles = profiles();
+        let dac = MockDac::default();
+        let (mut state, pwm) = running();
+
+        // Disabled by default, so any output current is regulated on voltage alone
+        state.i_out = 0.09;
+        regulator.step(&mut state, &mut profiles, 300.0, 0.0, &dac, &pwm);
+        regulator.step(&mut state, &mut profiles, 300.0, 0.0, &dac, &pwm);
+        assert_eq!(state.cc_mode, CcMode::Voltage);
+
+        // 40mA over the limit lowers the voltage reference by 2V every 1ms,
+        // so the current reference falls away
+        regulator.set_current_limit(LIMITS.i_cc, LIMITS.cc_time);
+        for _ in 0..101 {
+            regulator.step(&mut state, &mut profiles, 300.0, 0.0, &dac, &pwm);
+        }
+        assert_eq!(state.cc_mode, CcMode::Limiting);
+        assert_eq!(dac.level.get(), 0);
+
+        // Still over the limit after cc_time, the limit folds back to a quarter
+        for _ in 0..950 {
+            regulator.step(&mut state, &mut profiles, 300.0, 0.0, &dac, &pwm);
+        }
+        assert_eq!(state.cc_mode, CcMode::Foldback);
+        assert!(state.fault_state == FaultState::Running);
+
+        // Between the foldback and full limits the ceiling keeps falling
+        state.i_out = 0.02;
+        for _ in 0..1000 {
+            regulator.step(&mut state, &mut profiles, 0.0, 0.0, &dac, &pwm);
+        }
+        assert_eq!(state.cc_mode, CcMode::Foldback);
+
+        // Once the load releases the output returns to voltage regulation
+        // at the full limit
+        state.i_out = 0.0;
+        for _ in 0..6000 {
+            regulator.step(&mut state, &mut profiles, 0.0, 0.0, &dac, &pwm);
+        }
+        assert_eq!(state.cc_mode, CcMode::Voltage);
+        state.i_out = 0.04;
+        regulator.step(&mut state, &mut profiles, 0.0, 0.0, &dac, &pwm);
+        assert_eq!(state.cc_mode, CcMode::Voltage);
+
+        // Stopping resets the limit
+        state.i_out = 0.09;
+        regulator.step(&mut state, &mut profiles, 0.0, 0.0, &dac, &pwm);
+        assert_eq!(state.cc_mode, CcMode::Limiting);
+        state.set_state_stopped();
+        regulator.step(&mut state, &mut profiles, 0.0, 0.0, &dac, &pwm);
+        assert_eq!(state.cc_mode, CcMode::Voltage);
+    }
+
+    #[test]
+    fn starts_after_self_test() {
+        let mut supervisor = Supervisor::new(LIMITS);
+        let run_control = RunControl::new(true);
+        let board = MockBoard::default();
+        let pwm = MockPwm::default();
+        let mut state = State::new();
+        state.temp = 40.0;
+
+        // Nothing happens until nRUN is asserted
+        assert!(!supervisor.heartbeat(&mut state, &run_control, &board, &board, &pwm, pass));
+        assert!(state.fault_state == FaultState::Stopped);
+
+        // The self-test runs first, then the converter starts on the next heartbeat
+        board.nrun.set(true);
+        assert!(!supervisor.heartbeat(&mut state, &run_control, &board, &board, &pwm, pass));
+        assert!(state.fault_state == FaultState::Stopped);
+        let mut tested = false;
+        let started = supervisor.heartbeat(&mut state, &run_control, &board, &board, &pwm, |_, _| {
+            tested = true;
+            Ok(())
+        });
+        assert!(started && !tested);
+        assert!(state.fault_state == FaultState::Running && pwm.enabled.get());
+
+        // The 400V LED flashes while running
+        supervisor.heartbeat(&mut state, &run_control, &board, &board, &pwm, pass);
+        let led = board.led_400v.get();
+        supervisor.heartbeat(&mut state, &run_control, &board, &board, &pwm, pass);
+        assert_eq!(board.led_400v.get(), !led);
+        assert!(!board.led_err.get());
+    }
+
+    #[test]
+    fn self_test_failure_faults() {
+        let mut supervisor = Supervisor::new(LIMITS);
+        let run_control = RunControl::new(true);
+        let board = MockBoard { nrun: Cell::new(true), ..Default::default() };
+        let pwm = MockPwm::default();
+        let mut state = State::new();
+
+        let fail = |_: &State, _: &Limits| Err(FaultCode::TestDAC1);
+        assert!(!supervisor.heartbeat(&mut state, &run_control, &board, &board, &pwm, fail));
+        assert!(state.fault_state == FaultState::Fault);
+        assert_eq!(state.fault_code, FaultCode::TestDAC1);
+        assert!(!pwm.enabled.get());
+
+        supervisor.heartbeat(&mut state, &run_control, &board, &board, &pwm, fail);
+        assert!(board.led_err.get() && !board.led_400v.get());
+    }
+
+    #[test]
+    fn over_temperature() {
+        let mut supervisor = Supervisor::new(LIMITS);
+        let run_control = RunControl::new(true);
+        let board = MockBoard { nrun: Cell::new(true), ..Default::default() };
+        let (mut state, pwm) = running();
+
+        state.temp = 85.0;
+        supervisor.heartbeat(&mut state, &run_control, &board, &board, &pwm, pass);
+        assert!(state.fault_state == FaultState::Fault);
+        assert_eq!(state.fault_code, FaultCode::OverTemp);
+        assert!(!pwm.enabled.get());
+
+        // Once cleared, remain stopped until below the restart temperature
+        state.set_state_stopped();
+        state.temp = 75.0;
+        supervisor.heartbeat(&mut state, &run_control, &board, &board, &pwm, pass);
+        supervisor.heartbeat(&mut state, &run_control, &board, &board, &pwm, pass);
+        assert!(state.fault_state == FaultState::Stopped && !pwm.enabled.get());
+        assert_eq!(state.fault_code, FaultCode::OverTemp);
+
+        state.temp = 60.0;
+        supervisor.heartbeat(&mut state, &run_control, &board, &board, &pwm, pass);
+        assert!(supervisor.heartbeat(&mut state, &run_control, &board, &board, &pwm, pass));
+        assert_eq!(state.fault_code, FaultCode::NoFault);
+    }
+}
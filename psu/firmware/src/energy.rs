@@ -0,0 +1,301 @@
+//! Energy, efficiency and runtime accounting
+//!
+//! Input and output power are integrated every control step into lifetime energy
+//! totals, alongside running time and start and fault counts. The totals are
+//! held as `Counters`, which are stored as a log of records in a flash page:
+//! each save appends a new record with an incremented sequence number, and the
+//! page is only erased once full.
+
+use crate::state::{State, FaultState, ToBytes};
+
+/// Joules per milliwatt-hour.
+const J_PER_MWH: f32 = 3.6;
+
+/// Lifetime counters, as stored in flash.
+///
+/// All fields are plain words so any bit pattern, including erased flash, is a valid
+/// (if not necessarily checksum-valid) record.
+#[repr(C)]
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Counters {
+    /// Incremented on every save.
+    pub seq: u32,
+    /// Input energy (mWh).
+    pub mwh_in: u32,
+    /// Output energy (mWh).
+    pub mwh_out: u32,
+    /// Time spent running (s).
+    pub run_seconds: u32,
+    /// Number of times the converter has started.
+    pub starts: u32,
+    /// Number of faults while running.
+    pub faults: u32,
+    check: u32,
+}
+
+impl Counters {
+    pub const fn new() -> Self {
+        Counters { seq: 0, mwh_in: 0, mwh_out: 0, run_seconds: 0, starts: 0, faults: 0, check: 0 }
+    }
+
+    /// Returns true if the record's checksum is correct.
+    pub fn valid(&self) -> bool {
+        self.check == self.checksum()
+    }
+
+    /// Returns true if the record is erased flash.
+    pub fn erased(&self) -> bool {
+        self.as_halfwords().iter().all(|w| *w == 0xFFFF)
+    }
+
+    /// Record contents as half-words for programming into flash.
+    pub fn as_halfwords(&self) -> [u16; 14] {
+        let mut out = [0; 14];
+        for (o, b) in out.iter_mut().zip(self.to_bytes().chunks(2)) {
+            *o = u16::from_le_bytes([b[0], b[1]]);
+        }
+        out
+    }
+
+    fn checksum(&self) -> u32 {
+        let words = [self.seq, self.mwh_in, self.mwh_out, self.run_seconds, self.starts, self.faults];
+        !words.iter().fold(0u32, |a, w| a.rotate_left(5).wrapping_add(*w))
+    }
+
+    fn seal(&mut self) {
+        self.check = self.checksum();
+    }
+}
+
+impl Default for Counters {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+unsafe impl ToBytes for Counters {}
+
+/// Find the most recent valid record in a log page.
+pub fn latest(records: &[Counters]) -> Option<Counters> {
+    records.iter().filter(|r| r.valid()).max_by_key(|r| r.seq).copied()
+}
+
+/// Find the index of the first erased record in a log page.
+///
+/// Records are only ever appended, so all records after the first erased record
+/// are also erased.
+pub fn free_slot(records: &[Counters]) -> Option<usize> {
+    records.iter().position(|r| r.erased())
+}
+
+/// Energy telemetry packet.
+#[repr(C)]
+#[repr(align(4))]
+pub struct Report {
+    magic: u32,
+    /// Filtered input power (W).
+    pub p_in: f32,
+    /// Filtered output power (W).
+    pub p_out: f32,
+    /// Filtered output power over input power.
+    pub efficiency: f32,
+    /// Lifetime input energy (Wh).
+    pub wh_in: f32,
+    /// Lifetime output energy (Wh).
+    pub wh_out: f32,
+    /// Lifetime running time (hours).
+    pub run_hours: f32,
+    pub starts: u32,
+    pub faults: u32,
+}
+
+impl Report {
+    pub const fn new() -> Self {
+        Report {
+            magic: 0x656e6572,
+            p_in: 0.0, p_out: 0.0, efficiency: 0.0, wh_in: 0.0, wh_out: 0.0, run_hours: 0.0,
+            starts: 0, faults: 0,
+        }
+    }
+}
+
+impl Default for Report {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+unsafe impl ToBytes for Report {}
+
+/// Integrates power and counts runtime, starts and faults.
+pub struct Energy {
+    counters: Counters,
+    /// Energy not yet added to the counters (J).
+    j_in: f32,
+    j_out: f32,
+    /// Running time not yet added to the counters (s).
+    t_run: f32,
+    p_in: f32,
+    p_out: f32,
+    tau: f32,
+    save_due: bool,
+    prev: FaultState,
+}
+
+impl Energy {
+    /// Create a new accumulator, filtering power with time constant `tau` (s).
+    ///
+    /// A save is requested whenever the converter stops or faults, since programming
+    /// flash while running would stall the protection in the ADC ISR.
+    pub const fn new(tau: f32) -> Self {
+        Energy {
+            counters: Counters::new(),
+            j_in: 0.0, j_out: 0.0, t_run: 0.0, p_in: 0.0, p_out: 0.0,
+            tau, save_due: false,
+            prev: FaultState::Stopped,
+        }
+    }
+
+    /// Continue from counters previously loaded from flash.
+    pub fn restore(&mut self, counters: Counters) {
+        self.counters = counters;
+    }
+
+    /// Integrate the current state over `dt` seconds.
+    pub fn step(&mut self, dt: f32, state: &State) {
+        // Currents can read slightly negative from offsets at zero load
+        let p_in = (state.v_in * state.i_in).max(0.0);
+        let p_out = (state.v_out * state.i_out).max(0.0);
+        let alpha = dt / self.tau;
+        self.p_in += alpha * (p_in - self.p_in);
+        self.p_out += alpha * (p_out - self.p_out);
+
+        self.j_in += p_in * dt;
+        self.j_out += p_out * dt;
+        if state.fault_state == FaultState::Running {
+            self.t_run += dt;
+        }
+
+        let c = &mut self.counters;
+        let mwh_in = (self.j_in / J_PER_MWH) as u32;
+        self.j_in -= mwh_in as f32 * J_PER_MWH;
+        c.mwh_in = c.mwh_in.wrapping_add(mwh_in);
+        let mwh_out = (self.j_out / J_PER_MWH) as u32;
+        self.j_out -= mwh_out as f32 * J_PER_MWH;
+        c.mwh_out = c.mwh_out.wrapping_add(mwh_out);
+        if self.t_run >= 1.0 {
+            self.t_run -= 1.0;
+            c.run_seconds += 1;
+        }
+
+        match (self.prev, state.fault_state) {
+            (FaultState::Stopped, FaultState::Running) => c.starts += 1,
+            (FaultState::Running, FaultState::Fault) => {
+                c.faults += 1;
+                self.save_due = true;
+            },
+            (FaultState::Running, FaultState::Stopped) => self.save_due = true,
+            _ => (),
+        }
+        self.prev = state.fault_state;
+    }
+
+    /// Returns true if the counters should be saved.
+    pub fn save_due(&self) -> bool {
+        self.save_due
+    }
+
+    /// Get a sealed record of the current counters for saving, with a new sequence number.
+    pub fn save(&mut self) -> Counters {
+        self.counters.seq = self.counters.seq.wrapping_add(1);
+        self.counters.seal();
+        self.save_due = false;
+        self.counters
+    }
+
+    /// Filtered output power over filtered input power, or zero with no input power.
+    pub fn efficiency(&self) -> f32 {
+        if self.p_in > 0.1 { self.p_out / self.p_in } else { 0.0 }
+    }
+
+    /// Fill in the energy telemetry packet.
+    pub fn report(&self, report: &mut Report) {
+        let c = &self.counters;
+        report.p_in = self.p_in;
+        report.p_out = self.p_out;
+        report.efficiency = self.efficiency();
+        report.wh_in = (c.mwh_in as f32 + self.j_in / J_PER_MWH) / 1000.0;
+        report.wh_out = (c.mwh_out as f32 + self.j_out / J_PER_MWH) / 1000.0;
+        report.run_hours = (c.run_seconds as f32 + self.t_run) / 3600.0;
+        report.starts = c.starts;
+        report.faults = c.faults;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::FaultCode;
+
+    #[test]
+    fn integrates_power_and_counts_events() {
+        let mut energy = Energy::new(0.1);
+        let mut state = State::new();
+        state.v_in = 24.0;
+        state.i_in = 0.5;
+        state.v_out = 225.0;
+        state.i_out = 0.048;
+        state.set_state_running();
+
+        // One hour at 12W in and 10.8W out
+        for _ in 0..3600 {
+            for _ in 0..100 {
+                energy.step(0.01, &state);
+            }
+        }
+        let mut report = Report::new();
+        energy.report(&mut report);
+        assert!((report.wh_in - 12.0).abs() < 0.01);
+        assert!((report.wh_out - 10.8).abs() < 0.01);
+        assert!((report.efficiency - 0.9).abs() < 1e-3);
+        assert!((report.run_hours - 1.0).abs() < 1e-3);
+        assert_eq!(report.starts, 1);
+        assert!(!energy.save_due());
+
+        state.set_state_stopped();
+        energy.step(0.01, &state);
+        assert!(energy.save_due());
+        energy.save();
+        assert!(!energy.save_due());
+
+        state.set_state_running();
+        energy.step(0.01, &state);
+        state.set_fault(FaultCode::VLim);
+        state.set_state_fault();
+        energy.step(0.01, &state);
+        assert!(energy.save_due());
+        let saved = energy.save();
+        assert_eq!((saved.starts, saved.faults), (2, 1));
+    }
+
+    #[test]
+    fn log_records() {
+        let erased: Counters = unsafe { core::mem::transmute([0xFFFF_FFFFu32; 7]) };
+        let mut log = [erased; 8];
+        assert_eq!(latest(&log), None);
+        assert_eq!(free_slot(&log), Some(0));
+
+        let mut energy = Energy::new(1.0);
+        log[0] = energy.save();
+        log[1] = energy.save();
+        log[2] = log[1];
+        log[2].mwh_in ^= 1;
+        assert!(log[0].valid() && !log[2].valid());
+        assert_eq!(latest(&log).unwrap().seq, 2);
+        assert_eq!(free_slot(&log), Some(3));
+
+        let halfwords = log[1].as_halfwords();
+        assert_eq!(halfwords[0], 2);
+        assert_eq!((halfwords[13] as u32) << 16 | halfwords[12] as u32, log[1].check);
+    }
+}
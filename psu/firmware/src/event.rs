@@ -0,0 +1,236 @@
+//! Timestamped event stream
+//!
+//! Periodic telemetry only samples the state, so a short sequence such as a fault
+//! followed by a stop can fall entirely between two state packets. Each event is
+//! instead recorded by the task where it happens, timestamped, and sent as soon as
+//! the serial link is free. Events are queued without blocking; if the queue is full
+//! new events are dropped and counted in the next event sent.
+//!
+//! Each event packet is 16 bytes:
+//!
+//!     [ magic | kind | fault code | dropped (u16) | time (u64, µs since power on) ]
+//!
+//! The timestamp is 64 bits so it never wraps, while keeping the microsecond
+//! resolution which orders events within one control loop period.
+
+use crate::state::{State, FaultState, FaultCode, ToBytes};
+use crate::timing::F_SYSCLK;
+
+/// Event kinds.
+#[repr(u8)]
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Kind {
+    /// nRUN asserted, permitting the converter to run.
+    NRunAsserted = 1,
+    /// nRUN released, which stops switching through HRTIM FLT2.
+    NRunReleased = 2,
+    /// HRTIM outputs enabled on starting the converter.
+    HrtimEnabled = 3,
+    /// HRTIM outputs disabled on stopping or on a fault.
+    HrtimDisabled = 4,
+    /// A fault code was raised, given in the event's fault code.
+    Fault = 5,
+    /// The start timeout elapsed, after which the output must reach the profile's v_min.
+    StartTimeout = 6,
+    /// HRTIM system fault (SYSFLT).
+    SysFault = 7,
+}
+
+/// One event, as sent to the host.
+#[repr(C)]
+#[repr(align(4))]
+#[derive(Copy, Clone, Debug)]
+pub struct Event {
+    magic: u32,
+    pub kind: Kind,
+    /// Fault code raised, or NoFault for other kinds of event.
+    pub code: FaultCode,
+    /// Number of events dropped since the previous one was sent.
+    pub dropped: u16,
+    /// Time since power on (µs).
+    pub time: u64,
+}
+
+unsafe impl ToBytes for Event {}
+
+impl Event {
+    const fn new(time: u64, kind: Kind, code: FaultCode) -> Self {
+        Event { magic: 0x65766e74, kind, code, dropped: 0, time }
+    }
+}
+
+/// Queue of up to `N` events waiting to be sent.
+///
+/// Also tracks the run state and nRUN level, so events are recorded for their
+/// transitions however they came about, and extends the 32-bit cycle counter
+/// into timestamps, which requires calls at least once every 2^32 cycles.
+pub struct Queue<const N: usize> {
+    events: [Event; N],
+    head: usize,
+    len: usize,
+    dropped: u16,
+    packet: Event,
+    cycles: u64,
+    last_cycles: u32,
+    fault_state: FaultState,
+    fault_code: FaultCode,
+    nrun: bool,
+}
+
+impl<const N: usize> Queue<N> {
+    pub const fn new() -> Self {
+        const EMPTY: Event = Event::new(0, Kind::Fault, FaultCode::NoFault);
+        Queue {
+            events: [EMPTY; N], head: 0, len: 0, dropped: 0, packet: EMPTY,
+            cycles: 0, last_cycles: 0,
+            fault_state: FaultState::Stopped, fault_code: FaultCode::NoFault, nrun: false,
+        }
+    }
+
+    /// Record an event at `now`, a reading of the cycle counter.
+    ///
+    /// Returns false if the queue was full and the event dropped.
+    pub fn push(&mut self, kind: Kind, code: FaultCode, now: u32) -> bool {
+        let time = self.time(now);
+        if self.len == N {
+            self.dropped = self.dropped.saturating_add(1);
+            return false;
+        }
+        self.events[(self.head + self.len) % N] = Event::new(time, kind, code);
+        self.len += 1;
+        true
+    }
+
+    /// Record any changes in run state and fault code since the last call:
+    /// HRTIM is enabled on entering the running state and disabled on leaving it.
+    ///
+    /// Call this after each task which may change the state. Faults raised together
+    /// within one call are reported as the one latched in the state.
+    pub fn observe(&mut self, state: &State, now: u32) {
+        let was_running = self.fault_state == FaultState::Running;
+        let running = state.fault_state == FaultState::Running;
+        if state.fault_code != self.fault_code && state.fault_code != FaultCode::NoFault {
+            self.push(Kind::Fault, state.fault_code, now);
+        }
+        if running && !was_running {
+            self.push(Kind::HrtimEnabled, FaultCode::NoFault, now);
+        } else if was_running && !running {
+            self.push(Kind::HrtimDisabled, FaultCode::NoFault, now);
+        }
+        self.fault_state = state.fault_state;
+        self.fault_code = state.fault_code;
+        self.time(now);
+    }
+
+    /// Record a change in the nRUN input, where true means asserted.
+    pub fn nrun(&mut self, asserted: bool, now: u32) {
+        if asserted != self.nrun {
+            self.nrun = asserted;
+            let kind = if asserted { Kind::NRunAsserted } else { Kind::NRunReleased };
+            self.push(kind, FaultCode::NoFault, now);
+        }
+    }
+
+    /// Take the oldest event into the packet buffer, returning its bytes to send.
+    ///
+    /// The buffer is overwritten by the next call, so only call this once the
+    /// previous packet has been transmitted.
+    pub fn packet(&mut self) -> Option<&[u8]> {
+        if self.len == 0 {
+            return None;
+        }
+        self.packet = Event { dropped: self.dropped, ..self.events[self.head] };
+        self.dropped = 0;
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+        Some(self.packet.to_bytes())
+    }
+
+    /// Extend the cycle counter reading `now`, returning the time since power on in µs.
+    fn time(&mut self, now: u32) -> u64 {
+        self.cycles += now.wrapping_sub(self.last_cycles) as u64;
+        self.last_cycles = now;
+        self.cycles / (F_SYSCLK / 1_000_000) as u64
+    }
+}
+
+impl<const N: usize> Default for Queue<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(packet: &[u8]) -> (u64, u8, u8, u16) {
+        assert_eq!(packet.len(), 16);
+        assert_eq!(&packet[..4], &0x65766e74u32.to_le_bytes());
+        let mut time = [0; 8];
+        time.copy_from_slice(&packet[8..16]);
+        (u64::from_le_bytes(time), packet[4], packet[5], u16::from_le_bytes([packet[6], packet[7]]))
+    }
+
+    #[test]
+    fn records_transitions() {
+        let mut queue = Queue::<8>::new();
+        let mut state = State::new();
+        let us = F_SYSCLK / 1_000_000;
+
+        queue.nrun(true, 10 * us);
+        queue.nrun(true, 11 * us);
+        state.set_state_running();
+        queue.observe(&state, 20 * us);
+        state.set_fault(FaultCode::VLim);
+        state.set_state_fault();
+        queue.observe(&state, 30 * us);
+        queue.observe(&state, 40 * us);
+        state.set_fault(FaultCode::NoFault);
+        state.set_state_stopped();
+        queue.observe(&state, 50 * us);
+
+        assert_eq!(decode(queue.packet().unwrap()), (10, Kind::NRunAsserted as u8, 0, 0));
+        assert_eq!(decode(queue.packet().unwrap()), (20, Kind::HrtimEnabled as u8, 0, 0));
+        assert_eq!(decode(queue.packet().unwrap()),
+                   (30, Kind::Fault as u8, FaultCode::VLim as u8, 0));
+        assert_eq!(decode(queue.packet().unwrap()), (30, Kind::HrtimDisabled as u8, 0, 0));
+        assert!(queue.packet().is_none());
+    }
+
+    #[test]
+    fn drops_when_full() {
+        let mut queue = Queue::<2>::new();
+        assert!(queue.push(Kind::StartTimeout, FaultCode::NoFault, 0));
+        assert!(queue.push(Kind::SysFault, FaultCode::NoFault, 0));
+        assert!(!queue.push(Kind::StartTimeout, FaultCode::NoFault, 0));
+        assert!(!queue.push(Kind::StartTimeout, FaultCode::NoFault, 0));
+
+        assert_eq!(decode(queue.packet().unwrap()).3, 2);
+        assert!(queue.push(Kind::StartTimeout, FaultCode::NoFault, 0));
+        assert_eq!(decode(queue.packet().unwrap()), (0, Kind::SysFault as u8, 0, 0));
+        assert_eq!(decode(queue.packet().unwrap()), (0, Kind::StartTimeout as u8, 0, 0));
+        assert!(queue.packet().is_none());
+    }
+
+    #[test]
+    fn timestamps_extend_cycle_counter() {
+        let mut queue = Queue::<4>::new();
+        queue.observe(&State::new(), 3_000_000_000);
+        queue.push(Kind::StartTimeout, FaultCode::NoFault, 1_000_000_000);
+        let expected = ((1u64 << 32) + 1_000_000_000) / (F_SYSCLK / 1_000_000) as u64;
+        assert_eq!(decode(queue.packet().unwrap()).0, expected);
+    }
+
+    #[test]
+    fn timestamps_do_not_wrap() {
+        // Two hours of cycle counter readings, past the 2^32µs (71 minute) wrap
+        let mut queue = Queue::<4>::new();
+        let step = F_SYSCLK / 2;
+        for k in 1..=2 * 3600 * 2 {
+            queue.observe(&State::new(), step.wrapping_mul(k));
+        }
+        queue.push(Kind::StartTimeout, FaultCode::NoFault, step.wrapping_mul(2 * 3600 * 2));
+        assert_eq!(decode(queue.packet().unwrap()).0, 2 * 3600 * 1_000_000);
+    }
+}
@@ -1,56 +1,102 @@
-use stm32ral::{adc, modify_reg, write_reg, read_reg};
+use stm32ral::{adc, adc_common, modify_reg, write_reg, read_reg};
+use iggie_psu::calibration::FactoryCal;
+use iggie_psu::sampling::{SamplingProfile, TEMPERATURE_SAMPLE_TIME};
+use iggie_psu::timing;
 use super::dma::DMA;
 
 pub struct ADC {
     adc1: adc::Instance,
+    adc2: adc::Instance,
+    common: adc_common::Instance,
 }
 
 impl ADC {
-    pub fn new(adc1: adc::Instance) -> Self {
-        ADC { adc1 }
+    pub fn new(adc1: adc::Instance, adc2: adc::Instance, common: adc_common::Instance) -> Self {
+        ADC { adc1, adc2, common }
     }
 
-    pub fn setup(&self) {
+    // Sequence lengths are written as N - 1, including JL: 1 - 1
+    #[allow(clippy::eq_op)]
+    pub fn setup(&self, profile: &SamplingProfile) {
         // Configures ADC1 for continuous sampling into DMA.
-        // ADC1: Channels 1, 2, 3, 4
+        // ADC1: Channels 1, 2, 3, 4 in the profile's order, plus injected channel 16 (temperature sensor)
+        // ADC2: Channels 18 (VREFINT), 1, 2, 4, 3
 
-        // Enable ADC voltage regulator and wait at least 10µs
-        write_reg!(stm32ral::adc, self.adc1, CR, ADVREGEN: Intermediate);
-        write_reg!(stm32ral::adc, self.adc1, CR, ADVREGEN: Enabled);
-        cortex_m::asm::delay(700);
+        // Enable temperature sensor and VREFINT
+        modify_reg!(stm32ral::adc_common, self.common, CCR, TSEN: Enabled, VREFEN: Enabled);
 
-        // Run calibration and wait for completion
-        modify_reg!(stm32ral::adc, self.adc1, CR, ADCAL: Calibration, ADCALDIF: SingleEnded);
-        while read_reg!(stm32ral::adc, self.adc1, CR, ADCAL != Complete) {}
+        for adc in [&self.adc1, &self.adc2].iter() {
+            // Enable ADC voltage regulator and wait at least 10µs
+            write_reg!(stm32ral::adc, adc, CR, ADVREGEN: Intermediate);
+            write_reg!(stm32ral::adc, adc, CR, ADVREGEN: Enabled);
+            cortex_m::asm::delay(timing::cycles(10e-6));
 
-        // Enable interrupt on end of conversion sequence
-        write_reg!(stm32ral::adc, self.adc1, IER, EOSIE: Enabled);
+            // Run calibration and wait for completion
+            modify_reg!(stm32ral::adc, adc, CR, ADCAL: Calibration, ADCALDIF: SingleEnded);
+            while read_reg!(stm32ral::adc, adc, CR, ADCAL != Complete) {}
+
+            // Configure for continuous DMA data
+            write_reg!(stm32ral::adc, adc, CFGR,
+                       CONT: Continuous, DMACFG: Circular, DMAEN: Enabled);
+        }
 
-        // Configure for continuous DMA data
-        write_reg!(stm32ral::adc, self.adc1, CFGR,
-                   CONT: Continuous, DMACFG: Circular, DMAEN: Enabled);
+        // Convert the ADC1 injected sequence automatically after every regular sequence,
+        // so the temperature sensor is sampled at a fixed point in the sequence timing
+        modify_reg!(stm32ral::adc, self.adc1, CFGR, JAUTO: Enabled);
+
+        // Enable interrupt on end of ADC1 conversion sequence
+        write_reg!(stm32ral::adc, self.adc1, IER, EOSIE: Enabled);
 
         // Configure sample times
         // ADC clock is 70MHz (direct from PLL, no division).
-        // We set 181.5 cycles of sampling time. Additionally at 12 bits there are 12.5 cycles
-        // of conversion time, for a total of 194 samples per channel.
-        // With four channels, ADC1 obtains 90.2kS/s
-        write_reg!(stm32ral::adc, self.adc1, SMPR1,
-                   SMP1: Cycles181_5, SMP2: Cycles181_5, SMP3: Cycles181_5, SMP4: Cycles181_5);
+        // ADC1 channels use the profile's sample time. Additionally at 12 bits there are
+        // 12.5 cycles of conversion time per channel; see `SamplingProfile::sequence_period`.
+        // The temperature sensor requires at least 2.2µs; see `sampling::TEMPERATURE_SAMPLE_TIME`.
+        let smp = profile.sample_time as u32;
+        write_reg!(stm32ral::adc, self.adc1, SMPR1, SMP1: smp, SMP2: smp, SMP3: smp, SMP4: smp);
+        write_reg!(stm32ral::adc, self.adc1, SMPR2, SMP16: TEMPERATURE_SAMPLE_TIME as u32);
+
+        // ADC2 readings are only used for slow monitoring, so use the longest sample time
+        // for all channels to reduce DMA load: 614 cycles per channel gives 22.8kS/s.
+        write_reg!(stm32ral::adc, self.adc2, SMPR1,
+                   SMP1: Cycles601_5, SMP2: Cycles601_5, SMP3: Cycles601_5, SMP4: Cycles601_5);
+        write_reg!(stm32ral::adc, self.adc2, SMPR2, SMP18: Cycles601_5);
+
+        // Configure sampling sequences
+        let [sq1, sq2, sq3, sq4] = profile.sequence;
+        write_reg!(stm32ral::adc, self.adc1, SQR1, L: 4 - 1,
+                   SQ1: sq1.channel(), SQ2: sq2.channel(), SQ3: sq3.channel(), SQ4: sq4.channel());
+        write_reg!(stm32ral::adc, self.adc2, SQR1, L: 5 - 1, SQ1: 18, SQ2: 1, SQ3: 2, SQ4: 4);
+        write_reg!(stm32ral::adc, self.adc2, SQR2, SQ5: 3);
 
-        // Configure sampling sequence
-        write_reg!(stm32ral::adc, self.adc1, SQR1, L: 4 - 1, SQ1: 1, SQ2: 2, SQ3: 3, SQ4: 4);
+        // Configure ADC1 injected sequence for a single conversion of the temperature sensor.
+        // JEXTEN must be disabled in auto-injected mode.
+        write_reg!(stm32ral::adc, self.adc1, JSQR, JL: 1 - 1, JSQ1: 16, JEXTEN: Disabled);
 
-        // Enable ADC
+        // Enable ADCs
         modify_reg!(stm32ral::adc, self.adc1, CR, ADEN: Enable);
+        modify_reg!(stm32ral::adc, self.adc2, CR, ADEN: Enable);
     }
 
-    pub fn start(&mut self, dma: &DMA, buf: &mut [u16; 4]) {
+    pub fn start(&mut self, dma: &DMA, buf: &mut [u16; 4], buf2: &mut [u16; 5]) {
         // Start DMA
         dma.adc1_enable(buf);
+        dma.adc2_enable(buf2);
 
         // Start conversions
         modify_reg!(stm32ral::adc, self.adc1, CR, ADSTART: Start);
+        modify_reg!(stm32ral::adc, self.adc2, CR, ADSTART: Start);
+    }
+
+    /// Read the result of the latest temperature sensor conversion, if one has
+    /// completed since the last read.
+    pub fn read_temperature(&self) -> Option<u16> {
+        if read_reg!(stm32ral::adc, self.adc1, ISR, JEOC == Complete) {
+            write_reg!(stm32ral::adc, self.adc1, ISR, JEOC: Clear, JEOS: Clear);
+            Some(read_reg!(stm32ral::adc, self.adc1, JDR1) as u16)
+        } else {
+            None
+        }
     }
 
     pub fn isr(&self) {
@@ -58,4 +104,16 @@ impl ADC {
             write_reg!(stm32ral::adc, self.adc1, ISR, EOS: 1);
         }
     }
+
+    /// Read factory calibration values from system memory.
+    pub fn factory_cal() -> FactoryCal {
+        // UNSAFE: These addresses are fixed read-only locations in system memory.
+        unsafe {
+            FactoryCal {
+                ts_cal1: core::ptr::read_volatile(0x1FFF_F7B8 as *const u16),
+                ts_cal2: core::ptr::read_volatile(0x1FFF_F7C2 as *const u16),
+                vrefint_cal: core::ptr::read_volatile(0x1FFF_F7BA as *const u16),
+            }
+        }
+    }
 }
@@ -1,4 +1,4 @@
-use stm32ral::{comp, write_reg};
+use stm32ral::{comp, read_reg, write_reg, modify_reg};
 
 pub struct Comp {
     comp: comp::Instance,
@@ -15,4 +15,24 @@ impl Comp {
         write_reg!(stm32ral::comp, self.comp, COMP4_CSR,
                    COMP4INMSEL: DAC1_CH2, COMP4EN: Enabled);
     }
+
+    /// Read COMP2 (I_Q against DAC1 CH1) output.
+    pub fn comp2_out(&self) -> bool {
+        read_reg!(stm32ral::comp, self.comp, COMP2_CSR, COMP2OUT == 1)
+    }
+
+    /// Read COMP4 (V_Q against DAC1 CH2) output.
+    pub fn comp4_out(&self) -> bool {
+        read_reg!(stm32ral::comp, self.comp, COMP4_CSR, COMP4OUT == 1)
+    }
+
+    /// Set COMP2 output polarity. Only for use while the converter is stopped.
+    pub fn set_comp2_inverted(&self, inverted: bool) {
+        modify_reg!(stm32ral::comp, self.comp, COMP2_CSR, COMP2POL: inverted as u32);
+    }
+
+    /// Set COMP4 output polarity. Only for use while the converter is stopped.
+    pub fn set_comp4_inverted(&self, inverted: bool) {
+        modify_reg!(stm32ral::comp, self.comp, COMP4_CSR, COMP4POL: inverted as u32);
+    }
 }
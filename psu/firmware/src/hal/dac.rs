@@ -1,5 +1,7 @@
 use stm32ral::{dac, write_reg};
 
+use iggie_psu::hw;
+
 pub struct DAC {
     dac: dac::Instance,
 }
@@ -21,3 +23,9 @@ impl DAC {
         write_reg!(stm32ral::dac, self.dac, DHR12R2, val as u32);
     }
 }
+
+impl hw::CurrentRef for DAC {
+    fn set_i_ref(&self, level: u16) {
+        self.set_ch1(level);
+    }
+}
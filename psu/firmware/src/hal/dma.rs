@@ -46,6 +46,11 @@ impl DMA {
         read_reg!(dma, self.dma, ISR, TCIF4 == NotComplete)
     }
 
+    /// Returns true if no USART1 transfer is in progress.
+    pub fn usart1_idle(&self) -> bool {
+        read_reg!(dma, self.dma, CR4, EN == Disabled)
+    }
+
     pub fn usart1_disable(&self) {
         modify_reg!(dma, self.dma, CR4, EN: Disabled);
     }
@@ -0,0 +1,29 @@
+use stm32ral::{exti, syscfg, modify_reg, write_reg, read_reg};
+
+pub struct EXTI {
+    exti: exti::Instance,
+    syscfg: syscfg::Instance,
+}
+
+impl EXTI {
+    pub fn new(exti: exti::Instance, syscfg: syscfg::Instance) -> Self {
+        EXTI { exti, syscfg }
+    }
+
+    /// Enable interrupts on both edges of PB5, the profile sync input.
+    pub fn setup(&self) {
+        // Route PB5 to EXTI5
+        modify_reg!(stm32ral::syscfg, self.syscfg, EXTICR2, EXTI5: 0b001);
+
+        // Trigger on rising and falling edges and unmask
+        modify_reg!(stm32ral::exti, self.exti, RTSR1, TR5: Enabled);
+        modify_reg!(stm32ral::exti, self.exti, FTSR1, TR5: Enabled);
+        modify_reg!(stm32ral::exti, self.exti, IMR1, MR5: Unmasked);
+    }
+
+    pub fn isr(&self) {
+        if read_reg!(stm32ral::exti, self.exti, PR1, PR5 == Pending) {
+            write_reg!(stm32ral::exti, self.exti, PR1, PR5: Clear);
+        }
+    }
+}
@@ -0,0 +1,98 @@
+use stm32ral::{flash, read_reg, write_reg, modify_reg};
+
+/// Flash page size in bytes.
+pub const PAGE_SIZE: usize = 2048;
+
+pub struct Flash {
+    flash: flash::Instance,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum Error {
+    /// Programming error, such as writing to a location which was not erased.
+    Program,
+    /// Write protection error.
+    WriteProtect,
+}
+
+impl Flash {
+    pub fn new(flash: flash::Instance) -> Self {
+        Flash { flash }
+    }
+
+    pub fn setup(&self) {
+        // Configure flash to two wait states ready for 70MHz operation
+        modify_reg!(stm32ral::flash, self.flash, ACR, LATENCY: 2);
+    }
+
+    /// Read a page of flash as a slice of `T`.
+    ///
+    /// `T` must be valid for any bit pattern, including erased (all 0xFF) flash.
+    pub fn read<T>(&self, address: u32) -> &[T] {
+        // UNSAFE: The page is in memory-mapped flash and only modified through &mut self.
+        unsafe {
+            core::slice::from_raw_parts(address as *const T,
+                                        PAGE_SIZE / core::mem::size_of::<T>())
+        }
+    }
+
+    /// Erase the page starting at `address`.
+    ///
+    /// The CPU stalls for the duration of the erase (up to 40ms) if executing from flash.
+    pub fn erase_page(&mut self, address: u32) -> Result<(), Error> {
+        self.unlock();
+        modify_reg!(stm32ral::flash, self.flash, CR, PER: 1);
+        write_reg!(stm32ral::flash, self.flash, AR, address);
+        modify_reg!(stm32ral::flash, self.flash, CR, STRT: 1);
+        let result = self.wait();
+        modify_reg!(stm32ral::flash, self.flash, CR, PER: 0);
+        self.lock();
+        result
+    }
+
+    /// Program `data` into previously erased flash starting at `address`.
+    ///
+    /// Each half-word stalls the CPU for around 50µs if executing from flash.
+    pub fn program(&mut self, address: u32, data: &[u16]) -> Result<(), Error> {
+        self.unlock();
+        modify_reg!(stm32ral::flash, self.flash, CR, PG: 1);
+        let mut result = Ok(());
+        for (idx, word) in data.iter().enumerate() {
+            // UNSAFE: Writes to flash only take effect as half-word programming operations
+            // while PG is set.
+            unsafe { core::ptr::write_volatile((address as *mut u16).add(idx), *word) };
+            result = self.wait();
+            if result.is_err() {
+                break;
+            }
+        }
+        modify_reg!(stm32ral::flash, self.flash, CR, PG: 0);
+        self.lock();
+        result
+    }
+
+    fn unlock(&self) {
+        if read_reg!(stm32ral::flash, self.flash, CR, LOCK == 1) {
+            write_reg!(stm32ral::flash, self.flash, KEYR, 0x4567_0123);
+            write_reg!(stm32ral::flash, self.flash, KEYR, 0xCDEF_89AB);
+        }
+    }
+
+    fn lock(&self) {
+        modify_reg!(stm32ral::flash, self.flash, CR, LOCK: 1);
+    }
+
+    /// Wait for the current operation to complete and check for errors.
+    fn wait(&self) -> Result<(), Error> {
+        while read_reg!(stm32ral::flash, self.flash, SR, BSY == 1) {}
+        let (pgerr, wrprterr) = read_reg!(stm32ral::flash, self.flash, SR, PGERR, WRPRT);
+        write_reg!(stm32ral::flash, self.flash, SR, EOP: 1, PGERR: 1, WRPRT: 1);
+        if pgerr == 1 {
+            Err(Error::Program)
+        } else if wrprterr == 1 {
+            Err(Error::WriteProtect)
+        } else {
+            Ok(())
+        }
+    }
+}
@@ -1,5 +1,7 @@
 use stm32ral::{gpio, modify_reg, write_reg, read_reg};
 
+use iggie_psu::hw;
+
 pub struct GPIO {
     gpioa: gpio::Instance,
     gpiob: gpio::Instance,
@@ -24,9 +26,19 @@ impl GPIO {
         modify_reg!(stm32ral::gpio, gpiob, MODER, MODER3: Output, MODER4: Output);
         modify_reg!(stm32ral::gpio, gpiob, ODR, ODR3: 0, ODR4: 0);
 
-        // Set PB6 to USART Tx (AF7)
-        modify_reg!(stm32ral::gpio, gpiob, AFRL, AFRL6: AF7);
-        modify_reg!(stm32ral::gpio, gpiob, MODER, MODER6: Alternate);
+        // Set PB6 to USART Tx (AF7) and PB7 to USART Rx (AF7)
+        modify_reg!(stm32ral::gpio, gpiob, AFRL, AFRL6: AF7, AFRL7: AF7);
+        modify_reg!(stm32ral::gpio, gpiob, MODER, MODER6: Alternate, MODER7: Alternate);
+
+        // Set PB5 to input with pull-down for the profile sync signal.
+        // PB5 is unconnected on r1 boards and must be wired to the display driver.
+        modify_reg!(stm32ral::gpio, gpiob, PUPDR, PUPDR5: PullDown);
+        modify_reg!(stm32ral::gpio, gpiob, MODER, MODER5: Input);
+
+        // Set PA11 to input with pull-up for the preset strap, read at power on.
+        // Linking PA11 to ground selects the alternate preset.
+        modify_reg!(stm32ral::gpio, gpioa, PUPDR, PUPDR11: PullUp);
+        modify_reg!(stm32ral::gpio, gpioa, MODER, MODER11: Input);
 
         // Set PA0, 1, 2, 3, 6, 7 to analogue input for ADCs and COMPs
         modify_reg!(stm32ral::gpio, gpioa, MODER, MODER0: Analog, MODER1: Analog, MODER2: Analog,
@@ -76,7 +88,33 @@ impl GPIO {
         read_reg!(stm32ral::gpio, self.gpioa, IDR, IDR15 == Low)
     }
 
+    /// Read the profile sync input; high requests the strike profile.
+    pub fn get_sync(&self) -> bool {
+        read_reg!(stm32ral::gpio, self.gpiob, IDR, IDR5 == High)
+    }
+
+    /// Read the preset strap; true when fitted (PA11 low).
+    pub fn get_preset_strap(&self) -> bool {
+        read_reg!(stm32ral::gpio, self.gpioa, IDR, IDR11 == Low)
+    }
+
     pub unsafe fn global_set_err_led() {
         write_reg!(stm32ral::gpio, GPIOB, BSRR, BS4: Set);
     }
 }
+
+impl hw::RunInput for GPIO {
+    fn run(&self) -> bool {
+        self.get_run()
+    }
+}
+
+impl hw::StatusLeds for GPIO {
+    fn set_400v_led(&self, on: bool) {
+        GPIO::set_400v_led(self, on);
+    }
+
+    fn set_err_led(&self, on: bool) {
+        GPIO::set_err_led(self, on);
+    }
+}
@@ -1,9 +1,12 @@
 use stm32ral::{hrtim_master, hrtim_tima, hrtim_common, read_reg, write_reg, modify_reg};
 
+use iggie_psu::{hw, timing};
+
 pub struct HRTIM {
     master: hrtim_master::Instance,
     tima: hrtim_tima::Instance,
     common: hrtim_common::Instance,
+    burst_period: u16,
 }
 
 impl HRTIM {
@@ -12,23 +15,27 @@ impl HRTIM {
         tima: hrtim_tima::Instance,
         common: hrtim_common::Instance
     ) -> Self {
-        HRTIM { master, tima, common }
+        HRTIM { master, tima, common, burst_period: 1000 }
     }
 
     pub fn setup(&self) {
         // Begin DLL calibration
         write_reg!(stm32ral::hrtim_common, self.common, DLLCR, CAL: Start);
-        // Wait for calibration complete
-        while read_reg!(stm32ral::hrtim_common, self.common, ISR, DLLRDY != 1) {}
+        // Wait for calibration complete, giving up after at least 1ms
+        // so that a failure is reported by the self-test instead of hanging here.
+        let mut timeout = timing::cycles(1e-3);
+        while read_reg!(stm32ral::hrtim_common, self.common, ISR, DLLRDY != 1) && timeout > 0 {
+            timeout -= 1;
+        }
         // Enable periodic recalibration
         write_reg!(stm32ral::hrtim_common, self.common, DLLCR, CALRTE: Micros910, CALEN: Enabled);
 
         // Configure master counter
 
-        // Set prescaler to Div64 to obtain fHRTIM=70MHz, master counter to continuous,
+        // Set prescaler to count at F_HRCK, master counter to continuous,
         // enable preloading and update on repetition (=rollover with rep=0)
         write_reg!(stm32ral::hrtim_master, self.master, MCR,
-                   MREPU: Enabled, PREEN: Enabled, CONT: Continuous, CKPSC: 6);
+                   MREPU: Enabled, PREEN: Enabled, CONT: Continuous, CKPSC: timing::HRTIM_CKPSC);
 
         // Set period to ensure regular pulses at startup; during operation DCM detection on V_Q
         // triggers the next charge cycle and causes a software reset of the master timer.
@@ -40,7 +47,7 @@ impl HRTIM {
 
         // Enable preload with update on reset, prescaler same as master.
         write_reg!(stm32ral::hrtim_tima, self.tima, TIMACR,
-                   PREEN: Enabled, TxRSTU: Enabled, CKPSCx: 6);
+                   PREEN: Enabled, TxRSTU: Enabled, CKPSCx: timing::HRTIM_CKPSC);
 
         // Configure period to 120 counts maximum.
         // In principle 100 counts gives 1.4µs, which at 5µH and 24V gives 6.7A,
@@ -85,7 +92,7 @@ impl HRTIM {
         write_reg!(stm32ral::hrtim_common, self.common, BMCMPR, 0);
 
         // Set period to 1000 counts = 68Hz
-        write_reg!(stm32ral::hrtim_common, self.common, BMPER, 1000);
+        write_reg!(stm32ral::hrtim_common, self.common, BMPER, self.burst_period as u32);
 
         // Configure external event conditioning for EEV1 and EEV2
         write_reg!(stm32ral::hrtim_common, self.common, EECR1,
@@ -108,6 +115,11 @@ impl HRTIM {
         write_reg!(stm32ral::hrtim_common, self.common, CR2, MSWU: Update, TASWU: Update);
     }
 
+    /// Returns true if DLL calibration has completed.
+    pub fn dll_ready(&self) -> bool {
+        read_reg!(stm32ral::hrtim_common, self.common, ISR, DLLRDY == 1)
+    }
+
     /// Enable HRTIM and begin driving outputs.
     pub fn enable(&self) {
         // Enable fault interrupt
@@ -131,7 +143,23 @@ impl HRTIM {
     /// and 1000 is 100% duty (outputs never forced off).
     pub fn set_duty(&self, duty: u16) {
         let duty = if duty > 1000 { 1000u32 } else { duty as u32 };
-        write_reg!(stm32ral::hrtim_common, self.common, BMCMPR, 1000-duty);
+        let period = self.burst_period as u32;
+        write_reg!(stm32ral::hrtim_common, self.common, BMCMPR, period - (duty * period) / 1000);
+    }
+
+    /// Set burst mode clock prescaler and period.
+    ///
+    /// The burst mode counter is clocked at f_HRTIM/2^`prescaler`, and each burst lasts
+    /// `period` counts. `prescaler` may be 0 to 15, and `period` must be at least 1.
+    /// The default is prescaler 9 (68kHz) and period 1000 (68Hz).
+    ///
+    /// Only call while the HRTIM is disabled.
+    pub fn set_burst_period(&mut self, prescaler: u8, period: u16) {
+        let prescaler = if prescaler > 15 { 15 } else { prescaler as u32 };
+        let period = if period < 1 { 1 } else { period };
+        self.burst_period = period;
+        modify_reg!(stm32ral::hrtim_common, self.common, BMCR, BMPRSC: prescaler);
+        write_reg!(stm32ral::hrtim_common, self.common, BMPER, period as u32);
     }
 
     /// Disable HRTIM, stopping outputs and counters.
@@ -146,12 +174,15 @@ impl HRTIM {
         modify_reg!(stm32ral::hrtim_master, self.master, MCR, TACEN: Disabled, MCEN: Disabled);
     }
 
-    pub fn flt_isr(&self) {
+    /// Handle a fault interrupt, returning whether FLT2 (nRUN) and SYSFLT were flagged.
+    pub fn flt_isr(&self) -> (bool, bool) {
         // Clear ISR bits
-        if read_reg!(stm32ral::hrtim_common, self.common, ISR, FLT2 == Event) {
+        let flt2 = read_reg!(stm32ral::hrtim_common, self.common, ISR, FLT2 == Event);
+        if flt2 {
             write_reg!(stm32ral::hrtim_common, self.common, ICR, FLT2C: Clear);
         }
-        if read_reg!(stm32ral::hrtim_common, self.common, ISR, SYSFLT == Event) {
+        let sysflt = read_reg!(stm32ral::hrtim_common, self.common, ISR, SYSFLT == Event);
+        if sysflt {
             write_reg!(stm32ral::hrtim_common, self.common, ICR, SYSFLTC: Clear);
         }
 
@@ -160,6 +191,8 @@ impl HRTIM {
 
         // Move from FAULT state to normal disabled state
         self.disable();
+
+        (flt2, sysflt)
     }
 
     pub unsafe fn global_disable() {
@@ -167,3 +200,17 @@ impl HRTIM {
         write_reg!(stm32ral::hrtim_master, HRTIM_Master, MCR, TACEN: Disabled, MCEN: Disabled);
     }
 }
+
+impl hw::BurstPwm for HRTIM {
+    fn enable(&self) {
+        HRTIM::enable(self);
+    }
+
+    fn disable(&self) {
+        HRTIM::disable(self);
+    }
+
+    fn set_duty(&self, duty: u16) {
+        HRTIM::set_duty(self, duty);
+    }
+}
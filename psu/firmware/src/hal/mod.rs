@@ -7,3 +7,5 @@ pub mod comp;
 pub mod dac;
 pub mod hrtim;
 pub mod tim2;
+pub mod exti;
+pub mod flash;
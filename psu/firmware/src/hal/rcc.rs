@@ -1,23 +1,22 @@
-use stm32ral::{rcc, flash, read_reg, write_reg, modify_reg};
+use stm32ral::{rcc, read_reg, write_reg, modify_reg};
+
+use iggie_psu::timing;
 
 pub struct RCC {
     rcc: rcc::Instance,
-    flash: flash::Instance,
 }
 
 impl RCC {
-    pub fn new(rcc: rcc::Instance, flash: flash::Instance) -> Self {
-        RCC { rcc, flash }
+    pub fn new(rcc: rcc::Instance) -> Self {
+        RCC { rcc }
     }
 
-    /// Set up device clocks
+    /// Set up device clocks.
+    ///
+    /// Flash wait states must be configured first, see `Flash::setup`.
     pub fn setup(&self) {
-        let flash = &self.flash;
         let rcc = &self.rcc;
 
-        // Configure flash to two wait states ready for 70MHz operation
-        modify_reg!(stm32ral::flash, flash, ACR, LATENCY: 2);
-
         // Ensure HSI is enabled, stable, and in use
         modify_reg!(stm32ral::rcc, rcc, CR, HSION: On);
         while read_reg!(stm32ral::rcc, rcc, CR, HSIRDY != Ready) {}
@@ -33,11 +32,12 @@ impl RCC {
         modify_reg!(stm32ral::rcc, rcc, CR, PLLON: Off);
         while read_reg!(stm32ral::rcc, rcc, CR, PLLRDY == Ready) {}
 
-        // Configure PLL: 25MHz HSE /5 *14 = 70MHz PLL
-        // APB1: 35MHz, APB2: 70MHz, AHB: 70MHz
-        modify_reg!(stm32ral::rcc, rcc, CFGR2, PREDIV: Div5, ADC12PRES: Div1);
-        modify_reg!(stm32ral::rcc, rcc, CFGR, PLLSRC: HSE_Div_PREDIV, PLLMUL: Mul14);
-        modify_reg!(stm32ral::rcc, rcc, CFGR, PPRE2: Div1, PPRE1: Div2, HPRE: Div1);
+        // Configure PLL from HSE and bus dividers, see `timing`.
+        // AHB, APB2 and ADC run at the PLL output, APB1 is divided.
+        modify_reg!(stm32ral::rcc, rcc, CFGR2, PREDIV: timing::RCC_PREDIV, ADC12PRES: Div1);
+        modify_reg!(stm32ral::rcc, rcc, CFGR,
+                    PLLSRC: HSE_Div_PREDIV, PLLMUL: timing::RCC_PLLMUL);
+        modify_reg!(stm32ral::rcc, rcc, CFGR, PPRE2: Div1, PPRE1: timing::RCC_PPRE1, HPRE: Div1);
         modify_reg!(stm32ral::rcc, rcc, CR, PLLON: On);
 
         // Wait for PLL to be ready and swap to it
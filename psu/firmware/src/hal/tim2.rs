@@ -1,5 +1,7 @@
 use stm32ral::{tim2, write_reg, modify_reg};
 
+use iggie_psu::timing;
+
 pub struct TIM2 {
     tim2: tim2::Instance,
 }
@@ -11,10 +13,9 @@ impl TIM2 {
 
     pub fn setup(&self) {
         write_reg!(stm32ral::tim2, self.tim2, DIER, UIE: Enabled);
-        // TIM2 runs at 70MHz. Prescale by 70 to count at 1MHz
-        write_reg!(stm32ral::tim2, self.tim2, PSC, 70);
-        // ARR at 100 gives 10kHz interrupt generation
-        write_reg!(stm32ral::tim2, self.tim2, ARR, 100);
+        // Prescale to count at TIM2_TICK, and update at the control loop rate
+        write_reg!(stm32ral::tim2, self.tim2, PSC, timing::TIM2_PSC);
+        write_reg!(stm32ral::tim2, self.tim2, ARR, timing::TIM2_ARR);
     }
 
     pub fn start(&self) {
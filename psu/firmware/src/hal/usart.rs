@@ -1,5 +1,7 @@
 use stm32ral::{usart, modify_reg, write_reg, read_reg};
 
+use iggie_psu::timing;
+
 use super::dma::DMA;
 
 pub struct USART {
@@ -12,13 +14,27 @@ impl USART {
     }
 
     pub fn setup(&self) {
-        // Configure USART. Enable DMA for transmission, enable transmitter, set to 3.5MBd.
+        // Configure USART. Enable DMA for transmission, enable transmitter and receiver
+        // with receive interrupt for commands, set to USART1_BAUD.
         // Other settings are default: 8n1
         modify_reg!(stm32ral::usart, self.usart, CR3, DMAT: Enabled);
         modify_reg!(stm32ral::usart, self.usart, CR1, OVER8: Oversampling8);
-        write_reg!(stm32ral::usart, self.usart, BRR, 18);
-        modify_reg!(stm32ral::usart, self.usart, CR1, TCIE: Enabled, TE: Enabled, UE: Enabled);
+        write_reg!(stm32ral::usart, self.usart, BRR, timing::USART1_BRR);
+        modify_reg!(stm32ral::usart, self.usart, CR1,
+                    TCIE: Enabled, RXNEIE: Enabled, TE: Enabled, RE: Enabled, UE: Enabled);
+    }
 
+    /// Configure for Modbus RTU instead of telemetry: 8 data bits with even parity,
+    /// at MODBUS_BAUD, with the receiver timeout interrupt marking the end of each frame.
+    pub fn setup_modbus(&self) {
+        modify_reg!(stm32ral::usart, self.usart, CR3, DMAT: Enabled);
+        modify_reg!(stm32ral::usart, self.usart, CR2, RTOEN: Enabled);
+        write_reg!(stm32ral::usart, self.usart, RTOR, RTO: timing::MODBUS_RTO);
+        modify_reg!(stm32ral::usart, self.usart, CR1,
+                    OVER8: Oversampling8, M: Bit9, PCE: Enabled, PS: Even);
+        write_reg!(stm32ral::usart, self.usart, BRR, timing::MODBUS_BRR);
+        modify_reg!(stm32ral::usart, self.usart, CR1, RTOIE: Enabled,
+                    TCIE: Enabled, RXNEIE: Enabled, TE: Enabled, RE: Enabled, UE: Enabled);
     }
 
     /// Transmit a &[u8] via DMA
@@ -37,13 +53,50 @@ impl USART {
         write_reg!(stm32ral::usart, self.usart, TDR, w2 as u32);
     }
 
+    /// Read a received byte, if one is available.
+    ///
+    /// Any overrun is cleared so reception continues, losing the overwritten bytes.
+    pub fn read(&self) -> Option<u8> {
+        if read_reg!(stm32ral::usart, self.usart, ISR, ORE == 1) {
+            write_reg!(stm32ral::usart, self.usart, ICR, ORECF: Clear);
+        }
+        if read_reg!(stm32ral::usart, self.usart, ISR, RXNE == 1) {
+            Some(read_reg!(stm32ral::usart, self.usart, RDR) as u8)
+        } else {
+            None
+        }
+    }
+
+    /// Returns true, clearing the flag, if a parity error has been received.
+    pub fn parity_error(&self) -> bool {
+        let error = read_reg!(stm32ral::usart, self.usart, ISR, PE == 1);
+        if error {
+            write_reg!(stm32ral::usart, self.usart, ICR, PECF: Clear);
+        }
+        error
+    }
+
+    /// Returns true, clearing the flag, if the receiver timeout has elapsed
+    /// since the last received byte.
+    pub fn rx_timeout(&self) -> bool {
+        let timeout = read_reg!(stm32ral::usart, self.usart, ISR, RTOF == 1);
+        if timeout {
+            write_reg!(stm32ral::usart, self.usart, ICR, RTOCF: Clear);
+        }
+        timeout
+    }
+
     /// If TC flag is set and the DMA transfer has completed, clear TC and disable DMA.
-    pub fn isr(&self, dma: &DMA) {
+    ///
+    /// Returns true if a transfer has just completed.
+    pub fn isr(&self, dma: &DMA) -> bool {
         if read_reg!(stm32ral::usart, self.usart, ISR, TC == 1) {
             if !dma.usart1_busy() {
                 write_reg!(stm32ral::usart, self.usart, ICR, TCCF: Clear);
                 dma.usart1_disable();
+                return true;
             }
         }
+        false
     }
 }
@@ -0,0 +1,206 @@
+//! Pre-fault history of decimated state snapshots
+//!
+//! Snapshots are recorded continuously into a ring buffer. When the PSU enters
+//! the fault state, recording stops and the buffer is put into time order and
+//! held until it has been transmitted, so each fault is reported along with the
+//! readings that led up to it. Recording resumes once the fault is cleared.
+//!
+//! The packet is a 12 byte header followed by `count` snapshots, oldest first:
+//!
+//!     [ magic | dt (f32) | fault code | pad | count (u16) ]
+
+use crate::state::{State, FaultState, ToBytes};
+
+/// History packet header length.
+const HEADER_LEN: usize = 12;
+
+/// Selected `State` fields at one instant.
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct Snapshot {
+    pub v_in: f32,
+    pub i_in: f32,
+    pub v_out: f32,
+    pub i_out: f32,
+    pub pid_i: f32,
+    pub ref_i_q: u16,
+    pub duty: u16,
+}
+
+impl Snapshot {
+    pub const fn new() -> Self {
+        Snapshot { v_in: 0.0, i_in: 0.0, v_out: 0.0, i_out: 0.0, pid_i: 0.0, ref_i_q: 0, duty: 0 }
+    }
+
+    fn from_state(state: &State) -> Self {
+        Snapshot {
+            v_in: state.v_in, i_in: state.i_in, v_out: state.v_out, i_out: state.i_out,
+            pid_i: state.pid_i, ref_i_q: state.ref_i_q, duty: state.duty,
+        }
+    }
+}
+
+impl Default for Snapshot {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Stage {
+    Recording,
+    Pending,
+    Sending,
+    Frozen,
+}
+
+/// Fault history packet, holding up to `N` snapshots.
+#[repr(C)]
+#[repr(align(4))]
+pub struct FaultHistory<const N: usize> {
+    magic: u32,
+    /// Time between snapshots (s).
+    pub dt: f32,
+    /// Fault code which froze the history.
+    pub fault_code: u8,
+    _padding: u8,
+    /// Number of valid snapshots.
+    pub count: u16,
+    pub snapshots: [Snapshot; N],
+}
+
+unsafe impl<const N: usize> ToBytes for FaultHistory<N> {}
+
+/// Records one in every `decimation` states into a ring of `N` snapshots.
+pub struct History<const N: usize> {
+    decimation: u16,
+    skip: u16,
+    pos: usize,
+    filled: usize,
+    stage: Stage,
+    packet: FaultHistory<N>,
+}
+
+impl<const N: usize> History<N> {
+    /// Create a new history, where `dt` is the time between calls to `push`.
+    pub const fn new(decimation: u16, dt: f32) -> Self {
+        History {
+            decimation, skip: 0, pos: 0, filled: 0,
+            stage: Stage::Recording,
+            packet: FaultHistory {
+                magic: 0x68697374, dt: dt * decimation as f32, fault_code: 0, _padding: 0,
+                count: 0, snapshots: [Snapshot::new(); N],
+            },
+        }
+    }
+
+    /// Record the current state, freezing the history if it has entered the fault state.
+    pub fn push(&mut self, state: &State) {
+        let fault = state.fault_state == FaultState::Fault;
+        match self.stage {
+            Stage::Recording if fault => {
+                self.store(state);
+                self.freeze(state);
+            },
+            Stage::Recording => {
+                if self.skip == 0 {
+                    self.store(state);
+                    self.skip = self.decimation;
+                }
+                self.skip -= 1;
+            },
+            Stage::Frozen if !fault => {
+                self.pos = 0;
+                self.filled = 0;
+                self.skip = 0;
+                self.stage = Stage::Recording;
+            },
+            _ => (),
+        }
+    }
+
+    /// Returns the history packet if a fault has frozen the history and it has not yet
+    /// been sent, marking it as being transmitted.
+    pub fn packet(&mut self) -> Option<&[u8]> {
+        if self.stage == Stage::Pending {
+            self.stage = Stage::Sending;
+            let len = HEADER_LEN + self.packet.count as usize * core::mem::size_of::<Snapshot>();
+            Some(&self.packet.to_bytes()[..len])
+        } else {
+            None
+        }
+    }
+
+    /// Call when a serial transfer completes, releasing the buffer if it was being sent.
+    pub fn transmitted(&mut self) {
+        if self.stage == Stage::Sending {
+            self.stage = Stage::Frozen;
+        }
+    }
+
+    /// Returns true while the history packet is being transmitted.
+    pub fn sending(&self) -> bool {
+        self.stage == Stage::Sending
+    }
+
+    fn store(&mut self, state: &State) {
+        self.packet.snapshots[self.pos] = Snapshot::from_state(state);
+        self.pos = (self.pos + 1) % N;
+        self.filled = (self.filled + 1).min(N);
+    }
+
+    fn freeze(&mut self, state: &State) {
+        // Until the ring has filled, the oldest snapshot is at the start already.
+        if self.filled == N {
+            self.packet.snapshots.rotate_left(self.pos);
+        }
+        self.packet.count = self.filled as u16;
+        self.packet.fault_code = state.fault_code as u8;
+        self.stage = Stage::Pending;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::FaultCode;
+
+    fn v_outs(packet: &[u8]) -> std::vec::Vec<f32> {
+        packet[HEADER_LEN..].chunks(24)
+            .map(|s| f32::from_le_bytes([s[8], s[9], s[10], s[11]])).collect()
+    }
+
+    #[test]
+    fn freezes_on_fault() {
+        let mut history = History::<4>::new(2, 1e-4);
+        let mut state = State::new();
+        state.set_state_running();
+        for k in 0..10 {
+            state.v_out = k as f32;
+            history.push(&state);
+        }
+        state.v_out = 10.0;
+        state.set_fault(FaultCode::VLim);
+        state.set_state_fault();
+        history.push(&state);
+
+        // Further states are ignored while frozen
+        state.v_out = 11.0;
+        history.push(&state);
+
+        let packet = history.packet().unwrap();
+        assert_eq!(packet[8], FaultCode::VLim as u8);
+        assert_eq!(f32::from_le_bytes([packet[4], packet[5], packet[6], packet[7]]), 2e-4);
+        assert_eq!(v_outs(packet), [4.0, 6.0, 8.0, 10.0]);
+        assert!(history.packet().is_none());
+        history.transmitted();
+
+        // Recording restarts once the fault clears
+        state.set_state_running();
+        history.push(&state);
+        state.v_out = 12.0;
+        state.set_state_fault();
+        history.push(&state);
+        assert_eq!(v_outs(history.packet().unwrap()), [12.0]);
+    }
+}
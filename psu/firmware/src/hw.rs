@@ -0,0 +1,34 @@
+//! Hardware interfaces used by the application logic
+//!
+//! The firmware implements these on its HAL types, while host tests implement
+//! them on mocks which record what the logic drove.
+
+/// Peak inductor current reference, set on the DAC feeding the current comparator.
+pub trait CurrentRef {
+    /// Set the reference in DAC counts, from 0 to 4095.
+    fn set_i_ref(&self, level: u16);
+}
+
+/// Switching output, gated in bursts under light load.
+pub trait BurstPwm {
+    /// Start switching.
+    fn enable(&self);
+
+    /// Stop switching immediately.
+    fn disable(&self);
+
+    /// Set the burst duty cycle, in parts per 1000.
+    fn set_duty(&self, duty: u16);
+}
+
+/// Hardware nRUN input.
+pub trait RunInput {
+    /// Returns true while nRUN is asserted.
+    fn run(&self) -> bool;
+}
+
+/// Front panel status LEDs.
+pub trait StatusLeds {
+    fn set_400v_led(&self, on: bool);
+    fn set_err_led(&self, on: bool);
+}
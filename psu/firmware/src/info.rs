@@ -0,0 +1,160 @@
+//! Firmware identity and build information
+//!
+//! The hello packet identifies the running firmware, and is sent at boot and
+//! on request so the host can tell boards apart:
+//!
+//!     [ magic | protocol (u16) | pad (u16) | config CRC (u32) | version (12 bytes)
+//!       | commit (16 bytes) | profile (8 bytes) | features (32 bytes) ]
+//!
+//! Strings are NUL padded, and truncated if too long. The git commit, build profile
+//! and features are provided by the build script.
+
+use crate::state::ToBytes;
+
+/// Version of the serial protocol, incremented whenever a packet or command
+/// layout changes incompatibly.
+pub const PROTOCOL_VERSION: u16 = 3;
+
+/// Crate version.
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Short git commit hash, suffixed with "-dirty" if there were uncommitted changes.
+pub const COMMIT: &str = env!("IGGIE_GIT_COMMIT");
+
+/// Cargo build profile, "debug" or "release".
+pub const PROFILE: &str = env!("IGGIE_BUILD_PROFILE");
+
+/// Comma separated list of enabled cargo features.
+pub const FEATURES: &str = env!("IGGIE_FEATURES");
+
+/// Byte-wise lookup table for CRC-32 with the reflected IEEE polynomial.
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// CRC-32 (IEEE 802.3) accumulator, for fingerprinting configuration constants
+/// and checking firmware images.
+///
+/// All methods are const so the checksum can be computed at compile time.
+#[derive(Copy, Clone)]
+pub struct Crc32 {
+    crc: u32,
+}
+
+impl Crc32 {
+    pub const fn new() -> Self {
+        Crc32 { crc: 0xFFFF_FFFF }
+    }
+
+    /// Add `data` to the checksum.
+    pub const fn bytes(mut self, data: &[u8]) -> Self {
+        let mut i = 0;
+        while i < data.len() {
+            self.crc = CRC_TABLE[((self.crc ^ data[i] as u32) & 0xFF) as usize] ^ (self.crc >> 8);
+            i += 1;
+        }
+        self
+    }
+
+    /// Add a u32 to the checksum, in little-endian byte order.
+    pub const fn u32(self, x: u32) -> Self {
+        self.bytes(&x.to_le_bytes())
+    }
+
+    /// Add an f32 to the checksum, by its bit pattern.
+    pub const fn f32(self, x: f32) -> Self {
+        self.u32(x.to_bits())
+    }
+
+    pub const fn finish(self) -> u32 {
+        !self.crc
+    }
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Hello packet.
+#[repr(C)]
+#[repr(align(4))]
+pub struct Hello {
+    magic: u32,
+    pub protocol: u16,
+    _padding: u16,
+    /// Checksum of the configuration constants the firmware was built with.
+    pub config_crc: u32,
+    version: [u8; 12],
+    commit: [u8; 16],
+    profile: [u8; 8],
+    features: [u8; 32],
+}
+
+impl Hello {
+    pub const fn new(config_crc: u32) -> Self {
+        Hello {
+            magic: 0x68656c6f,
+            protocol: PROTOCOL_VERSION,
+            _padding: 0,
+            config_crc,
+            version: pad(VERSION),
+            commit: pad(COMMIT),
+            profile: pad(PROFILE),
+            features: pad(FEATURES),
+        }
+    }
+}
+
+unsafe impl ToBytes for Hello {}
+
+/// Copy `s` into a NUL padded array, truncating if required.
+const fn pad<const N: usize>(s: &str) -> [u8; N] {
+    let s = s.as_bytes();
+    let mut out = [0; N];
+    let mut i = 0;
+    while i < s.len() && i < N {
+        out[i] = s[i];
+        i += 1;
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc() {
+        assert_eq!(Crc32::new().bytes(b"123456789").finish(), 0xCBF4_3926);
+        assert_eq!(Crc32::new().u32(0x3433_3231).finish(), Crc32::new().bytes(b"1234").finish());
+        assert_ne!(Crc32::new().f32(1.0).finish(), Crc32::new().f32(-1.0).finish());
+    }
+
+    #[test]
+    fn hello() {
+        let hello = Hello::new(0x1234_5678);
+        let bytes = hello.to_bytes();
+        assert_eq!(bytes.len(), 80);
+        assert_eq!(&bytes[..4], b"oleh");
+        assert_eq!(&bytes[4..6], &PROTOCOL_VERSION.to_le_bytes());
+        assert_eq!(&bytes[8..12], &[0x78, 0x56, 0x34, 0x12]);
+        assert_eq!(&bytes[12..12 + VERSION.len()], VERSION.as_bytes());
+        assert_eq!(bytes[12 + VERSION.len()], 0);
+        assert_eq!(pad::<4>("abcdef"), *b"abcd");
+    }
+}
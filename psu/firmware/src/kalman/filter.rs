@@ -0,0 +1,101 @@
+//! Generic linear Kalman filter over N states and M measurements
+
+use crate::matrix::{Matrix, Vector};
+
+/// A linear process model for use with `Filter`.
+///
+/// The model provides the (constant) state transition model 𝗙, process noise
+/// covariance 𝗤 and observation model 𝗛. The measurement noise covariance 𝗥
+/// is a property of the sensors rather than the process, so is given directly
+/// to `Filter::new`.
+#[allow(non_snake_case)]
+pub trait Model<const N: usize, const M: usize> {
+    /// State transition model 𝗙, such that 𝘅_k = 𝗙 𝘅_k-1 + 𝘄_k.
+    fn F(&self) -> Matrix<N, N>;
+
+    /// Process noise covariance 𝗤 = E[𝘄_k 𝘄_k'].
+    fn Q(&self) -> Matrix<N, N>;
+
+    /// Observation model 𝗛, such that 𝘇_k = 𝗛 𝘅_k + 𝘃_k.
+    fn H(&self) -> Matrix<M, N>;
+}
+
+/// Kalman filter with N states and M measurements.
+///
+/// The model matrices are computed once at construction, so each step
+/// only performs the predict and update matrix arithmetic. Naming follows
+/// `Kalman`: `x` is 𝘅_k|k, `xp` is 𝘅_k|k-1, `P` is 𝗣_k|k, `Pp` is 𝗣_k|k-1.
+#[allow(non_snake_case)]
+pub struct Filter<const N: usize, const M: usize> {
+    F: Matrix<N, N>,
+    Q: Matrix<N, N>,
+    H: Matrix<M, N>,
+    R: Matrix<M, M>,
+    x: Vector<N>,
+    xp: Vector<N>,
+    P: Matrix<N, N>,
+    Pp: Matrix<N, N>,
+}
+
+impl<const N: usize, const M: usize> Filter<N, M> {
+    /// Create a new filter from a process model.
+    ///
+    /// R: measurement noise covariance
+    /// x0: initial state
+    ///
+    /// The error covariance is initialised to a small value along the diagonal.
+    #[allow(non_snake_case)]
+    pub fn new<T: Model<N, M>>(model: &T, R: Matrix<M, M>, x0: [f32; N]) -> Self {
+        let x = Vector::from_array(x0);
+        Filter {
+            F: model.F(), Q: model.Q(), H: model.H(), R,
+            x, xp: x,
+            P: Matrix::diag(1e-3), Pp: Matrix::zero(),
+        }
+    }
+
+    /// Run a Kalman predict step
+    ///
+    /// 𝘅_k|k-1 = 𝗙 𝘅_k-1|k-1
+    /// 𝗣_k|k-1 = 𝗙 𝗣_k-1|k-1 𝗙' + 𝗤
+    pub fn predict(&mut self) {
+        self.xp = self.F * self.x;
+        self.Pp = self.F * self.P * self.F.t() + self.Q;
+    }
+
+    /// Run a Kalman update step from measurements z
+    ///
+    /// 𝘆_k = 𝘇_k - 𝗛 𝘅_k|k-1
+    /// 𝗦_k = 𝗛 𝗣_k|k-1 𝗛' + 𝗥
+    /// 𝗞_k = 𝗣_k|k-1 𝗛' 𝗦_k^-1
+    /// 𝘅_k|k = 𝘅_k|k-1 + 𝗞_k 𝘆_k
+    /// 𝗣_k|k = (𝗜 - 𝗞_k 𝗛)𝗣_k|k-1
+    ///
+    /// If 𝗦_k is singular the update is skipped and the prediction is kept.
+    #[allow(non_snake_case)]
+    pub fn update(&mut self, z: [f32; M]) {
+        let y = Vector::from_array(z) - self.H * self.xp;
+        let S = self.H * self.Pp * self.H.t() + self.R;
+        match S.inverse() {
+            Some(Si) => {
+                let K = self.Pp * self.H.t() * Si;
+                self.x = self.xp + K * y;
+                self.P = (Matrix::identity() - K * self.H) * self.Pp;
+            },
+            None => {
+                self.x = self.xp;
+                self.P = self.Pp;
+            },
+        }
+    }
+
+    /// Get the current a posteriori state estimate.
+    pub fn get(&self) -> [f32; N] {
+        self.x.to_array()
+    }
+
+    /// Get the current a posteriori error covariance.
+    pub fn covariance(&self) -> Matrix<N, N> {
+        self.P
+    }
+}
@@ -0,0 +1,383 @@
+//! A simple Kalman filter for one-dimensional readings
+//!
+//! `Kalman` is hand-unrolled for the constant-velocity model used in the ADC ISR.
+//! The `filter` and `models` submodules provide a generic filter over any small
+//! number of states and measurements, with ready-made process models.
+
+pub mod filter;
+pub mod models;
+
+/// Kalman filter implementation for estimating the first derivative of a 1-d variable,
+/// with a fixed sample rate 1/dt for samples of that variable.
+///
+/// Q is set to a representative process variance; it is the variance of an unknown
+/// change in second derivative over each sampling period dt.
+/// R is set to the expected sensor variance.
+/// x tracks the current state and xp tracks the current state prediction.
+/// P tracks the state covariance matrix and Pp its prediction.
+/// Q0 is the constant process covariance matrix and is computed from Q.
+///
+/// ## Model
+///
+/// For convenience, variables in this explanation are named following the Wikipedia
+/// article on Kalman filters, refer there for more details.
+///
+/// Our state vector `𝘅_k` is:
+///
+///     𝘅_k = [ x_k       ]
+///           [ dx_k/dt   ]
+///
+/// i.e., the modelled process variable `x_k` and its first derivative.
+///
+/// The dynamics are modelled by the implicit state transition model `𝗙_k`,
+/// produced by discretising the dynamics with a velocity Verlet integrator
+/// with constant second derivative:
+///
+///     𝗙_k = [ 1  dt ]
+///           [ 0   1 ]
+///
+/// We model the system as undergoing an unknown change in derivative
+/// a=d²x/dt² whose value a~N(0, Q), with a constant over each integration period dt.
+///
+/// Integrating the constant a over a time period dt leads to the process noise 𝘄_k:
+///
+///     𝘄_k = [ a dt²/2 ]
+///           [ a dt    ]
+///
+/// We therefore find 𝗤_k as E[𝘄_k . 𝘄_k'], where E[j²]=Q, the provided process variance.
+/// Note that 𝘄_k~N(0, 𝗤_k).
+///
+///     𝗤_k = Q . [ dt^4/4   dt^3/2 ]
+///               [ dt^3/2   dt^2/1 ]
+///
+/// As 𝗤_k does not depend on k, we compute 𝗤_k once and store it as `Kalman.Q0`.
+///
+/// Since there is no modelled control input (𝘂_k=0), the state evolution is:
+///
+///     𝘅_k = 𝗙_k . 𝘅_k-1 + 𝘄_k
+///
+/// The implicit observation model `𝗛_k` is simple:
+///
+///     𝗛_k = [ 1 0 ]
+///
+/// In other words, we directly observe the process variable itself in noise:
+///
+///     z_k = 𝗛_k . 𝘅_k + v_k
+///         =       x_k + v_k
+///
+/// We track the a posteriori state estimate 𝘅_k|k as `Kalman.x`, and the a priori state estimate
+/// 𝘅_k|k-1 as `Kalman.xp`. Likewise the a posteriori error covariance 𝗣_k|k is `Kalman.P` and
+/// the a priori prediction 𝗣_k|k-1 is `Kalman.Pp`
+///
+/// ## Steady-state mode
+///
+/// Since dt, Q and R are all constant, 𝗣_k|k-1 and therefore 𝗞_k converge to constant
+/// values, the solution of the discrete algebraic Riccati equation. A filter created
+/// with `Kalman::new_steady_state` or `Kalman::with_gain` uses this fixed gain 𝗞 and
+/// skips all covariance updates, reducing to an alpha-beta filter with α=𝗞[0] and β=𝗞[1].dt.
+/// Once the full filter has converged the two give the same output.
+///
+#[allow(non_snake_case)]
+pub struct Kalman {
+    R: f32,
+    dt: f32,
+    x: [f32; 2],
+    xp: [f32; 2],
+    P: [[f32; 2]; 2],
+    Pp: [[f32; 2]; 2],
+    Q0: [[f32; 2]; 2],
+    K: Option<[f32; 2]>,
+}
+
+/// Maximum number of Riccati iterations used to find the steady-state gain,
+/// above the 11,000 or so the slowest filter in use takes to converge.
+const RICCATI_MAX_ITERS: u32 = 20_000;
+
+/// Change in each gain element, relative to its value, at which the Riccati
+/// iteration is taken to have converged.
+const RICCATI_TOLERANCE: f32 = 1e-6;
+
+impl Kalman {
+    /// Create a new Kalman struct initialised to a current value `z` and zero-valued derivatives.
+    ///
+    /// Q: process variance (variance of random change in derivative per second)
+    /// R: sensor variance
+    /// dt: time interval between filter updates
+    /// z: initial state of process variable
+    #[allow(non_snake_case)]
+    pub fn new(Q: f32, R: f32, dt: f32, z: f32) -> Self {
+        let mut k = Kalman {
+            R, dt,
+            x: [0f32; 2], xp: [0f32; 2],
+            P: [[0f32; 2]; 2], Pp: [[0f32; 2]; 2],
+            Q0: [[0f32; 2]; 2],
+            K: None,
+        };
+
+        // Initialise state to (z, 0, 0) with small error covariance along diagonal
+        k.x[0] = z;
+        for i in 0..2 {
+            for j in 0..2 {
+                if i == j {
+                    k.P[i][j] = 1e-3;
+                }
+            }
+        }
+
+        // Initialise process noise covariance from dynamic model
+        k.Q0[0][0] = Q * (dt*dt*dt*dt      )/4.0;
+        k.Q0[0][1] = Q * (dt*dt*dt         )/2.0;
+        k.Q0[1][0] = Q * (dt*dt*dt         )/2.0;
+        k.Q0[1][1] = Q * (dt*dt            )/1.0;
+
+        k
+    }
+
+    /// Run a Kalman update step from measurement z
+    ///
+    /// Call this function at regular `dt` intervals for proper filtering.
+    ///
+    /// This function models the Kalman update equations:
+    ///
+    /// 𝘆_k = 𝘇_k - 𝗛_k . 𝘅_k|k-1
+    ///     = z_k - x_k|k-1
+    /// 𝗦_k = 𝗛_k 𝗣_k|k-1 𝗛'_k + 𝗥_k
+    ///     = P00_k|k-1 + R
+    /// 𝗞_k = 𝗣_k|k-1 𝗛'_k (𝗦_k)^-1
+    ///     = [P00 P10]'_k|k-1 / (P00_k|k-1 + R)
+    /// 𝘅_k|k = 𝘅_k|k-1 + 𝗞_k 𝘆_k
+    /// 𝗣_k|k = (𝗜 - 𝗞_k 𝗛_k)𝗣_k|k-1
+    ///
+    /// Note that `x` is 𝘅_k|k, `xp` is 𝘅_k|k-1, `P` is 𝗣_k|k, `Pp` is 𝗣_k|k-1.
+    pub fn update(&mut self, z: f32) {
+        if let Some(k) = self.K {
+            let y = z - self.xp[0];
+            self.x[0] = self.xp[0] + k[0] * y;
+            self.x[1] = self.xp[1] + k[1] * y;
+            return;
+        }
+
+        let y = z - self.xp[0];
+        let k = 1.0 / (self.Pp[0][0] + self.R);
+        self.x[0] = self.xp[0] + k * self.Pp[0][0] * y;
+        self.x[1] = self.xp[1] + k * self.Pp[1][0] * y;
+
+        self.P[0][0] = self.Pp[0][0] - k * self.Pp[0][0] * self.Pp[0][0];
+        self.P[0][1] = self.Pp[0][1] - k * self.Pp[0][0] * self.Pp[0][1];
+        self.P[1][0] = self.Pp[1][0] - k * self.Pp[1][0] * self.Pp[0][0];
+        self.P[1][1] = self.Pp[1][1] - k * self.Pp[1][0] * self.Pp[0][1];
+    }
+
+    /// Run a Kalman predict step
+    ///
+    /// Call this function whenever an updated prediction `xp` is required.
+    ///
+    /// This function models the Kalman predict equations:
+    ///
+    /// 𝘅_k|k-1 = 𝗙_k 𝘅_k-1|k-1
+    /// 𝗣_k|k-1 = 𝗙_k 𝗣_k-1|k-1 𝗙'_k + 𝗤_k
+    pub fn predict(&mut self) {
+        let dt = self.dt;
+        self.xp[0] = self.x[0] + dt*self.x[1];
+        self.xp[1] = self.x[1];
+
+        if self.K.is_some() {
+            return;
+        }
+
+        self.Pp[0][0] = self.P[0][0] + self.P[1][0]*dt;
+        self.Pp[0][1] = self.P[0][1] + self.P[1][1]*dt;
+        self.Pp[1][0] = self.P[1][0] + self.P[1][1]*dt;
+        self.Pp[1][1] = self.P[1][1];
+        self.Pp[0][0] += self.Pp[0][1]*dt;
+
+        for i in 0..2 {
+            for j in 0..2 {
+                self.Pp[i][j] += self.Q0[i][j];
+            }
+        }
+    }
+
+    pub fn get(&self) -> (f32, f32) {
+        (self.x[0], self.x[1])
+    }
+
+    /// Create a new steady-state Kalman struct, initialised as for `Kalman::new`.
+    ///
+    /// The discrete Riccati equation is solved by iterating the covariance predict and
+    /// update steps until the gain changes by less than `RICCATI_TOLERANCE`. This takes
+    /// up to around 11,000 iterations for the filters in use, so call it once from `init`.
+    #[allow(non_snake_case)]
+    pub fn new_steady_state(Q: f32, R: f32, dt: f32, z: f32) -> Self {
+        let mut k = Kalman::new(Q, R, dt, z);
+        let mut gain = k.gain();
+        let mut converged = false;
+        for _ in 0..RICCATI_MAX_ITERS {
+            k.predict();
+            k.update(0.0);
+            let new_gain = k.gain();
+            converged = new_gain.iter().zip(gain.iter())
+                                .all(|(new, old)| (new - old).abs() <= RICCATI_TOLERANCE * new.abs());
+            gain = new_gain;
+            if converged {
+                break;
+            }
+        }
+        debug_assert!(converged, "steady-state gain did not converge");
+
+        // Reset state estimate, keeping the converged covariance for reference.
+        k.x = [z, 0.0];
+        k.xp = k.x;
+        k.K = Some(gain);
+        k
+    }
+
+    /// Create a new steady-state Kalman struct using a gain computed elsewhere,
+    /// for example on the host using `Kalman::new_steady_state(..).gain()`.
+    pub fn with_gain(gain: [f32; 2], dt: f32, z: f32) -> Self {
+        let mut k = Kalman::new(0.0, 0.0, dt, z);
+        k.K = Some(gain);
+        k
+    }
+
+    /// Get the Kalman gain 𝗞 which will be applied at the next update.
+    ///
+    /// In steady-state mode this is the fixed gain, otherwise it is computed from
+    /// the current a priori covariance.
+    pub fn gain(&self) -> [f32; 2] {
+        match self.K {
+            Some(k) => k,
+            None => {
+                let s = 1.0 / (self.Pp[0][0] + self.R);
+                [self.Pp[0][0] * s, self.Pp[1][0] * s]
+            },
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::Kalman;
+    use super::filter::Filter;
+    use super::models::{ConstantVelocity, ConstantAcceleration, Capacitor};
+    use crate::matrix::Matrix;
+
+    const DT: f32 = 1.10857e-5;
+
+    /// Deterministic pseudo-random noise in [-0.5, 0.5).
+    fn noise(seed: &mut u32) -> f32 {
+        *seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+        (*seed >> 8) as f32 / (1u32 << 24) as f32 - 0.5
+    }
+
+    fn close(a: f32, b: f32, tol: f32) -> bool {
+        (a - b).abs() <= tol * (1.0 + a.abs().max(b.abs()))
+    }
+
+    #[test]
+    fn constant_velocity_matches_kalman() {
+        // Same parameters as the Vout and Iout filters
+        let ((vq, vr), (iq, ir)) = (crate::config::VOUT_KALMAN, crate::config::IOUT_KALMAN);
+        for &(q, r, scale) in &[(vq, vr, 10.0), (iq, ir, 0.01)] {
+            let mut kal = Kalman::new(q, r, DT, 0.0);
+            let mut gen = Filter::new(&ConstantVelocity { q, dt: DT },
+                                      Matrix::new([[r]]), [0.0, 0.0]);
+            let mut seed = 1;
+            for k in 0..20_000 {
+                // Ramp then hold, with noise
+                let slope = 3000.0 * scale;
+                let t = k.min(10_000) as f32 * DT;
+                let z = slope * t + scale * noise(&mut seed);
+                kal.predict();
+                kal.update(z);
+                gen.predict();
+                gen.update([z]);
+                let (x, dx) = kal.get();
+                let [gx, gdx] = gen.get();
+                assert!(close(x, gx, 1e-4), "step {}: x {} != {}", k, x, gx);
+                // The derivative is sensitive to rounding in P, so compare to its scale
+                assert!((dx - gdx).abs() <= 1e-3 * slope, "step {}: dx {} != {}", k, dx, gdx);
+            }
+        }
+    }
+
+    /// Closed-form steady-state alpha-beta gains for this model (Kalata 1984).
+    fn kalata_gain(q: f64, r: f64, dt: f64) -> [f32; 2] {
+        let l = q.sqrt() * dt * dt / r.sqrt();
+        let s = (l * l + 8.0 * l).sqrt();
+        let alpha = -(l * l + 8.0 * l - (l + 4.0) * s) / 8.0;
+        let beta = (l * l + 4.0 * l - l * s) / 4.0;
+        [alpha as f32, (beta / dt) as f32]
+    }
+
+    #[test]
+    fn steady_state_gain_solves_riccati() {
+        for &(q, r) in &[(1e6, 1e0), (1e1, 1e-5), (1e8, 1e-2)] {
+            let k = Kalman::new_steady_state(q, r, DT, 0.0).gain();
+            let kk = kalata_gain(q as f64, r as f64, DT as f64);
+            assert!(close(k[0], kk[0], 1e-3), "alpha {} != {}", k[0], kk[0]);
+            assert!(close(k[1], kk[1], 1e-2), "beta/dt {} != {}", k[1], kk[1]);
+        }
+    }
+
+    #[test]
+    fn steady_state_matches_converged_kalman() {
+        let (q, r) = (1e6, 1e0);
+        let mut kal = Kalman::new(q, r, DT, 375.0);
+        let mut ss = Kalman::new_steady_state(q, r, DT, 375.0);
+        let mut seed = 1;
+        for k in 0..100_000 {
+            let z = 375.0 + 5.0 * noise(&mut seed);
+            kal.predict();
+            kal.update(z);
+            ss.predict();
+            ss.update(z);
+            // Allow the full filter time to converge before comparing
+            if k > 50_000 {
+                assert!(close(kal.get().0, ss.get().0, 1e-4));
+            }
+        }
+
+        let mut off = Kalman::with_gain(ss.gain(), DT, 375.0);
+        off.predict();
+        off.update(376.0);
+        assert_eq!(off.gain(), ss.gain());
+        assert!(off.get().0 > 375.0);
+    }
+
+    #[test]
+    fn constant_acceleration_tracks_quadratic() {
+        let mut f = Filter::new(&ConstantAcceleration { q: 1e8, dt: DT },
+                                Matrix::new([[1e-2]]), [0.0, 0.0, 0.0]);
+        let a = 1e5;
+        for k in 0..50_000 {
+            let t = k as f32 * DT;
+            f.predict();
+            f.update([a * t * t / 2.0]);
+        }
+        let t = 49_999.0 * DT;
+        let [x, dx, ddx] = f.get();
+        assert!(close(x, a * t * t / 2.0, 1e-2));
+        assert!(close(dx, a * t, 5e-2));
+        assert!(close(ddx, a, 1e-1));
+    }
+
+    #[test]
+    fn capacitor_estimates_converter_current() {
+        // Hold v_out at 375V with a 10mA load: the converter must be
+        // delivering the same average current into the capacitor.
+        let model = Capacitor { c: 34e-6, q_d: 1e2, q_o: 1e1, dt: DT };
+        let mut f = Filter::new(&model, Matrix::new([[1e0, 0.0], [0.0, 1e-5]]),
+                                [375.0, 0.0, 0.0]);
+        let mut seed = 1;
+        for _ in 0..50_000 {
+            f.predict();
+            f.update([375.0 + noise(&mut seed), 0.010 + 1e-3 * noise(&mut seed)]);
+        }
+        let [v, i_d, i_out] = f.get();
+        assert!(close(v, 375.0, 1e-3));
+        assert!((i_out - 0.010).abs() < 1e-3);
+        assert!((i_d - 0.010).abs() < 2e-3);
+    }
+}
@@ -0,0 +1,121 @@
+//! Ready-made process models for `kalman::filter::Filter`
+
+use crate::matrix::Matrix;
+use super::filter::Model;
+
+/// Constant-velocity model of a 1-d variable, observed directly.
+///
+/// State is `[x, dx/dt]`. This is the same model as `Kalman`: an unknown
+/// second derivative a~N(0, q) is held constant over each period dt, giving
+///
+///     𝗙 = [ 1  dt ]    𝗤 = q . [ dt^4/4   dt^3/2 ]    𝗛 = [ 1 0 ]
+///         [ 0   1 ]            [ dt^3/2   dt^2/1 ]
+pub struct ConstantVelocity {
+    /// Process variance (variance of random change in derivative per second)
+    pub q: f32,
+    /// Time interval between filter updates
+    pub dt: f32,
+}
+
+#[allow(non_snake_case)]
+impl Model<2, 1> for ConstantVelocity {
+    fn F(&self) -> Matrix<2, 2> {
+        Matrix::new([[1.0, self.dt],
+                     [0.0, 1.0   ]])
+    }
+
+    fn Q(&self) -> Matrix<2, 2> {
+        let (q, dt) = (self.q, self.dt);
+        Matrix::new([[q * (dt*dt*dt*dt)/4.0, q * (dt*dt*dt)/2.0],
+                     [q * (dt*dt*dt   )/2.0, q * (dt*dt   )/1.0]])
+    }
+
+    fn H(&self) -> Matrix<1, 2> {
+        Matrix::new([[1.0, 0.0]])
+    }
+}
+
+/// Constant-acceleration model of a 1-d variable, observed directly.
+///
+/// State is `[x, dx/dt, d²x/dt²]`. An unknown third derivative j~N(0, q)
+/// is held constant over each period dt, so the process noise is
+/// 𝘄_k = j . [dt³/6, dt²/2, dt]' and 𝗤 = E[𝘄_k 𝘄_k'].
+///
+///     𝗙 = [ 1  dt  dt²/2 ]    𝗛 = [ 1 0 0 ]
+///         [ 0   1     dt ]
+///         [ 0   0      1 ]
+pub struct ConstantAcceleration {
+    /// Process variance (variance of random change in second derivative per second)
+    pub q: f32,
+    /// Time interval between filter updates
+    pub dt: f32,
+}
+
+#[allow(non_snake_case)]
+impl Model<3, 1> for ConstantAcceleration {
+    fn F(&self) -> Matrix<3, 3> {
+        let dt = self.dt;
+        Matrix::new([[1.0, dt,  dt*dt/2.0],
+                     [0.0, 1.0, dt       ],
+                     [0.0, 0.0, 1.0      ]])
+    }
+
+    fn Q(&self) -> Matrix<3, 3> {
+        let dt = self.dt;
+        let g = Matrix::new([[dt*dt*dt/6.0], [dt*dt/2.0], [dt]]);
+        (g * g.t()).scale(self.q)
+    }
+
+    fn H(&self) -> Matrix<1, 3> {
+        Matrix::new([[1.0, 0.0, 0.0]])
+    }
+}
+
+/// Joint model of output voltage and current across the output capacitor.
+///
+/// State is `[v_out, i_d, i_out]`, where i_d is the average current delivered
+/// into the output node by the converter and i_out is the load current.
+/// The capacitor relation C dv_out/dt = i_d - i_out couples the voltage to
+/// both currents, which are each modelled as random walks with rates
+/// a_d~N(0, q_d) and a_o~N(0, q_o) held constant over dt.
+///
+///     𝗙 = [ 1  dt/C  -dt/C ]    𝗛 = [ 1 0 0 ]
+///         [ 0     1      0 ]        [ 0 0 1 ]
+///         [ 0     0      1 ]
+///
+/// The process noise is 𝘄_k = a_d . 𝗴_d + a_o . 𝗴_o where
+/// 𝗴_d = [dt²/2C, dt, 0]' and 𝗴_o = [-dt²/2C, 0, dt]',
+/// so 𝗤 = q_d 𝗴_d 𝗴_d' + q_o 𝗴_o 𝗴_o'.
+pub struct Capacitor {
+    /// Output capacitance (F). C216 and C217 in series give around 34µF.
+    pub c: f32,
+    /// Process variance of converter current (A²/s²)
+    pub q_d: f32,
+    /// Process variance of load current (A²/s²)
+    pub q_o: f32,
+    /// Time interval between filter updates
+    pub dt: f32,
+}
+
+#[allow(non_snake_case)]
+impl Model<3, 2> for Capacitor {
+    fn F(&self) -> Matrix<3, 3> {
+        let k = self.dt / self.c;
+        Matrix::new([[1.0, k,   -k ],
+                     [0.0, 1.0, 0.0],
+                     [0.0, 0.0, 1.0]])
+    }
+
+    fn Q(&self) -> Matrix<3, 3> {
+        let dt = self.dt;
+        let k = dt * dt / (2.0 * self.c);
+        let gd = Matrix::new([[k],  [dt],  [0.0]]);
+        let go = Matrix::new([[-k], [0.0], [dt]]);
+        (gd * gd.t()).scale(self.q_d) + (go * go.t()).scale(self.q_o)
+    }
+
+    fn H(&self) -> Matrix<2, 3> {
+        Matrix::new([[1.0, 0.0, 0.0],
+                     [0.0, 0.0, 1.0]])
+    }
+}
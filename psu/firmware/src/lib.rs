@@ -0,0 +1,33 @@
+//! Hardware-independent parts of the PSU firmware.
+//!
+//! These modules have no dependency on the microcontroller, so can be
+//! tested on the host with `cargo test-host`.
+
+#![cfg_attr(not(test), no_std)]
+
+pub mod state;
+pub mod pid;
+pub mod kalman;
+pub mod matrix;
+pub mod burst;
+pub mod profile;
+pub mod command;
+pub mod stats;
+pub mod calibration;
+pub mod sampling;
+pub mod scope;
+pub mod history;
+pub mod energy;
+pub mod selftest;
+pub mod telemetry;
+pub mod info;
+pub mod boot;
+pub mod run;
+pub mod profiling;
+pub mod timing;
+pub mod hw;
+pub mod control;
+pub mod preset;
+pub mod event;
+pub mod modbus;
+pub mod config;
@@ -1,66 +1,17 @@
 #![no_std]
 #![no_main]
 
-// Control loop parameters
-
-/// Setpoint voltage (V).
-/// Typically 370V.
-const V_SET: f32 = 375.0;
-
-/// Overvoltage limit before a fault is triggered (V).
-/// This has some filtering.
-const V_LIM: f32 = 420.0;
-
-/// Overcurrent limit before a fault is triggered (A).
-/// This is slightly filtered.
-const I_LIM: f32 = 0.100;
-
-/// Minimum output voltage before a fault is triggered after timeout (V).
-const V_MIN: f32 = 330.0;
-
-/// Timeout after which VOut must be at least V_MIN (cycles at 70MHz).
-const V_TIMEOUT: u32 = 500_000_000;
-
-/// Minimum permitted input voltage (V).
-const VIN_MIN: f32 = 18.0;
-
-/// Maximum permitted input voltage (V).
-const VIN_MAX: f32 = 30.0;
-
-/// Maximum permitted input current (A).
-const IIN_MAX: f32 = 3.0;
-
-/// Maximum control signal. Absolute maximum is 4095.
-/// This controls the per-cycle current limit, where 3800=6A.
-const IREF_MAX: i16 = 3800;
-
-/// Proportional gain
-const K_P: f32 = 20.0;
-
-/// Integral gain
-const K_I: f32 = 120.0;
-
-/// Derivative gain
-const K_D: f32 = 20.0;
-
-/// Limits on integral gain.
-/// Since we expect the final control signal to be significantly integral based,
-/// set a high limit sufficient to reach the maximum control value.
-const I_MAX: f32 = (IREF_MAX as f32) / K_I;
-const I_MIN: f32 = -I_MAX;
-
-const TELEM_ADC_DIRECT: bool = false;
-const TELEM_ADC_CH: usize = 0;
-
 use core::panic::PanicInfo;
 use cortex_m_rt::exception;
+use cortex_m::peripheral::DWT;
 use rtic::cyccnt::{Instant, Duration, U32Ext};
 
 pub mod hal;
-pub mod state;
-pub mod pid;
-pub mod kalman;
 
+use iggie_psu::{state, pid, kalman, burst, profile, command, stats, calibration, sampling, scope,
+                history, energy, selftest, telemetry, info, run, profiling, timing, control,
+                preset, event, modbus};
+use iggie_psu::config::*;
 use state::ToBytes;
 
 #[rtic::app(device=stm32ral::stm32f3::stm32f3x4, monotonic=rtic::cyccnt::CYCCNT, peripherals=true)]
@@ -80,49 +31,141 @@ const APP: () = {
         hrtim: hal::hrtim::HRTIM,
         // TIM2 generates periodic interrupts for control loop operation
         tim2: hal::tim2::TIM2,
+        // Comparators are checked by the self-test
+        comp: hal::comp::Comp,
+        // EXTI interrupts on profile sync input edges
+        exti: hal::exti::EXTI,
+        // Flash stores presets and lifetime counters
+        flash: hal::flash::Flash,
 
         #[init([0; 4])]
         adc_buf: [u16; 4],
+        #[init([0; 5])]
+        adc2_buf: [u16; 5],
+        #[init(sampling::Decimator::new(SAMPLING))]
+        decimator: sampling::Decimator,
+        #[init(scope::Scope::new(SAMPLING.sequence_period()))]
+        scope: scope::Scope<SCOPE_SAMPLES>,
+        #[init(history::History::new(HISTORY_DECIMATION, timing::CTRL_DT))]
+        history: history::History<HISTORY_LEN>,
         #[init(state::State::new())]
         state: state::State,
         #[init(false)]
         start_elapsed: bool,
+        #[init(control::Regulator::new(
+            pid::PID::new(timing::CTRL_DT, K_P, K_I, K_D, I_MIN, I_MAX),
+            burst::LightLoad::new(BURST_STRATEGY, BURST_ENTER, BURST_EXIT),
+            control::Derating::new(IIN_DERATE, DERATE_GAIN),
+            control::CurrentLimit::new(CC_GAIN, CC_FOLDBACK),
+            IREF_MAX, timing::CTRL_DT))]
+        regulator: control::Regulator,
+        #[init(control::Supervisor::new(LIMITS))]
+        supervisor: control::Supervisor,
+        // Strike and hold profiles are replaced by the active preset in init
+        #[init(profile::Profiles::new(PROFILE_OFF, PROFILE_STRIKE, PROFILE_HOLD,
+                                      profile::ProfileId::Strike))]
+        profiles: profile::Profiles,
+        #[init(preset::Presets::new(PRESETS))]
+        presets: preset::Presets,
+        #[init(preset::Report::new(PRESETS[0]))]
+        preset_report: preset::Report,
+        #[init(false)]
+        preset_pending: bool,
+        #[init(command::Parser::new(MAXIMA))]
+        cmd_parser: command::Parser,
+        #[init(stats::RegulationStats::new())]
+        reg_stats: stats::RegulationStats,
+        #[init(stats::Metrics::new())]
+        metrics: stats::Metrics,
+        #[init(false)]
+        metrics_pending: bool,
+        #[init(energy::Energy::new(ENERGY_TAU))]
+        energy: energy::Energy,
+        #[init(energy::Report::new())]
+        energy_report: energy::Report,
+        #[init(false)]
+        energy_pending: bool,
+        #[init(telemetry::Config::new(TELEM_RATE, TELEM_MASK))]
+        telem_config: telemetry::Config,
+        #[init(telemetry::Fields::new())]
+        telem_fields: telemetry::Fields,
+        #[init(telemetry::Description::new())]
+        description: telemetry::Description,
+        #[init(PACKETS)]
+        description_pending: bool,
+        #[init(info::Hello::new(CONFIG_CRC))]
+        hello: info::Hello,
+        #[init(PACKETS)]
+        hello_pending: bool,
+        #[init(run::RunControl::new(RUN_REQUEST))]
+        run_control: run::RunControl,
+        #[init(run::Status::new())]
+        run_status: run::Status,
+        #[init(false)]
+        run_status_pending: bool,
+        // The telemetry period is set by send_telem, as it can change at runtime
+        #[init(profiling::Profiler::new([timing::cycles(SAMPLING.sequence_period()),
+                                         timing::F_SYSCLK / timing::CTRL_RATE, 0,
+                                         timing::HEARTBEAT_PERIOD]))]
+        profiler: profiling::Profiler,
+        #[init(profiling::Report::new())]
+        profile_report: profiling::Report,
+        #[init(false)]
+        profile_pending: bool,
+        #[init(event::Queue::new())]
+        events: event::Queue<EVENT_QUEUE_LEN>,
+        #[init(modbus::Slave::new(MODBUS_ADDRESS, MAXIMA))]
+        modbus: modbus::Slave,
 
-        ctrl_pid: pid::PID,
         vout_kal: kalman::Kalman,
         iout_kal: kalman::Kalman,
         start_time: Instant,
+        factory_cal: calibration::FactoryCal,
     }
 
-    #[init(spawn=[heartbeat, send_telem], resources=[adc_buf])]
+    #[init(spawn=[heartbeat, send_telem],
+           resources=[adc_buf, adc2_buf, profiles, energy, presets, state, regulator, supervisor])]
     fn init(mut cx: init::Context) -> init::LateResources {
         // Enable DWT to allow use for software tasks
         cx.core.DCB.enable_trace();
         cx.core.DWT.enable_cycle_counter();
 
         // Set up Kalman filters for Vout and Iout.
-        let vout_kal = kalman::Kalman::new(1e6, 1e0,  1.10857e-5, 0.0);
-        let iout_kal = kalman::Kalman::new(1e1, 1e-5, 1.10857e-5, 0.0);
-
-        // Set up PID control loop.
-        // We run PID off TIM2 at 10kHz so dt=1/10e3
-        let ctrl_pid = pid::PID::new(1.0/10e3, K_P, K_I, K_D, I_MIN, I_MAX);
-
-        // Initialise device clocks
-        let rcc = hal::rcc::RCC::new(cx.device.RCC, cx.device.Flash);
+        let (vout_kal, iout_kal) = if KALMAN_STEADY_STATE {
+            (kalman::Kalman::new_steady_state(VOUT_KALMAN.0, VOUT_KALMAN.1, SAMPLING.dt(), 0.0),
+             kalman::Kalman::new_steady_state(IOUT_KALMAN.0, IOUT_KALMAN.1, SAMPLING.dt(), 0.0))
+        } else {
+            (kalman::Kalman::new(VOUT_KALMAN.0, VOUT_KALMAN.1, SAMPLING.dt(), 0.0),
+             kalman::Kalman::new(IOUT_KALMAN.0, IOUT_KALMAN.1, SAMPLING.dt(), 0.0))
+        };
+
+        // Initialise flash and device clocks
+        let flash = hal::flash::Flash::new(cx.device.Flash);
+        flash.setup();
+        let rcc = hal::rcc::RCC::new(cx.device.RCC);
         rcc.setup();
 
-        // Initialise USART for telemetry
+        // Load lifetime counters
+        if let Some(counters) = energy::latest(flash.read(COUNTERS_ADDR)) {
+            cx.resources.energy.restore(counters);
+        }
+
+        // Initialise USART for telemetry or Modbus
         let usart1 = hal::usart::USART::new(cx.device.USART1);
-        usart1.setup();
+        if MODBUS {
+            usart1.setup_modbus();
+        } else {
+            usart1.setup();
+        }
 
         // Initialise DMA controller
         let dma1 = hal::dma::DMA::new(cx.device.DMA1);
         dma1.setup();
 
         // Initialise ADCs
-        let mut adc = hal::adc::ADC::new(cx.device.ADC1);
-        adc.setup();
+        let mut adc = hal::adc::ADC::new(cx.device.ADC1, cx.device.ADC2, cx.device.ADC_Common);
+        adc.setup(&SAMPLING);
+        let factory_cal = hal::adc::ADC::factory_cal();
 
         // Initialise comparators
         let comp = hal::comp::Comp::new(cx.device.COMP);
@@ -133,9 +176,10 @@ const APP: () = {
         dac.setup();
 
         // Initialise HRTIM
-        let hrtim = hal::hrtim::HRTIM::new(
+        let mut hrtim = hal::hrtim::HRTIM::new(
             cx.device.HRTIM_Master, cx.device.HRTIM_TIMA, cx.device.HRTIM_Common);
         hrtim.setup();
+        hrtim.set_burst_period(BURST_PRESCALER, BURST_PERIOD);
 
         // Initialise GPIOs
         let gpio = hal::gpio::GPIO::new(cx.device.GPIOA, cx.device.GPIOB);
@@ -145,20 +189,33 @@ const APP: () = {
         let tim2 = hal::tim2::TIM2::new(cx.device.TIM2);
         tim2.setup();
 
+        // Load stored presets and apply the one selected by the strap
+        let presets = cx.resources.presets;
+        presets.load(flash.read(PRESETS_ADDR), &MAXIMA);
+        let slot = if gpio.get_preset_strap() { PRESET_STRAP } else { PRESET_DEFAULT };
+        presets.select(slot, cx.resources.state, cx.resources.regulator, cx.resources.supervisor,
+                       cx.resources.profiles);
+
+        // Initialise EXTI and select initial profile from sync input if in use
+        let exti = hal::exti::EXTI::new(cx.device.EXTI, cx.device.SYSCFG);
+        if PROFILE_SYNC {
+            exti.setup();
+            cx.resources.profiles.select(sync_profile(&gpio));
+        }
+        cx.resources.profiles.reset();
+
         // Set initial DAC level for current feedback
         dac.set_ch1(0);
 
         // Set initial DAC level for DCM detection
-        // Measured 1.28V at Vq(adc) node in midpoint of falling edge at DCM
-        // 1.28/3.30 * 4096 = 1589
-        dac.set_ch2(1589);
+        dac.set_ch2(DCM_THRESHOLD);
 
         // Start ADC conversion
-        adc.start(&dma1, &mut cx.resources.adc_buf);
+        adc.start(&dma1, cx.resources.adc_buf, cx.resources.adc2_buf);
 
         // Start telem sender
-        if !TELEM_ADC_DIRECT {
-            cx.spawn.send_telem().unwrap();
+        if PACKETS {
+            cx.spawn.send_telem(Some(0)).unwrap();
         }
 
         // Start periodic control loop interrupts
@@ -172,116 +229,155 @@ const APP: () = {
 
         // Release peripherals as late resources for use by other tasks
         init::LateResources {
-            ctrl_pid, vout_kal, iout_kal, usart1, dma1, adc, gpio, dac, hrtim, tim2, start_time,
+            vout_kal, iout_kal, usart1, dma1, adc, gpio, dac, hrtim, tim2, exti,
+            start_time, factory_cal, flash, comp,
         }
     }
 
     // Heartbeat task runs 50 times a second.
-    // Sets status LEDs and checks for nRUN.
-    #[task(resources=[gpio, state, hrtim, start_time, start_elapsed], schedule=[heartbeat])]
+    // Sets status LEDs, checks for nRUN and the software run request, runs the self-test
+    // before the first start, monitors die temperature and VDDA, and saves lifetime counters
+    // and stored presets.
+    #[task(resources=[gpio, state, hrtim, start_time, start_elapsed, profiles, adc, factory_cal,
+                      adc2_buf, flash, energy, dac, comp, run_control, supervisor, profiler,
+                      profile_report, profile_pending, presets, events, usart1, dma1],
+           schedule=[heartbeat])]
     fn heartbeat(cx: heartbeat::Context) {
-        static mut LED_STATE: bool = false;
-        *LED_STATE = !*LED_STATE;
-
-        match cx.resources.state.fault_state {
-            state::FaultState::Stopped => {
-                cx.resources.gpio.set_400v_led(false);
-                cx.resources.gpio.set_err_led(false);
-                if cx.resources.gpio.get_run() {
-                    *cx.resources.start_time = Instant::now();
-                    *cx.resources.start_elapsed = false;
-                    cx.resources.state.set_fault(state::FaultCode::NoFault);
-                    cx.resources.state.set_state_running();
-                    cx.resources.hrtim.enable();
-                }
-            },
-            state::FaultState::Fault => {
-                cx.resources.gpio.set_400v_led(false);
-                cx.resources.gpio.set_err_led(true);
-            },
-            state::FaultState::Running => {
-                if !*cx.resources.start_elapsed {
-                    // Calling elapsed() after more than 2^31 cycles have passed (~30s)
-                    // causes an unavoidable panic, so try to avoid that (!).
-                    let timeout = Duration::from_cycles(V_TIMEOUT);
-                    if cx.resources.start_time.elapsed() > timeout {
-                        *cx.resources.start_elapsed = true;
-                    }
-                }
-                cx.resources.gpio.set_400v_led(*LED_STATE);
-                cx.resources.gpio.set_err_led(false);
-            },
+        static mut PROFILE_COUNT: u32 = 0;
+        let start = DWT::get_cycle_count();
+
+        *PROFILE_COUNT += 1;
+        if *PROFILE_COUNT == PROFILE_INTERVAL {
+            *PROFILE_COUNT = 0;
+            cx.resources.profiler.finish(start, cx.resources.profile_report);
+            *cx.resources.profile_pending = true;
+        }
+
+        // Read the latest temperature conversion, made after each ADC1 sequence
+        if let Some(raw) = cx.resources.adc.read_temperature() {
+            let temp = cx.resources.factory_cal.die_temperature(raw);
+            cx.resources.state.update_temp(temp);
+        }
+
+        // Update VDDA estimate from the latest VREFINT conversion
+        if let Some(vdda) = cx.resources.factory_cal.vdda(cx.resources.adc2_buf[0]) {
+            let state = &mut *cx.resources.state;
+            state.update_vdda(state.vdda + VDDA_ALPHA * (vdda - state.vdda));
+        }
+
+        if cx.resources.energy.save_due()
+           && cx.resources.state.fault_state != state::FaultState::Running
+        {
+            save_counters(cx.resources.flash, cx.resources.energy);
         }
 
-        cx.schedule.heartbeat(cx.scheduled + 1_400_000.cycles()).unwrap();
+        if cx.resources.presets.save_due()
+           && cx.resources.state.fault_state != state::FaultState::Running
+        {
+            save_presets(cx.resources.flash, cx.resources.presets);
+        }
+
+        let (gpio, hrtim, dac, comp) = (&*cx.resources.gpio, &*cx.resources.hrtim,
+                                        &*cx.resources.dac, &*cx.resources.comp);
+        cx.resources.events.nrun(gpio.get_run(), DWT::get_cycle_count());
+        let adc2_buf = &*cx.resources.adc2_buf;
+        let test = |state: &state::State, limits: &control::Limits|
+            self_test(hrtim, dac, comp, adc2_buf, state, limits);
+        let started = cx.resources.supervisor.heartbeat(
+            cx.resources.state, cx.resources.run_control, gpio, gpio, hrtim, test);
+        if started {
+            *cx.resources.start_time = Instant::now();
+            *cx.resources.start_elapsed = false;
+            cx.resources.profiles.reset();
+        } else if cx.resources.state.fault_state == state::FaultState::Running
+                  && !*cx.resources.start_elapsed
+        {
+            // Calling elapsed() after more than 2^31 cycles have passed (~30s)
+            // causes an unavoidable panic, so try to avoid that (!). V_TIMEOUT is
+            // checked to be shorter at compile time.
+            let timeout = Duration::from_cycles(V_TIMEOUT);
+            if cx.resources.start_time.elapsed() > timeout {
+                *cx.resources.start_elapsed = true;
+                cx.resources.events.push(event::Kind::StartTimeout, state::FaultCode::NoFault,
+                                         DWT::get_cycle_count());
+            }
+        }
+
+        cx.resources.events.observe(cx.resources.state, DWT::get_cycle_count());
+        send_event(cx.resources.usart1, cx.resources.dma1, cx.resources.events);
+
+        cx.schedule.heartbeat(cx.scheduled + timing::HEARTBEAT_PERIOD.cycles()).unwrap();
+        cx.resources.profiler.record(profiling::Task::Heartbeat, start, DWT::get_cycle_count());
     }
 
-    // Send serialised state over UART via DMA at 10Hz
-    #[task(resources=[state, usart1, dma1], schedule=[send_telem])]
-    fn send_telem(cx: send_telem::Context) {
-        let state = cx.resources.state;
+    // Send serialised state, or the selected fields, over UART via DMA at the telemetry rate,
+    // followed by regulation metrics and energy once the state has been sent.
+    // `chain` identifies the periodic sends for one rate setting, or is None for a poll.
+    #[task(resources=[state, usart1, dma1, reg_stats, metrics, metrics_pending,
+                      energy, energy_report, energy_pending, telem_config, telem_fields,
+                      profiler],
+           schedule=[send_telem], capacity=3)]
+    fn send_telem(cx: send_telem::Context, chain: Option<u32>) {
+        // Periodic sends from a previous rate setting stop here
+        let config = cx.resources.telem_config;
+        if chain.is_some() && chain != Some(config.chain()) {
+            return;
+        }
+        let start = DWT::get_cycle_count();
+
+        cx.resources.reg_stats.finish(cx.resources.metrics);
+        *cx.resources.metrics_pending = true;
+        cx.resources.energy.report(cx.resources.energy_report);
+        *cx.resources.energy_pending = true;
+
+        // Skip this state packet if another packet is still being sent;
+        // metrics will follow it instead.
         let dma = cx.resources.dma1;
-        cx.resources.usart1.transmit(dma, &state.to_bytes());
-        cx.schedule.send_telem(cx.scheduled + 7_000_000.cycles()).unwrap();
+        if dma.usart1_idle() {
+            let state = cx.resources.state;
+            if config.mask() == telemetry::ALL {
+                cx.resources.usart1.transmit(dma, &state.to_bytes());
+            } else {
+                let packet = cx.resources.telem_fields.pack(state, config.mask());
+                cx.resources.usart1.transmit(dma, packet);
+            }
+        }
+
+        // Polled sends are not periodic, so only time them without checking the period
+        let period = chain.and(config.period(timing::F_SYSCLK));
+        if let Some(period) = period {
+            cx.schedule.send_telem(cx.scheduled + period.cycles(), chain).unwrap();
+        }
+        let profiler = cx.resources.profiler;
+        profiler.set_period(profiling::Task::SendTelem, period.unwrap_or(0));
+        profiler.record(profiling::Task::SendTelem, start, DWT::get_cycle_count());
     }
 
     // Run control loop at fixed frequency on TIM2
-    #[task(binds=TIM2, resources=[tim2, dac, hrtim, state, ctrl_pid, vout_kal])]
+    #[task(binds=TIM2, resources=[tim2, dac, hrtim, state, regulator, vout_kal, profiles,
+                                  history, energy, profiler])]
     fn ctrl_loop(cx: ctrl_loop::Context) {
+        let start = DWT::get_cycle_count();
+
         // Control loop output ranges from 0 to 4096 and sets the I_Q limit reference.
         // 4096 corresponds to 3.3V at the comparator which corresponds to 6.47A.
         // We limit to IREF_MAX=3800 -> 3.06V -> 6.0A, our design point peak current.
 
+        // Slew the setpoint and update the current reference and burst duty while running
         let (vout, dvout) = cx.resources.vout_kal.get();
-        let iout = cx.resources.state.i_out;
-        let pid = cx.resources.ctrl_pid;
-
-        match cx.resources.state.fault_state {
-            state::FaultState::Running => {
-                // When running, compute PID update
-                let action = pid.control_step(V_SET, vout, dvout) as i16;
-                // Clamp action to bounds
-                let action = if action < 0 { 0 }
-                             else if action > IREF_MAX { IREF_MAX }
-                             else { action };
-                // Update DAC and telemetry
-                cx.resources.dac.set_ch1(action as u16);
-                cx.resources.state.update_ref_i_q(action as u16);
-
-                // Update duty cycle. When Vout is above 95% of target we
-                // reduce duty cycle based on output current.
-                let duty = if vout > 0.95*V_SET {
-                    if iout < 0.002 {
-                        // Set to 5% below 2mA
-                        50
-                    } else if iout < 0.020 {
-                        // Scale from 10% to 100% between 2mA and 20mA
-                        (50.0 + (1000.0-50.0)/(0.020-0.002) * (iout - 0.002)) as u16
-                    } else {
-                        // Set to 100% above 20mA
-                        1000
-                    }
-                } else {
-                    // Full duty cycle while charging
-                    1000
-                };
-                cx.resources.hrtim.set_duty(duty);
-                cx.resources.state.update_duty(duty);
-            },
+        cx.resources.regulator.step(cx.resources.state, cx.resources.profiles, vout, dvout,
+                                    &*cx.resources.dac, &*cx.resources.hrtim);
 
-            state::FaultState::Stopped | state::FaultState::Fault => {
-                // When stopped or faulted, reset the controller and clear the DAC.
-                pid.zero();
-                cx.resources.dac.set_ch1(0);
-                cx.resources.state.update_ref_i_q(0);
-            },
-        }
+        // Record state history, which freezes once a fault occurs
+        cx.resources.history.push(cx.resources.state);
 
-        // Update integrator in state
-        cx.resources.state.update_pid_i(pid.get_i());
+        // Integrate power and runtime
+        cx.resources.energy.step(timing::CTRL_DT, cx.resources.state);
 
         // Clear interrupt pending flag
         cx.resources.tim2.isr();
+
+        cx.resources.profiler.record(profiling::Task::CtrlLoop, start, DWT::get_cycle_count());
     }
 
     // Define an idle task with just NOPs to prevent the microcontroller entering sleep mode,
@@ -294,16 +390,179 @@ const APP: () = {
         }
     }
 
-    // Run USART1 ISR to handle disabling DMA at end of transfer
-    #[task(binds=USART1_EXTI25, resources=[usart1, dma1])]
+    // Run USART1 ISR to handle disabling DMA at end of transfer and received commands
+    #[task(binds=USART1_EXTI25, resources=[usart1, dma1, cmd_parser, profiles,
+                                           metrics, metrics_pending, scope, history,
+                                           energy_report, energy_pending, telem_config,
+                                           description, description_pending,
+                                           hello, hello_pending, state, hrtim, gpio,
+                                           run_control, run_status, run_status_pending,
+                                           profile_report, profile_pending, presets,
+                                           preset_report, preset_pending, regulator, supervisor,
+                                           events, modbus],
+           spawn=[send_telem])]
     fn usart1(cx: usart1::Context) {
-        cx.resources.usart1.isr(cx.resources.dma1);
+        if cx.resources.usart1.isr(cx.resources.dma1) {
+            cx.resources.scope.transmitted();
+            cx.resources.history.transmitted();
+        }
+
+        if MODBUS {
+            let slave = &mut *cx.resources.modbus;
+            while let Some(byte) = cx.resources.usart1.read() {
+                slave.push(byte);
+            }
+            if cx.resources.usart1.parity_error() {
+                slave.discard();
+            }
+            if cx.resources.usart1.rx_timeout() {
+                // A request arriving while the previous response is still being sent
+                // breaks the protocol, and is ignored rather than overwrite the response
+                if !cx.resources.dma1.usart1_idle() {
+                    slave.discard();
+                }
+                let mut target = modbus::Target {
+                    state: cx.resources.state, profiles: cx.resources.profiles,
+                    regulator: cx.resources.regulator, supervisor: cx.resources.supervisor,
+                    run_control: cx.resources.run_control, presets: cx.resources.presets,
+                    nrun: cx.resources.gpio.get_run(), pwm: &*cx.resources.hrtim,
+                };
+                if let Some(response) = slave.end_frame(&mut target) {
+                    cx.resources.usart1.transmit(cx.resources.dma1, response);
+                }
+            }
+            cx.resources.events.observe(cx.resources.state, DWT::get_cycle_count());
+            return;
+        }
+
+        while let Some(byte) = cx.resources.usart1.read() {
+            match cx.resources.cmd_parser.push(byte) {
+                Some(command::Command::SetProfile(id)) => cx.resources.profiles.select(id),
+                Some(command::Command::ScopeArm(config)) => {
+                    cx.resources.scope.arm(config);
+                },
+                // If the queue is full of sends from earlier chains the change is
+                // rejected, rather than leave no chain running
+                Some(command::Command::SetTelemetry { rate, mask }) => {
+                    let spawn = &cx.spawn;
+                    cx.resources.telem_config.set(
+                        rate, mask, |chain| spawn.send_telem(Some(chain)).is_ok());
+                },
+                Some(command::Command::PollTelemetry) => {
+                    cx.spawn.send_telem(None).ok();
+                },
+                Some(command::Command::GetDescription) => *cx.resources.description_pending = true,
+                Some(command::Command::GetInfo) => *cx.resources.hello_pending = true,
+                Some(command::Command::EnterBootloader)
+                    if cx.resources.state.fault_state != state::FaultState::Running =>
+                {
+                    // Stop the outputs before resetting; the bootloader never enables HRTIM.
+                    cx.resources.hrtim.disable();
+                    cortex_m::peripheral::SCB::sys_reset();
+                },
+                // Ignored while running
+                Some(command::Command::EnterBootloader) => (),
+                Some(command::Command::Run) => {
+                    cx.resources.run_control.run();
+                    *cx.resources.run_status_pending = true;
+                },
+                Some(command::Command::Stop) => {
+                    if cx.resources.run_control.stop(cx.resources.state) {
+                        cx.resources.hrtim.disable();
+                    }
+                    *cx.resources.run_status_pending = true;
+                },
+                Some(command::Command::ClearFault) => {
+                    cx.resources.run_control.clear_fault(cx.resources.state);
+                    *cx.resources.run_status_pending = true;
+                },
+                Some(command::Command::GetStatus) => *cx.resources.run_status_pending = true,
+                // Presets only change while not running, but the reply is always sent
+                Some(command::Command::SelectPreset(slot)) => {
+                    let presets = &mut *cx.resources.presets;
+                    if cx.resources.state.fault_state != state::FaultState::Running {
+                        presets.select(slot, cx.resources.state, cx.resources.regulator,
+                                       cx.resources.supervisor, cx.resources.profiles);
+                    }
+                    let report = &mut *cx.resources.preset_report;
+                    *cx.resources.preset_pending = presets.report(presets.active(), report);
+                },
+                Some(command::Command::GetPreset(slot)) => {
+                    let report = &mut *cx.resources.preset_report;
+                    *cx.resources.preset_pending = cx.resources.presets.report(slot, report);
+                },
+                Some(command::Command::StorePreset { slot, preset }) => {
+                    let presets = &mut *cx.resources.presets;
+                    if cx.resources.state.fault_state != state::FaultState::Running {
+                        presets.store(slot, preset);
+                    }
+                    let report = &mut *cx.resources.preset_report;
+                    *cx.resources.preset_pending = presets.report(slot, report);
+                },
+                None => (),
+            }
+        }
+        cx.resources.events.observe(cx.resources.state, DWT::get_cycle_count());
+
+        // Send the next pending packet once the previous transfer has finished,
+        // with the rest of a capture packet first, then queued events
+        if cx.resources.dma1.usart1_idle() && PACKETS {
+            if cx.resources.scope.continuing() {
+                if let Some(segment) = cx.resources.scope.packet() {
+                    cx.resources.usart1.transmit(cx.resources.dma1, segment);
+                }
+            } else if let Some(packet) = cx.resources.events.packet() {
+                cx.resources.usart1.transmit(cx.resources.dma1, packet);
+            } else if *cx.resources.hello_pending {
+                *cx.resources.hello_pending = false;
+                cx.resources.usart1.transmit(cx.resources.dma1, cx.resources.hello.to_bytes());
+            } else if *cx.resources.run_status_pending {
+                *cx.resources.run_status_pending = false;
+                let nrun = cx.resources.gpio.get_run();
+                let status = &mut *cx.resources.run_status;
+                cx.resources.run_control.status(cx.resources.state, nrun, status);
+                cx.resources.usart1.transmit(cx.resources.dma1, status.to_bytes());
+            } else if *cx.resources.preset_pending {
+                *cx.resources.preset_pending = false;
+                let report = cx.resources.preset_report.to_bytes();
+                cx.resources.usart1.transmit(cx.resources.dma1, report);
+            } else if *cx.resources.metrics_pending {
+                *cx.resources.metrics_pending = false;
+                cx.resources.usart1.transmit(cx.resources.dma1, cx.resources.metrics.to_bytes());
+            } else if *cx.resources.profile_pending {
+                *cx.resources.profile_pending = false;
+                let report = cx.resources.profile_report.to_bytes();
+                cx.resources.usart1.transmit(cx.resources.dma1, report);
+            } else if *cx.resources.energy_pending {
+                *cx.resources.energy_pending = false;
+                let report = cx.resources.energy_report.to_bytes();
+                cx.resources.usart1.transmit(cx.resources.dma1, report);
+            } else if *cx.resources.description_pending {
+                *cx.resources.description_pending = false;
+                let description = cx.resources.description.to_bytes();
+                cx.resources.usart1.transmit(cx.resources.dma1, description);
+            } else if let Some(packet) = cx.resources.history.packet() {
+                cx.resources.usart1.transmit(cx.resources.dma1, packet);
+            } else if let Some(packet) = cx.resources.scope.packet() {
+                cx.resources.usart1.transmit(cx.resources.dma1, packet);
+            }
+        }
+    }
+
+    // Handle edges on the profile sync input
+    #[task(binds=EXTI9_5, resources=[exti, gpio, profiles])]
+    fn exti9_5(cx: exti9_5::Context) {
+        cx.resources.exti.isr();
+        cx.resources.profiles.select(sync_profile(cx.resources.gpio));
     }
 
     // Run ADC ISR to handle new ADC data.
     #[task(binds=ADC1_2, resources=[adc, hrtim, state, adc_buf, usart1,
-                                    vout_kal, iout_kal, start_elapsed])]
+                                    vout_kal, iout_kal, start_elapsed, regulator, profiles,
+                                    reg_stats, decimator, scope, profiler, supervisor, dma1,
+                                    events])]
     fn adc1_2(cx: adc1_2::Context) {
+        let start = DWT::get_cycle_count();
         cx.resources.adc.isr();
 
         // Transmit ADC telemetry directly if other telem disabled
@@ -311,8 +570,24 @@ const APP: () = {
             cx.resources.usart1.transmit_u16(cx.resources.adc_buf[TELEM_ADC_CH]);
         }
 
+        // Record raw readings at the full ADC rate for waveform capture
+        let fault = cx.resources.state.fault_state == state::FaultState::Fault;
+        cx.resources.scope.push(&SAMPLING.reorder(cx.resources.adc_buf), fault);
+
+        // Wait for a complete set of averaged readings
+        let buf = match cx.resources.decimator.push(cx.resources.adc_buf) {
+            Some(buf) => buf,
+            None => {
+                cx.resources.profiler.record(profiling::Task::Adc, start, DWT::get_cycle_count());
+                return;
+            },
+        };
+
         let state = cx.resources.state;
-        state.update_adc(*cx.resources.adc_buf);
+        state.update_adc(buf);
+
+        // Track raw Vout ripple for light-load control
+        cx.resources.regulator.sample(state.v_out);
 
         // Update Kalman filters
         cx.resources.vout_kal.predict();
@@ -320,54 +595,42 @@ const APP: () = {
         cx.resources.iout_kal.predict();
         cx.resources.iout_kal.update(state.i_out);
 
-        // Check output voltage and current against limits.
+        // Read filtered output voltage and current
         let (vout, _) = cx.resources.vout_kal.get();
         let (iout, _) = cx.resources.iout_kal.get();
 
+        // Accumulate regulation statistics for raw and filtered outputs
+        cx.resources.reg_stats.push(
+            cx.resources.profiles.reference(), state.v_out, vout, state.i_out, iout);
+
         // Store filtered Vout and iout in state
         state.v_out = vout;
         state.i_out = iout;
 
-        if state.fault_state == state::FaultState::Running {
-            let mut fault = false;
-            if vout >= V_LIM {
-                state.set_fault(state::FaultCode::VLim);
-                fault = true;
-            }
-            if iout >= I_LIM {
-                state.set_fault(state::FaultCode::ILim);
-                fault = true;
-            }
-            if state.v_in <= VIN_MIN {
-                state.set_fault(state::FaultCode::VInLow);
-                fault = true;
-            } else if state.v_in >= VIN_MAX {
-                state.set_fault(state::FaultCode::VInHigh);
-                fault = true;
-            }
-            if state.i_in >= IIN_MAX {
-                state.set_fault(state::FaultCode::IInHigh);
-                fault = true;
-            }
-            if state.fault_state == state::FaultState::Running {
-                if *cx.resources.start_elapsed && state.v_out <= V_MIN {
-                    state.set_fault(state::FaultCode::NoVOut);
-                    fault = true;
-                }
-            }
-            if fault {
-                state.set_state_fault();
-                cx.resources.hrtim.disable();
-            }
-        }
+        // Check outputs and inputs against limits
+        let limits = cx.resources.supervisor.limits();
+        control::protect(state, limits, cx.resources.profiles, *cx.resources.start_elapsed,
+                         &*cx.resources.hrtim);
+        cx.resources.events.observe(state, DWT::get_cycle_count());
+        send_event(cx.resources.usart1, cx.resources.dma1, cx.resources.events);
+
+        cx.resources.profiler.record(profiling::Task::Adc, start, DWT::get_cycle_count());
     }
 
     // Handle HRTIM fault: caused by SYSFLT or FLT2 (our nRUN input)
-    #[task(binds=HRTIM_FLT, resources=[hrtim, state])]
+    #[task(binds=HRTIM_FLT, resources=[hrtim, state, events, usart1, dma1])]
     fn hrtim_flt(cx: hrtim_flt::Context) {
-        cx.resources.hrtim.flt_isr();
-        cx.resources.state.set_fault(state::FaultCode::NoRun);
-        cx.resources.state.set_state_stopped();
+        let now = DWT::get_cycle_count();
+        let (nrun, sysflt) = cx.resources.hrtim.flt_isr();
+        if sysflt {
+            cx.resources.events.push(event::Kind::SysFault, state::FaultCode::NoFault, now);
+        }
+        if nrun {
+            cx.resources.events.nrun(false, now);
+        }
+        control::nrun_released(cx.resources.state);
+        cx.resources.events.observe(cx.resources.state, now);
+        send_event(cx.resources.usart1, cx.resources.dma1, cx.resources.events);
     }
 
     // We require at least one interrupt vector defined here per software task
@@ -377,6 +640,132 @@ const APP: () = {
     }
 };
 
+/// Send the oldest queued event if the serial link is idle; otherwise the USART1 ISR
+/// sends it once the current transfer finishes.
+fn send_event(usart1: &hal::usart::USART, dma1: &hal::dma::DMA,
+              events: &mut event::Queue<EVENT_QUEUE_LEN>)
+{
+    if dma1.usart1_idle() && PACKETS {
+        if let Some(packet) = events.packet() {
+            usart1.transmit(dma1, packet);
+        }
+    }
+}
+
+/// Append the lifetime counters to the log page in flash, erasing it first if full.
+///
+/// Programming and erasing flash stall instruction fetch, leaving the ADC ISR unable
+/// to run protection, so this must only be called while the converter is not running.
+fn save_counters(flash: &mut hal::flash::Flash, energy: &mut energy::Energy) {
+    let slot = match energy::free_slot(flash.read(COUNTERS_ADDR)) {
+        Some(slot) => slot,
+        None => {
+            if flash.erase_page(COUNTERS_ADDR).is_err() {
+                return;
+            }
+            0
+        },
+    };
+    let record = energy.save();
+    let address = COUNTERS_ADDR + (slot * core::mem::size_of::<energy::Counters>()) as u32;
+    flash.program(address, &record.as_halfwords()).ok();
+}
+
+/// Rewrite the preset page with every stored preset.
+///
+/// The page erase stalls the CPU for tens of milliseconds, so this must only be
+/// called while the converter is not running.
+fn save_presets(flash: &mut hal::flash::Flash, presets: &mut preset::Presets) {
+    if flash.erase_page(PRESETS_ADDR).is_err() {
+        return;
+    }
+    for (slot, record) in presets.save() {
+        let address = PRESETS_ADDR + (slot * core::mem::size_of::<preset::Preset>()) as u32;
+        flash.program(address, &record.as_halfwords()).ok();
+    }
+}
+
+/// Power-on self-test, run with the converter stopped.
+///
+/// Checks HRTIM DLL calibration and ADC readings, then that each DAC output reads back
+/// correctly on ADC2 and each comparator output flips as its DAC threshold is moved
+/// across its input. The DAC and comparators are restored afterwards.
+fn self_test(
+    hrtim: &hal::hrtim::HRTIM, dac: &hal::dac::DAC, comp: &hal::comp::Comp,
+    adc2_buf: &[u16; 5], state: &state::State, limits: &control::Limits,
+) -> Result<(), state::FaultCode> {
+    let result = self_test_steps(hrtim, dac, comp, adc2_buf, state, limits);
+    comp.set_comp2_inverted(false);
+    comp.set_comp4_inverted(false);
+    dac.set_ch1(0);
+    dac.set_ch2(DCM_THRESHOLD);
+    result
+}
+
+fn self_test_steps(
+    hrtim: &hal::hrtim::HRTIM, dac: &hal::dac::DAC, comp: &hal::comp::Comp,
+    adc2_buf: &[u16; 5], state: &state::State, limits: &control::Limits,
+) -> Result<(), state::FaultCode> {
+    use state::FaultCode;
+    let limits = &selftest::Limits {
+        v_in_min: limits.v_in_min, v_in_max: limits.v_in_max, ..SELFTEST_LIMITS
+    };
+
+    // Wait for the DAC to settle and ADC2 to complete two new sequences (~100µs)
+    let settle = || cortex_m::asm::delay(timing::cycles(100e-6));
+    // ADC2 sequence is VREFINT, DAC1 CH1, DAC1 CH2, I_Q, V_Q
+    // UNSAFE: Volatile read of an aligned element of the ADC2 DMA buffer.
+    let adc2 = |idx: usize| unsafe { core::ptr::read_volatile(&adc2_buf[idx]) };
+
+    if !hrtim.dll_ready() {
+        return Err(FaultCode::TestDLL);
+    }
+    selftest::check_adc(state, limits)?;
+
+    for &level in [1000, 3000].iter() {
+        dac.set_ch1(level);
+        dac.set_ch2(level);
+        settle();
+        if !selftest::check_dac(level, adc2(1), limits) {
+            return Err(FaultCode::TestDAC1);
+        }
+        if !selftest::check_dac(level, adc2(2), limits) {
+            return Err(FaultCode::TestDAC2);
+        }
+    }
+
+    let comp2_ok = selftest::check_comparator(adc2(3), limits, |threshold, inverted| {
+        comp.set_comp2_inverted(inverted);
+        dac.set_ch1(threshold);
+        settle();
+        comp.comp2_out()
+    });
+    if !comp2_ok {
+        return Err(FaultCode::TestComp2);
+    }
+
+    let comp4_ok = selftest::check_comparator(adc2(4), limits, |threshold, inverted| {
+        comp.set_comp4_inverted(inverted);
+        dac.set_ch2(threshold);
+        settle();
+        comp.comp4_out()
+    });
+    if !comp4_ok {
+        return Err(FaultCode::TestComp4);
+    }
+
+    Ok(())
+}
+
+/// Profile requested by the sync input from the display driver.
+fn sync_profile(gpio: &hal::gpio::GPIO) -> profile::ProfileId {
+    if gpio.get_sync() {
+        profile::ProfileId::Strike
+    } else {
+        profile::ProfileId::Hold
+    }
+}
+
 #[panic_handler]
 unsafe fn panic(_info: &PanicInfo) -> ! {
     // On panic, manually trigger fault and hard loop.
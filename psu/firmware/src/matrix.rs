@@ -0,0 +1,176 @@
+//! Small fixed-size matrices for filtering without allocation
+
+use core::ops::{Add, Sub, Mul, Index, IndexMut};
+
+/// Row-major R×C matrix of f32.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Matrix<const R: usize, const C: usize>(pub [[f32; C]; R]);
+
+/// Column vector of length N.
+pub type Vector<const N: usize> = Matrix<N, 1>;
+
+impl<const R: usize, const C: usize> Matrix<R, C> {
+    /// Create a new matrix with all elements zero.
+    pub const fn zero() -> Self {
+        Matrix([[0.0; C]; R])
+    }
+
+    /// Create a new matrix from row-major elements.
+    pub const fn new(m: [[f32; C]; R]) -> Self {
+        Matrix(m)
+    }
+
+    /// Return the transpose of this matrix.
+    pub fn t(&self) -> Matrix<C, R> {
+        let mut t = Matrix::zero();
+        for i in 0..R {
+            for j in 0..C {
+                t.0[j][i] = self.0[i][j];
+            }
+        }
+        t
+    }
+
+    /// Return this matrix with every element multiplied by `k`.
+    pub fn scale(&self, k: f32) -> Self {
+        let mut m = *self;
+        for row in m.0.iter_mut() {
+            for x in row.iter_mut() {
+                *x *= k;
+            }
+        }
+        m
+    }
+}
+
+impl<const N: usize> Matrix<N, N> {
+    /// Create a new N×N identity matrix.
+    pub fn identity() -> Self {
+        Self::diag(1.0)
+    }
+
+    /// Create a new N×N matrix with `d` along the diagonal.
+    pub fn diag(d: f32) -> Self {
+        let mut m = Self::zero();
+        for i in 0..N {
+            m.0[i][i] = d;
+        }
+        m
+    }
+
+    /// Return the inverse of this matrix, or None if it is singular.
+    ///
+    /// Uses Gauss-Jordan elimination with partial pivoting, which is
+    /// adequate for the very small matrices used here.
+    pub fn inverse(&self) -> Option<Self> {
+        let mut a = *self;
+        let mut inv = Self::identity();
+        for col in 0..N {
+            // Find pivot row with the largest magnitude in this column
+            let mut pivot = col;
+            for row in (col + 1)..N {
+                if a.0[row][col].abs() > a.0[pivot][col].abs() {
+                    pivot = row;
+                }
+            }
+            if a.0[pivot][col] == 0.0 {
+                return None;
+            }
+            a.0.swap(col, pivot);
+            inv.0.swap(col, pivot);
+
+            // Normalise pivot row
+            let p = 1.0 / a.0[col][col];
+            for j in 0..N {
+                a.0[col][j] *= p;
+                inv.0[col][j] *= p;
+            }
+
+            // Eliminate this column from all other rows
+            for row in 0..N {
+                if row != col {
+                    let f = a.0[row][col];
+                    for j in 0..N {
+                        a.0[row][j] -= f * a.0[col][j];
+                        inv.0[row][j] -= f * inv.0[col][j];
+                    }
+                }
+            }
+        }
+        Some(inv)
+    }
+}
+
+impl<const N: usize> Vector<N> {
+    /// Create a new column vector from its elements.
+    pub fn from_array(v: [f32; N]) -> Self {
+        let mut m = Self::zero();
+        for (row, x) in m.0.iter_mut().zip(v.iter()) {
+            row[0] = *x;
+        }
+        m
+    }
+
+    /// Return the elements of this column vector.
+    pub fn to_array(&self) -> [f32; N] {
+        let mut v = [0.0; N];
+        for (x, row) in v.iter_mut().zip(self.0.iter()) {
+            *x = row[0];
+        }
+        v
+    }
+}
+
+impl<const R: usize, const C: usize> Add for Matrix<R, C> {
+    type Output = Self;
+    fn add(mut self, rhs: Self) -> Self {
+        for i in 0..R {
+            for j in 0..C {
+                self.0[i][j] += rhs.0[i][j];
+            }
+        }
+        self
+    }
+}
+
+impl<const R: usize, const C: usize> Sub for Matrix<R, C> {
+    type Output = Self;
+    fn sub(mut self, rhs: Self) -> Self {
+        for i in 0..R {
+            for j in 0..C {
+                self.0[i][j] -= rhs.0[i][j];
+            }
+        }
+        self
+    }
+}
+
+impl<const R: usize, const K: usize, const C: usize> Mul<Matrix<K, C>> for Matrix<R, K> {
+    type Output = Matrix<R, C>;
+    fn mul(self, rhs: Matrix<K, C>) -> Matrix<R, C> {
+        let mut m = Matrix::zero();
+        for i in 0..R {
+            for j in 0..C {
+                let mut acc = 0.0;
+                for k in 0..K {
+                    acc += self.0[i][k] * rhs.0[k][j];
+                }
+                m.0[i][j] = acc;
+            }
+        }
+        m
+    }
+}
+
+impl<const R: usize, const C: usize> Index<(usize, usize)> for Matrix<R, C> {
+    type Output = f32;
+    fn index(&self, idx: (usize, usize)) -> &f32 {
+        &self.0[idx.0][idx.1]
+    }
+}
+
+impl<const R: usize, const C: usize> IndexMut<(usize, usize)> for Matrix<R, C> {
+    fn index_mut(&mut self, idx: (usize, usize)) -> &mut f32 {
+        &mut self.0[idx.0][idx.1]
+    }
+}
@@ -0,0 +1,823 @@
+//! Modbus RTU slave
+//!
+//! With Modbus enabled the serial link carries Modbus RTU instead of the binary
+//! telemetry and command protocol, so the PSU can be monitored and controlled by a
+//! PLC or test rig. Requests are framed by a silent interval of 3.5 characters,
+//! detected by the USART receiver timeout, and bytes are only interpreted once a
+//! whole frame has arrived.
+//!
+//! Measurements are input registers, setpoints and limits are holding registers,
+//! and run control is on coils, all scaled to fixed-point integers as listed in
+//! the register map in MODBUS.md. As with presets, limits and the preset slot only
+//! change while the converter is not running; writing them while it runs fails with
+//! a busy exception. Each write is validated as a whole before any of it is applied.
+
+use core::convert::TryFrom;
+
+use crate::state::{State, FaultState};
+use crate::profile::{Profile, Profiles, ProfileId};
+use crate::control::{Limits, Regulator, Supervisor};
+use crate::run::RunControl;
+use crate::preset::{Maxima, Preset, Presets};
+use crate::hw::BurstPwm;
+
+/// Longest RTU frame: address, function code, up to 252 data bytes and the CRC.
+pub const MAX_FRAME: usize = 256;
+
+/// Requests to this address are carried out by every slave, without a response.
+pub const BROADCAST: u8 = 0;
+
+/// Function codes.
+pub mod function {
+    pub const READ_COILS: u8 = 0x01;
+    pub const READ_DISCRETE_INPUTS: u8 = 0x02;
+    pub const READ_HOLDING_REGISTERS: u8 = 0x03;
+    pub const READ_INPUT_REGISTERS: u8 = 0x04;
+    pub const WRITE_SINGLE_COIL: u8 = 0x05;
+    pub const WRITE_SINGLE_REGISTER: u8 = 0x06;
+    pub const WRITE_MULTIPLE_COILS: u8 = 0x0F;
+    pub const WRITE_MULTIPLE_REGISTERS: u8 = 0x10;
+}
+
+/// Exception codes, returned for requests which cannot be carried out.
+#[repr(u8)]
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Exception {
+    /// Function code not supported.
+    IllegalFunction = 0x01,
+    /// Addresses outside the register map.
+    IllegalAddress = 0x02,
+    /// Malformed request, or a value out of range.
+    IllegalValue = 0x03,
+    /// Limits or preset written while the converter is running.
+    Busy = 0x06,
+}
+
+/// Input register addresses. Read-only measurements and status.
+pub mod input {
+    /// Input voltage (10mV).
+    pub const V_IN: u16 = 0;
+    /// Input current (mA).
+    pub const I_IN: u16 = 1;
+    /// Output voltage (0.1V).
+    pub const V_OUT: u16 = 2;
+    /// Output current (10µA).
+    pub const I_OUT: u16 = 3;
+    /// Voltage loop integrator (0.01, signed).
+    pub const PID_I: u16 = 4;
+    /// Burst duty cycle (parts per 1000).
+    pub const DUTY: u16 = 5;
+    /// Peak current reference (DAC counts).
+    pub const REF_I_Q: u16 = 6;
+    /// Die temperature (0.1°C, signed).
+    pub const TEMP: u16 = 7;
+    /// Analogue supply voltage (mV).
+    pub const VDDA: u16 = 8;
+    /// Current reference permitted by input current derating (0.1%).
+    pub const DERATE: u16 = 9;
+    /// Run state, as a `FaultState`.
+    pub const FAULT_STATE: u16 = 10;
+    /// Latched fault, as a `FaultCode`.
+    pub const FAULT_CODE: u16 = 11;
+    /// Profile in use, as a `ProfileId`.
+    pub const PROFILE: u16 = 12;
+    /// Active preset slot.
+    pub const PRESET: u16 = 13;
+    /// Constant-current mode, as a `CcMode`.
+    pub const CC_MODE: u16 = 14;
+    pub const COUNT: u16 = 15;
+}
+
+/// Holding register addresses. Setpoints and limits.
+pub mod holding {
+    /// Selected profile, as a `ProfileId`.
+    pub const PROFILE: u16 = 0;
+    /// Strike profile setpoint (0.1V).
+    pub const STRIKE_V_SET: u16 = 1;
+    /// Hold profile setpoint (0.1V).
+    pub const HOLD_V_SET: u16 = 2;
+    /// Output current limit (10µA).
+    pub const I_LIM: u16 = 3;
+    /// Constant-current limit, or 0 to disable (10µA).
+    pub const I_CC: u16 = 4;
+    /// Time current limited before folding back (ms).
+    pub const CC_TIME: u16 = 5;
+    /// Permitted input voltage range (10mV).
+    pub const V_IN_MIN: u16 = 6;
+    pub const V_IN_MAX: u16 = 7;
+    /// Input current derating threshold (mA).
+    pub const I_IN_DERATE: u16 = 8;
+    /// Input current limit (mA).
+    pub const I_IN_MAX: u16 = 9;
+    /// Die temperature limit (0.1°C, signed).
+    pub const TEMP_MAX: u16 = 10;
+    /// Die temperature below which the converter may start (0.1°C, signed).
+    pub const TEMP_RESTART: u16 = 11;
+    /// Active preset slot. Writing applies the preset before any other registers
+    /// in the same request.
+    pub const PRESET: u16 = 12;
+    pub const COUNT: u16 = 13;
+}
+
+/// Coil addresses. Run control.
+pub mod coil {
+    /// Software run request. Writing 0 stops the converter.
+    pub const RUN: u16 = 0;
+    /// Writing 1 acknowledges and clears a latched fault. Always reads 0.
+    pub const CLEAR_FAULT: u16 = 1;
+    pub const COUNT: u16 = 2;
+}
+
+/// Discrete input addresses. Run status.
+pub mod discrete {
+    /// Hardware nRUN input asserted.
+    pub const NRUN: u16 = 0;
+    /// Converter running.
+    pub const RUNNING: u16 = 1;
+    /// Fault latched.
+    pub const FAULT: u16 = 2;
+    pub const COUNT: u16 = 3;
+}
+
+/// Modbus CRC-16 of `data`, sent low byte first.
+pub fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0xFFFF, |crc, b| {
+        (0..8).fold(crc ^ *b as u16, |crc, _| {
+            if crc & 1 == 1 { (crc >> 1) ^ 0xA001 } else { crc >> 1 }
+        })
+    })
+}
+
+/// The PSU state and controls which requests read and write.
+pub struct Target<'a, P: BurstPwm> {
+    pub state: &'a mut State,
+    pub profiles: &'a mut Profiles,
+    pub regulator: &'a mut Regulator,
+    pub supervisor: &'a mut Supervisor,
+    pub run_control: &'a mut RunControl,
+    pub presets: &'a mut Presets,
+    /// Whether nRUN is asserted.
+    pub nrun: bool,
+    /// Switching output, disabled when a request stops the converter.
+    pub pwm: &'a P,
+}
+
+/// Receives request frames and builds their responses.
+pub struct Slave {
+    address: u8,
+    maxima: Maxima,
+    rx: [u8; MAX_FRAME],
+    len: usize,
+    discard: bool,
+    tx: [u8; MAX_FRAME],
+}
+
+impl Slave {
+    /// Create a slave answering at `address`, which rejects limits outside `maxima`.
+    pub const fn new(address: u8, maxima: Maxima) -> Self {
+        Slave {
+            address, maxima, rx: [0; MAX_FRAME], len: 0, discard: false, tx: [0; MAX_FRAME],
+        }
+    }
+
+    /// Add a received byte to the frame in progress.
+    pub fn push(&mut self, byte: u8) {
+        if self.len < MAX_FRAME {
+            self.rx[self.len] = byte;
+            self.len += 1;
+        } else {
+            self.discard = true;
+        }
+    }
+
+    /// Mark the frame in progress to be ignored, as after a parity error.
+    pub fn discard(&mut self) {
+        self.discard = true;
+    }
+
+    /// End the frame in progress after the line has been silent for 3.5 characters,
+    /// carrying out its request if it is valid and addressed to this slave.
+    ///
+    /// Returns the response to send, or None for invalid frames, frames addressed to
+    /// other slaves and broadcasts. The response buffer is overwritten by the next
+    /// call, so only end a frame once the previous response has been transmitted.
+    pub fn end_frame<P: BurstPwm>(&mut self, target: &mut Target<P>) -> Option<&[u8]> {
+        let (len, discard) = (self.len, self.discard);
+        self.len = 0;
+        self.discard = false;
+        if discard || len < 4 {
+            return None;
+        }
+        let (body, crc) = self.rx[..len].split_at(len - 2);
+        let address = body[0];
+        if crc16(body).to_le_bytes() != [crc[0], crc[1]]
+           || (address != self.address && address != BROADCAST)
+        {
+            return None;
+        }
+
+        let function = body[1];
+        let out = &mut self.tx[2..MAX_FRAME - 2];
+        let n = match execute(function, &body[2..], out, &self.maxima, target) {
+            Ok(n) => {
+                self.tx[1] = function;
+                n
+            },
+            Err(exception) => {
+                self.tx[1] = function | 0x80;
+                self.tx[2] = exception as u8;
+                1
+            },
+        };
+        if address == BROADCAST {
+            return None;
+        }
+        self.tx[0] = address;
+        let crc = crc16(&self.tx[..2 + n]);
+        self.tx[2 + n..4 + n].copy_from_slice(&crc.to_le_bytes());
+        Some(&self.tx[..4 + n])
+    }
+}
+
+/// Carry out one request, writing the response data after the function code to `out`
+/// and returning its length.
+fn execute<P: BurstPwm>(function: u8, data: &[u8], out: &mut [u8], maxima: &Maxima,
+                        target: &mut Target<P>)
+    -> Result<usize, Exception>
+{
+    let word = |i: usize| u16::from_be_bytes([data[2 * i], data[2 * i + 1]]);
+    match function {
+        function::READ_COILS | function::READ_DISCRETE_INPUTS => {
+            let coils = function == function::READ_COILS;
+            let (start, count) = read_request(data, 2000)?;
+            check_range(start, count, if coils { coil::COUNT } else { discrete::COUNT })?;
+            let bytes = (count as usize).div_ceil(8);
+            out[0] = bytes as u8;
+            out[1..=bytes].iter_mut().for_each(|b| *b = 0);
+            for i in 0..count {
+                let on = if coils {
+                    read_coil(target, start + i)
+                } else {
+                    read_discrete(target, start + i)
+                };
+                out[1 + i as usize / 8] |= (on as u8) << (i % 8);
+            }
+            Ok(1 + bytes)
+        },
+        function::READ_HOLDING_REGISTERS | function::READ_INPUT_REGISTERS => {
+            let holding = function == function::READ_HOLDING_REGISTERS;
+            let (start, count) = read_request(data, 125)?;
+            check_range(start, count, if holding { holding::COUNT } else { input::COUNT })?;
+            let settings = Settings::read(target);
+            out[0] = 2 * count as u8;
+            for (i, addr) in (start..start + count).enumerate() {
+                let value = if holding {
+                    settings.get(addr)
+                } else {
+                    read_input(target.state, addr)
+                };
+                out[1 + 2 * i..3 + 2 * i].copy_from_slice(&value.to_be_bytes());
+            }
+            Ok(1 + 2 * count as usize)
+        },
+        function::WRITE_SINGLE_COIL => {
+            if data.len() != 4 {
+                return Err(Exception::IllegalValue);
+            }
+            let on = match word(1) {
+                0xFF00 => true,
+                0x0000 => false,
+                _ => return Err(Exception::IllegalValue),
+            };
+            check_range(word(0), 1, coil::COUNT)?;
+            write_coil(target, word(0), on);
+            out[..4].copy_from_slice(data);
+            Ok(4)
+        },
+        function::WRITE_SINGLE_REGISTER => {
+            if data.len() != 4 {
+                return Err(Exception::IllegalValue);
+            }
+            check_range(word(0), 1, holding::COUNT)?;
+            write_holding(target, maxima, word(0), &data[2..])?;
+            out[..4].copy_from_slice(data);
+            Ok(4)
+        },
+        function::WRITE_MULTIPLE_COILS => {
+            if data.len() < 5 {
+                return Err(Exception::IllegalValue);
+            }
+            let (start, count, bytes) = (word(0), word(1), data[4] as usize);
+            if !(1..=1968).contains(&count) || bytes != (count as usize).div_ceil(8)
+               || data.len() != 5 + bytes
+            {
+                return Err(Exception::IllegalValue);
+            }
+            check_range(start, count, coil::COUNT)?;
+            for i in 0..count {
+                write_coil(target, start + i, (data[5 + i as usize / 8] >> (i % 8)) & 1 == 1);
+            }
+            out[..4].copy_from_slice(&data[..4]);
+            Ok(4)
+        },
+        function::WRITE_MULTIPLE_REGISTERS => {
+            if data.len() < 5 {
+                return Err(Exception::IllegalValue);
+            }
+            let (start, count, bytes) = (word(0), word(1), data[4] as usize);
+            if !(1..=123).contains(&count) || bytes != 2 * count as usize
+               || data.len() != 5 + bytes
+            {
+                return Err(Exception::IllegalValue);
+            }
+            check_range(start, count, holding::COUNT)?;
+            write_holding(target, maxima, start, &data[5..])?;
+            out[..4].copy_from_slice(&data[..4]);
+            Ok(4)
+        },
+        _ => Err(Exception::IllegalFunction),
+    }
+}
+
+/// Decode the start address and count of a read request, with at most `max` items.
+fn read_request(data: &[u8], max: u16) -> Result<(u16, u16), Exception> {
+    match *data {
+        [s0, s1, c0, c1] if (1..=max).contains(&u16::from_be_bytes([c0, c1])) =>
+            Ok((u16::from_be_bytes([s0, s1]), u16::from_be_bytes([c0, c1]))),
+        _ => Err(Exception::IllegalValue),
+    }
+}
+
+/// Check `count` items from `start` fall within a table of `size` items.
+fn check_range(start: u16, count: u16, size: u16) -> Result<(), Exception> {
+    if start as u32 + count as u32 <= size as u32 {
+        Ok(())
+    } else {
+        Err(Exception::IllegalAddress)
+    }
+}
+
+/// Scale a value to an unsigned register, saturating at its range.
+fn unsigned(x: f32, scale: f32) -> u16 {
+    libm::roundf(x * scale) as u16
+}
+
+/// Scale a value to a signed register, saturating at its range.
+fn signed(x: f32, scale: f32) -> u16 {
+    libm::roundf(x * scale) as i16 as u16
+}
+
+fn read_input(state: &State, addr: u16) -> u16 {
+    match addr {
+        input::V_IN => unsigned(state.v_in, 100.0),
+        input::I_IN => unsigned(state.i_in, 1000.0),
+        input::V_OUT => unsigned(state.v_out, 10.0),
+        input::I_OUT => unsigned(state.i_out, 1e5),
+        input::PID_I => signed(state.pid_i, 100.0),
+        input::DUTY => state.duty,
+        input::REF_I_Q => state.ref_i_q,
+        input::TEMP => signed(state.temp, 10.0),
+        input::VDDA => unsigned(state.vdda, 1000.0),
+        input::DERATE => unsigned(state.derate, 1000.0),
+        input::FAULT_STATE => state.fault_state as u16,
+        input::FAULT_CODE => state.fault_code as u16,
+        input::PROFILE => state.profile as u16,
+        input::PRESET => state.preset as u16,
+        input::CC_MODE => state.cc_mode as u16,
+        _ => 0,
+    }
+}
+
+fn read_discrete<P: BurstPwm>(target: &Target<P>, addr: u16) -> bool {
+    match addr {
+        discrete::NRUN => target.nrun,
+        discrete::RUNNING => target.state.fault_state == FaultState::Running,
+        discrete::FAULT => target.state.fault_state == FaultState::Fault,
+        _ => false,
+    }
+}
+
+fn read_coil<P: BurstPwm>(target: &Target<P>, addr: u16) -> bool {
+    addr == coil::RUN && target.run_control.requested()
+}
+
+fn write_coil<P: BurstPwm>(target: &mut Target<P>, addr: u16, on: bool) {
+    match (addr, on) {
+        (coil::RUN, true) => target.run_control.run(),
+        (coil::RUN, false) => {
+            let was_running = target.run_control.stop(target.state);
+            if was_running {
+                target.pwm.disable();
+            }
+        },
+        (coil::CLEAR_FAULT, true) => target.run_control.clear_fault(target.state),
+        _ => (),
+    }
+}
+
+/// Write big-endian register values from `data` to holding registers from `start`,
+/// applying them only if the resulting settings are all valid and within `maxima`.
+fn write_holding<P: BurstPwm>(target: &mut Target<P>, maxima: &Maxima, start: u16, data: &[u8])
+    -> Result<(), Exception>
+{
+    let values = || (start..).zip(data.chunks(2).map(|b| u16::from_be_bytes([b[0], b[1]])));
+    let running = target.state.fault_state == FaultState::Running;
+    let mut settings = Settings::read(target);
+
+    // A preset replaces every other setting, so apply it first
+    let slot = values().find(|(addr, _)| *addr == holding::PRESET).map(|(_, value)| value);
+    let slot = match slot {
+        Some(slot) => {
+            let slot = u8::try_from(slot).map_err(|_| Exception::IllegalValue)?;
+            let preset = target.presets.get(slot).ok_or(Exception::IllegalValue)?;
+            settings = settings.with_preset(slot, preset);
+            Some(slot)
+        },
+        None => None,
+    };
+    for (addr, value) in values().filter(|(addr, _)| *addr != holding::PRESET) {
+        settings.set(addr, value)?;
+    }
+    if !settings.valid(maxima) {
+        return Err(Exception::IllegalValue);
+    }
+    let limits = values().any(|(addr, _)| (holding::I_LIM..=holding::TEMP_RESTART).contains(&addr));
+    if running && (limits || slot.is_some()) {
+        return Err(Exception::Busy);
+    }
+
+    if let Some(slot) = slot {
+        target.presets.select(slot, target.state, target.regulator, target.supervisor,
+                              target.profiles);
+    }
+    if limits {
+        let limits = settings.limits;
+        target.regulator.set_derating(limits.i_in_derate);
+        target.regulator.set_current_limit(limits.i_cc, limits.cc_time);
+        target.supervisor.set_limits(limits);
+    }
+    target.profiles.set_v_set(ProfileId::Strike, settings.strike.v_set);
+    target.profiles.set_v_set(ProfileId::Hold, settings.hold.v_set);
+    target.profiles.select(settings.profile);
+    Ok(())
+}
+
+/// Settings as seen through the holding registers.
+#[derive(Copy, Clone)]
+struct Settings {
+    profile: ProfileId,
+    strike: Profile,
+    hold: Profile,
+    limits: Limits,
+    preset: u8,
+}
+
+impl Settings {
+    fn read<P: BurstPwm>(target: &Target<P>) -> Self {
+        Settings {
+            profile: target.profiles.active(),
+            strike: target.profiles.get(ProfileId::Strike),
+            hold: target.profiles.get(ProfileId::Hold),
+            limits: *target.supervisor.limits(),
+            preset: target.presets.active(),
+        }
+    }
+
+    /// Settings after applying a preset, which keeps the selected profile
+    /// if the preset's is unknown.
+    fn with_preset(self, slot: u8, preset: &Preset) -> Self {
+        Settings {
+            profile: ProfileId::from_u8(preset.profile).unwrap_or(self.profile),
+            strike: preset.strike,
+            hold: preset.hold,
+            limits: preset.limits,
+            preset: slot,
+        }
+    }
+
+    fn get(&self, addr: u16) -> u16 {
+        let l = &self.limits;
+        match addr {
+            holding::PROFILE => self.profile as u16,
+            holding::STRIKE_V_SET => unsigned(self.strike.v_set, 10.0),
+            holding::HOLD_V_SET => unsigned(self.hold.v_set, 10.0),
+            holding::I_LIM => unsigned(l.i_lim, 1e5),
+            holding::I_CC => unsigned(l.i_cc, 1e5),
+            holding::CC_TIME => unsigned(l.cc_time, 1000.0),
+            holding::V_IN_MIN => unsigned(l.v_in_min, 100.0),
+            holding::V_IN_MAX => unsigned(l.v_in_max, 100.0),
+            holding::I_IN_DERATE => unsigned(l.i_in_derate, 1000.0),
+            holding::I_IN_MAX => unsigned(l.i_in_max, 1000.0),
+            holding::TEMP_MAX => signed(l.temp_max, 10.0),
+            holding::TEMP_RESTART => signed(l.temp_restart, 10.0),
+            holding::PRESET => self.preset as u16,
+            _ => 0,
+        }
+    }
+
+    fn set(&mut self, addr: u16, value: u16) -> Result<(), Exception> {
+        let (l, x, s) = (&mut self.limits, value as f32, value as i16 as f32);
+        match addr {
+            holding::PROFILE => {
+                let id = u8::try_from(value).ok().and_then(ProfileId::from_u8);
+                self.profile = id.ok_or(Exception::IllegalValue)?;
+            },
+            holding::STRIKE_V_SET => self.strike.v_set = x / 10.0,
+            holding::HOLD_V_SET => self.hold.v_set = x / 10.0,
+            holding::I_LIM => l.i_lim = x / 1e5,
+            holding::I_CC => l.i_cc = x / 1e5,
+            holding::CC_TIME => l.cc_time = x / 1000.0,
+            holding::V_IN_MIN => l.v_in_min = x / 100.0,
+            holding::V_IN_MAX => l.v_in_max = x / 100.0,
+            holding::I_IN_DERATE => l.i_in_derate = x / 1000.0,
+            holding::I_IN_MAX => l.i_in_max = x / 1000.0,
+            holding::TEMP_MAX => l.temp_max = s / 10.0,
+            holding::TEMP_RESTART => l.temp_restart = s / 10.0,
+            _ => return Err(Exception::IllegalAddress),
+        }
+        Ok(())
+    }
+
+    /// Returns true if each profile's setpoint is within its limits and the
+    /// limits are consistent and within `maxima`.
+    fn valid(&self, maxima: &Maxima) -> bool {
+        let within = |p: &Profile| p.v_set > p.v_min && p.v_set < p.v_lim;
+        within(&self.strike) && within(&self.hold) && self.limits.valid()
+        && maxima.limits(&self.limits)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::Cell;
+    use crate::pid::{PID, Gains};
+    use crate::burst::{LightLoad, Strategy};
+    use crate::control::{Derating, CurrentLimit};
+    use crate::state::FaultCode;
+    use crate::preset;
+
+    const OFF: Profile = Profile { v_set: 0.0, v_lim: 420.0, v_min: f32::NEG_INFINITY, slew: 2000.0 };
+    const STRIKE: Profile = Profile { v_set: 375.0, v_lim: 420.0, v_min: 330.0, slew: 2000.0 };
+    const HOLD: Profile = Profile { v_set: 225.0, v_lim: 280.0, v_min: 190.0, slew: 2000.0 };
+    const LOW: Profile = Profile { v_set: 50.0, v_lim: 70.0, v_min: 40.0, slew: 500.0 };
+    const LIMITS: Limits = Limits {
+        i_lim: 0.1, i_cc: 0.06, cc_time: 0.5, v_in_min: 18.0, v_in_max: 30.0,
+        i_in_derate: 3.0, i_in_max: 3.5, temp_max: 85.0, temp_restart: 70.0,
+    };
+    const BENCH: Limits = Limits { i_lim: 0.02, i_cc: 0.015, v_in_min: 10.0, ..LIMITS };
+    const GAINS: Gains = Gains { k_p: 20.0, k_i: 120.0, k_d: 20.0 };
+    const MAXIMA: Maxima = Maxima {
+        v_out: 420.0, i_lim: 0.1, i_in_max: 3.5, v_in_min: 10.0, v_in_max: 30.0, temp_max: 85.0,
+    };
+
+    #[derive(Default)]
+    struct MockPwm {
+        enabled: Cell<bool>,
+    }
+
+    impl BurstPwm for MockPwm {
+        fn enable(&self) {
+            self.enabled.set(true);
+        }
+
+        fn disable(&self) {
+            self.enabled.set(false);
+        }
+
+        fn set_duty(&self, _duty: u16) {}
+    }
+
+    struct Psu {
+        slave: Slave,
+        state: State,
+        profiles: Profiles,
+        regulator: Regulator,
+        supervisor: Supervisor,
+        run_control: RunControl,
+        presets: Presets,
+        pwm: MockPwm,
+    }
+
+    impl Psu {
+        fn new() -> Self {
+            let light_load = LightLoad::new(Strategy::Off, 0.95, 0.93);
+            let (derating, current_limit) = (Derating::new(3.0, 20.0), CurrentLimit::new(5e4, 0.25));
+            let pid = PID::new(1e-4, 1.0, 1.0, 1.0, -1.0, 1.0);
+            let defaults = [
+                Preset::new("IGG1-strike", STRIKE, HOLD, LIMITS, GAINS, ProfileId::Strike),
+                Preset::new("IGG1-hold", STRIKE, HOLD, LIMITS, GAINS, ProfileId::Hold),
+                Preset::new("bench-lowV", LOW, LOW, BENCH, GAINS, ProfileId::Strike),
+                Preset::new("bench-highV", STRIKE, HOLD, BENCH, GAINS, ProfileId::Strike),
+            ];
+            assert_eq!(defaults.len(), preset::COUNT);
+            Psu {
+                slave: Slave::new(7, MAXIMA),
+                state: State::new(),
+                profiles: Profiles::new(OFF, STRIKE, HOLD, ProfileId::Strike),
+                regulator: Regulator::new(pid, light_load, derating, current_limit, 3800, 1e-4),
+                supervisor: Supervisor::new(LIMITS),
+                run_control: RunControl::new(false),
+                presets: Presets::new(defaults),
+                pwm: MockPwm::default(),
+            }
+        }
+
+        /// Send a request frame, with its CRC appended, returning the response.
+        fn request(&mut self, body: &[u8]) -> Option<std::vec::Vec<u8>> {
+            for b in frame(body) {
+                self.slave.push(b);
+            }
+            let mut target = Target {
+                state: &mut self.state, profiles: &mut self.profiles,
+                regulator: &mut self.regulator, supervisor: &mut self.supervisor,
+                run_control: &mut self.run_control, presets: &mut self.presets,
+                nrun: true, pwm: &self.pwm,
+            };
+            self.slave.end_frame(&mut target).map(|r| r.to_vec())
+        }
+    }
+
+    fn frame(body: &[u8]) -> std::vec::Vec<u8> {
+        let mut f = body.to_vec();
+        f.extend_from_slice(&crc16(body).to_le_bytes());
+        f
+    }
+
+    #[test]
+    fn crc() {
+        // Read 10 holding registers from slave 1, as in the Modbus over serial line guide
+        assert_eq!(frame(&[0x01, 0x03, 0x00, 0x00, 0x00, 0x0A]),
+                   [0x01, 0x03, 0x00, 0x00, 0x00, 0x0A, 0xC5, 0xCD]);
+    }
+
+    #[test]
+    fn reads_measurements() {
+        let mut psu = Psu::new();
+        psu.state.v_in = 24.123;
+        psu.state.i_in = 1.5;
+        psu.state.v_out = 374.96;
+        psu.state.i_out = 0.0421;
+        psu.state.pid_i = -2.5;
+        psu.state.duty = 750;
+        assert_eq!(psu.request(&[7, 0x04, 0, 0, 0, 6]).unwrap(),
+                   frame(&[7, 0x04, 12, 0x09, 0x6C, 0x05, 0xDC, 0x0E, 0xA6,
+                           0x10, 0x72, 0xFF, 0x06, 0x02, 0xEE]));
+
+        psu.state.temp = -5.0;
+        psu.state.set_fault(FaultCode::VLim);
+        psu.state.set_state_fault();
+        let response = psu.request(&[7, 0x04, 0, input::TEMP as u8, 0, 5]).unwrap();
+        assert_eq!(&response[2..13], &[10, 0xFF, 0xCE, 0x0C, 0xE4, 0x03, 0xE8, 0, 2,
+                                       0, FaultCode::VLim as u8]);
+    }
+
+    #[test]
+    fn writes_setpoints() {
+        let mut psu = Psu::new();
+        let request = [7, 0x06, 0, holding::HOLD_V_SET as u8, 0x09, 0x60];
+        assert_eq!(psu.request(&request).unwrap(), frame(&request));
+        assert_eq!(psu.profiles.get(ProfileId::Hold).v_set, 240.0);
+
+        // Strike setpoint and hold profile selection
+        let request = [7, 0x10, 0, 0, 0, 2, 4, 0, 2, 0x0E, 0x10];
+        assert_eq!(psu.request(&request).unwrap(), frame(&request[..6]));
+        assert_eq!(psu.profiles.get(ProfileId::Strike).v_set, 360.0);
+        assert_eq!(psu.profiles.active(), ProfileId::Hold);
+        assert_eq!(psu.request(&[7, 0x03, 0, 0, 0, 3]).unwrap(),
+                   frame(&[7, 0x03, 6, 0, 2, 0x0E, 0x10, 0x09, 0x60]));
+
+        // Setpoint outside the profile's limits
+        assert_eq!(psu.request(&[7, 0x06, 0, holding::HOLD_V_SET as u8, 0x0B, 0xB8]).unwrap(),
+                   frame(&[7, 0x86, 0x03]));
+        assert_eq!(psu.profiles.get(ProfileId::Hold).v_set, 240.0);
+    }
+
+    #[test]
+    fn writes_limits_while_stopped() {
+        let mut psu = Psu::new();
+        // i_lim 50mA and i_cc 40mA
+        let request = [7, 0x10, 0, holding::I_LIM as u8, 0, 2, 4, 0x13, 0x88, 0x0F, 0xA0];
+        assert_eq!(psu.request(&request).unwrap(), frame(&request[..6]));
+        assert_eq!(psu.supervisor.limits().i_lim, 0.05);
+        assert_eq!(psu.supervisor.limits().i_cc, 0.04);
+
+        // i_cc must stay below i_lim
+        let request = [7, 0x06, 0, holding::I_CC as u8, 0x13, 0x88];
+        assert_eq!(psu.request(&request).unwrap(), frame(&[7, 0x86, 0x03]));
+        assert_eq!(psu.supervisor.limits().i_cc, 0.04);
+
+        psu.state.set_state_running();
+        let request = [7, 0x06, 0, holding::I_CC as u8, 0x03, 0xE8];
+        assert_eq!(psu.request(&request).unwrap(), frame(&[7, 0x86, 0x06]));
+        assert_eq!(psu.supervisor.limits().i_cc, 0.04);
+        let request = [7, 0x06, 0, holding::PRESET as u8, 0, 2];
+        assert_eq!(psu.request(&request).unwrap(), frame(&[7, 0x86, 0x06]));
+    }
+
+    #[test]
+    fn rejects_limits_beyond_maxima() {
+        let mut psu = Psu::new();
+        let rejected = [
+            (holding::I_LIM, 10_001),
+            (holding::I_IN_MAX, 3_501),
+            (holding::V_IN_MIN, 999),
+            (holding::V_IN_MAX, 3_001),
+            (holding::TEMP_MAX, 851),
+            (holding::TEMP_RESTART, 851),
+        ];
+        for &(addr, value) in &rejected {
+            let [hi, lo] = u16::to_be_bytes(value);
+            let request = [7, 0x06, 0, addr as u8, hi, lo];
+            assert_eq!(psu.request(&request).unwrap(), frame(&[7, 0x86, 0x03]), "{}", addr);
+            assert_eq!(*psu.supervisor.limits(), LIMITS);
+        }
+
+        // A multiple write with one limit beyond its maximum changes nothing
+        let request = [7, 0x10, 0, holding::V_IN_MIN as u8, 0, 2, 4, 0x03, 0xE8, 0x0F, 0xA0];
+        assert_eq!(psu.request(&request).unwrap(), frame(&[7, 0x90, 0x03]));
+        assert_eq!(*psu.supervisor.limits(), LIMITS);
+
+        // Each maximum itself is accepted
+        let request = [7, 0x10, 0, holding::V_IN_MIN as u8, 0, 2, 4, 0x03, 0xE8, 0x0B, 0xB8];
+        assert_eq!(psu.request(&request).unwrap(), frame(&request[..6]));
+        assert_eq!(psu.supervisor.limits().v_in_min, 10.0);
+    }
+
+    #[test]
+    fn selects_preset_before_other_registers() {
+        let mut psu = Psu::new();
+        let request = [7, 0x10, 0, holding::HOLD_V_SET as u8, 0, 11, 22,
+                       0x02, 0x58, 0x07, 0xD0, 0x05, 0xDC, 0x01, 0xF4,
+                       0x03, 0xE8, 0x0B, 0xB8, 0x0C, 0x80, 0x0D, 0xAC, 0x03, 0x52, 0x02, 0xBC,
+                       0, 2];
+        assert_eq!(psu.request(&request).unwrap(), frame(&request[..6]));
+        assert_eq!(psu.presets.active(), 2);
+        assert_eq!(psu.state.preset, 2);
+        assert_eq!(psu.profiles.get(ProfileId::Strike), LOW);
+        assert_eq!(psu.profiles.get(ProfileId::Hold).v_set, 60.0);
+        assert_eq!(*psu.supervisor.limits(), Limits { i_in_derate: 3.2, ..BENCH });
+
+        assert_eq!(psu.request(&[7, 0x06, 0, holding::PRESET as u8, 0, 4]).unwrap(),
+                   frame(&[7, 0x86, 0x03]));
+    }
+
+    #[test]
+    fn run_control_coils() {
+        let mut psu = Psu::new();
+        let request = [7, 0x05, 0, coil::RUN as u8, 0xFF, 0x00];
+        assert_eq!(psu.request(&request).unwrap(), frame(&request));
+        assert!(psu.run_control.requested());
+        assert_eq!(psu.request(&[7, 0x01, 0, 0, 0, 2]).unwrap(), frame(&[7, 0x01, 1, 0b01]));
+
+        psu.state.set_state_running();
+        psu.pwm.enable();
+        assert_eq!(psu.request(&[7, 0x02, 0, 0, 0, 3]).unwrap(), frame(&[7, 0x02, 1, 0b011]));
+        let request = [7, 0x05, 0, coil::RUN as u8, 0x00, 0x00];
+        assert_eq!(psu.request(&request).unwrap(), frame(&request));
+        assert!(!psu.pwm.enabled.get());
+        assert!(psu.state.fault_state == FaultState::Stopped);
+
+        psu.state.set_fault(FaultCode::ILim);
+        psu.state.set_state_fault();
+        let request = [7, 0x0F, 0, 0, 0, 2, 1, 0b10];
+        assert_eq!(psu.request(&request).unwrap(), frame(&request[..6]));
+        assert!(psu.state.fault_state == FaultState::Stopped);
+        assert_eq!(psu.request(&[7, 0x05, 0, 0, 0x12, 0x34]).unwrap(), frame(&[7, 0x85, 0x03]));
+    }
+
+    #[test]
+    fn exceptions() {
+        let mut psu = Psu::new();
+        assert_eq!(psu.request(&[7, 0x2B, 0x0E, 1, 0]).unwrap(), frame(&[7, 0xAB, 0x01]));
+        assert_eq!(psu.request(&[7, 0x04, 0, 14, 0, 2]).unwrap(), frame(&[7, 0x84, 0x02]));
+        assert_eq!(psu.request(&[7, 0x03, 0, 0, 0, 0]).unwrap(), frame(&[7, 0x83, 0x03]));
+        assert_eq!(psu.request(&[7, 0x06, 0, 0, 0, 3]).unwrap(), frame(&[7, 0x86, 0x03]));
+        assert_eq!(psu.request(&[7, 0x10, 0, 0, 0, 1, 3, 0, 1, 0]).unwrap(),
+                   frame(&[7, 0x90, 0x03]));
+    }
+
+    #[test]
+    fn ignores_other_frames() {
+        let mut psu = Psu::new();
+        // Another slave's request and reply
+        assert!(psu.request(&[8, 0x04, 0, 0, 0, 1]).is_none());
+        assert!(psu.request(&[8, 0x04, 2, 0, 0]).is_none());
+
+        // Corrupted CRC
+        let mut f = frame(&[7, 0x04, 0, 0, 0, 1]);
+        *f.last_mut().unwrap() ^= 1;
+        f.iter().for_each(|b| psu.slave.push(*b));
+        let mut target = Target {
+            state: &mut psu.state, profiles: &mut psu.profiles,
+            regulator: &mut psu.regulator, supervisor: &mut psu.supervisor,
+            run_control: &mut psu.run_control, presets: &mut psu.presets,
+            nrun: true, pwm: &psu.pwm,
+        };
+        assert!(psu.slave.end_frame(&mut target).is_none());
+
+        // Broadcasts are carried out without a response
+        assert!(psu.request(&[0, 0x05, 0, coil::RUN as u8, 0xFF, 0x00]).is_none());
+        assert!(psu.run_control.requested());
+    }
+}
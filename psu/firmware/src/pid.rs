@@ -1,5 +1,14 @@
 //! A simple PID control loop
 
+/// Controller gains.
+#[repr(C)]
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Gains {
+    pub k_p: f32,
+    pub k_i: f32,
+    pub k_d: f32,
+}
+
 /// PID controller implementation.
 ///
 /// At each timestep the current process value and its derivative are required.
@@ -26,6 +35,15 @@ impl PID {
         PID { dt, k_p, k_i, k_d, i_min, i_max, i: 0.0 }
     }
 
+    /// Change gains and integrator limits, keeping the integrator.
+    pub fn set_gains(&mut self, gains: Gains, i_min: f32, i_max: f32) {
+        self.k_p = gains.k_p;
+        self.k_i = gains.k_i;
+        self.k_d = gains.k_d;
+        self.i_min = i_min;
+        self.i_max = i_max;
+    }
+
     pub fn zero(&mut self) {
         self.i = 0.0;
     }
@@ -0,0 +1,476 @@
+//! Named operating presets
+//!
+//! The same PSU runs from a bench supply while debugging and from the display's own
+//! supply in its enclosure, which need different setpoints and protection limits.
+//! A preset bundles the strike and hold profiles, the fault limits and the regulator
+//! gains for one such setup under a short name.
+//!
+//! Presets occupy numbered slots. Each slot holds a built-in default unless a preset
+//! has been stored into it over the serial link, in which case the stored copy is kept
+//! as a record in a dedicated flash page, at the slot's offset. Records which are
+//! erased or fail their checksum leave the slot on its default.
+//!
+//! One preset is active at a time: it is chosen at power on, by a strap input, and
+//! may be changed while the converter is not running.
+
+use crate::state::{State, ToBytes};
+use crate::profile::{Profile, ProfileId, Profiles};
+use crate::control::{Limits, Regulator, Supervisor};
+use crate::pid::Gains;
+
+/// Number of preset slots.
+pub const COUNT: usize = 4;
+
+/// Longest preset name in bytes; shorter names are padded with zeros.
+pub const NAME_LEN: usize = 12;
+
+/// Size of a preset as sent over the serial link, which excludes its checksum.
+pub const DATA_LEN: usize = core::mem::size_of::<Preset>() - 4;
+
+/// Setpoints, limits and gains for one operating setup, as stored in flash.
+///
+/// All fields are plain bytes and words so any bit pattern, including erased flash,
+/// is a valid (if not necessarily checksum-valid) record.
+#[repr(C)]
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Preset {
+    pub name: [u8; NAME_LEN],
+    pub strike: Profile,
+    pub hold: Profile,
+    pub limits: Limits,
+    pub gains: Gains,
+    /// Profile selected when the preset is applied, as a `ProfileId`.
+    pub profile: u8,
+    _padding: [u8; 3],
+    check: u32,
+}
+
+impl Preset {
+    pub const fn new(name: &str, strike: Profile, hold: Profile, limits: Limits, gains: Gains,
+                     profile: ProfileId) -> Self
+    {
+        let mut name_bytes = [0; NAME_LEN];
+        let name = name.as_bytes();
+        let mut i = 0;
+        while i < name.len() {
+            name_bytes[i] = name[i];
+            i += 1;
+        }
+        Preset {
+            name: name_bytes, strike, hold, limits, gains, profile: profile as u8,
+            _padding: [0; 3], check: 0,
+        }
+    }
+
+    /// Decode a preset sent over the serial link, as `DATA_LEN` bytes laid out as in flash.
+    ///
+    /// Returns None unless every value is finite, the name is UTF-8, the input voltage
+    /// range is not empty, derating starts below the input current limit, any
+    /// constant-current limit is below the output current limit, the integral gain is
+    /// positive, the profile is known and the preset is within `maxima`.
+    pub fn decode(data: &[u8], maxima: &Maxima) -> Option<Self> {
+        if data.len() != DATA_LEN {
+            return None;
+        }
+        let mut name = [0; NAME_LEN];
+        name.copy_from_slice(&data[..NAME_LEN]);
+        let mut words = data[NAME_LEN..DATA_LEN - 4].chunks(4)
+                            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]));
+        let mut next = || words.next().unwrap_or(f32::NAN);
+        let mut profile = || Profile { v_set: next(), v_lim: next(), v_min: next(), slew: next() };
+        let (strike, hold) = (profile(), profile());
+        let limits = Limits {
+            i_lim: next(), i_cc: next(), cc_time: next(), v_in_min: next(), v_in_max: next(),
+            i_in_derate: next(), i_in_max: next(), temp_max: next(), temp_restart: next(),
+        };
+        let gains = Gains { k_p: next(), k_i: next(), k_d: next() };
+        let id = ProfileId::from_u8(data[DATA_LEN - 4])?;
+        let preset = Preset::new("", strike, hold, limits, gains, id);
+        let preset = Preset { name, ..preset };
+
+        let finite = preset.to_bytes()[NAME_LEN..DATA_LEN - 4].chunks(4)
+                           .all(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]).is_finite());
+        let sane = limits.valid() && gains.k_i > 0.0 && maxima.allows(&preset);
+        if finite && sane && core::str::from_utf8(&name).is_ok() {
+            Some(preset)
+        } else {
+            None
+        }
+    }
+
+    /// Name with trailing zeros removed.
+    pub fn name(&self) -> &str {
+        let len = self.name.iter().position(|b| *b == 0).unwrap_or(NAME_LEN);
+        core::str::from_utf8(&self.name[..len]).unwrap_or("")
+    }
+
+    /// Returns true if the record's checksum is correct.
+    pub fn valid(&self) -> bool {
+        self.check == self.checksum()
+    }
+
+    /// Record contents as half-words for programming into flash.
+    pub fn as_halfwords(&self) -> [u16; core::mem::size_of::<Preset>() / 2] {
+        let mut out = [0; core::mem::size_of::<Preset>() / 2];
+        for (o, b) in out.iter_mut().zip(self.to_bytes().chunks(2)) {
+            *o = u16::from_le_bytes([b[0], b[1]]);
+        }
+        out
+    }
+
+    /// Configure the regulator, fault limits and output profiles from this preset.
+    pub fn apply(&self, regulator: &mut Regulator, supervisor: &mut Supervisor,
+                 profiles: &mut Profiles)
+    {
+        regulator.set_gains(self.gains);
+        regulator.set_derating(self.limits.i_in_derate);
+        regulator.set_current_limit(self.limits.i_cc, self.limits.cc_time);
+        supervisor.set_limits(self.limits);
+        profiles.set_profiles(self.strike, self.hold);
+        if let Some(id) = ProfileId::from_u8(self.profile) {
+            profiles.select(id);
+        }
+        profiles.reset();
+    }
+
+    fn checksum(&self) -> u32 {
+        let words = self.to_bytes()[..DATA_LEN].chunks(4)
+                        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]));
+        !words.fold(0u32, |a, w| a.rotate_left(5).wrapping_add(w))
+    }
+
+    fn seal(&mut self) {
+        self.check = self.checksum();
+    }
+}
+
+unsafe impl ToBytes for Preset {}
+
+/// Absolute maxima which no preset may exceed, however it was set.
+#[derive(Copy, Clone, Debug)]
+pub struct Maxima {
+    /// Highest output voltage setpoint or limit (V).
+    pub v_out: f32,
+    /// Highest output current limit (A).
+    pub i_lim: f32,
+    /// Highest input current limit (A).
+    pub i_in_max: f32,
+    /// Lowest and highest input voltage the converter may run from (V).
+    pub v_in_min: f32,
+    pub v_in_max: f32,
+    /// Highest die temperature limit (°C).
+    pub temp_max: f32,
+}
+
+impl Maxima {
+    /// Returns true if the profile's setpoint and overvoltage limit are within the maxima.
+    pub const fn profile(&self, profile: &Profile) -> bool {
+        profile.v_set <= self.v_out && profile.v_lim <= self.v_out
+    }
+
+    /// Returns true if every fault limit is within the maxima.
+    ///
+    /// The constant-current limit, derating threshold and restart temperature are held
+    /// to the maxima of the limits they sit below. `cc_time` has no maximum, as it only
+    /// delays folding back a current that is already below `i_lim`, and the die
+    /// temperature limit still applies while it runs.
+    pub const fn limits(&self, limits: &Limits) -> bool {
+        limits.i_lim > 0.0 && limits.i_lim <= self.i_lim && limits.i_cc <= self.i_lim
+        && limits.i_in_max <= self.i_in_max && limits.i_in_derate <= self.i_in_max
+        && limits.v_in_min >= self.v_in_min && limits.v_in_max <= self.v_in_max
+        && limits.temp_max <= self.temp_max && limits.temp_restart <= self.temp_max
+    }
+
+    /// Returns true if both of the preset's profiles and its limits are within the maxima.
+    pub const fn allows(&self, preset: &Preset) -> bool {
+        self.profile(&preset.strike) && self.profile(&preset.hold) && self.limits(&preset.limits)
+    }
+}
+
+/// Preset packet, describing one slot.
+#[repr(C)]
+#[repr(align(4))]
+pub struct Report {
+    magic: u32,
+    pub slot: u8,
+    /// Currently active slot.
+    pub active: u8,
+    /// 1 if the slot holds a preset stored in flash, 0 if it holds its default.
+    pub stored: u8,
+    _padding: u8,
+    pub preset: Preset,
+}
+
+impl Report {
+    pub const fn new(preset: Preset) -> Self {
+        Report { magic: 0x70726573, slot: 0, active: 0, stored: 0, _padding: 0, preset }
+    }
+}
+
+unsafe impl ToBytes for Report {}
+
+/// Preset slots and the active selection.
+pub struct Presets {
+    table: [Preset; COUNT],
+    stored: [bool; COUNT],
+    active: u8,
+    save_due: bool,
+}
+
+impl Presets {
+    pub const fn new(defaults: [Preset; COUNT]) -> Self {
+        Presets { table: defaults, stored: [false; COUNT], active: 0, save_due: false }
+    }
+
+    /// Replace defaults with the valid records from a preset flash page,
+    /// skipping any outside `maxima`.
+    pub fn load(&mut self, records: &[Preset], maxima: &Maxima) {
+        for (slot, record) in records.iter().take(COUNT).enumerate() {
+            if record.valid() && maxima.allows(record) {
+                self.table[slot] = *record;
+                self.stored[slot] = true;
+            }
+        }
+    }
+
+    pub fn get(&self, slot: u8) -> Option<&Preset> {
+        self.table.get(slot as usize)
+    }
+
+    /// Currently active slot.
+    pub fn active(&self) -> u8 {
+        self.active
+    }
+
+    /// Make `slot` the active preset and apply it, which must only be done while the
+    /// converter is not running. Returns false if there is no such slot.
+    pub fn select(&mut self, slot: u8, state: &mut State, regulator: &mut Regulator,
+                  supervisor: &mut Supervisor, profiles: &mut Profiles) -> bool
+    {
+        let preset = match self.get(slot) {
+            Some(preset) => *preset,
+            None => return false,
+        };
+        preset.apply(regulator, supervisor, profiles);
+        self.active = slot;
+        state.update_preset(slot);
+        true
+    }
+
+    /// Store `preset` into `slot` and request the preset page is saved.
+    ///
+    /// The active preset is not reapplied until it is next selected.
+    /// Returns false if there is no such slot.
+    pub fn store(&mut self, slot: u8, mut preset: Preset) -> bool {
+        if slot as usize >= COUNT {
+            return false;
+        }
+        preset.seal();
+        self.table[slot as usize] = preset;
+        self.stored[slot as usize] = true;
+        self.save_due = true;
+        true
+    }
+
+    /// Returns true if stored presets have changed since they were last saved.
+    pub fn save_due(&self) -> bool {
+        self.save_due
+    }
+
+    /// Records to program into the erased preset page, as each stored slot and its
+    /// record, after which the save is no longer due.
+    pub fn save(&mut self) -> impl Iterator<Item = (usize, &Preset)> {
+        self.save_due = false;
+        let stored = self.stored;
+        self.table.iter().enumerate().filter(move |(slot, _)| stored[*slot])
+    }
+
+    /// Fill `report` with the contents of `slot`, returning false if there is no such slot.
+    pub fn report(&self, slot: u8, report: &mut Report) -> bool {
+        let preset = match self.get(slot) {
+            Some(preset) => *preset,
+            None => return false,
+        };
+        report.slot = slot;
+        report.active = self.active;
+        report.stored = self.stored[slot as usize] as u8;
+        report.preset = preset;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pid::PID;
+    use crate::burst::{LightLoad, Strategy};
+    use crate::control::{Derating, CurrentLimit};
+
+    const STRIKE: Profile = Profile { v_set: 375.0, v_lim: 420.0, v_min: 330.0, slew: 2000.0 };
+    const HOLD: Profile = Profile { v_set: 225.0, v_lim: 280.0, v_min: 190.0, slew: 2000.0 };
+    const LOW: Profile = Profile { v_set: 50.0, v_lim: 70.0, v_min: 40.0, slew: 500.0 };
+    const LIMITS: Limits = Limits {
+        i_lim: 0.1, i_cc: 0.06, cc_time: 0.5, v_in_min: 18.0, v_in_max: 30.0,
+        i_in_derate: 3.0, i_in_max: 3.5, temp_max: 85.0, temp_restart: 70.0,
+    };
+    const BENCH: Limits = Limits {
+        i_lim: 0.02, i_cc: 0.0, v_in_min: 10.0, i_in_derate: 1.2, i_in_max: 1.5, ..LIMITS
+    };
+    const GAINS: Gains = Gains { k_p: 20.0, k_i: 120.0, k_d: 20.0 };
+    const MAXIMA: Maxima = Maxima {
+        v_out: 420.0, i_lim: 0.1, i_in_max: 3.5, v_in_min: 10.0, v_in_max: 30.0, temp_max: 85.0,
+    };
+
+    const DEFAULTS: [Preset; COUNT] = [
+        Preset::new("IGG1-strike", STRIKE, HOLD, LIMITS, GAINS, ProfileId::Strike),
+        Preset::new("IGG1-hold", STRIKE, HOLD, LIMITS, GAINS, ProfileId::Hold),
+        Preset::new("bench-lowV", LOW, LOW, BENCH, GAINS, ProfileId::Strike),
+        Preset::new("bench-highV", STRIKE, HOLD, BENCH, GAINS, ProfileId::Strike),
+    ];
+
+    fn regulator() -> Regulator {
+        let light_load = LightLoad::new(Strategy::Off, 0.95, 0.93);
+        let (derating, current_limit) = (Derating::new(3.0, 20.0), CurrentLimit::new(5e4, 0.25));
+        let pid = PID::new(1e-4, 1.0, 1.0, 1.0, -1.0, 1.0);
+        Regulator::new(pid, light_load, derating, current_limit, 3800, 1e-4)
+    }
+
+    fn encode(preset: &Preset) -> std::vec::Vec<u8> {
+        preset.to_bytes()[..DATA_LEN].to_vec()
+    }
+
+    #[test]
+    fn record_layout() {
+        assert_eq!(core::mem::size_of::<Preset>(), 100);
+        assert_eq!(DATA_LEN, 96);
+        assert_eq!(DEFAULTS[2].name(), "bench-lowV");
+        assert_eq!(&DEFAULTS[2].name[10..], &[0, 0]);
+        assert_eq!(DEFAULTS[1].profile, ProfileId::Hold as u8);
+    }
+
+    #[test]
+    fn select_applies_preset() {
+        let mut presets = Presets::new(DEFAULTS);
+        let mut state = State::new();
+        let mut regulator = regulator();
+        let mut supervisor = Supervisor::new(LIMITS);
+        let mut profiles = Profiles::new(STRIKE, STRIKE, HOLD, ProfileId::Strike);
+
+        assert!(presets.select(2, &mut state, &mut regulator, &mut supervisor, &mut profiles));
+        assert_eq!((presets.active(), state.preset), (2, 2));
+        assert_eq!(supervisor.limits().i_in_derate, 1.2);
+        assert_eq!(supervisor.limits().v_in_min, 10.0);
+        assert_eq!(profiles.reference(), 50.0);
+        assert_eq!(profiles.v_lim(), 70.0);
+
+        assert!(presets.select(1, &mut state, &mut regulator, &mut supervisor, &mut profiles));
+        assert_eq!(profiles.active(), ProfileId::Hold);
+        assert!(!profiles.settling());
+        assert_eq!(profiles.reference(), 225.0);
+
+        assert!(!presets.select(4, &mut state, &mut regulator, &mut supervisor, &mut profiles));
+        assert_eq!(state.preset, 1);
+    }
+
+    #[test]
+    fn decode_round_trip() {
+        let preset = Preset::decode(&encode(&DEFAULTS[3]), &MAXIMA).unwrap();
+        assert_eq!(preset.name(), "bench-highV");
+        assert_eq!(preset.limits.i_lim, 0.02);
+        assert_eq!(preset.gains.k_d, 20.0);
+        assert_eq!(preset.hold.v_set, 225.0);
+
+        assert!(Preset::decode(&encode(&DEFAULTS[3])[1..], &MAXIMA).is_none());
+        let mut bad_profile = encode(&DEFAULTS[0]);
+        bad_profile[DATA_LEN - 4] = 3;
+        assert!(Preset::decode(&bad_profile, &MAXIMA).is_none());
+        let nan = Preset { strike: Profile { v_min: f32::NAN, ..STRIKE }, ..DEFAULTS[0] };
+        assert!(Preset::decode(&encode(&nan), &MAXIMA).is_none());
+        let empty = Preset { limits: Limits { v_in_min: 30.0, ..LIMITS }, ..DEFAULTS[0] };
+        assert!(Preset::decode(&encode(&empty), &MAXIMA).is_none());
+        let no_derating = Preset { limits: Limits { i_in_derate: 3.5, ..LIMITS }, ..DEFAULTS[0] };
+        assert!(Preset::decode(&encode(&no_derating), &MAXIMA).is_none());
+        let cc_over = Preset { limits: Limits { i_cc: 0.1, ..LIMITS }, ..DEFAULTS[0] };
+        assert!(Preset::decode(&encode(&cc_over), &MAXIMA).is_none());
+    }
+
+    #[test]
+    fn decode_rejects_beyond_maxima() {
+        let over = |limits: Limits| Preset { limits, ..DEFAULTS[0] };
+        let over_v = |strike: Profile, hold: Profile| Preset { strike, hold, ..DEFAULTS[0] };
+        let rejected = [
+            over_v(Profile { v_set: 421.0, v_lim: 420.0, ..STRIKE }, HOLD),
+            over_v(Profile { v_lim: 421.0, ..STRIKE }, HOLD),
+            over_v(STRIKE, Profile { v_set: 430.0, ..HOLD }),
+            over_v(STRIKE, Profile { v_lim: 500.0, ..HOLD }),
+            over(Limits { i_lim: 0.101, ..LIMITS }),
+            over(Limits { i_lim: 0.0, i_cc: 0.0, ..LIMITS }),
+            over(Limits { i_in_max: 3.6, ..LIMITS }),
+            over(Limits { v_in_min: 9.0, ..LIMITS }),
+            over(Limits { v_in_max: 31.0, ..LIMITS }),
+            over(Limits { temp_max: 90.0, ..LIMITS }),
+        ];
+        for preset in &rejected {
+            assert!(Preset::decode(&encode(preset), &MAXIMA).is_none(), "{:?}", preset);
+        }
+
+        // Each maximum itself is accepted
+        let at_max = over(Limits { i_lim: 0.1, i_in_max: 3.5, v_in_min: 10.0, v_in_max: 30.0,
+                                   temp_max: 85.0, ..LIMITS });
+        assert!(Preset::decode(&encode(&at_max), &MAXIMA).is_some());
+    }
+
+    #[test]
+    fn maxima_cover_dependent_limits() {
+        assert!(MAXIMA.limits(&LIMITS));
+        assert!(!MAXIMA.limits(&Limits { i_cc: 0.11, ..LIMITS }));
+        assert!(!MAXIMA.limits(&Limits { i_in_derate: 3.6, ..LIMITS }));
+        assert!(!MAXIMA.limits(&Limits { temp_restart: 86.0, ..LIMITS }));
+        assert!(!MAXIMA.limits(&Limits { temp_max: f32::NAN, ..LIMITS }));
+    }
+
+    #[test]
+    fn stores_and_loads_from_flash() {
+        let mut presets = Presets::new(DEFAULTS);
+        assert!(!presets.save_due());
+        let custom = Preset::new("IGG2", HOLD, HOLD, LIMITS, GAINS, ProfileId::Hold);
+        assert!(presets.store(3, custom));
+        assert!(!presets.store(4, custom));
+        assert!(presets.save_due());
+
+        // Lay out a page as the firmware programs it, with unsealed records in unstored slots
+        let mut page = [DEFAULTS[0]; 23];
+        for (slot, record) in presets.save() {
+            page[slot] = *record;
+        }
+        assert!(!presets.save_due());
+
+        let mut loaded = Presets::new(DEFAULTS);
+        page[2].check ^= 1;
+        loaded.load(&page, &MAXIMA);
+        assert_eq!(loaded.get(3).unwrap().name(), "IGG2");
+        assert_eq!(loaded.get(0).unwrap().name(), "IGG1-strike");
+
+        // Records beyond the maxima are skipped, even with a correct checksum
+        let mut hot = Preset::new("hot", STRIKE, HOLD, Limits { temp_max: 100.0, ..LIMITS },
+                                  GAINS, ProfileId::Strike);
+        hot.seal();
+        let mut high = Preset::new("high", Profile { v_lim: 450.0, ..STRIKE }, HOLD, LIMITS,
+                                   GAINS, ProfileId::Strike);
+        high.seal();
+        assert!(hot.valid() && high.valid());
+        let mut skipped = Presets::new(DEFAULTS);
+        skipped.load(&[hot, high], &MAXIMA);
+        assert_eq!(skipped.get(0).unwrap().name(), "IGG1-strike");
+        assert_eq!(skipped.get(1).unwrap().name(), "IGG1-hold");
+
+        let mut report = Report::new(DEFAULTS[0]);
+        assert!(loaded.report(3, &mut report));
+        assert_eq!((report.slot, report.stored), (3, 1));
+        assert_eq!(report.to_bytes().len(), 8 + 100);
+        assert_eq!(&report.to_bytes()[..4], &0x70726573u32.to_le_bytes());
+        assert!(loaded.report(2, &mut report));
+        assert_eq!(report.stored, 0);
+        assert!(!loaded.report(4, &mut report));
+    }
+}
@@ -0,0 +1,241 @@
+//! Named output voltage profiles
+//!
+//! The panels need a high strike voltage to ignite and then a lower hold voltage
+//! to sustain the discharge. Each profile bundles an output setpoint with its
+//! fault limits, and `Profiles` slews the setpoint between profiles when a new
+//! one is selected.
+
+/// Identifies one of the output profiles.
+#[repr(u8)]
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ProfileId {
+    Off    = 0,
+    Strike = 1,
+    Hold   = 2,
+}
+
+impl ProfileId {
+    pub fn from_u8(x: u8) -> Option<Self> {
+        match x {
+            0 => Some(ProfileId::Off),
+            1 => Some(ProfileId::Strike),
+            2 => Some(ProfileId::Hold),
+            _ => None,
+        }
+    }
+}
+
+/// Output setpoint and limits for one profile.
+#[repr(C)]
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Profile {
+    /// Setpoint voltage (V)
+    pub v_set: f32,
+    /// Overvoltage limit before a fault is triggered (V)
+    pub v_lim: f32,
+    /// Minimum output voltage before a fault is triggered after the start timeout (V)
+    pub v_min: f32,
+    /// Rate at which the setpoint moves when changing into this profile (V/s)
+    pub slew: f32,
+}
+
+/// Selects between output profiles and tracks transitions between them.
+///
+/// While a transition is in progress the setpoint moves at the new profile's slew
+/// rate, and the limits are the widest of the old and new profiles. The transition
+/// completes once the setpoint has arrived and Vout is within the new profile's limits,
+/// since the output can only fall as fast as the load discharges it.
+pub struct Profiles {
+    profiles: [Profile; 3],
+    active: ProfileId,
+    previous: ProfileId,
+    v_ref: f32,
+    settling: bool,
+}
+
+impl Profiles {
+    pub const fn new(off: Profile, strike: Profile, hold: Profile, initial: ProfileId) -> Self {
+        Profiles {
+            profiles: [off, strike, hold],
+            active: initial,
+            previous: initial,
+            v_ref: 0.0,
+            settling: false,
+        }
+    }
+
+    /// Begin a transition to a new profile.
+    pub fn select(&mut self, id: ProfileId) {
+        if id != self.active {
+            self.previous = self.active;
+            self.active = id;
+            self.settling = true;
+        }
+    }
+
+    /// Replace the strike and hold profiles, as when changing preset.
+    ///
+    /// The new profiles take effect at the next `reset()` or transition.
+    pub fn set_profiles(&mut self, strike: Profile, hold: Profile) {
+        self.profiles[ProfileId::Strike as usize] = strike;
+        self.profiles[ProfileId::Hold as usize] = hold;
+    }
+
+    /// Settings for one profile.
+    pub fn get(&self, id: ProfileId) -> Profile {
+        self.profiles[id as usize]
+    }
+
+    /// Change one profile's setpoint. If that profile is active the setpoint slews
+    /// to the new value at its slew rate, as for a transition.
+    pub fn set_v_set(&mut self, id: ProfileId, v_set: f32) {
+        if v_set != self.profiles[id as usize].v_set {
+            self.profiles[id as usize].v_set = v_set;
+            if id == self.active {
+                self.settling = true;
+            }
+        }
+    }
+
+    /// Move the setpoint directly to the active profile, abandoning any transition.
+    ///
+    /// Call when the converter is stopped, so that it starts with the same
+    /// setpoint and limits as if the active profile had always been in use.
+    pub fn reset(&mut self) {
+        self.previous = self.active;
+        self.settling = false;
+        self.v_ref = self.profile().v_set;
+    }
+
+    /// Advance the setpoint by `dt` seconds, given the present output voltage,
+    /// and return the new setpoint.
+    pub fn step(&mut self, dt: f32, v_out: f32) -> f32 {
+        if self.settling {
+            let p = self.profile();
+            let max_step = p.slew * dt;
+            let err = p.v_set - self.v_ref;
+            if err > max_step {
+                self.v_ref += max_step;
+            } else if err < -max_step {
+                self.v_ref -= max_step;
+            } else {
+                self.v_ref = p.v_set;
+                if v_out > p.v_min && v_out < p.v_lim {
+                    self.settling = false;
+                    self.previous = self.active;
+                }
+            }
+        }
+        self.v_ref
+    }
+
+    /// Current (possibly slewing) output setpoint (V).
+    pub fn reference(&self) -> f32 {
+        self.v_ref
+    }
+
+    /// Current overvoltage limit (V).
+    pub fn v_lim(&self) -> f32 {
+        let (a, b) = (self.profile(), self.profiles[self.previous as usize]);
+        if a.v_lim > b.v_lim { a.v_lim } else { b.v_lim }
+    }
+
+    /// Current minimum output voltage (V).
+    pub fn v_min(&self) -> f32 {
+        let (a, b) = (self.profile(), self.profiles[self.previous as usize]);
+        if a.v_min < b.v_min { a.v_min } else { b.v_min }
+    }
+
+    /// Currently selected profile.
+    pub fn active(&self) -> ProfileId {
+        self.active
+    }
+
+    /// Returns true while moving between profiles.
+    pub fn settling(&self) -> bool {
+        self.settling
+    }
+
+    fn profile(&self) -> Profile {
+        self.profiles[self.active as usize]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OFF: Profile = Profile { v_set: 0.0, v_lim: 420.0, v_min: f32::NEG_INFINITY, slew: 2000.0 };
+    const STRIKE: Profile = Profile { v_set: 370.0, v_lim: 420.0, v_min: 330.0, slew: 2000.0 };
+    const HOLD: Profile = Profile { v_set: 225.0, v_lim: 280.0, v_min: 190.0, slew: 1000.0 };
+
+    #[test]
+    fn slews_between_profiles() {
+        let mut p = Profiles::new(OFF, STRIKE, HOLD, ProfileId::Hold);
+        p.reset();
+        assert_eq!(p.reference(), 225.0);
+        assert_eq!((p.v_lim(), p.v_min()), (280.0, 190.0));
+
+        p.select(ProfileId::Strike);
+        assert!(p.settling());
+        assert_eq!((p.v_lim(), p.v_min()), (420.0, 190.0));
+        let r = p.step(1e-3, 225.0);
+        assert!((r - 227.0).abs() < 1e-3);
+
+        // Setpoint arrives after 72.5ms but Vout must also reach the new limits
+        for _ in 0..80 {
+            p.step(1e-3, 300.0);
+        }
+        assert_eq!(p.reference(), 370.0);
+        assert!(p.settling());
+        p.step(1e-3, 365.0);
+        assert!(!p.settling());
+        assert_eq!((p.v_lim(), p.v_min()), (420.0, 330.0));
+    }
+
+    #[test]
+    fn waits_for_output_to_fall() {
+        let mut p = Profiles::new(OFF, STRIKE, HOLD, ProfileId::Strike);
+        p.reset();
+        p.select(ProfileId::Hold);
+        for _ in 0..1000 {
+            p.step(1e-3, 370.0);
+        }
+        assert_eq!(p.reference(), 225.0);
+        assert!(p.settling());
+        assert_eq!(p.v_lim(), 420.0);
+        p.step(1e-3, 240.0);
+        assert_eq!(p.v_lim(), 280.0);
+    }
+
+    #[test]
+    fn slews_to_changed_setpoint() {
+        let mut p = Profiles::new(OFF, STRIKE, HOLD, ProfileId::Strike);
+        p.reset();
+        p.set_v_set(ProfileId::Hold, 240.0);
+        assert!(!p.settling());
+        p.set_v_set(ProfileId::Strike, 360.0);
+        assert!(p.settling());
+        assert_eq!(p.step(1e-3, 370.0), 368.0);
+        for _ in 0..4 {
+            p.step(1e-3, 362.0);
+        }
+        assert_eq!(p.reference(), 360.0);
+        assert!(!p.settling());
+        assert_eq!(p.get(ProfileId::Hold).v_set, 240.0);
+    }
+
+    #[test]
+    fn reset_abandons_transition() {
+        let mut p = Profiles::new(OFF, STRIKE, HOLD, ProfileId::Strike);
+        p.reset();
+        p.select(ProfileId::Off);
+        p.step(1e-3, 370.0);
+        p.reset();
+        assert!(!p.settling());
+        assert_eq!(p.reference(), 0.0);
+        assert_eq!(p.v_min(), f32::NEG_INFINITY);
+        assert_eq!(ProfileId::from_u8(2), Some(ProfileId::Hold));
+        assert_eq!(ProfileId::from_u8(3), None);
+    }
+}
@@ -0,0 +1,226 @@
+//! Task execution time and CPU load profiling
+//!
+//! Each profiled task records its start and end times from the DWT cycle counter.
+//! Over each report window this gives the minimum, mean and maximum execution time,
+//! the worst deviation of the period between starts from the task's nominal period,
+//! and the number of overruns: runs which took longer than the nominal period, or
+//! which started more than half a period late and so missed a trigger.
+//!
+//! All profiled tasks run at the same priority and cannot preempt each other, so
+//! CPU load is their total execution time over the window.
+
+use crate::state::ToBytes;
+
+/// Profiled tasks, in report order.
+#[derive(Copy, Clone)]
+pub enum Task {
+    Adc       = 0,
+    CtrlLoop  = 1,
+    SendTelem = 2,
+    Heartbeat = 3,
+}
+
+const TASKS: usize = 4;
+
+/// Execution time summary for one task over a window, in cycles.
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct TaskReport {
+    pub count: u32,
+    pub min: u32,
+    pub mean: u32,
+    pub max: u32,
+    /// Largest difference between the measured and nominal period.
+    pub jitter: u32,
+    pub overruns: u32,
+}
+
+impl TaskReport {
+    pub const fn new() -> Self {
+        TaskReport { count: 0, min: 0, mean: 0, max: 0, jitter: 0, overruns: 0 }
+    }
+}
+
+impl Default for TaskReport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Profiling telemetry packet.
+#[repr(C)]
+#[repr(align(4))]
+pub struct Report {
+    magic: u32,
+    /// Window length (cycles).
+    pub window: u32,
+    /// Fraction of the window spent in profiled tasks.
+    pub load: f32,
+    /// Bit n is set if task n overran during the window.
+    pub warnings: u32,
+    pub tasks: [TaskReport; TASKS],
+}
+
+impl Report {
+    pub const fn new() -> Self {
+        Report {
+            magic: 0x70726f66, window: 0, load: 0.0, warnings: 0,
+            tasks: [TaskReport::new(); TASKS],
+        }
+    }
+}
+
+impl Default for Report {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+unsafe impl ToBytes for Report {}
+
+#[derive(Copy, Clone)]
+struct Timer {
+    /// Nominal period (cycles), or zero for tasks without a fixed period.
+    period: u32,
+    count: u32,
+    total: u32,
+    min: u32,
+    max: u32,
+    jitter: u32,
+    overruns: u32,
+    last_start: Option<u32>,
+}
+
+impl Timer {
+    const fn new(period: u32) -> Self {
+        Timer {
+            period, count: 0, total: 0, min: u32::MAX, max: 0, jitter: 0, overruns: 0,
+            last_start: None,
+        }
+    }
+
+    fn record(&mut self, start: u32, end: u32) {
+        let duration = end.wrapping_sub(start);
+        self.count += 1;
+        self.total = self.total.wrapping_add(duration);
+        self.min = self.min.min(duration);
+        self.max = self.max.max(duration);
+
+        let mut overrun = self.period != 0 && duration > self.period;
+        if let (Some(last), true) = (self.last_start, self.period != 0) {
+            let period = start.wrapping_sub(last);
+            self.jitter = self.jitter.max((period as i32).wrapping_sub(self.period as i32)
+                                                     .unsigned_abs());
+            overrun |= period > self.period + self.period / 2;
+        }
+        self.last_start = Some(start);
+        if overrun {
+            self.overruns += 1;
+        }
+    }
+
+    fn finish(&mut self, report: &mut TaskReport) {
+        report.count = self.count;
+        report.min = if self.count == 0 { 0 } else { self.min };
+        report.mean = self.total.checked_div(self.count).unwrap_or(0);
+        report.max = self.max;
+        report.jitter = self.jitter;
+        report.overruns = self.overruns;
+        *self = Timer { last_start: self.last_start, ..Timer::new(self.period) };
+    }
+}
+
+/// Collects task timings over a report window.
+pub struct Profiler {
+    timers: [Timer; TASKS],
+    window_start: u32,
+}
+
+impl Profiler {
+    /// Create a new profiler, given each task's nominal period in cycles, or zero if
+    /// it has none.
+    pub const fn new(periods: [u32; TASKS]) -> Self {
+        Profiler {
+            timers: [Timer::new(periods[0]), Timer::new(periods[1]),
+                     Timer::new(periods[2]), Timer::new(periods[3])],
+            window_start: 0,
+        }
+    }
+
+    /// Change a task's nominal period, such as when its rate is reconfigured.
+    pub fn set_period(&mut self, task: Task, period: u32) {
+        let timer = &mut self.timers[task as usize];
+        if timer.period != period {
+            timer.period = period;
+            timer.last_start = None;
+        }
+    }
+
+    /// Record one run of `task` between cycle counts `start` and `end`.
+    pub fn record(&mut self, task: Task, start: u32, end: u32) {
+        self.timers[task as usize].record(start, end);
+    }
+
+    /// Fill in the report for the window ending at cycle count `now` and start a new one.
+    pub fn finish(&mut self, now: u32, report: &mut Report) {
+        report.window = now.wrapping_sub(self.window_start);
+        self.window_start = now;
+        report.warnings = 0;
+        let mut busy = 0u32;
+        let tasks = self.timers.iter_mut().zip(report.tasks.iter_mut());
+        for (idx, (timer, task)) in tasks.enumerate() {
+            busy = busy.wrapping_add(timer.total);
+            timer.finish(task);
+            if task.overruns > 0 {
+                report.warnings |= 1 << idx;
+            }
+        }
+        report.load = if report.window == 0 { 0.0 } else { busy as f32 / report.window as f32 };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn times_tasks() {
+        let mut profiler = Profiler::new([1000, 0, 0, 0]);
+        let mut report = Report::new();
+        profiler.finish(0, &mut report);
+
+        // Three runs on time, one late, and one which takes too long
+        for (start, duration) in [(0, 100), (1000, 200), (2010, 300), (3600, 100), (4600, 1100)] {
+            profiler.record(Task::Adc, start, start + duration);
+        }
+        profiler.record(Task::Heartbeat, 6000, 6400);
+        profiler.finish(8000, &mut report);
+
+        let adc = report.tasks[0];
+        assert_eq!((adc.count, adc.min, adc.mean, adc.max), (5, 100, 360, 1100));
+        assert_eq!(adc.jitter, 590);
+        assert_eq!(adc.overruns, 2);
+        assert_eq!(report.warnings, 0b0001);
+        assert_eq!(report.window, 8000);
+        assert_eq!(report.load, 0.275);
+
+        // Counts restart each window, with periods measured across the boundary
+        profiler.record(Task::Adc, 5600, 5650);
+        profiler.finish(16000, &mut report);
+        assert_eq!((report.tasks[0].count, report.tasks[0].jitter), (1, 0));
+        assert_eq!(report.tasks[3].count, 0);
+        assert_eq!(report.warnings, 0);
+    }
+
+    #[test]
+    fn wraps_and_changes_period() {
+        let mut profiler = Profiler::new([0, 0, 100, 0]);
+        let mut report = Report::new();
+        profiler.record(Task::SendTelem, u32::MAX - 10, 20);
+        profiler.set_period(Task::SendTelem, 50);
+        profiler.record(Task::SendTelem, 40, 60);
+        profiler.finish(100, &mut report);
+        assert_eq!(report.tasks[2].max, 31);
+        assert_eq!(report.tasks[2].jitter, 0);
+    }
+}
@@ -0,0 +1,144 @@
+//! Remote run control
+//!
+//! The converter only starts while both the hardware nRUN input and the software
+//! run request are asserted. Releasing nRUN always stops it through HRTIM FLT2,
+//! whatever the software request, and withdrawing the software request stops it
+//! without waiting for nRUN.
+//!
+//! Faults latch until cleared remotely. Clearing a fault also withdraws the software
+//! run request, so the converter only restarts after a new run command.
+//!
+//! The run status packet answers status queries and acknowledges each run control
+//! command:
+//!
+//!     [ magic | fault state (u8) | fault code (u8) | run request (u8) | nRUN (u8) ]
+
+use crate::state::{State, FaultState, FaultCode, ToBytes};
+
+/// Run status packet.
+#[repr(C)]
+#[repr(align(4))]
+pub struct Status {
+    magic: u32,
+    pub fault_state: u8,
+    pub fault_code: u8,
+    /// Software run request.
+    pub request: u8,
+    /// Hardware nRUN input, 1 when asserted (pin low).
+    pub nrun: u8,
+}
+
+impl Status {
+    pub const fn new() -> Self {
+        Status { magic: 0x73746174, fault_state: 0, fault_code: 0, request: 0, nrun: 0 }
+    }
+}
+
+impl Default for Status {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+unsafe impl ToBytes for Status {}
+
+/// Software run request and fault clearing.
+pub struct RunControl {
+    request: bool,
+}
+
+impl RunControl {
+    /// Create a new run control, with the software run request initially `request`.
+    pub const fn new(request: bool) -> Self {
+        RunControl { request }
+    }
+
+    /// Returns true if the converter may start, given whether nRUN is asserted.
+    pub fn may_start(&self, nrun: bool) -> bool {
+        nrun && self.request
+    }
+
+    /// Returns true while the software run request is asserted.
+    pub fn requested(&self) -> bool {
+        self.request
+    }
+
+    /// Request the converter runs, once nRUN is also asserted.
+    pub fn run(&mut self) {
+        self.request = true;
+    }
+
+    /// Withdraw the run request, returning true if the converter was running
+    /// and must now be disabled.
+    pub fn stop(&mut self, state: &mut State) -> bool {
+        self.request = false;
+        if state.fault_state == FaultState::Running {
+            state.set_fault(FaultCode::RemoteStop);
+            state.set_state_stopped();
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Acknowledge and clear a latched fault, returning to the stopped state.
+    ///
+    /// Has no effect unless in the fault state.
+    pub fn clear_fault(&mut self, state: &mut State) {
+        if state.fault_state == FaultState::Fault {
+            self.request = false;
+            state.set_fault(FaultCode::NoFault);
+            state.set_state_stopped();
+        }
+    }
+
+    /// Fill in the run status packet.
+    pub fn status(&self, state: &State, nrun: bool, status: &mut Status) {
+        status.fault_state = state.fault_state as u8;
+        status.fault_code = state.fault_code as u8;
+        status.request = self.request as u8;
+        status.nrun = nrun as u8;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nrun_interlock() {
+        let mut run = RunControl::new(false);
+        assert!(!run.may_start(true));
+        run.run();
+        assert!(!run.may_start(false));
+        assert!(run.may_start(true));
+    }
+
+    #[test]
+    fn stop_and_clear() {
+        let mut run = RunControl::new(true);
+        let mut state = State::new();
+        assert!(!run.stop(&mut state));
+        run.run();
+        state.set_state_running();
+        assert!(run.stop(&mut state));
+        assert!(state.fault_state == FaultState::Stopped);
+        assert_eq!(state.fault_code, FaultCode::RemoteStop);
+
+        // Clearing does nothing unless faulted
+        run.run();
+        run.clear_fault(&mut state);
+        assert!(run.may_start(true));
+
+        state.set_fault(FaultCode::VLim);
+        state.set_state_fault();
+        run.clear_fault(&mut state);
+        assert!(state.fault_state == FaultState::Stopped);
+        assert_eq!(state.fault_code, FaultCode::NoFault);
+        assert!(!run.may_start(true));
+
+        let mut status = Status::new();
+        run.status(&state, true, &mut status);
+        assert_eq!(&status.to_bytes()[4..], &[0, 0, 0, 1]);
+    }
+}
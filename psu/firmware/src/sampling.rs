@@ -0,0 +1,247 @@
+//! ADC sampling profiles
+//!
+//! A sampling profile sets the ADC1 sample time, the order in which the four
+//! feedback channels are converted, and how many complete sequences are averaged
+//! in firmware before each sample is handed to the filters and control loop.
+//! Each sequence is followed by an injected conversion of the temperature sensor.
+//! The resulting sample period is used as the Kalman filter dt.
+
+use crate::timing;
+
+/// ADC clock frequency (Hz).
+pub const F_ADC: f32 = timing::F_ADC as f32;
+
+/// Conversion time at 12 bits, added to the sample time for each channel (ADC cycles).
+const T_CONV: f32 = 12.5;
+
+/// ADC channel sample time, matching the SMPx register field values.
+#[repr(u8)]
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum SampleTime {
+    Cycles1_5   = 0,
+    Cycles2_5   = 1,
+    Cycles4_5   = 2,
+    Cycles7_5   = 3,
+    Cycles19_5  = 4,
+    Cycles61_5  = 5,
+    Cycles181_5 = 6,
+    Cycles601_5 = 7,
+}
+
+impl SampleTime {
+    /// Sampling time in ADC clock cycles.
+    pub const fn cycles(self) -> f32 {
+        match self {
+            SampleTime::Cycles1_5   => 1.5,
+            SampleTime::Cycles2_5   => 2.5,
+            SampleTime::Cycles4_5   => 4.5,
+            SampleTime::Cycles7_5   => 7.5,
+            SampleTime::Cycles19_5  => 19.5,
+            SampleTime::Cycles61_5  => 61.5,
+            SampleTime::Cycles181_5 => 181.5,
+            SampleTime::Cycles601_5 => 601.5,
+        }
+    }
+}
+
+/// Sample time for the temperature sensor, converted after every ADC1 sequence.
+/// 181.5 cycles is the shortest that meets the sensor's 2.2µs minimum.
+pub const TEMPERATURE_SAMPLE_TIME: SampleTime = SampleTime::Cycles181_5;
+
+const _: () = assert!(TEMPERATURE_SAMPLE_TIME.cycles() / F_ADC >= 2.2e-6);
+
+/// Feedback signals sampled by ADC1, with values giving their ADC1 channel.
+#[repr(u8)]
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Signal {
+    VOut = 1,
+    IOut = 2,
+    IIn  = 3,
+    VIn  = 4,
+}
+
+impl Signal {
+    pub fn from_u8(x: u8) -> Option<Self> {
+        match x {
+            1 => Some(Signal::VOut),
+            2 => Some(Signal::IOut),
+            3 => Some(Signal::IIn),
+            4 => Some(Signal::VIn),
+            _ => None,
+        }
+    }
+
+    /// ADC1 input channel for this signal.
+    pub const fn channel(self) -> u32 {
+        self as u32
+    }
+
+    /// Position of this signal in the `[vout, iout, iin, vin]` order used by `State`.
+    pub const fn index(self) -> usize {
+        self as usize - 1
+    }
+}
+
+/// Sample time, sequence order and decimation for ADC1.
+///
+/// The ADC ISR runs at the end of every sequence, before decimation, so shorter
+/// sample times than `DEFAULT` leave it too little time to complete.
+#[derive(Copy, Clone)]
+pub struct SamplingProfile {
+    /// Sample time applied to all four channels.
+    pub sample_time: SampleTime,
+    /// Order of conversion within each sequence. Must contain each signal once.
+    pub sequence: [Signal; 4],
+    /// Number of sequences averaged into each output sample. Must be at least 1.
+    pub decimation: u16,
+}
+
+/// 181.5 cycle sampling with no averaging, giving a 72.2kS/s output.
+pub const DEFAULT: SamplingProfile = SamplingProfile {
+    sample_time: SampleTime::Cycles181_5,
+    sequence: [Signal::VOut, Signal::IOut, Signal::IIn, Signal::VIn],
+    decimation: 1,
+};
+
+const _: () = assert!(DEFAULT.is_valid());
+
+/// 601.5 cycle sampling averaged over four sequences, giving a 6.6kS/s output
+/// for the lowest noise and interrupt load.
+pub const LOW_NOISE: SamplingProfile = SamplingProfile {
+    sample_time: SampleTime::Cycles601_5,
+    sequence: [Signal::VOut, Signal::IOut, Signal::IIn, Signal::VIn],
+    decimation: 4,
+};
+
+const _: () = assert!(LOW_NOISE.is_valid());
+
+impl SamplingProfile {
+    /// Returns true if the sequence converts each signal exactly once and the
+    /// decimation is at least 1.
+    pub const fn is_valid(&self) -> bool {
+        let mut seen = [false; 4];
+        let mut i = 0;
+        while i < 4 {
+            let idx = self.sequence[i].index();
+            if seen[idx] {
+                return false;
+            }
+            seen[idx] = true;
+            i += 1;
+        }
+        self.decimation >= 1
+    }
+
+    /// Time to convert one complete sequence, including the temperature sensor (s).
+    pub const fn sequence_period(&self) -> f32 {
+        let feedback = 4.0 * (self.sample_time.cycles() + T_CONV);
+        let temperature = TEMPERATURE_SAMPLE_TIME.cycles() + T_CONV;
+        (feedback + temperature) / F_ADC
+    }
+
+    /// Time between output samples after decimation (s).
+    pub const fn dt(&self) -> f32 {
+        self.sequence_period() * self.decimation as f32
+    }
+
+    /// Reorder one raw sequence from conversion order to `[vout, iout, iin, vin]`.
+    pub fn reorder(&self, buf: &[u16; 4]) -> [u16; 4] {
+        let mut out = [0; 4];
+        for (signal, x) in self.sequence.iter().zip(buf.iter()) {
+            out[signal.index()] = *x;
+        }
+        out
+    }
+}
+
+/// Reorders and averages raw ADC sequences according to a sampling profile.
+pub struct Decimator {
+    profile: SamplingProfile,
+    sums: [u32; 4],
+    n: u16,
+}
+
+impl Decimator {
+    pub const fn new(profile: SamplingProfile) -> Self {
+        Decimator { profile, sums: [0; 4], n: 0 }
+    }
+
+    /// Add one raw sequence, in conversion order, returning the averaged
+    /// `[vout, iout, iin, vin]` readings once `decimation` sequences have been added.
+    pub fn push(&mut self, buf: &[u16; 4]) -> Option<[u16; 4]> {
+        for (s, x) in self.sums.iter_mut().zip(self.profile.reorder(buf).iter()) {
+            *s += *x as u32;
+        }
+        self.n += 1;
+        if self.n < self.profile.decimation {
+            return None;
+        }
+
+        let n = self.n as u32;
+        let mut out = [0; 4];
+        for (o, s) in out.iter_mut().zip(self.sums.iter()) {
+            *o = ((s + n / 2) / n) as u16;
+        }
+        self.sums = [0; 4];
+        self.n = 0;
+        Some(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_dt() {
+        assert!((DEFAULT.dt() - 5.0 * 194.0 / 70e6).abs() < 1e-10);
+        assert!((LOW_NOISE.dt() - 4.0 * (4.0 * 614.0 + 194.0) / 70e6).abs() < 1e-10);
+    }
+
+    #[test]
+    fn reorders_and_averages() {
+        let profile = SamplingProfile {
+            sample_time: SampleTime::Cycles61_5,
+            sequence: [Signal::VIn, Signal::VOut, Signal::IIn, Signal::IOut],
+            decimation: 2,
+        };
+        let mut d = Decimator::new(profile);
+        assert_eq!(d.push(&[40, 10, 30, 20]), None);
+        assert_eq!(d.push(&[42, 11, 30, 20]), Some([11, 20, 30, 41]));
+        assert_eq!(d.push(&[1, 2, 3, 4]), None);
+    }
+
+    #[test]
+    fn averages_each_channel_separately() {
+        let profile = SamplingProfile {
+            sample_time: SampleTime::Cycles61_5,
+            sequence: [Signal::IIn, Signal::VIn, Signal::VOut, Signal::IOut],
+            decimation: 8,
+        };
+        let mut d = Decimator::new(profile);
+
+        // Distinct ramps per signal, in [vout, iout, iin, vin] order
+        let ramp = |signal: usize, i: u16| [100, 2000, 3000, 40][signal] + i * [1, 3, 10, 7][signal];
+        for i in 0..7 {
+            let buf = [ramp(2, i), ramp(3, i), ramp(0, i), ramp(1, i)];
+            assert_eq!(d.push(&buf), None);
+        }
+        let out = d.push(&[ramp(2, 7), ramp(3, 7), ramp(0, 7), ramp(1, 7)]).unwrap();
+
+        for (signal, x) in out.iter().enumerate() {
+            let mean = (0..8).map(|i| ramp(signal, i) as f32).sum::<f32>() / 8.0;
+            assert!((*x as f32 - mean).abs() <= 0.5, "signal {}: {} != {}", signal, x, mean);
+        }
+    }
+
+    #[test]
+    fn validates_sequence() {
+        let mut profile = DEFAULT;
+        profile.sequence = [Signal::VOut, Signal::IOut, Signal::VOut, Signal::VIn];
+        assert!(!profile.is_valid());
+        profile.sequence = [Signal::VIn, Signal::IIn, Signal::IOut, Signal::VOut];
+        assert!(profile.is_valid());
+        profile.decimation = 0;
+        assert!(!profile.is_valid());
+    }
+}
@@ -0,0 +1,368 @@
+//! Triggered waveform capture ("scope mode")
+//!
+//! While armed, every raw ADC sequence is written for the selected channels into a
+//! ring buffer. Once the trigger condition is met and enough post-trigger frames
+//! have been recorded, the buffer is held until it has been transmitted as a
+//! capture packet.
+//!
+//! The packet is a 16 byte header followed by `samples` little-endian u16 words,
+//! oldest first, with the selected channels interleaved in `[vout, iout, iin, vin]`
+//! order. Rather than rotate the ring into time order, which is too slow for the
+//! ADC ISR, the packet is sent in up to three segments: the header, the ring from
+//! its oldest frame to the end, then the start of the ring.
+//!
+//!
+//!     [ magic | dt (f32) | samples (u16) | trigger (u16) | channels | kind | pad (u16) ]
+
+use crate::sampling::Signal;
+use crate::state::ToBytes;
+
+/// Capture packet header length.
+const HEADER_LEN: usize = 16;
+
+/// Condition that ends pre-trigger capture.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Trigger {
+    /// Trigger as soon as the pre-trigger frames are recorded.
+    Immediate,
+    /// Trigger while the signal is above the raw level.
+    Above(Signal, u16),
+    /// Trigger while the signal is below the raw level.
+    Below(Signal, u16),
+    /// Trigger when the signal crosses the raw level upwards.
+    Rising(Signal, u16),
+    /// Trigger when the signal crosses the raw level downwards.
+    Falling(Signal, u16),
+    /// Trigger when the PSU enters the fault state.
+    Fault,
+}
+
+impl Trigger {
+    /// Build a trigger from its wire encoding.
+    pub fn decode(kind: u8, signal: u8, level: u16) -> Option<Self> {
+        let signal = Signal::from_u8(signal);
+        match kind {
+            0 => Some(Trigger::Immediate),
+            1 => signal.map(|s| Trigger::Above(s, level)),
+            2 => signal.map(|s| Trigger::Below(s, level)),
+            3 => signal.map(|s| Trigger::Rising(s, level)),
+            4 => signal.map(|s| Trigger::Falling(s, level)),
+            5 => Some(Trigger::Fault),
+            _ => None,
+        }
+    }
+
+    fn kind(&self) -> u8 {
+        match self {
+            Trigger::Immediate => 0,
+            Trigger::Above(..) => 1,
+            Trigger::Below(..) => 2,
+            Trigger::Rising(..) => 3,
+            Trigger::Falling(..) => 4,
+            Trigger::Fault => 5,
+        }
+    }
+}
+
+/// Capture settings.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Config {
+    /// Channels to record, bit n set for position n in `[vout, iout, iin, vin]`.
+    pub channels: u8,
+    pub trigger: Trigger,
+    /// Number of frames to keep from before the trigger.
+    pub pre_trigger: u16,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Stage {
+    Idle,
+    Armed,
+    Triggered,
+    Done,
+    Sending,
+}
+
+/// Capture packet, holding up to `N` samples.
+#[repr(C)]
+#[repr(align(4))]
+pub struct Capture<const N: usize> {
+    magic: u32,
+    /// Time between frames (s).
+    pub dt: f32,
+    /// Number of valid words in `data`.
+    pub samples: u16,
+    /// Index of the trigger frame.
+    pub trigger: u16,
+    pub channels: u8,
+    pub kind: u8,
+    _padding: u16,
+    pub data: [u16; N],
+}
+
+unsafe impl<const N: usize> ToBytes for Capture<N> {}
+
+/// Waveform capture into a ring buffer of `N` samples.
+pub struct Scope<const N: usize> {
+    config: Config,
+    stage: Stage,
+    nch: usize,
+    frames: usize,
+    pos: usize,
+    filled: usize,
+    remaining: usize,
+    /// Index of the first word of the oldest frame once the capture is done.
+    start: usize,
+    /// Next segment of the packet to send, and whether a segment is being sent.
+    segment: u8,
+    in_flight: bool,
+    prev: Option<u16>,
+    prev_fault: bool,
+    capture: Capture<N>,
+}
+
+impl<const N: usize> Scope<N> {
+    /// Create a new idle scope, where `dt` is the time between ADC sequences.
+    pub const fn new(dt: f32) -> Self {
+        Scope {
+            config: Config { channels: 0, trigger: Trigger::Immediate, pre_trigger: 0 },
+            stage: Stage::Idle,
+            nch: 0, frames: 0, pos: 0, filled: 0, remaining: 0,
+            start: 0, segment: 0, in_flight: false,
+            prev: None, prev_fault: false,
+            capture: Capture {
+                magic: 0x73636f70, dt, samples: 0, trigger: 0, channels: 0, kind: 0,
+                _padding: 0, data: [0; N],
+            },
+        }
+    }
+
+    /// Start a new capture, abandoning any capture in progress.
+    ///
+    /// Returns false without arming if no channels are selected, or if a previous
+    /// capture is still being transmitted.
+    pub fn arm(&mut self, config: Config) -> bool {
+        let nch = (config.channels & 0x0F).count_ones() as usize;
+        if nch == 0 || self.stage == Stage::Sending {
+            return false;
+        }
+        self.nch = nch;
+        self.frames = N / nch;
+        self.config = Config {
+            channels: config.channels & 0x0F,
+            pre_trigger: config.pre_trigger.min(self.frames as u16 - 1),
+            ..config
+        };
+        self.pos = 0;
+        self.filled = 0;
+        self.prev = None;
+        self.stage = Stage::Armed;
+        true
+    }
+
+    /// Record one ADC sequence in `[vout, iout, iin, vin]` order, where `fault`
+    /// is true while the PSU is in the fault state.
+    pub fn push(&mut self, x: &[u16; 4], fault: bool) {
+        let fault_edge = fault && !self.prev_fault;
+        self.prev_fault = fault;
+        if self.stage != Stage::Armed && self.stage != Stage::Triggered {
+            return;
+        }
+
+        let channels = self.config.channels;
+        let frame = &mut self.capture.data[self.pos * self.nch..(self.pos + 1) * self.nch];
+        let selected = x.iter().enumerate().filter(|(i, _)| channels & (1 << i) != 0);
+        for (d, (_, v)) in frame.iter_mut().zip(selected) {
+            *d = *v;
+        }
+        self.pos = (self.pos + 1) % self.frames;
+        self.filled = self.filled.saturating_add(1);
+
+        if self.stage == Stage::Armed {
+            let triggered = self.triggered(x, fault_edge);
+            if triggered && self.filled > self.config.pre_trigger as usize {
+                self.stage = Stage::Triggered;
+                self.remaining = self.frames - self.config.pre_trigger as usize - 1;
+            }
+        } else {
+            self.remaining -= 1;
+        }
+
+        if self.stage == Stage::Triggered && self.remaining == 0 {
+            self.finish();
+        }
+    }
+
+    /// Returns the next segment of the capture packet if a capture has completed
+    /// and the previous segment has been sent, marking it as being transmitted.
+    pub fn packet(&mut self) -> Option<&[u8]> {
+        match self.stage {
+            Stage::Done => {
+                self.stage = Stage::Sending;
+                self.segment = 0;
+            },
+            Stage::Sending if !self.in_flight => (),
+            _ => return None,
+        }
+        self.in_flight = true;
+        let (start, len) = (self.start, self.capture.samples as usize);
+        let (a, b) = match self.segment {
+            0 => (0, HEADER_LEN),
+            1 => (HEADER_LEN + 2 * start, HEADER_LEN + 2 * len),
+            _ => (HEADER_LEN, HEADER_LEN + 2 * start),
+        };
+        self.segment += 1;
+        Some(&self.capture.to_bytes()[a..b])
+    }
+
+    /// Call when a serial transfer completes, releasing the buffer once the last
+    /// segment has been sent.
+    pub fn transmitted(&mut self) {
+        if self.stage == Stage::Sending && self.in_flight {
+            self.in_flight = false;
+            let segments = if self.start == 0 { 2 } else { 3 };
+            if self.segment == segments {
+                self.stage = Stage::Idle;
+            }
+        }
+    }
+
+    /// Returns true while the capture packet is being transmitted.
+    pub fn sending(&self) -> bool {
+        self.stage == Stage::Sending
+    }
+
+    /// Returns true between segments of the capture packet, when the next segment
+    /// must be sent before any other packet.
+    pub fn continuing(&self) -> bool {
+        self.stage == Stage::Sending && !self.in_flight
+    }
+
+    /// Returns true while waiting for the trigger or recording post-trigger frames.
+    pub fn armed(&self) -> bool {
+        self.stage == Stage::Armed || self.stage == Stage::Triggered
+    }
+
+    fn triggered(&mut self, x: &[u16; 4], fault_edge: bool) -> bool {
+        let (signal, prev) = match self.config.trigger {
+            Trigger::Above(s, _) | Trigger::Below(s, _) |
+            Trigger::Rising(s, _) | Trigger::Falling(s, _) => {
+                let prev = self.prev;
+                self.prev = Some(x[s.index()]);
+                (x[s.index()], prev)
+            },
+            _ => (0, None),
+        };
+        match self.config.trigger {
+            Trigger::Immediate => true,
+            Trigger::Above(_, level) => signal > level,
+            Trigger::Below(_, level) => signal < level,
+            Trigger::Rising(_, level) => matches!(prev, Some(p) if p < level && signal >= level),
+            Trigger::Falling(_, level) => matches!(prev, Some(p) if p > level && signal <= level),
+            Trigger::Fault => fault_edge,
+        }
+    }
+
+    fn finish(&mut self) {
+        // The trigger needs the pre-trigger frames and is followed by the rest of the
+        // buffer, so the ring is always full here, with the oldest frame at `pos`.
+        let len = self.frames * self.nch;
+        self.start = self.pos * self.nch;
+        self.capture.samples = len as u16;
+        self.capture.trigger = self.config.pre_trigger;
+        self.capture.channels = self.config.channels;
+        self.capture.kind = self.config.trigger.kind();
+        self.stage = Stage::Done;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Send every segment of the capture packet, returning the packet.
+    fn send<const N: usize>(scope: &mut Scope<N>) -> std::vec::Vec<u8> {
+        let mut packet = std::vec::Vec::new();
+        while let Some(segment) = scope.packet() {
+            packet.extend_from_slice(segment);
+            scope.transmitted();
+        }
+        packet
+    }
+
+    fn frames(packet: &[u8]) -> std::vec::Vec<u16> {
+        packet[HEADER_LEN..].chunks(2).map(|b| u16::from_le_bytes([b[0], b[1]])).collect()
+    }
+
+    #[test]
+    fn rising_edge_with_pre_trigger() {
+        let mut scope = Scope::<16>::new(1e-5);
+        let trigger = Trigger::Rising(Signal::IOut, 100);
+        // Two channels gives 8 frames, 3 before the trigger frame
+        assert!(scope.arm(Config { channels: 0b0011, trigger, pre_trigger: 3 }));
+        for k in 0..20u16 {
+            scope.push(&[k, k * 10, 0, 0], false);
+        }
+        assert!(!scope.armed());
+
+        let packet = send(&mut scope);
+        assert_eq!(packet.len(), HEADER_LEN + 32);
+        assert_eq!(u16::from_le_bytes([packet[10], packet[11]]), 3);
+        let data = frames(&packet);
+        // Trigger at k=10 (iout=100), frames 7..=14 recorded
+        assert_eq!(&data[..4], &[7, 70, 8, 80]);
+        assert_eq!(&data[6..8], &[10, 100]);
+        assert_eq!(&data[14..], &[14, 140]);
+
+        assert!(scope.packet().is_none());
+        assert!(scope.arm(Config { channels: 1, trigger, pre_trigger: 0 }));
+    }
+
+    #[test]
+    fn held_until_every_segment_sent() {
+        let mut scope = Scope::<4>::new(1e-5);
+        let config = Config { channels: 1, trigger: Trigger::Rising(Signal::VOut, 3), pre_trigger: 1 };
+        assert!(scope.arm(config));
+        for k in 0..6 {
+            scope.push(&[k, 0, 0, 0], false);
+        }
+        // Header, then frames 2..=3 from the end of the ring, then 4..=5 from its start
+        assert_eq!(scope.packet().unwrap().len(), HEADER_LEN);
+        assert!(scope.packet().is_none());
+        assert!(!scope.continuing());
+        scope.transmitted();
+        assert!(scope.continuing());
+        assert_eq!(scope.packet().unwrap(), &[2, 0, 3, 0]);
+        scope.transmitted();
+        assert!(!scope.arm(config));
+        assert_eq!(scope.packet().unwrap(), &[4, 0, 5, 0]);
+        assert!(!scope.arm(config));
+        scope.transmitted();
+        assert!(!scope.sending());
+        assert!(scope.arm(config));
+    }
+
+    #[test]
+    fn fault_trigger_waits_for_transition() {
+        let mut scope = Scope::<8>::new(1e-5);
+        scope.push(&[0; 4], true);
+        assert!(scope.arm(Config { channels: 0b1000, trigger: Trigger::Fault, pre_trigger: 2 }));
+        for k in 0..4 {
+            scope.push(&[0, 0, 0, k], true);
+        }
+        assert!(scope.armed());
+        scope.push(&[0, 0, 0, 10], false);
+        for k in 11..20 {
+            scope.push(&[0, 0, 0, k], true);
+        }
+        let data = frames(&send(&mut scope));
+        assert_eq!(data, [3, 10, 11, 12, 13, 14, 15, 16]);
+    }
+
+    #[test]
+    fn decode_trigger() {
+        assert_eq!(Trigger::decode(3, 2, 500), Some(Trigger::Rising(Signal::IOut, 500)));
+        assert_eq!(Trigger::decode(1, 9, 500), None);
+        assert_eq!(Trigger::decode(5, 0, 0), Some(Trigger::Fault));
+    }
+}
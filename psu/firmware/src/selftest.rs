@@ -0,0 +1,105 @@
+//! Power-on self-test checks
+//!
+//! The hardware sequencing lives in the firmware binary; these functions decide
+//! what to drive and whether the responses are acceptable.
+
+use crate::state::{State, FaultCode};
+
+/// Self-test acceptance limits.
+pub struct Limits {
+    /// Maximum output voltage with the converter stopped (V).
+    pub v_out_max: f32,
+    /// Permitted input voltage range (V).
+    pub v_in_min: f32,
+    pub v_in_max: f32,
+    /// Maximum difference between a DAC setting and its ADC readback (counts).
+    pub dac_tolerance: u16,
+    /// Offset of comparator thresholds either side of the measured input (counts).
+    pub comp_margin: u16,
+}
+
+/// Check ADC readings with the converter stopped.
+pub fn check_adc(state: &State, limits: &Limits) -> Result<(), FaultCode> {
+    // Written so that NaN readings fail
+    let v_out_ok = state.v_out < limits.v_out_max;
+    let v_in_ok = state.v_in > limits.v_in_min && state.v_in < limits.v_in_max;
+    if !v_out_ok {
+        Err(FaultCode::TestVOut)
+    } else if !v_in_ok {
+        Err(FaultCode::TestVIn)
+    } else {
+        Ok(())
+    }
+}
+
+/// Check a DAC output read back by the ADC matches its setting.
+pub fn check_dac(set: u16, readback: u16, limits: &Limits) -> bool {
+    (set as i32 - readback as i32).abs() <= limits.dac_tolerance as i32
+}
+
+/// Check a comparator output flips as its threshold is moved either side of its input.
+///
+/// `input` is the measured comparator input in DAC counts. `compare(threshold, invert)`
+/// must set the threshold and output polarity, wait for it to settle, and return the
+/// comparator output, which should be high when the input is above the threshold
+/// (or below it, when inverted).
+///
+/// Where the input is too close to a supply rail to set a threshold on that side,
+/// the opposite output state is instead checked by inverting the output polarity.
+pub fn check_comparator<F>(input: u16, limits: &Limits, mut compare: F) -> bool
+    where F: FnMut(u16, bool) -> bool
+{
+    let margin = limits.comp_margin;
+    let below = input.checked_sub(margin);
+    let above = input.checked_add(margin).filter(|t| *t <= 4095);
+
+    match (below, above) {
+        (Some(lo), Some(hi)) => compare(lo, false) && !compare(hi, false),
+        (Some(lo), None) => compare(lo, false) && !compare(lo, true),
+        (None, Some(hi)) => !compare(hi, false) && compare(hi, true),
+        (None, None) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMITS: Limits = Limits {
+        v_out_max: 20.0, v_in_min: 18.0, v_in_max: 30.0, dac_tolerance: 50, comp_margin: 200,
+    };
+
+    /// Ideal comparator with an input at `input` counts, recording the thresholds used.
+    fn comparator(input: u16, stuck: Option<bool>) -> (bool, std::vec::Vec<(u16, bool)>) {
+        let mut calls = std::vec::Vec::new();
+        let ok = check_comparator(input, &LIMITS, |threshold, invert| {
+            calls.push((threshold, invert));
+            stuck.unwrap_or((input > threshold) != invert)
+        });
+        (ok, calls)
+    }
+
+    #[test]
+    fn comparator_flips() {
+        assert_eq!(comparator(2000, None), (true, std::vec![(1800, false), (2200, false)]));
+        // Input near ground can only be tested with the threshold above it
+        assert_eq!(comparator(10, None), (true, std::vec![(210, false), (210, true)]));
+        assert_eq!(comparator(4000, None), (true, std::vec![(3800, false), (3800, true)]));
+        assert!(!comparator(2000, Some(true)).0);
+        assert!(!comparator(10, Some(false)).0);
+    }
+
+    #[test]
+    fn adc_and_dac() {
+        let mut state = State::new();
+        state.v_in = 24.0;
+        assert_eq!(check_adc(&state, &LIMITS), Ok(()));
+        state.v_out = 50.0;
+        assert_eq!(check_adc(&state, &LIMITS), Err(FaultCode::TestVOut));
+        state.v_out = 0.0;
+        state.v_in = f32::NAN;
+        assert_eq!(check_adc(&state, &LIMITS), Err(FaultCode::TestVIn));
+        assert!(check_dac(1000, 1040, &LIMITS));
+        assert!(!check_dac(1000, 900, &LIMITS));
+    }
+}
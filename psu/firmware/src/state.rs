@@ -1,4 +1,8 @@
+use crate::profile::ProfileId;
+use crate::calibration::VDDA_NOMINAL;
+
 #[repr(u8)]
+#[derive(Copy, Clone, PartialEq, Debug)]
 pub enum FaultCode {
     NoFault = 0,
     NoRun   = 1,
@@ -9,16 +13,37 @@ pub enum FaultCode {
     VInLow  = 6,
     VInHigh = 7,
     IInHigh = 8,
+    OverTemp = 9,
+    TestDAC1 = 10,
+    TestDAC2 = 11,
+    TestComp2 = 12,
+    TestComp4 = 13,
+    TestVOut = 14,
+    TestVIn = 15,
+    TestDLL = 16,
+    RemoteStop = 17,
 }
 
 #[repr(u8)]
-#[derive(PartialEq)]
+#[derive(Copy, Clone, PartialEq)]
 pub enum FaultState {
     Stopped = 0,
     Running = 1,
     Fault   = 2,
 }
 
+/// Whether the output is regulated on its voltage or held at a current limit.
+#[repr(u8)]
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum CcMode {
+    /// Regulating the output voltage, or constant-current mode disabled.
+    Voltage  = 0,
+    /// Regulating the output current at the constant-current limit.
+    Limiting = 1,
+    /// Regulating the output current at the reduced foldback limit.
+    Foldback = 2,
+}
+
 #[repr(C)]
 #[repr(align(4))]
 pub struct State {
@@ -28,11 +53,19 @@ pub struct State {
     pub v_out: f32,
     pub i_out: f32,
     pub pid_i: f32,
+    pub temp: f32,
+    pub vdda: f32,
+    /// Fraction of the maximum current reference permitted by input current derating.
+    pub derate: f32,
     pub ref_i_q: u16,
     pub duty: u16,
-    _padding: u16,
+    pub profile: ProfileId,
+    /// Active operating preset slot.
+    pub preset: u8,
     pub fault_code: FaultCode,
     pub fault_state: FaultState,
+    pub cc_mode: CcMode,
+    _padding: [u8; 3],
 }
 
 impl State {
@@ -40,24 +73,39 @@ impl State {
         State {
             magic: 0x74656c65,
             v_in: 0.0, i_in: 0.0, v_out: 0.0, i_out: 0.0,
-            pid_i: 0.0, ref_i_q: 0, duty: 0, _padding: 0,
+            pid_i: 0.0, temp: 0.0, vdda: VDDA_NOMINAL, derate: 1.0,
+            ref_i_q: 0, duty: 0, profile: ProfileId::Strike, preset: 0,
             fault_code: FaultCode::NoFault, fault_state: FaultState::Stopped,
+            cc_mode: CcMode::Voltage, _padding: [0; 3],
         }
     }
 
     pub fn update_adc(&mut self, buf: [u16; 4]) {
         let [vout, iout, iin, vin] = buf;
+        let lsb = self.vdda / 4096.0;
+
+        self.v_in = (vin as f32) * (lsb * 11.0);
+        self.i_in = (iin as f32) * lsb;
+        self.v_out = (vout as f32) * (lsb * 200.6) * 1.0244266;
+        self.i_out = (iout as f32) * (lsb * 0.04);
+    }
 
-        self.v_in = (vin as f32) * (3.3 * 11.0 / 4096.0);
-        self.i_in = (iin as f32) * (3.3 / 4096.0);
-        self.v_out = (vout as f32) * (3.3 * 200.6 / 4096.0) * 1.0244266;
-        self.i_out = (iout as f32) * (3.3 * 0.04 / 4096.0);
+    pub fn update_vdda(&mut self, vdda: f32) {
+        self.vdda = vdda;
+    }
+
+    pub fn update_temp(&mut self, temp: f32) {
+        self.temp = temp;
     }
 
     pub fn update_pid_i(&mut self, pid_i: f32) {
         self.pid_i = pid_i;
     }
 
+    pub fn update_derate(&mut self, derate: f32) {
+        self.derate = derate;
+    }
+
     pub fn update_ref_i_q(&mut self, ref_i_q: u16) {
         self.ref_i_q = ref_i_q;
     }
@@ -66,6 +114,18 @@ impl State {
         self.duty = duty;
     }
 
+    pub fn update_profile(&mut self, profile: ProfileId) {
+        self.profile = profile;
+    }
+
+    pub fn update_cc_mode(&mut self, cc_mode: CcMode) {
+        self.cc_mode = cc_mode;
+    }
+
+    pub fn update_preset(&mut self, preset: u8) {
+        self.preset = preset;
+    }
+
     pub fn set_fault(&mut self, fault: FaultCode) {
         self.fault_code = fault;
     }
@@ -83,6 +143,12 @@ impl State {
     }
 }
 
+impl Default for State {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 /// Trait for structs which can be safely cast to &[u8].
 pub unsafe trait ToBytes: Sized {
     fn to_bytes(&self) -> &[u8] {
@@ -96,3 +162,26 @@ pub unsafe trait ToBytes: Sized {
 }
 
 unsafe impl ToBytes for State {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scales_by_measured_vdda() {
+        let mut state = State::new();
+        let buf = [1812, 1024, 3000, 2048];
+        state.update_adc(buf);
+        assert!((state.v_in - 18.15).abs() < 1e-4);
+        assert!((state.v_out - 300.0).abs() < 0.1);
+        let nominal = (state.v_in, state.i_in, state.v_out, state.i_out);
+
+        // The same readings with VDDA measured 10% low are 10% lower
+        state.update_vdda(0.9 * VDDA_NOMINAL);
+        state.update_adc(buf);
+        assert!((state.v_in - 0.9 * nominal.0).abs() < 1e-4);
+        assert!((state.i_in - 0.9 * nominal.1).abs() < 1e-6);
+        assert!((state.v_out - 0.9 * nominal.2).abs() < 1e-3);
+        assert!((state.i_out - 0.9 * nominal.3).abs() < 1e-7);
+    }
+}
@@ -0,0 +1,196 @@
+//! Windowed output regulation and ripple statistics
+//!
+//! Samples are accumulated in the ADC ISR and summarised whenever telemetry is sent,
+//! at which point the window is restarted.
+
+use crate::state::ToBytes;
+
+/// Running totals for one signal over the current window.
+///
+/// The mean is accumulated as deviations from the window's first sample, since
+/// summing raw ~375V samples at the ADC rate would soon exhaust f32 precision.
+#[derive(Copy, Clone)]
+struct Accumulator {
+    n: u32,
+    min: f32,
+    max: f32,
+    offset: f32,
+    sum: f32,
+    sum_sq_dev: f32,
+}
+
+impl Accumulator {
+    const fn new() -> Self {
+        Accumulator {
+            n: 0, min: f32::INFINITY, max: f32::NEG_INFINITY,
+            offset: 0.0, sum: 0.0, sum_sq_dev: 0.0,
+        }
+    }
+
+    fn push(&mut self, x: f32, reference: f32) {
+        let dev = x - reference;
+        if self.n == 0 {
+            self.offset = x;
+        }
+        self.n += 1;
+        self.sum += x - self.offset;
+        self.sum_sq_dev += dev * dev;
+        if x < self.min {
+            self.min = x;
+        }
+        if x > self.max {
+            self.max = x;
+        }
+    }
+
+    fn summary(&self) -> Summary {
+        if self.n == 0 {
+            return Summary::new();
+        }
+        let n = self.n as f32;
+        Summary {
+            min: self.min,
+            max: self.max,
+            mean: self.offset + self.sum / n,
+            rms_dev: libm::sqrtf(self.sum_sq_dev / n),
+            p2p: self.max - self.min,
+        }
+    }
+}
+
+/// Statistics for one signal over one window.
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct Summary {
+    pub min: f32,
+    pub max: f32,
+    pub mean: f32,
+    /// RMS deviation from the reference: the setpoint for voltages, zero for currents.
+    pub rms_dev: f32,
+    /// Peak-to-peak ripple, max - min.
+    pub p2p: f32,
+}
+
+impl Summary {
+    pub const fn new() -> Self {
+        Summary { min: 0.0, max: 0.0, mean: 0.0, rms_dev: 0.0, p2p: 0.0 }
+    }
+}
+
+impl Default for Summary {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Regulation metrics telemetry packet.
+#[repr(C)]
+#[repr(align(4))]
+pub struct Metrics {
+    magic: u32,
+    /// Number of ADC samples in the window.
+    pub samples: u32,
+    pub v_out_raw: Summary,
+    pub v_out: Summary,
+    pub i_out_raw: Summary,
+    pub i_out: Summary,
+}
+
+impl Metrics {
+    pub const fn new() -> Self {
+        Metrics {
+            magic: 0x6d657472,
+            samples: 0,
+            v_out_raw: Summary::new(), v_out: Summary::new(),
+            i_out_raw: Summary::new(), i_out: Summary::new(),
+        }
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+unsafe impl ToBytes for Metrics {}
+
+/// Accumulates raw and filtered Vout and Iout over each window.
+pub struct RegulationStats {
+    v_out_raw: Accumulator,
+    v_out: Accumulator,
+    i_out_raw: Accumulator,
+    i_out: Accumulator,
+}
+
+impl RegulationStats {
+    pub const fn new() -> Self {
+        RegulationStats {
+            v_out_raw: Accumulator::new(), v_out: Accumulator::new(),
+            i_out_raw: Accumulator::new(), i_out: Accumulator::new(),
+        }
+    }
+
+    /// Add one set of samples, with `v_set` the output setpoint at the time.
+    pub fn push(&mut self, v_set: f32, v_out_raw: f32, v_out: f32, i_out_raw: f32, i_out: f32) {
+        self.v_out_raw.push(v_out_raw, v_set);
+        self.v_out.push(v_out, v_set);
+        self.i_out_raw.push(i_out_raw, 0.0);
+        self.i_out.push(i_out, 0.0);
+    }
+
+    /// Summarise the current window into `metrics` and start a new window.
+    pub fn finish(&mut self, metrics: &mut Metrics) {
+        metrics.samples = self.v_out.n;
+        metrics.v_out_raw = self.v_out_raw.summary();
+        metrics.v_out = self.v_out.summary();
+        metrics.i_out_raw = self.i_out_raw.summary();
+        metrics.i_out = self.i_out.summary();
+        *self = Self::new();
+    }
+}
+
+impl Default for RegulationStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn window_statistics() {
+        let mut stats = RegulationStats::new();
+        let mut metrics = Metrics::new();
+        for k in 0..1000 {
+            // Square wave ripple of 2V peak-to-peak around 374V
+            let v = if k % 2 == 0 { 373.0 } else { 375.0 };
+            stats.push(375.0, v, 374.0, 0.010, 0.010);
+        }
+        stats.finish(&mut metrics);
+
+        assert_eq!(metrics.samples, 1000);
+        let v = metrics.v_out_raw;
+        assert_eq!((v.min, v.max, v.p2p), (373.0, 375.0, 2.0));
+        assert!((v.mean - 374.0).abs() < 1e-3);
+        // Half the samples are 2V below setpoint
+        assert!((v.rms_dev - 2f32.sqrt()).abs() < 1e-4);
+        assert!((metrics.v_out.rms_dev - 1.0).abs() < 1e-4);
+        assert!((metrics.i_out.rms_dev - 0.010).abs() < 1e-6);
+
+        // A 1s window at the ADC rate keeps a precise mean
+        for k in 0..90_000 {
+            let v = if k % 2 == 0 { 374.9 } else { 375.1 };
+            stats.push(375.0, v, v, 0.010, 0.010);
+        }
+        stats.finish(&mut metrics);
+        assert!((metrics.v_out_raw.mean - 375.0).abs() < 1e-3);
+
+        // The window restarts after each summary
+        stats.finish(&mut metrics);
+        assert_eq!(metrics.samples, 0);
+        assert_eq!(metrics.v_out_raw.p2p, 0.0);
+    }
+}
@@ -0,0 +1,255 @@
+//! Telemetry rate and field selection
+//!
+//! With every field selected the full `State` packet is sent. Otherwise a fields
+//! packet carries only the selected fields, packed in field order with no padding:
+//!
+//!     [ magic | mask (u16) | len (u16) | fields (len bytes) ]
+//!
+//! The description packet lists every field's type and name so the host can
+//! decode fields packets for any mask:
+//!
+//!     [ magic | count (u8) | pad (3 bytes) | count x [ type (u8) | name (7 bytes) ] ]
+
+use crate::state::{State, ToBytes};
+
+/// Slowest and fastest periodic rates (Hz).
+///
+/// At 1kHz a full state packet, followed by the metrics and energy packets,
+/// uses about half of the 3.5MBd link.
+pub const RATE_MIN: u16 = 1;
+pub const RATE_MAX: u16 = 1000;
+
+/// Field value types, as sent in the description packet.
+#[repr(u8)]
+#[derive(Copy, Clone)]
+pub enum Kind {
+    F32 = 0,
+    U16 = 1,
+    U8  = 2,
+}
+
+impl Kind {
+    const fn size(self) -> usize {
+        match self {
+            Kind::F32 => 4,
+            Kind::U16 => 2,
+            Kind::U8  => 1,
+        }
+    }
+}
+
+/// Names and types of each `State` field, with bit n of a mask selecting field n.
+pub const FIELDS: [(&str, Kind); 15] = [
+    ("v_in", Kind::F32), ("i_in", Kind::F32), ("v_out", Kind::F32), ("i_out", Kind::F32),
+    ("pid_i", Kind::F32), ("temp", Kind::F32), ("vdda", Kind::F32),
+    ("ref_i_q", Kind::U16), ("duty", Kind::U16),
+    ("profile", Kind::U8), ("fault", Kind::U8), ("state", Kind::U8), ("preset", Kind::U8),
+    ("derate", Kind::F32), ("cc_mode", Kind::U8),
+];
+
+/// Mask selecting every field.
+pub const ALL: u16 = (1 << FIELDS.len()) - 1;
+
+/// Total size of all fields.
+const MAX_LEN: usize = 8 * 4 + 2 * 2 + 5;
+
+/// Telemetry field description packet.
+#[repr(C)]
+#[repr(align(4))]
+pub struct Description {
+    magic: u32,
+    count: u8,
+    _padding: [u8; 3],
+    fields: [[u8; 8]; FIELDS.len()],
+}
+
+impl Description {
+    pub const fn new() -> Self {
+        let mut fields = [[0; 8]; FIELDS.len()];
+        let mut i = 0;
+        while i < FIELDS.len() {
+            let (name, kind) = FIELDS[i];
+            fields[i][0] = kind as u8;
+            let name = name.as_bytes();
+            let mut j = 0;
+            while j < name.len() {
+                fields[i][j + 1] = name[j];
+                j += 1;
+            }
+            i += 1;
+        }
+        Description { magic: 0x64657363, count: FIELDS.len() as u8, _padding: [0; 3], fields }
+    }
+}
+
+impl Default for Description {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+unsafe impl ToBytes for Description {}
+
+/// Telemetry packet holding a subset of fields.
+#[repr(C)]
+#[repr(align(4))]
+pub struct Fields {
+    magic: u32,
+    mask: u16,
+    len: u16,
+    data: [u8; MAX_LEN],
+}
+
+impl Fields {
+    pub const fn new() -> Self {
+        Fields { magic: 0x666c6473, mask: 0, len: 0, data: [0; MAX_LEN] }
+    }
+
+    /// Pack the fields selected by `mask` from `state`, returning the packet bytes.
+    pub fn pack(&mut self, state: &State, mask: u16) -> &[u8] {
+        let mut len = 0;
+        for (idx, (_, kind)) in FIELDS.iter().enumerate() {
+            if mask & (1 << idx) == 0 {
+                continue;
+            }
+            let out = &mut self.data[len..len + kind.size()];
+            match idx {
+                0 => out.copy_from_slice(&state.v_in.to_le_bytes()),
+                1 => out.copy_from_slice(&state.i_in.to_le_bytes()),
+                2 => out.copy_from_slice(&state.v_out.to_le_bytes()),
+                3 => out.copy_from_slice(&state.i_out.to_le_bytes()),
+                4 => out.copy_from_slice(&state.pid_i.to_le_bytes()),
+                5 => out.copy_from_slice(&state.temp.to_le_bytes()),
+                6 => out.copy_from_slice(&state.vdda.to_le_bytes()),
+                7 => out.copy_from_slice(&state.ref_i_q.to_le_bytes()),
+                8 => out.copy_from_slice(&state.duty.to_le_bytes()),
+                9 => out[0] = state.profile as u8,
+                10 => out[0] = state.fault_code as u8,
+                11 => out[0] = state.fault_state as u8,
+                12 => out[0] = state.preset,
+                13 => out.copy_from_slice(&state.derate.to_le_bytes()),
+                _ => out[0] = state.cc_mode as u8,
+            }
+            len += kind.size();
+        }
+        self.mask = mask & ALL;
+        self.len = len as u16;
+        &self.to_bytes()[..8 + len]
+    }
+}
+
+impl Default for Fields {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+unsafe impl ToBytes for Fields {}
+
+/// Current telemetry rate and field selection.
+///
+/// Each change of rate starts a new chain of periodic sends, identified by a
+/// chain number, so that sends already scheduled for the previous rate can stop.
+pub struct Config {
+    rate: u16,
+    mask: u16,
+    chain: u32,
+}
+
+impl Config {
+    /// Create a new configuration sending `mask` at `rate` Hz, or only when polled if zero.
+    pub const fn new(rate: u16, mask: u16) -> Self {
+        Config { rate, mask, chain: 0 }
+    }
+
+    /// Change rate and field mask, calling `start` with the new chain number to
+    /// queue its first send.
+    ///
+    /// Returns false without changing anything if the rate is out of range, no fields
+    /// are selected, or `start` returns false because the send could not be queued,
+    /// in which case the previous chain continues.
+    pub fn set(&mut self, rate: u16, mask: u16, start: impl FnOnce(u32) -> bool) -> bool {
+        if (rate != 0 && !(RATE_MIN..=RATE_MAX).contains(&rate)) || mask & ALL == 0 {
+            return false;
+        }
+        let chain = self.chain.wrapping_add(1);
+        if !start(chain) {
+            return false;
+        }
+        self.rate = rate;
+        self.mask = mask & ALL;
+        self.chain = chain;
+        true
+    }
+
+    pub fn mask(&self) -> u16 {
+        self.mask
+    }
+
+    /// Current chain number.
+    pub fn chain(&self) -> u32 {
+        self.chain
+    }
+
+    /// Time between periodic sends in cycles of a `clock` Hz clock, or None in poll mode.
+    pub fn period(&self, clock: u32) -> Option<u32> {
+        if self.rate == 0 { None } else { Some(clock / self.rate as u32) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::FaultState;
+
+    #[test]
+    fn packs_selected_fields() {
+        let mut state = State::new();
+        state.v_out = 225.0;
+        state.duty = 0x1234;
+        state.set_state_running();
+        let mut fields = Fields::new();
+        let packet = fields.pack(&state, (1 << 2) | (1 << 8) | (1 << 11));
+        assert_eq!(&packet[4..8], &[0x04, 0x09, 7, 0]);
+        assert_eq!(&packet[8..12], &225f32.to_le_bytes());
+        assert_eq!(&packet[12..], &[0x34, 0x12, FaultState::Running as u8]);
+
+        let packet = fields.pack(&state, ALL);
+        assert_eq!(packet.len(), 8 + MAX_LEN);
+    }
+
+    #[test]
+    fn description() {
+        let desc = Description::new();
+        let bytes = desc.to_bytes();
+        assert_eq!(bytes.len(), 8 + 8 * FIELDS.len());
+        assert_eq!(bytes[4], 15);
+        assert_eq!(&bytes[8 + 7 * 8..8 + 8 * 8], b"\x01ref_i_q");
+        assert_eq!(&bytes[8 + 11 * 8..8 + 12 * 8], b"\x02state\0\0");
+        assert_eq!(&bytes[8 + 12 * 8..8 + 13 * 8], b"\x02preset\0");
+        assert_eq!(&bytes[8 + 13 * 8..8 + 14 * 8], b"\x00derate\0");
+        assert_eq!(&bytes[8 + 14 * 8..], b"\x02cc_mode");
+    }
+
+    #[test]
+    fn config() {
+        let mut config = Config::new(10, ALL);
+        assert_eq!(config.period(70_000_000), Some(7_000_000));
+        assert!(!config.set(1001, ALL, |_| true));
+        assert!(!config.set(100, 0, |_| true));
+        assert!(config.set(0, 1, |chain| chain == 1));
+        assert_eq!(config.period(70_000_000), None);
+        assert!(config.set(1000, 0xFFFF, |chain| chain == 2));
+        assert_eq!(config.mask(), ALL);
+    }
+
+    #[test]
+    fn rejected_start_keeps_previous_chain() {
+        let mut config = Config::new(10, ALL);
+        assert!(!config.set(1, 1, |_| false));
+        assert_eq!((config.chain(), config.mask()), (0, ALL));
+        assert_eq!(config.period(70_000_000), Some(7_000_000));
+        assert!(config.set(1, 1, |chain| chain == 1));
+        assert_eq!(config.chain(), 1);
+    }
+}
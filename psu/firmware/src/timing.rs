@@ -0,0 +1,173 @@
+//! Clock and timing configuration
+//!
+//! Every clock, timer setting and loop rate is derived here from the HSE frequency
+//! and the PLL, timer and loop settings, so changing one cannot silently leave the
+//! others inconsistent. The derived register values are checked at compile time:
+//! any combination which does not divide exactly, or is outside what the hardware
+//! supports, fails the build instead of running at a slightly wrong rate.
+//!
+//! Register values are given as written to the hardware, so a divide-by-N
+//! prescaler is written as N-1.
+
+/// External clock input frequency (Hz).
+pub const F_HSE: u32 = 25_000_000;
+
+/// PLL input divider applied to the HSE.
+pub const PLL_PREDIV: u32 = 5;
+
+/// PLL multiplier.
+pub const PLL_MUL: u32 = 14;
+
+/// System clock, AHB and APB2 frequency (Hz), from the PLL.
+pub const F_SYSCLK: u32 = F_HSE / PLL_PREDIV * PLL_MUL;
+
+/// APB1 divider from the system clock.
+pub const APB1_DIV: u32 = 2;
+
+/// APB1 frequency (Hz). Also clocks USART1 at its reset clock selection.
+pub const F_PCLK1: u32 = F_SYSCLK / APB1_DIV;
+
+/// TIM2 kernel clock (Hz). APB1 timers run at twice the bus clock when it is divided.
+pub const F_TIM2: u32 = if APB1_DIV == 1 { F_PCLK1 } else { 2 * F_PCLK1 };
+
+/// ADC clock (Hz), taken directly from the PLL output.
+pub const F_ADC: u32 = F_SYSCLK;
+
+/// HRTIM input clock (Hz), from the PLL VCO output at twice the system clock.
+pub const F_HRTIM: u32 = 2 * F_SYSCLK;
+
+/// HRTIM counter clock prescaler, CKPSC. The DLL multiplies the input clock by 32
+/// and each prescaler step halves it, so 6 counts at F_HRTIM * 32 / 64.
+pub const HRTIM_CKPSC: u32 = 6;
+
+/// HRTIM counter clock (Hz).
+pub const F_HRCK: u32 = ((F_HRTIM as u64 * 32) >> HRTIM_CKPSC) as u32;
+
+/// TIM2 counter tick rate (Hz).
+pub const TIM2_TICK: u32 = 1_000_000;
+
+/// Control loop rate (Hz), set by TIM2's update interrupt.
+pub const CTRL_RATE: u32 = 10_000;
+
+/// Control loop period (s).
+pub const CTRL_DT: f32 = 1.0 / CTRL_RATE as f32;
+
+/// Heartbeat task rate (Hz).
+pub const HEARTBEAT_RATE: u32 = 50;
+
+/// Heartbeat task period (cycles).
+pub const HEARTBEAT_PERIOD: u32 = F_SYSCLK / HEARTBEAT_RATE;
+
+/// Telemetry link baud rate.
+pub const USART1_BAUD: u32 = 3_500_000;
+
+/// Modbus RTU baud rate, used instead of the telemetry link when Modbus is enabled.
+pub const MODBUS_BAUD: u32 = 115_200;
+
+/// TIM2 prescaler register value.
+pub const TIM2_PSC: u32 = F_TIM2 / TIM2_TICK - 1;
+
+/// TIM2 auto-reload register value.
+pub const TIM2_ARR: u32 = TIM2_TICK / CTRL_RATE - 1;
+
+/// RCC PREDIV register value.
+pub const RCC_PREDIV: u32 = PLL_PREDIV - 1;
+
+/// RCC PLLMUL register value.
+pub const RCC_PLLMUL: u32 = PLL_MUL - 2;
+
+/// RCC PPRE1 register value.
+pub const RCC_PPRE1: u32 = apb_prescaler(APB1_DIV);
+
+/// USART1 BRR register value, with 8x oversampling.
+pub const USART1_BRR: u32 = usart_brr_over8(F_PCLK1, USART1_BAUD);
+
+/// USART1 BRR register value for Modbus, with 8x oversampling.
+pub const MODBUS_BRR: u32 = usart_brr_over8(F_PCLK1, MODBUS_BAUD);
+
+/// USART1 receiver timeout for Modbus, in bit periods after the end of a character.
+/// Frames end after 3.5 characters of 11 bits, or after 1.75ms above 19200 baud
+/// as the standard recommends.
+pub const MODBUS_RTO: u32 = if MODBUS_BAUD > 19_200 {
+    (1750 * MODBUS_BAUD).div_ceil(1_000_000)
+} else {
+    (35 * 11u32).div_ceil(10)
+};
+
+/// Convert a duration in seconds to the nearest whole number of system clock cycles,
+/// as used by RTIC schedules and the DWT cycle counter.
+pub const fn cycles(seconds: f32) -> u32 {
+    (seconds * F_SYSCLK as f32 + 0.5) as u32
+}
+
+/// APB prescaler register value for a divider, failing the build if unsupported.
+const fn apb_prescaler(div: u32) -> u32 {
+    match div {
+        1 => 0b000,
+        2 => 0b100,
+        4 => 0b101,
+        8 => 0b110,
+        16 => 0b111,
+        _ => panic!("APB divider must be 1, 2, 4, 8 or 16"),
+    }
+}
+
+/// USART BRR register value with 8x oversampling, where USARTDIV = 2 * f / baud
+/// and its lowest four bits are stored shifted right by one.
+const fn usart_brr_over8(f: u32, baud: u32) -> u32 {
+    let div = 2 * f / baud;
+    (div & !0xF) | ((div & 0xF) >> 1)
+}
+
+// PLL settings must be supported by PREDIV and PLLMUL and keep the PLL in range.
+const _: () = assert!(PLL_PREDIV >= 1 && PLL_PREDIV <= 16);
+const _: () = assert!(PLL_MUL >= 2 && PLL_MUL <= 16);
+const _: () = assert!(F_HSE.is_multiple_of(PLL_PREDIV));
+const _: () = assert!(F_SYSCLK >= 16_000_000 && F_SYSCLK <= 72_000_000);
+const _: () = assert!(F_PCLK1 <= 36_000_000 && F_SYSCLK.is_multiple_of(APB1_DIV));
+const _: () = assert!(F_ADC <= 72_000_000);
+
+// HRTIM only supports its DLL from a 128MHz to 144MHz input clock.
+const _: () = assert!(F_HRTIM >= 128_000_000 && F_HRTIM <= 144_000_000);
+const _: () = assert!(HRTIM_CKPSC <= 7);
+
+// TIM2 must divide down exactly to its tick and the control loop rate,
+// with the prescaler and auto-reload registers holding one less than the divider.
+const _: () = assert!((TIM2_PSC + 1) * TIM2_TICK == F_TIM2);
+const _: () = assert!((TIM2_ARR + 1) * CTRL_RATE == TIM2_TICK);
+const _: () = assert!(TIM2_PSC <= 0xFFFF);
+
+// Scheduled tasks must have a whole number of cycles per period.
+const _: () = assert!(F_SYSCLK.is_multiple_of(HEARTBEAT_RATE));
+
+// The telemetry baud rate must be exact and within what 8x oversampling supports.
+const _: () = assert!((2 * F_PCLK1).is_multiple_of(USART1_BAUD));
+const _: () = assert!(2 * F_PCLK1 / USART1_BAUD >= 16);
+
+// Modbus devices have their own clocks, so its baud rate only needs to be within 1%.
+const _: () = assert!(100 * ((2 * F_PCLK1) % MODBUS_BAUD) < 2 * F_PCLK1);
+const _: () = assert!(2 * F_PCLK1 / MODBUS_BAUD >= 16);
+const _: () = assert!(MODBUS_RTO <= 0xFF_FFFF);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn derived_rates() {
+        assert_eq!(F_SYSCLK, 70_000_000);
+        assert_eq!(F_TIM2, 70_000_000);
+        assert_eq!(F_HRCK, 70_000_000);
+        assert_eq!((TIM2_PSC, TIM2_ARR), (69, 99));
+        assert_eq!(HEARTBEAT_PERIOD, 1_400_000);
+        assert_eq!(cycles(CTRL_DT), 7000);
+    }
+
+    #[test]
+    fn register_values() {
+        assert_eq!((RCC_PREDIV, RCC_PLLMUL, RCC_PPRE1), (4, 12, 0b100));
+        assert_eq!(USART1_BRR, 18);
+        assert_eq!(usart_brr_over8(8_000_000, 115_200), 0x85);
+        assert_eq!((MODBUS_BRR, MODBUS_RTO), (0x257, 202));
+    }
+}
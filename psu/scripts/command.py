@@ -0,0 +1,203 @@
+"""
+Send commands to the PSU over its serial port.
+
+Usage: python command.py PORT profile {off,strike,hold}
+       python command.py PORT scope CHANNELS TRIGGER [SIGNAL LEVEL] PRE
+       python command.py PORT telem RATE FIELDS
+       python command.py PORT poll
+       python command.py PORT describe
+       python command.py PORT info
+       python command.py PORT bootloader
+       python command.py PORT {run,stop,clear,status}
+       python command.py PORT preset {select,get} SLOT
+       python command.py PORT preset store SLOT FILE
+
+For scope captures, CHANNELS is a comma separated list of v_out, i_out, i_in
+and v_in; TRIGGER is one of immediate, above, below, rising, falling or fault;
+SIGNAL and LEVEL (raw ADC counts) are required for level and edge triggers;
+and PRE is the number of frames to keep from before the trigger. Captures are
+saved by telem.py.
+
+For telemetry, RATE is from 1 to 1000Hz, or 0 to only send when polled, and
+FIELDS is a comma separated list of state fields or "all".
+
+The info command requests the hello packet describing the firmware build,
+which is displayed by telem.py. The bootloader command resets the PSU into its
+serial bootloader if the converter is stopped; use the Rust updater in
+psu/updater to then write new firmware.
+
+Run requests only start the converter while the hardware nRUN input is also
+asserted. Clear acknowledges a latched fault and also withdraws the run request.
+Each of these commands is answered with a run status packet, shown by telem.py.
+
+Presets bundle the strike and hold profiles, limits and PID gains; SLOT is from 0
+to 3. Select applies a preset and get shows one, each answered by a preset packet
+shown by telem.py. Store saves a preset to flash from a JSON FILE such as:
+
+    {"name": "bench-lowV", "profile": "strike",
+     "strike": {"v_set": 50, "v_lim": 70, "v_min": 40, "slew": 500},
+     "hold": {"v_set": 50, "v_lim": 70, "v_min": 40, "slew": 500},
+     "limits": {"i_lim": 0.02, "i_cc": 0.015, "cc_time": 0.5, "v_in_min": 10,
+                "v_in_max": 30, "i_in_derate": 1.2, "i_in_max": 1.5, "temp_max": 85,
+                "temp_restart": 70},
+     "gains": {"k_p": 20, "k_i": 120, "k_d": 20}}
+
+The name is at most 12 bytes. An i_cc above 0 enables constant-current mode,
+which the built-in presets leave disabled. Presets only change while the
+converter is not running; a stored preset takes effect when it is next selected.
+
+Commands are framed as [0xA5, id, len, payload..., checksum], where
+checksum is the 8-bit sum of id, len and payload.
+"""
+
+import sys
+import json
+import struct
+import serial
+
+SYNC = 0xA5
+
+# Serial protocol version these scripts implement, checked against the hello packet
+PROTOCOL_VERSION = 3
+
+CMD_SET_PROFILE = 0x01
+CMD_SCOPE_ARM = 0x02
+CMD_SET_TELEM = 0x03
+CMD_POLL_TELEM = 0x04
+CMD_GET_DESCRIPTION = 0x05
+CMD_GET_INFO = 0x06
+CMD_ENTER_BOOTLOADER = 0x07
+CMD_RUN = 0x08
+CMD_STOP = 0x09
+CMD_CLEAR_FAULT = 0x0A
+CMD_GET_STATUS = 0x0B
+CMD_SELECT_PRESET = 0x0C
+CMD_GET_PRESET = 0x0D
+CMD_STORE_PRESET = 0x0E
+
+RUN_COMMANDS = {
+    "run": CMD_RUN,
+    "stop": CMD_STOP,
+    "clear": CMD_CLEAR_FAULT,
+    "status": CMD_GET_STATUS,
+}
+
+PROFILES = {
+    "off": 0,
+    "strike": 1,
+    "hold": 2,
+}
+
+CHANNELS = ["v_out", "i_out", "i_in", "v_in"]
+
+# Telemetry fields in mask bit order
+FIELDS = ["v_in", "i_in", "v_out", "i_out", "pid_i", "temp", "vdda",
+          "ref_i_q", "duty", "profile", "fault", "state", "preset", "derate",
+          "cc_mode"]
+
+TRIGGERS = {
+    "immediate": 0,
+    "above": 1,
+    "below": 2,
+    "rising": 3,
+    "falling": 4,
+    "fault": 5,
+}
+
+
+def frame(cmd_id, payload=b""):
+    body = bytes([cmd_id, len(payload)]) + bytes(payload)
+    return bytes([SYNC]) + body + bytes([sum(body) & 0xFF])
+
+
+def send(port, cmd_id, payload=b""):
+    with serial.Serial(port, 3500000) as s:
+        s.write(frame(cmd_id, payload))
+
+
+def usage():
+    print(__doc__.strip())
+    sys.exit(1)
+
+
+def scope_payload(args):
+    if len(args) not in (3, 5) or args[1] not in TRIGGERS:
+        usage()
+    mask = 0
+    for name in args[0].split(","):
+        if name not in CHANNELS:
+            usage()
+        mask |= 1 << CHANNELS.index(name)
+    signal, level = 0, 0
+    if len(args) == 5:
+        if args[2] not in CHANNELS:
+            usage()
+        signal, level = CHANNELS.index(args[2]) + 1, int(args[3])
+    pre = int(args[-1])
+    return struct.pack("<BBBHH", mask, TRIGGERS[args[1]], signal, level, pre)
+
+
+def telem_payload(args):
+    if len(args) != 2:
+        usage()
+    if args[1] == "all":
+        mask = (1 << len(FIELDS)) - 1
+    else:
+        mask = 0
+        for name in args[1].split(","):
+            if name not in FIELDS:
+                usage()
+            mask |= 1 << FIELDS.index(name)
+    return struct.pack("<HH", int(args[0]), mask)
+
+
+def preset_payload(slot, fname):
+    with open(fname) as f:
+        p = json.load(f)
+    name = p["name"].encode()
+    if len(name) > 12 or p["profile"] not in PROFILES:
+        usage()
+    values = [p[profile][k] for profile in ("strike", "hold")
+              for k in ("v_set", "v_lim", "v_min", "slew")]
+    values += [p["limits"][k] for k in ("i_lim", "i_cc", "cc_time", "v_in_min", "v_in_max",
+                                        "i_in_derate", "i_in_max", "temp_max", "temp_restart")]
+    values += [p["gains"][k] for k in ("k_p", "k_i", "k_d")]
+    return struct.pack("<B12s20fB3x", slot, name, *values, PROFILES[p["profile"]])
+
+
+def main():
+    if len(sys.argv) < 3:
+        usage()
+    if sys.argv[2] == "profile":
+        if len(sys.argv) != 4 or sys.argv[3] not in PROFILES:
+            usage()
+        send(sys.argv[1], CMD_SET_PROFILE, [PROFILES[sys.argv[3]]])
+    elif sys.argv[2] == "scope":
+        send(sys.argv[1], CMD_SCOPE_ARM, scope_payload(sys.argv[3:]))
+    elif sys.argv[2] == "telem":
+        send(sys.argv[1], CMD_SET_TELEM, telem_payload(sys.argv[3:]))
+    elif sys.argv[2] == "poll":
+        send(sys.argv[1], CMD_POLL_TELEM)
+    elif sys.argv[2] == "describe":
+        send(sys.argv[1], CMD_GET_DESCRIPTION)
+    elif sys.argv[2] == "info":
+        send(sys.argv[1], CMD_GET_INFO)
+    elif sys.argv[2] == "bootloader":
+        send(sys.argv[1], CMD_ENTER_BOOTLOADER)
+    elif sys.argv[2] in RUN_COMMANDS:
+        send(sys.argv[1], RUN_COMMANDS[sys.argv[2]])
+    elif sys.argv[2] == "preset":
+        if len(sys.argv) == 5 and sys.argv[3] == "select":
+            send(sys.argv[1], CMD_SELECT_PRESET, [int(sys.argv[4])])
+        elif len(sys.argv) == 5 and sys.argv[3] == "get":
+            send(sys.argv[1], CMD_GET_PRESET, [int(sys.argv[4])])
+        elif len(sys.argv) == 6 and sys.argv[3] == "store":
+            send(sys.argv[1], CMD_STORE_PRESET, preset_payload(int(sys.argv[4]), sys.argv[5]))
+        else:
+            usage()
+    else:
+        usage()
+
+
+if __name__ == "__main__":
+    main()
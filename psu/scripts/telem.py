@@ -1,7 +1,53 @@
 import struct
+import time
 import serial
 
+from command import frame, CMD_GET_DESCRIPTION, CMD_GET_INFO, PROTOCOL_VERSION
+
 MAGIC = 0x74656c65
+METRICS_MAGIC = 0x6d657472
+SCOPE_MAGIC = 0x73636f70
+HISTORY_MAGIC = 0x68697374
+ENERGY_MAGIC = 0x656e6572
+FIELDS_MAGIC = 0x666c6473
+DESC_MAGIC = 0x64657363
+HELLO_MAGIC = 0x68656c6f
+STATUS_MAGIC = 0x73746174
+PROFILE_MAGIC = 0x70726f66
+PRESET_MAGIC = 0x70726573
+EVENT_MAGIC = 0x65766e74
+
+# Packet lengths after the magic
+LENGTHS = {
+    MAGIC: 11*4,
+    METRICS_MAGIC: 21*4,
+    ENERGY_MAGIC: 8*4,
+    # Followed by the number of samples given in the header
+    SCOPE_MAGIC: 3*4,
+    HISTORY_MAGIC: 2*4,
+    # Followed by the field data length given in the header
+    FIELDS_MAGIC: 4,
+    # Followed by 8 bytes for each field counted in the header
+    DESC_MAGIC: 4,
+    HELLO_MAGIC: 19*4,
+    STATUS_MAGIC: 4,
+    PROFILE_MAGIC: 3*4 + 4*6*4,
+    PRESET_MAGIC: 4 + 25*4,
+    EVENT_MAGIC: 3*4,
+}
+
+# Field value types from the description packet
+FIELD_TYPES = {0: "f", 1: "H", 2: "B"}
+
+# Names and struct formats of each field, set by the description packet
+fields = []
+
+# Event log file, opened on the first event. Event timestamps are 64-bit µs since
+# power on rather than 32-bit, which wrapped after 71 minutes, or ms, which could
+# not resolve events within one control loop period.
+event_log = None
+
+CHANNELS = ["v_out", "i_out", "i_in", "v_in"]
 
 FAULTS = {
     0: "None      ",
@@ -13,6 +59,26 @@ FAULTS = {
     6: "VIn Low   ",
     7: "VIn High  ",
     8: "IIn High  ",
+    9: "Over Temp ",
+    10: "Test DAC1 ",
+    11: "Test DAC2 ",
+    12: "Test COMP2",
+    13: "Test COMP4",
+    14: "Test Vout ",
+    15: "Test Vin  ",
+    16: "Test DLL  ",
+    17: "RemoteStop",
+}
+
+
+EVENTS = {
+    1: "nRUN asserted",
+    2: "nRUN released",
+    3: "HRTIM enabled",
+    4: "HRTIM disabled",
+    5: "Fault",
+    6: "Start timeout",
+    7: "HRTIM SYSFLT",
 }
 
 
@@ -23,36 +89,245 @@ STATES = {
 }
 
 
+CC_MODES = {
+    0: "Off     ",
+    1: "Limiting",
+    2: "Foldback",
+}
+
+
+PROFILES = {
+    0: "Off   ",
+    1: "Strike",
+    2: "Hold  ",
+}
+
+
+def print_state(rx, blink):
+    (v_in, i_in, v_out, i_out,
+     pid_i, temp, vdda, derate, ref_i_q, duty, profile, preset, fault, state,
+     cc_mode) = struct.unpack("<ffffffffHHBBBBB3x", rx)
+    fault = FAULTS.get(fault, "?")
+    state = STATES.get(state, "?")
+    profile = PROFILES.get(profile, "?")
+    cc_mode = CC_MODES.get(cc_mode, "?")
+    print(f"{blink} V_in: {v_in: 3.02f}V    I_in: {i_in: 3.02f}A    "
+          f"V_out: {v_out: 6.00f}V    I_out: {1000*i_out: 6.01f}mA    "
+          f"PID I: {pid_i:5.01f}    Temp: {temp:4.01f}C    VDDA: {vdda:4.03f}V    ",
+          f"Ref I_Q: {ref_i_q:05}    Derate: {100*derate:3.0f}%    Duty: {duty:05}   "
+          f"Fault: {fault} "
+          f"State: {state}    Profile: {profile}    Preset: {preset}    CC: {cc_mode}",
+          " "*10,
+          end="\r", flush=True)
+
+
+def read_description(header, data):
+    fields.clear()
+    for n in range(header[0]):
+        entry = data[n*8:(n+1)*8]
+        fields.append((entry[1:].rstrip(b"\0").decode(), FIELD_TYPES[entry[0]]))
+
+
+def print_fields(header, data, blink):
+    mask = struct.unpack("<H", header[:2])[0]
+    if not fields:
+        print(f"{blink} Waiting for field description", end="\r", flush=True)
+        return
+    selected = [f for (k, f) in enumerate(fields) if mask & (1 << k)]
+    values = struct.unpack("<" + "".join(fmt for (_, fmt) in selected), data)
+    text = "    ".join(f"{name}: {value:.04g}" if fmt == "f" else f"{name}: {value}"
+                       for ((name, fmt), value) in zip(selected, values))
+    print(f"{blink} {text}", " "*10, end="\r", flush=True)
+
+
+def print_hello(rx):
+    protocol, _, crc, *strings = struct.unpack("<HHI12s16s8s32s", rx)
+    version, commit, profile, features = (
+        x.rstrip(b"\0").decode(errors="replace") for x in strings)
+    # Print five lines below the state, then return to the state line
+    print(f"\n\n\n\n\n  Firmware {version} ({commit}, {profile}) "
+          f"features: {features or 'none'}    config CRC: {crc:08X}    "
+          f"protocol: {protocol}",
+          " "*10,
+          end="\x1b[5A\r", flush=True)
+    if protocol != PROTOCOL_VERSION:
+        print(f"\n\n\n\n\n\n\n\n  WARNING: firmware protocol version {protocol} does not "
+              f"match telem.py version {PROTOCOL_VERSION}",
+              end="\x1b[8A\r", flush=True)
+
+
+def print_status(rx):
+    state, fault, request, nrun = rx
+    # Print six lines below the state, then return to the state line
+    print(f"\n\n\n\n\n\n  Run control: {STATES.get(state, '?').strip()}, "
+          f"fault {FAULTS.get(fault, '?').strip()}, "
+          f"run request {'on' if request else 'off'}, nRUN {'on' if nrun else 'off'}",
+          " "*10,
+          end="\x1b[6A\r", flush=True)
+
+
+def print_preset(rx):
+    slot, active, stored, _, name, *values = struct.unpack("<BBBB12s20fB3xI", rx)
+    name = name.rstrip(b"\0").decode(errors="replace")
+    strike, hold, limits, gains = values[0:4], values[4:8], values[8:17], values[17:20]
+    profile = PROFILES.get(values[20], "?").strip()
+    cc = f"{1000*limits[1]:.0f}mA for {limits[2]:g}s" if limits[1] > 0 else "off"
+    # Print nine lines below the state, then return to the state line
+    print(f"\n\n\n\n\n\n\n\n\n  Preset {slot} {name}"
+          f" ({'active' if slot == active else 'inactive'}, "
+          f"{'stored' if stored else 'default'}):    "
+          f"strike {strike[0]:.0f}V ({strike[2]:.0f}-{strike[1]:.0f}V)    "
+          f"hold {hold[0]:.0f}V ({hold[2]:.0f}-{hold[1]:.0f}V)    start {profile}    "
+          f"I_lim {1000*limits[0]:.0f}mA    CC {cc}    V_in {limits[3]:.1f}-{limits[4]:.1f}V    "
+          f"I_in derate {limits[5]:.2f}A, max {limits[6]:.2f}A    PID {gains[0]:g}/{gains[1]:g}/{gains[2]:g}",
+          " "*10,
+          end="\x1b[9A\r", flush=True)
+
+
+def log_event(rx):
+    global event_log
+    kind, fault, dropped, time_us = struct.unpack("<BBHQ", rx)
+    t = time_us / 1e6
+    name = EVENTS.get(kind, "?")
+    if kind == 5:
+        name += " " + FAULTS.get(fault, "?").strip()
+    if event_log is None:
+        event_log = open(time.strftime("events-%Y%m%d-%H%M%S.csv"), "w")
+        event_log.write("host_time,device_time,event,fault,dropped\n")
+    host = time.strftime("%Y-%m-%d %H:%M:%S") + f"{time.time() % 1:.3f}"[1:]
+    event_log.write(f"{host},{t:.6f},{EVENTS.get(kind, kind)},{fault},{dropped}\n")
+    event_log.flush()
+    lost = f" ({dropped} dropped before)" if dropped else ""
+    # Print ten lines below the state, then return to the state line
+    print(f"\n\n\n\n\n\n\n\n\n\n  Event at {t:.6f}s: {name}{lost}",
+          " "*20,
+          end="\x1b[10A\r", flush=True)
+
+
+# Profiled tasks in report order
+TASKS = ["adc1_2", "ctrl_loop", "send_telem", "heartbeat"]
+
+
+def print_profile(rx):
+    window, load, warnings = struct.unpack("<IfI", rx[:12])
+    tasks = [struct.unpack("<6I", rx[12+24*n:36+24*n]) for n in range(len(TASKS))]
+    # Times in microseconds at 70MHz
+    text = "    ".join(f"{name}: {mean/70:.01f}/{tmax/70:.01f}us jitter {jitter/70:.01f}us"
+                       for (name, (_, _, mean, tmax, jitter, _)) in zip(TASKS, tasks))
+    overruns = ", ".join(f"{name} {task[5]}x" for (k, (name, task))
+                         in enumerate(zip(TASKS, tasks)) if warnings & (1 << k))
+    warning = f"    WARNING overruns: {overruns}" if overruns else ""
+    # Print seven lines below the state, then return to the state line
+    print(f"\n\n\n\n\n\n\n  CPU: {100*load:4.01f}%    {text}{warning}",
+          " "*10,
+          end="\x1b[7A\r", flush=True)
+
+
+def print_metrics(rx):
+    samples, *summaries = struct.unpack("<I20f", rx)
+    v_raw, v, i_raw, i = (summaries[n:n+5] for n in range(0, 20, 5))
+    # Print on the line below the state, then return to the state line
+    print(f"\n  {samples:5} samples    "
+          f"V_out raw: {v_raw[2]: 6.01f}V mean, {v_raw[4]: 5.02f}V p-p, "
+          f"{v_raw[3]: 5.02f}V rms dev    "
+          f"V_out: {v[2]: 6.01f}V mean, {v[4]: 5.02f}V p-p, "
+          f"{v[3]: 5.02f}V rms dev    "
+          f"I_out raw: {1000*i_raw[4]: 5.02f}mA p-p    "
+          f"I_out: {1000*i[2]: 6.01f}mA mean, {1000*i[3]: 6.01f}mA rms",
+          " "*10,
+          end="\x1b[1A\r", flush=True)
+
+
+def print_energy(rx):
+    p_in, p_out, eff, wh_in, wh_out, hours, starts, faults = struct.unpack(
+        "<ffffffII", rx)
+    # Print two lines below the state, then return to the state line
+    print(f"\n\n  P_in: {p_in: 6.02f}W    P_out: {p_out: 6.02f}W    "
+          f"Efficiency: {100*eff: 5.01f}%    "
+          f"Lifetime: {wh_in:.02f}Wh in, {wh_out:.02f}Wh out, "
+          f"{hours:.02f}h running, {starts} starts, {faults} faults",
+          " "*10,
+          end="\x1b[2A\r", flush=True)
+
+
+def save_scope(header, data):
+    dt, samples, trigger, channels, _kind, _ = struct.unpack("<fHHBBH", header)
+    names = [n for (k, n) in enumerate(CHANNELS) if channels & (1 << k)]
+    words = struct.unpack(f"<{samples}H", data)
+    fname = time.strftime("scope-%Y%m%d-%H%M%S.csv")
+    with open(fname, "w") as f:
+        f.write("t," + ",".join(names) + "\n")
+        for n in range(samples // len(names)):
+            frame = words[n*len(names):(n+1)*len(names)]
+            t = (n - trigger) * dt
+            f.write(f"{t:.9f}," + ",".join(str(x) for x in frame) + "\n")
+    # Print three lines below the state, then return to the state line
+    print(f"\n\n\n  Saved {samples // len(names)} frame capture to {fname}",
+          end="\x1b[3A\r", flush=True)
+
+
+def save_history(header, data):
+    dt, fault, _, count = struct.unpack("<fBBH", header)
+    fname = time.strftime("fault-%Y%m%d-%H%M%S.csv")
+    with open(fname, "w") as f:
+        f.write("t,v_in,i_in,v_out,i_out,pid_i,ref_i_q,duty\n")
+        for n in range(count):
+            snapshot = struct.unpack("<fffffHH", data[n*24:(n+1)*24])
+            t = (n - count + 1) * dt
+            f.write(f"{t:.4f}," + ",".join(str(x) for x in snapshot) + "\n")
+    # Print four lines below the state, then return to the state line
+    print(f"\n\n\n\n  Fault {FAULTS.get(fault, '?').strip()}: "
+          f"saved {count} snapshots to {fname}",
+          end="\x1b[4A\r", flush=True)
+
+
 def main():
     s = serial.Serial("/dev/ttyACM1", 3500000)
+    s.write(frame(CMD_GET_INFO) + frame(CMD_GET_DESCRIPTION))
 
     while True:
         # Align to magic
         rx = s.read(4)
-        while struct.unpack("<I", rx)[0] != MAGIC:
-            s.read()
-            rx = s.read(4)
-        s.read(7*4)
+        while struct.unpack("<I", rx)[0] not in LENGTHS:
+            rx = rx[1:] + s.read()
 
-        # Read telemetry
+        # Read telemetry packets, each starting with a magic
         blink = "."
         while True:
-            rx = s.read(8*4)
-            (magic, v_in, i_in, v_out, i_out,
-             pid_i, ref_i_q, duty, _, fault, state) = struct.unpack(
-                "<IfffffHHHBB", rx)
-            if magic != MAGIC:
+            magic = struct.unpack("<I", rx)[0]
+            if magic not in LENGTHS:
                 break
-            fault = FAULTS.get(fault, "?")
-            state = STATES.get(state, "?")
-            print(f"{blink} V_in: {v_in: 3.02f}V    I_in: {i_in: 3.02f}A    "
-                  f"V_out: {v_out: 6.00f}V    I_out: {1000*i_out: 6.01f}mA    "
-                  f"PID I: {pid_i:5.01f}    ",
-                  f"Ref I_Q: {ref_i_q:05}    Duty: {duty:05}   Fault: {fault} "
-                  f"State: {state}",
-                  " "*10,
-                  end="\r", flush=True)
-            blink = " " if blink == "." else "."
+            body = s.read(LENGTHS[magic])
+            if magic == MAGIC:
+                print_state(body, blink)
+                blink = " " if blink == "." else "."
+            elif magic == METRICS_MAGIC:
+                print_metrics(body)
+            elif magic == ENERGY_MAGIC:
+                print_energy(body)
+            elif magic == SCOPE_MAGIC:
+                samples = struct.unpack("<H", body[4:6])[0]
+                save_scope(body, s.read(2*samples))
+            elif magic == HISTORY_MAGIC:
+                count = struct.unpack("<H", body[6:8])[0]
+                save_history(body, s.read(24*count))
+            elif magic == FIELDS_MAGIC:
+                length = struct.unpack("<H", body[2:4])[0]
+                print_fields(body, s.read(length), blink)
+                blink = " " if blink == "." else "."
+            elif magic == PROFILE_MAGIC:
+                print_profile(body)
+            elif magic == STATUS_MAGIC:
+                print_status(body)
+            elif magic == PRESET_MAGIC:
+                print_preset(body)
+            elif magic == EVENT_MAGIC:
+                log_event(body)
+            elif magic == HELLO_MAGIC:
+                print_hello(body)
+            elif magic == DESC_MAGIC:
+                read_description(body, s.read(8*body[0]))
+            rx = s.read(4)
 
 
 if __name__ == "__main__":
@@ -0,0 +1,214 @@
+"""
+Test the PSU's Modbus RTU slave against the simulator, using the minimalmodbus master.
+
+Usage: python test_modbus.py
+
+Builds psu/simulator and starts a fresh virtual PSU in Modbus mode for each test,
+then reads and writes the registers listed in psu/firmware/MODBUS.md as a PLC would.
+Conditions such as faults are set through the simulator's script commands.
+
+Requires minimalmodbus and pyserial.
+"""
+
+import os
+import subprocess
+import time
+import unittest
+
+import serial
+import minimalmodbus
+
+SIMULATOR = os.path.join(os.path.dirname(os.path.abspath(__file__)), "..", "simulator")
+BINARY = os.path.join(SIMULATOR, "target", "debug", "iggie-psu-sim")
+
+SLAVE = 1
+BAUD = 115200
+
+# Input registers
+IN_V_IN = 0
+IN_I_IN = 1
+IN_V_OUT = 2
+IN_I_OUT = 3
+IN_PID_I = 4
+IN_DUTY = 5
+IN_FAULT_STATE = 10
+IN_FAULT_CODE = 11
+IN_PROFILE = 12
+IN_PRESET = 13
+IN_COUNT = 15
+
+# Holding registers
+HR_PROFILE = 0
+HR_STRIKE_V_SET = 1
+HR_HOLD_V_SET = 2
+HR_I_LIM = 3
+HR_I_CC = 4
+HR_I_IN_DERATE = 8
+HR_PRESET = 12
+HR_COUNT = 13
+
+# Coils and discrete inputs
+COIL_RUN = 0
+DI_NRUN = 0
+DI_RUNNING = 1
+DI_FAULT = 2
+
+STATE_STOPPED = 0
+STATE_RUNNING = 1
+FAULT_NO_RUN = 1
+FAULT_OVER_TEMP = 9
+FAULT_REMOTE_STOP = 17
+
+
+class ModbusTest(unittest.TestCase):
+    @classmethod
+    def setUpClass(cls):
+        subprocess.run(["cargo", "build", "--quiet"], cwd=SIMULATOR, check=True)
+
+    def setUp(self):
+        self.sim = subprocess.Popen([BINARY, "--modbus"], stdin=subprocess.PIPE,
+                                    stdout=subprocess.PIPE, text=True)
+        port = self.sim.stdout.readline().split()[-1]
+        self.psu = minimalmodbus.Instrument(port, SLAVE, mode=minimalmodbus.MODE_RTU)
+        self.psu.serial.baudrate = BAUD
+        self.psu.serial.parity = serial.PARITY_EVEN
+        self.psu.serial.timeout = 0.5
+        self.psu.clear_buffers_before_each_transaction = True
+
+    def tearDown(self):
+        self.psu.serial.close()
+        self.sim.communicate("quit\n", timeout=5)
+
+    def script(self, line):
+        """Send one command to the simulator's plant script."""
+        self.sim.stdin.write(line + "\n")
+        self.sim.stdin.flush()
+
+    def wait_until(self, condition, timeout=3.0):
+        end = time.monotonic() + timeout
+        while not condition():
+            self.assertLess(time.monotonic(), end, "Timed out waiting for the PSU")
+            time.sleep(0.05)
+
+    def v_out(self):
+        return self.psu.read_register(IN_V_OUT, 1, functioncode=4)
+
+    def fault_state(self):
+        return self.psu.read_register(IN_FAULT_STATE, functioncode=4)
+
+    def stop(self):
+        self.psu.write_bit(COIL_RUN, 0)
+        self.wait_until(lambda: self.fault_state() == STATE_STOPPED)
+
+    def test_measurements(self):
+        self.wait_until(lambda: self.v_out() > 370.0)
+        values = self.psu.read_registers(0, IN_COUNT, functioncode=4)
+        self.assertAlmostEqual(values[IN_V_IN] / 100, 24.0, delta=0.5)
+        self.assertGreater(values[IN_I_IN], 0)
+        self.assertAlmostEqual(values[IN_V_OUT] / 10, 375.0, delta=5.0)
+        self.assertGreater(values[IN_I_OUT], 0)
+        self.assertLessEqual(values[IN_DUTY], 1000)
+        self.assertEqual(values[IN_FAULT_STATE], STATE_RUNNING)
+        self.assertEqual(values[IN_PROFILE], 1)
+        self.assertEqual(values[IN_PRESET], 0)
+        # Within the integrator limit of IREF_MAX / K_I
+        pid_i = self.psu.read_register(IN_PID_I, 2, functioncode=4, signed=True)
+        self.assertLessEqual(abs(pid_i), 31.67)
+
+    def test_setpoints(self):
+        self.assertEqual(self.psu.read_register(HR_STRIKE_V_SET, 1), 375.0)
+        self.psu.write_register(HR_HOLD_V_SET, 240.0, 1, functioncode=6)
+        self.psu.write_register(HR_PROFILE, 2, functioncode=6)
+        self.assertEqual(self.psu.read_registers(HR_PROFILE, 3), [2, 3750, 2400])
+        self.wait_until(lambda: abs(self.v_out() - 240.0) < 5.0)
+        self.wait_until(lambda: self.psu.read_register(IN_PROFILE, functioncode=4) == 2)
+
+        # Setpoints must lie within their profile's limits
+        with self.assertRaises(minimalmodbus.IllegalRequestError):
+            self.psu.write_register(HR_HOLD_V_SET, 300.0, 1)
+        self.assertEqual(self.psu.read_register(HR_HOLD_V_SET, 1), 240.0)
+
+    def test_limits(self):
+        self.wait_until(lambda: self.fault_state() == STATE_RUNNING)
+        with self.assertRaises(minimalmodbus.SlaveDeviceBusyError):
+            self.psu.write_register(HR_I_CC, 0.04, 5)
+
+        self.stop()
+        self.psu.write_registers(HR_I_LIM, [5000, 4000, 250])
+        self.assertEqual(self.psu.read_registers(HR_I_LIM, 3), [5000, 4000, 250])
+        self.assertEqual(self.psu.read_register(HR_I_IN_DERATE, 3), 3.0)
+
+        # i_cc must stay below i_lim, and a rejected write changes nothing
+        with self.assertRaises(minimalmodbus.IllegalRequestError):
+            self.psu.write_registers(HR_I_LIM, [3000, 3000])
+        self.assertEqual(self.psu.read_registers(HR_I_LIM, 2), [5000, 4000])
+
+    def test_presets(self):
+        self.stop()
+        self.psu.write_register(HR_PRESET, 2)
+        self.assertEqual(self.psu.read_registers(HR_PROFILE, 6), [1, 500, 500, 2000, 0, 500])
+        self.assertEqual(self.psu.read_register(IN_PRESET, functioncode=4), 2)
+
+        # The preset applies before other registers in the same write
+        limits = [10000, 6000, 500, 1800, 3000, 2800, 3500, 850, 700]
+        self.psu.write_registers(HR_HOLD_V_SET, [2300] + limits + [0])
+        self.assertEqual(self.psu.read_registers(HR_PROFILE, HR_COUNT),
+                         [1, 3750, 2300] + limits + [0])
+
+        with self.assertRaises(minimalmodbus.IllegalRequestError):
+            self.psu.write_register(HR_PRESET, 4)
+
+        self.psu.write_bit(COIL_RUN, 1)
+        self.wait_until(lambda: self.fault_state() == STATE_RUNNING)
+        with self.assertRaises(minimalmodbus.SlaveDeviceBusyError):
+            self.psu.write_register(HR_PRESET, 1)
+
+    def test_run_control(self):
+        self.wait_until(lambda: self.psu.read_bit(DI_RUNNING) == 1)
+        self.assertEqual(self.psu.read_bits(DI_NRUN, 3), [1, 1, 0])
+        self.assertEqual(self.psu.read_bits(COIL_RUN, 2, functioncode=1), [1, 0])
+
+        self.psu.write_bit(COIL_RUN, 0)
+        self.assertEqual(self.psu.read_bits(DI_NRUN, 3), [1, 0, 0])
+        self.assertEqual(self.psu.read_register(IN_FAULT_CODE, functioncode=4),
+                         FAULT_REMOTE_STOP)
+        self.psu.write_bit(COIL_RUN, 1)
+        self.wait_until(lambda: self.psu.read_bit(DI_RUNNING) == 1)
+
+        # A fault latches until cleared, which also withdraws the run request
+        self.script("fault OverTemp")
+        self.wait_until(lambda: self.psu.read_bit(DI_FAULT) == 1)
+        self.assertEqual(self.psu.read_register(IN_FAULT_CODE, functioncode=4),
+                         FAULT_OVER_TEMP)
+        self.script("clear")
+        self.psu.write_bits(COIL_RUN, [0, 1])
+        self.assertEqual(self.fault_state(), STATE_STOPPED)
+        self.assertEqual(self.psu.read_bits(COIL_RUN, 2, functioncode=1), [0, 0])
+        self.psu.write_bit(COIL_RUN, 1)
+        self.wait_until(lambda: self.fault_state() == STATE_RUNNING)
+
+        # Releasing nRUN stops the converter whatever the run request
+        self.script("nrun 0")
+        self.wait_until(lambda: self.psu.read_bit(DI_NRUN) == 0)
+        self.assertEqual(self.psu.read_bits(DI_NRUN, 3), [0, 0, 0])
+        self.assertEqual(self.psu.read_bit(COIL_RUN, functioncode=1), 1)
+        self.assertEqual(self.psu.read_register(IN_FAULT_CODE, functioncode=4), FAULT_NO_RUN)
+
+    def test_exceptions(self):
+        with self.assertRaises(minimalmodbus.IllegalRequestError):
+            self.psu.read_registers(IN_COUNT - 1, 2, functioncode=4)
+        with self.assertRaises(minimalmodbus.IllegalRequestError):
+            self.psu.read_register(HR_COUNT)
+        with self.assertRaises(minimalmodbus.IllegalRequestError):
+            self.psu.write_register(HR_PROFILE, 3, functioncode=6)
+        with self.assertRaises(minimalmodbus.IllegalRequestError):
+            self.psu.write_bit(2, 1)
+
+        # Requests to other slaves are not answered
+        other = minimalmodbus.Instrument(self.psu.serial.port, SLAVE + 1)
+        with self.assertRaises(minimalmodbus.NoResponseError):
+            other.read_register(IN_V_IN, functioncode=4)
+
+
+if __name__ == "__main__":
+    unittest.main()
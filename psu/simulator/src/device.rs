@@ -0,0 +1,372 @@
+//! Emulated firmware, running the same control, protection and telemetry code
+//! as the PSU against the plant model.
+//!
+//! Configuration is the firmware's, from `iggie_psu::config`, with the preset strap
+//! not fitted. Stored presets are kept only until the simulator exits.
+//! In Modbus mode the serial link carries Modbus RTU instead, as with `MODBUS` set.
+
+use iggie_psu::{state, pid, kalman, burst, profile, command, stats, scope, history, energy,
+                selftest, telemetry, info, run, control, timing, preset, event, modbus};
+use iggie_psu::config::*;
+use iggie_psu::hw::BurstPwm;
+use state::ToBytes;
+
+use crate::plant::{Hw, Plant};
+
+/// Control loop steps per heartbeat.
+const HEARTBEAT_STEPS: u64 = (timing::CTRL_RATE / timing::HEARTBEAT_RATE) as u64;
+
+/// Control loop steps in a microsecond clock, used to schedule telemetry.
+const STEP_US: u32 = 1_000_000 / timing::CTRL_RATE;
+
+/// Control loop steps in system clock cycles, used to timestamp events.
+const STEP_CYCLES: u64 = (timing::F_SYSCLK / timing::CTRL_RATE) as u64;
+
+/// Kalman filter with the given (Q, R), steady-state if the firmware uses it.
+fn kalman_filter((q, r): (f32, f32), dt: f32) -> kalman::Kalman {
+    if KALMAN_STEADY_STATE {
+        kalman::Kalman::new_steady_state(q, r, dt, 0.0)
+    } else {
+        kalman::Kalman::new(q, r, dt, 0.0)
+    }
+}
+
+/// Request from the host to leave the application.
+pub enum Event {
+    EnterBootloader,
+}
+
+pub struct Device {
+    pub hw: Hw,
+    pub plant: Plant,
+    state: state::State,
+    regulator: control::Regulator,
+    supervisor: control::Supervisor,
+    run_control: run::RunControl,
+    profiles: profile::Profiles,
+    presets: preset::Presets,
+    preset_report: preset::Report,
+    vout_kal: kalman::Kalman,
+    iout_kal: kalman::Kalman,
+    parser: command::Parser,
+    telem_config: telemetry::Config,
+    telem_fields: telemetry::Fields,
+    reg_stats: stats::RegulationStats,
+    metrics: stats::Metrics,
+    energy: energy::Energy,
+    energy_report: energy::Report,
+    scope: Box<scope::Scope<SCOPE_SAMPLES>>,
+    history: history::History<HISTORY_LEN>,
+    events: event::Queue<EVENT_QUEUE_LEN>,
+    run_status: run::Status,
+    /// Modbus slave, replacing commands and telemetry in Modbus mode.
+    modbus: Option<modbus::Slave>,
+    steps: u64,
+    start_step: u64,
+    start_elapsed: bool,
+    /// Step at which the next periodic telemetry is due.
+    next_telem: Option<u64>,
+    hello_pending: bool,
+    description_pending: bool,
+    run_status_pending: bool,
+    preset_pending: bool,
+    metrics_pending: bool,
+    energy_pending: bool,
+    /// Bytes waiting to be sent to the host.
+    pub tx: Vec<u8>,
+}
+
+impl Device {
+    /// Power on, with the given analogue conditions, serving Modbus at the given
+    /// slave address if any.
+    pub fn new(plant: Plant, modbus: Option<u8>) -> Self {
+        let dt = timing::CTRL_DT;
+        let profiles = profile::Profiles::new(
+            PROFILE_OFF, PROFILE_STRIKE, PROFILE_HOLD, profile::ProfileId::Strike);
+        let mut device = Device {
+            hw: Hw::new(),
+            plant,
+            state: state::State::new(),
+            regulator: control::Regulator::new(
+                pid::PID::new(dt, K_P, K_I, K_D, I_MIN, I_MAX),
+                burst::LightLoad::new(BURST_STRATEGY, BURST_ENTER, BURST_EXIT),
+                control::Derating::new(LIMITS.i_in_derate, DERATE_GAIN),
+                control::CurrentLimit::new(CC_GAIN, CC_FOLDBACK),
+                IREF_MAX, dt),
+            supervisor: control::Supervisor::new(LIMITS),
+            run_control: run::RunControl::new(RUN_REQUEST),
+            profiles,
+            presets: preset::Presets::new(PRESETS),
+            preset_report: preset::Report::new(PRESETS[0]),
+            vout_kal: kalman_filter(VOUT_KALMAN, dt),
+            iout_kal: kalman_filter(IOUT_KALMAN, dt),
+            parser: command::Parser::new(MAXIMA),
+            telem_config: telemetry::Config::new(TELEM_RATE, TELEM_MASK),
+            telem_fields: telemetry::Fields::new(),
+            reg_stats: stats::RegulationStats::new(),
+            metrics: stats::Metrics::new(),
+            energy: energy::Energy::new(ENERGY_TAU),
+            energy_report: energy::Report::new(),
+            scope: Box::new(scope::Scope::new(dt)),
+            history: history::History::new(HISTORY_DECIMATION, dt),
+            events: event::Queue::new(),
+            run_status: run::Status::new(),
+            modbus: modbus.map(|address| modbus::Slave::new(address, MAXIMA)),
+            steps: 0,
+            start_step: 0,
+            start_elapsed: false,
+            next_telem: modbus.map_or(Some(0), |_| None),
+            hello_pending: modbus.is_none(),
+            description_pending: modbus.is_none(),
+            run_status_pending: false,
+            preset_pending: false,
+            metrics_pending: false,
+            energy_pending: false,
+            tx: Vec::new(),
+        };
+        device.select_preset(PRESET_DEFAULT);
+        device
+    }
+
+    /// Run one control loop period: sample the ADC, check limits, update the
+    /// regulator, and run the heartbeat and telemetry when due.
+    pub fn step(&mut self) {
+        let dt = timing::CTRL_DT;
+        self.plant.step(dt, &self.hw);
+
+        // Releasing nRUN stops switching in hardware
+        if !self.hw.nrun.get() && self.hw.enabled.get() {
+            self.hw.disable();
+            self.events.nrun(false, self.cycles());
+            control::nrun_released(&mut self.state);
+        }
+
+        let buf = self.plant.adc(self.state.vdda);
+        let fault = self.state.fault_state == state::FaultState::Fault;
+        self.scope.push(&buf, fault);
+        let state = &mut self.state;
+        state.update_adc(buf);
+        self.regulator.sample(state.v_out);
+        self.vout_kal.predict();
+        self.vout_kal.update(state.v_out);
+        self.iout_kal.predict();
+        self.iout_kal.update(state.i_out);
+        let (vout, dvout) = self.vout_kal.get();
+        let (iout, _) = self.iout_kal.get();
+        self.reg_stats.push(self.profiles.reference(), state.v_out, vout, state.i_out, iout);
+        state.v_out = vout;
+        state.i_out = iout;
+        let limits = self.supervisor.limits();
+        control::protect(state, limits, &self.profiles, self.start_elapsed, &self.hw);
+
+        self.regulator.step(state, &mut self.profiles, vout, dvout, &self.hw, &self.hw);
+        self.history.push(state);
+        self.energy.step(dt, state);
+
+        if self.steps.is_multiple_of(HEARTBEAT_STEPS) {
+            self.heartbeat();
+        }
+        self.events.observe(&self.state, self.cycles());
+        if self.next_telem.is_some_and(|due| self.steps >= due) {
+            self.send_telem();
+            self.next_telem = self.telem_config.period(1_000_000)
+                                  .map(|period| self.steps + (period / STEP_US).max(1) as u64);
+        }
+        self.steps += 1;
+        self.flush();
+    }
+
+    fn heartbeat(&mut self) {
+        self.state.update_temp(self.plant.temp);
+        self.events.nrun(self.hw.nrun.get(), self.cycles());
+        let self_test = |state: &state::State, limits: &control::Limits| {
+            let limits = selftest::Limits {
+                v_in_min: limits.v_in_min, v_in_max: limits.v_in_max, ..SELFTEST_LIMITS
+            };
+            selftest::check_adc(state, &limits)
+        };
+        let started = self.supervisor.heartbeat(
+            &mut self.state, &self.run_control, &self.hw, &self.hw, &self.hw, self_test);
+        if started {
+            self.start_step = self.steps;
+            self.start_elapsed = false;
+            self.profiles.reset();
+        } else if self.state.fault_state == state::FaultState::Running {
+            let elapsed = (self.steps - self.start_step) * STEP_CYCLES;
+            if !self.start_elapsed && elapsed > V_TIMEOUT as u64 {
+                self.start_elapsed = true;
+                self.events.push(event::Kind::StartTimeout, state::FaultCode::NoFault,
+                                 self.cycles());
+            }
+        }
+    }
+
+    fn send_telem(&mut self) {
+        self.reg_stats.finish(&mut self.metrics);
+        self.metrics_pending = true;
+        self.energy.report(&mut self.energy_report);
+        self.energy_pending = true;
+        let mask = self.telem_config.mask();
+        if mask == telemetry::ALL {
+            self.tx.extend_from_slice(self.state.to_bytes());
+        } else {
+            self.tx.extend_from_slice(self.telem_fields.pack(&self.state, mask));
+        }
+    }
+
+    /// Handle a byte received from the host.
+    pub fn receive(&mut self, byte: u8) -> Option<Event> {
+        if let Some(slave) = &mut self.modbus {
+            slave.push(byte);
+            return None;
+        }
+        match self.parser.push(byte)? {
+            command::Command::SetProfile(id) => self.profiles.select(id),
+            command::Command::ScopeArm(config) => {
+                self.scope.arm(config);
+            },
+            command::Command::SetTelemetry { rate, mask } => {
+                if self.telem_config.set(rate, mask, |_| true) {
+                    self.next_telem = Some(self.steps);
+                }
+            },
+            command::Command::PollTelemetry => self.send_telem(),
+            command::Command::GetDescription => self.description_pending = true,
+            command::Command::GetInfo => self.hello_pending = true,
+            command::Command::EnterBootloader
+                if self.state.fault_state != state::FaultState::Running =>
+            {
+                self.hw.disable();
+                return Some(Event::EnterBootloader);
+            },
+            // Ignored while running
+            command::Command::EnterBootloader => (),
+            command::Command::Run => {
+                self.run_control.run();
+                self.run_status_pending = true;
+            },
+            command::Command::Stop => {
+                if self.run_control.stop(&mut self.state) {
+                    self.hw.disable();
+                }
+                self.events.observe(&self.state, self.cycles());
+                self.run_status_pending = true;
+            },
+            command::Command::ClearFault => {
+                self.run_control.clear_fault(&mut self.state);
+                self.events.observe(&self.state, self.cycles());
+                self.run_status_pending = true;
+            },
+            command::Command::GetStatus => self.run_status_pending = true,
+            command::Command::SelectPreset(slot) => {
+                if self.state.fault_state != state::FaultState::Running {
+                    self.select_preset(slot);
+                }
+                self.preset_pending = self.presets.report(self.presets.active(),
+                                                          &mut self.preset_report);
+            },
+            command::Command::GetPreset(slot) => {
+                self.preset_pending = self.presets.report(slot, &mut self.preset_report);
+            },
+            command::Command::StorePreset { slot, preset } => {
+                if self.state.fault_state != state::FaultState::Running {
+                    self.presets.store(slot, preset);
+                }
+                self.preset_pending = self.presets.report(slot, &mut self.preset_report);
+            },
+        }
+        None
+    }
+
+    /// End a Modbus request frame once the line has been silent, queuing the response.
+    pub fn end_frame(&mut self) {
+        let slave = match &mut self.modbus {
+            Some(slave) => slave,
+            None => return,
+        };
+        let mut target = modbus::Target {
+            state: &mut self.state, profiles: &mut self.profiles,
+            regulator: &mut self.regulator, supervisor: &mut self.supervisor,
+            run_control: &mut self.run_control, presets: &mut self.presets,
+            nrun: self.hw.nrun.get(), pwm: &self.hw,
+        };
+        if let Some(response) = slave.end_frame(&mut target) {
+            self.tx.extend_from_slice(response);
+        }
+        self.events.observe(&self.state, self.cycles());
+    }
+
+    fn select_preset(&mut self, slot: u8) {
+        self.presets.select(slot, &mut self.state, &mut self.regulator, &mut self.supervisor,
+                            &mut self.profiles);
+    }
+
+    /// Cycle counter reading at the current step.
+    fn cycles(&self) -> u32 {
+        (self.steps * STEP_CYCLES) as u32
+    }
+
+    /// Queue pending packets, in the order the firmware sends them.
+    fn flush(&mut self) {
+        if self.modbus.is_some() {
+            return;
+        }
+        while let Some(packet) = self.events.packet() {
+            self.tx.extend_from_slice(packet);
+        }
+        if self.hello_pending {
+            self.hello_pending = false;
+            self.tx.extend_from_slice(info::Hello::new(CONFIG_CRC).to_bytes());
+        }
+        if self.run_status_pending {
+            self.run_status_pending = false;
+            self.run_control.status(&self.state, self.hw.nrun.get(), &mut self.run_status);
+            self.tx.extend_from_slice(self.run_status.to_bytes());
+        }
+        if self.preset_pending {
+            self.preset_pending = false;
+            self.tx.extend_from_slice(self.preset_report.to_bytes());
+        }
+        if self.metrics_pending {
+            self.metrics_pending = false;
+            self.tx.extend_from_slice(self.metrics.to_bytes());
+        }
+        if self.energy_pending {
+            self.energy_pending = false;
+            self.tx.extend_from_slice(self.energy_report.to_bytes());
+        }
+        if self.description_pending {
+            self.description_pending = false;
+            self.tx.extend_from_slice(telemetry::Description::new().to_bytes());
+        }
+        if let Some(packet) = self.history.packet() {
+            self.tx.extend_from_slice(packet);
+            self.history.transmitted();
+        }
+        while let Some(segment) = self.scope.packet() {
+            self.tx.extend_from_slice(segment);
+            self.scope.transmitted();
+        }
+    }
+
+    pub fn fault_state(&self) -> state::FaultState {
+        self.state.fault_state
+    }
+
+    pub fn fault_code(&self) -> state::FaultCode {
+        self.state.fault_code
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hello_reports_config_crc() {
+        let mut device = Device::new(Plant::new(), None);
+        device.step();
+        let hello = info::Hello::new(CONFIG_CRC);
+        assert!(device.tx.windows(hello.to_bytes().len()).any(|w| w == hello.to_bytes()));
+    }
+}
@@ -0,0 +1,334 @@
+//! Virtual PSU on a pseudo-terminal, for developing host tools without hardware.
+//!
+//! Usage: iggie-psu-sim [--modbus] [SCRIPT]
+//!
+//! Prints the path of a pty which behaves like the PSU's serial port: it sends
+//! telemetry from a model of the converter and its load, answers the same commands,
+//! and resets into an emulated bootloader on request. Host tools such as telem.py,
+//! command.py and the updater can be pointed at the pty instead of a real board.
+//!
+//! With --modbus the pty instead behaves like firmware built with Modbus enabled,
+//! as slave address 1, for Modbus masters such as test_modbus.py. A request frame
+//! ends once no more bytes arrive within a millisecond.
+//!
+//! Conditions are changed by commands read one per line from SCRIPT, or from stdin
+//! if no script is given:
+//!
+//!     vin V         set the input voltage
+//!     load OHMS     set the output load resistance, or "open"
+//!     temp C        set the die temperature
+//!     iin A         draw extra input current elsewhere on the input supply
+//!     nrun 0|1      release or assert nRUN
+//!     fault NAME    create the conditions for a fault: VLim, ILim, NoVOut, VInLow,
+//!                   VInHigh, IInHigh, OverTemp or NoRun
+//!     clear         restore nominal conditions
+//!     wait S        pause the script for S seconds
+//!     quit          exit
+//!
+//! Blank lines and lines starting with # are ignored.
+
+use std::io::{BufRead, Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+use serialport::{SerialPort, TTYPort};
+
+use iggie_psu::{boot, command, config, timing};
+
+mod plant;
+mod device;
+
+use device::{Device, Event};
+use plant::Plant;
+
+/// Most control loop steps run at once when catching up with real time;
+/// if the simulation falls further behind, the extra time is skipped.
+const MAX_STEPS: u64 = 100;
+
+/// Flash contents, updated by the emulated bootloader.
+struct Flash {
+    mem: Vec<u8>,
+}
+
+const FLASH_START: u32 = 0x0800_0000;
+
+impl Flash {
+    fn new() -> Self {
+        Flash { mem: vec![0xFF; 64 * 1024] }
+    }
+
+    fn offset(address: u32) -> usize {
+        (address - FLASH_START) as usize
+    }
+}
+
+impl boot::Target for Flash {
+    fn erase_page(&mut self, address: u32) -> Result<(), boot::FlashError> {
+        let start = Self::offset(address);
+        self.mem[start..start + boot::PAGE_SIZE as usize].fill(0xFF);
+        Ok(())
+    }
+
+    fn program(&mut self, address: u32, data: &[u16]) -> Result<(), boot::FlashError> {
+        let start = Self::offset(address);
+        for (idx, word) in data.iter().enumerate() {
+            let at = start + 2 * idx;
+            if self.mem[at..at + 2] != [0xFF, 0xFF] {
+                return Err(boot::FlashError);
+            }
+            self.mem[at..at + 2].copy_from_slice(&word.to_le_bytes());
+        }
+        Ok(())
+    }
+
+    fn read(&self, address: u32, len: usize) -> &[u8] {
+        &self.mem[Self::offset(address)..Self::offset(address) + len]
+    }
+}
+
+/// Emulated bootloader, waiting for update requests until told to boot.
+struct Bootloader {
+    framer: command::Framer<{ boot::MAX_PAYLOAD }>,
+    updater: boot::Updater,
+}
+
+impl Bootloader {
+    fn new() -> Self {
+        Bootloader { framer: command::Framer::new(), updater: boot::Updater::new() }
+    }
+
+    /// Handle a byte received from the host, returning true once the application
+    /// should start.
+    fn receive(&mut self, flash: &mut Flash, byte: u8, tx: &mut Vec<u8>) -> bool {
+        let (id, payload) = match self.framer.push(byte) {
+            Some(frame) => frame,
+            None => return false,
+        };
+        let request = boot::Request::decode(id, payload);
+        let status = match request {
+            Some(request) => self.updater.handle(flash, request),
+            None => boot::Status::BadRequest,
+        };
+        let hello = boot::hello_payload();
+        let reply = match (request, status) {
+            (Some(boot::Request::Hello), boot::Status::Ok) => &hello[..],
+            _ => &[],
+        };
+        let mut buf = [0; 16];
+        let len = command::encode(status as u8, reply, &mut buf);
+        tx.extend_from_slice(&buf[..len]);
+        matches!((request, status), (Some(boot::Request::Boot), boot::Status::Ok))
+    }
+}
+
+enum Mode {
+    App(Box<Device>),
+    Boot(Bootloader),
+}
+
+/// Apply one script command to the plant, returning false to quit.
+fn apply(line: &str, device: &mut Device) -> Result<bool, String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let value = |idx: usize| -> Result<f32, String> {
+        let word = words.get(idx).ok_or_else(|| format!("Missing value in '{}'", line))?;
+        word.parse().map_err(|_| format!("Invalid value '{}'", word))
+    };
+    let plant = &mut device.plant;
+    match words.first().copied() {
+        Some("vin") => plant.v_in = value(1)?,
+        Some("load") if words.get(1) == Some(&"open") => plant.r_load = f32::INFINITY,
+        Some("load") => plant.r_load = value(1)?,
+        Some("temp") => plant.temp = value(1)?,
+        Some("iin") => plant.i_in_extra = value(1)?,
+        Some("nrun") => device.hw.nrun.set(value(1)? != 0.0),
+        Some("fault") => match words.get(1).copied() {
+            Some("VLim") => plant.v_out += 60.0,
+            Some("ILim") => plant.r_load = 1e3,
+            Some("NoVOut") => plant.open = true,
+            Some("VInLow") => plant.v_in = 16.0,
+            Some("VInHigh") => plant.v_in = 32.0,
+            Some("IInHigh") => plant.i_in_extra = 3.6,
+            Some("OverTemp") => plant.temp = 90.0,
+            Some("NoRun") => device.hw.nrun.set(false),
+            _ => return Err(format!("Unknown fault in '{}'", line)),
+        },
+        Some("clear") => {
+            plant.clear();
+            device.hw.nrun.set(true);
+        },
+        Some("quit") => return Ok(false),
+        _ => return Err(format!("Unknown command '{}'", line)),
+    }
+    Ok(true)
+}
+
+/// Read script commands on a separate thread, carrying out waits there so the
+/// simulation keeps running.
+fn spawn_script(path: Option<String>) -> Result<mpsc::Receiver<String>, String> {
+    let reader: Box<dyn BufRead + Send> = match path {
+        Some(path) => {
+            let file = std::fs::File::open(&path)
+                .map_err(|e| format!("Error opening {}: {}", path, e))?;
+            Box::new(std::io::BufReader::new(file))
+        },
+        None => Box::new(std::io::BufReader::new(std::io::stdin())),
+    };
+    let (send, recv) = mpsc::channel();
+    std::thread::spawn(move || {
+        for line in reader.lines().map_while(Result::ok) {
+            let line = line.trim().to_string();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut words = line.split_whitespace();
+            if words.next() == Some("wait") {
+                match words.next().and_then(|s| s.parse::<f32>().ok()) {
+                    Some(s) if s >= 0.0 => std::thread::sleep(Duration::from_secs_f32(s)),
+                    _ => eprintln!("Invalid wait '{}'", line),
+                }
+            } else if send.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    Ok(recv)
+}
+
+fn run(script: Option<String>, modbus: bool) -> Result<(), String> {
+    let (mut master, slave) = TTYPort::pair().map_err(|e| format!("Error opening pty: {}", e))?;
+    master.set_timeout(Duration::from_millis(1)).map_err(|e| e.to_string())?;
+    // Writes must not block while nothing is reading the pty
+    // UNSAFE: Only changes the file status flags of a descriptor owned by `master`.
+    if unsafe { libc::fcntl(master.as_raw_fd(), libc::F_SETFL, libc::O_NONBLOCK) } < 0 {
+        return Err(format!("Error configuring pty: {}", std::io::Error::last_os_error()));
+    }
+    println!("Virtual PSU on {}", slave.name().unwrap_or_default());
+    let script = spawn_script(script)?;
+
+    let mut flash = Flash::new();
+    let modbus = if modbus { Some(config::MODBUS_ADDRESS) } else { None };
+    let mut mode = Mode::App(Box::new(Device::new(Plant::new(), modbus)));
+    let mut fault = None;
+    let step = Duration::from_micros(1_000_000 / timing::CTRL_RATE as u64);
+    let start = Instant::now();
+    let mut steps = 0u64;
+    let mut rx = [0; 256];
+    let mut tx = Vec::new();
+
+    loop {
+        let received = match master.read(&mut rx) {
+            Ok(n) => &rx[..n],
+            Err(e) if e.kind() == std::io::ErrorKind::TimedOut => &[],
+            Err(e) => return Err(format!("Error reading pty: {}", e)),
+        };
+
+        // The line has been silent since any request frame's last byte
+        if received.is_empty() {
+            if let Mode::App(device) = &mut mode {
+                device.end_frame();
+            }
+        }
+        for &byte in received {
+            mode = match mode {
+                Mode::App(mut device) => match device.receive(byte) {
+                    Some(Event::EnterBootloader) => {
+                        println!("Entering bootloader");
+                        tx.append(&mut device.tx);
+                        Mode::Boot(Bootloader::new())
+                    },
+                    None => Mode::App(device),
+                },
+                Mode::Boot(mut bootloader) => {
+                    if bootloader.receive(&mut flash, byte, &mut tx) {
+                        println!("Starting application");
+                        Mode::App(Box::new(Device::new(Plant::new(), modbus)))
+                    } else {
+                        Mode::Boot(bootloader)
+                    }
+                },
+            };
+        }
+
+        // Catch up with real time, without falling behind indefinitely if too slow
+        let due = (start.elapsed().as_nanos() / step.as_nanos()) as u64;
+        if due - steps > MAX_STEPS {
+            steps = due - MAX_STEPS;
+        }
+        if let Mode::App(device) = &mut mode {
+            while let Ok(line) = script.try_recv() {
+                match apply(&line, device) {
+                    Ok(true) => (),
+                    Ok(false) => return Ok(()),
+                    Err(e) => eprintln!("{}", e),
+                }
+            }
+            for _ in steps..due {
+                device.step();
+            }
+            tx.append(&mut device.tx);
+
+            let now = (device.fault_state() as usize, device.fault_code());
+            if fault != Some(now) {
+                println!("{}, fault {:?}", ["Stopped", "Running", "Fault"][now.0], now.1);
+                fault = Some(now);
+            }
+        }
+        steps = due;
+
+        // Nothing is connected once the pty's buffer fills, so drop anything unsent
+        if !tx.is_empty() {
+            master.write_all(&tx).ok();
+            tx.clear();
+        }
+    }
+}
+
+fn main() {
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let modbus = args.first().map(String::as_str) == Some("--modbus");
+    if modbus {
+        args.remove(0);
+    }
+    if let Err(e) = run(args.into_iter().next(), modbus) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use iggie_psu::state::{FaultCode, FaultState};
+
+    /// Step `device` for `seconds` of control loop time.
+    fn run_for(device: &mut Device, seconds: f32) {
+        for _ in 0..(seconds * timing::CTRL_RATE as f32) as u32 {
+            device.step();
+        }
+        device.tx.clear();
+    }
+
+    #[test]
+    fn scripted_fault() {
+        let mut device = Device::new(Plant::new(), None);
+        run_for(&mut device, 1.0);
+        assert!(device.fault_state() == FaultState::Running);
+        let strike = config::PROFILE_STRIKE;
+        assert!(device.plant.v_out > strike.v_min && device.plant.v_out < strike.v_lim);
+
+        assert_eq!(apply("fault OverTemp", &mut device), Ok(true));
+        run_for(&mut device, 0.1);
+        assert!(device.fault_state() == FaultState::Fault);
+        assert_eq!(device.fault_code(), FaultCode::OverTemp);
+        assert!(!device.hw.enabled.get());
+
+        // Clearing the conditions alone does not restart the converter
+        assert_eq!(apply("clear", &mut device), Ok(true));
+        run_for(&mut device, 0.1);
+        assert!(device.fault_state() == FaultState::Fault);
+
+        assert!(apply("fault Unknown", &mut device).is_err());
+        assert_eq!(apply("quit", &mut device), Ok(false));
+    }
+}
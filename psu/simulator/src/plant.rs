@@ -0,0 +1,203 @@
+//! Model of the converter, its load and the board's analogue front end.
+//!
+//! Each switching cycle transfers the energy stored in the inductor at the peak
+//! current set by the current reference, so the output power scales with the square
+//! of the reference and with the burst duty cycle. The output capacitor is charged
+//! by this and discharged by a resistive load.
+
+use std::cell::Cell;
+
+use iggie_psu::hw::{CurrentRef, BurstPwm, RunInput, StatusLeds};
+
+/// Primary inductance (H).
+const L: f32 = 5e-6;
+
+/// Average switching frequency at full duty (Hz).
+const F_SW: f32 = 300e3;
+
+/// Peak current at full scale on the current reference DAC (A).
+const I_PK_FULL_SCALE: f32 = 6.47;
+
+/// Power conversion efficiency.
+const EFFICIENCY: f32 = 0.9;
+
+/// Output capacitance (F).
+const C_OUT: f32 = 2.2e-6;
+
+/// Input current drawn with the converter idle (A).
+const I_IN_QUIESCENT: f32 = 0.005;
+
+/// Nominal operating conditions.
+const V_IN: f32 = 24.0;
+const R_LOAD: f32 = 150e3;
+const TEMP: f32 = 40.0;
+
+/// Signals driven by the firmware logic, and the nRUN input it reads.
+pub struct Hw {
+    pub i_ref: Cell<u16>,
+    pub enabled: Cell<bool>,
+    pub duty: Cell<u16>,
+    pub nrun: Cell<bool>,
+    pub led_400v: Cell<bool>,
+    pub led_err: Cell<bool>,
+}
+
+impl Hw {
+    pub fn new() -> Self {
+        Hw {
+            i_ref: Cell::new(0), enabled: Cell::new(false), duty: Cell::new(1000),
+            nrun: Cell::new(true), led_400v: Cell::new(false), led_err: Cell::new(false),
+        }
+    }
+}
+
+impl CurrentRef for Hw {
+    fn set_i_ref(&self, level: u16) {
+        self.i_ref.set(level);
+    }
+}
+
+impl BurstPwm for Hw {
+    fn enable(&self) {
+        self.enabled.set(true);
+    }
+
+    fn disable(&self) {
+        self.enabled.set(false);
+    }
+
+    fn set_duty(&self, duty: u16) {
+        self.duty.set(duty.min(1000));
+    }
+}
+
+impl RunInput for Hw {
+    fn run(&self) -> bool {
+        self.nrun.get()
+    }
+}
+
+impl StatusLeds for Hw {
+    fn set_400v_led(&self, on: bool) {
+        self.led_400v.set(on);
+    }
+
+    fn set_err_led(&self, on: bool) {
+        self.led_err.set(on);
+    }
+}
+
+/// Analogue conditions, which scripts change to inject faults.
+pub struct Plant {
+    pub v_in: f32,
+    /// Output load resistance (Ω), infinite when open circuit.
+    pub r_load: f32,
+    pub temp: f32,
+    /// Extra input current drawn elsewhere on the input supply (A).
+    pub i_in_extra: f32,
+    /// No energy reaches the output, as with a failed rectifier.
+    pub open: bool,
+    pub v_out: f32,
+    pub i_in: f32,
+    noise: u32,
+}
+
+impl Plant {
+    pub fn new() -> Self {
+        Plant {
+            v_in: V_IN, r_load: R_LOAD, temp: TEMP, i_in_extra: 0.0, open: false,
+            v_out: 0.0, i_in: I_IN_QUIESCENT, noise: 1,
+        }
+    }
+
+    /// Restore nominal input, load and temperature, keeping the output charge.
+    pub fn clear(&mut self) {
+        *self = Plant { v_out: self.v_out, noise: self.noise, ..Plant::new() };
+    }
+
+    pub fn i_out(&self) -> f32 {
+        self.v_out / self.r_load
+    }
+
+    /// Advance the model by `dt` seconds.
+    pub fn step(&mut self, dt: f32, hw: &Hw) {
+        let p_in = if hw.enabled.get() {
+            let i_pk = hw.i_ref.get() as f32 / 4096.0 * I_PK_FULL_SCALE;
+            0.5 * L * i_pk * i_pk * F_SW * hw.duty.get() as f32 / 1000.0
+        } else {
+            0.0
+        };
+        let p_out = if self.open { 0.0 } else { p_in * EFFICIENCY };
+        let i_charge = p_out / self.v_out.max(self.v_in).max(1.0);
+        self.v_out = (self.v_out + (i_charge - self.i_out()) * dt / C_OUT).max(0.0);
+        self.i_in = p_in / self.v_in.max(1.0) + I_IN_QUIESCENT + self.i_in_extra;
+    }
+
+    /// ADC readings of `[vout, iout, iin, vin]` with one count of noise,
+    /// scaled as `State::update_adc` expects for a supply of `vdda`.
+    pub fn adc(&mut self, vdda: f32) -> [u16; 4] {
+        let lsb = vdda / 4096.0;
+        let scales = [lsb * 200.6 * 1.0244266, lsb * 0.04, lsb, lsb * 11.0];
+        let values = [self.v_out, self.i_out(), self.i_in, self.v_in];
+        let mut buf = [0; 4];
+        for ((b, v), s) in buf.iter_mut().zip(values.iter()).zip(scales.iter()) {
+            let counts = v / s + self.noise() as f32;
+            *b = counts.round().clamp(0.0, 4095.0) as u16;
+        }
+        buf
+    }
+
+    /// Uniform noise of -1, 0 or +1 counts, from a xorshift generator.
+    fn noise(&mut self) -> i32 {
+        self.noise ^= self.noise << 13;
+        self.noise ^= self.noise >> 17;
+        self.noise ^= self.noise << 5;
+        (self.noise % 3) as i32 - 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use iggie_psu::state::State;
+    use iggie_psu::calibration::VDDA_NOMINAL;
+
+    #[test]
+    fn adc_matches_state_scaling() {
+        let mut plant = Plant::new();
+        plant.v_out = 375.0;
+        let mut state = State::new();
+        state.update_adc(plant.adc(VDDA_NOMINAL));
+        assert!((state.v_out - 375.0).abs() < 0.5);
+        assert!((state.i_out - plant.i_out()).abs() < 2e-4);
+        assert!((state.v_in - V_IN).abs() < 0.05);
+        assert!((state.i_in - I_IN_QUIESCENT).abs() < 2e-3);
+    }
+
+    #[test]
+    fn charges_while_enabled_and_discharges_into_load() {
+        let hw = Hw::new();
+        let mut plant = Plant::new();
+        hw.set_i_ref(1000);
+        hw.enable();
+        for _ in 0..100 {
+            plant.step(1e-4, &hw);
+        }
+        let charged = plant.v_out;
+        assert!(charged > 10.0);
+        assert!(plant.i_in > I_IN_QUIESCENT);
+
+        hw.disable();
+        for _ in 0..100 {
+            plant.step(1e-4, &hw);
+        }
+        assert!(plant.v_out < charged);
+        assert_eq!(plant.i_in, I_IN_QUIESCENT);
+
+        // No energy reaches an open output
+        let mut plant = Plant { open: true, ..Plant::new() };
+        hw.enable();
+        plant.step(1e-4, &hw);
+        assert_eq!(plant.v_out, 0.0);
+    }
+}
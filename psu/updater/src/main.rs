@@ -0,0 +1,169 @@
+//! Push a new application image to the PSU over its serial port.
+//!
+//! Usage: iggie-psu-updater PORT IMAGE
+//!
+//! IMAGE is a raw binary of the application, as made by
+//! `arm-none-eabi-objcopy -O binary target/thumbv7em-none-eabihf/release/iggie-psu iggie-psu.bin`.
+//!
+//! The running firmware is asked to reset into the bootloader, which only happens
+//! while the converter is stopped. The image is then written, verified against its
+//! CRC by the bootloader, and started. If anything fails part way, the bootloader
+//! keeps waiting for a new image, so the update can simply be run again.
+
+use std::io::{Read, Write};
+use std::time::{Duration, Instant};
+
+use iggie_psu::{boot, command, info::Crc32};
+
+/// Baud rate of the application's telemetry link.
+const APP_BAUD: u32 = 3_500_000;
+
+/// How long to keep trying to reach the bootloader after requesting a reset.
+const HELLO_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Reply timeouts. Beginning an update erases the whole application region.
+const REPLY_TIMEOUT: Duration = Duration::from_millis(200);
+const BEGIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+type Result<T> = std::result::Result<T, String>;
+
+/// Request and reply link to the bootloader.
+struct Link {
+    port: Box<dyn serialport::SerialPort>,
+    framer: command::Framer<16>,
+}
+
+impl Link {
+    fn open(path: &str) -> Result<Self> {
+        let port = serialport::new(path, boot::BAUD)
+            .timeout(REPLY_TIMEOUT)
+            .open()
+            .map_err(|e| format!("Error opening {}: {}", path, e))?;
+        Ok(Link { port, framer: command::Framer::new() })
+    }
+
+    /// Send a request and wait up to `timeout` for its reply status and payload.
+    fn request(&mut self, id: u8, payload: &[u8], timeout: Duration)
+        -> Result<(boot::Status, Vec<u8>)>
+    {
+        let mut buf = vec![0; payload.len() + 4];
+        command::encode(id, payload, &mut buf);
+        self.port.write_all(&buf).map_err(|e| format!("Error writing: {}", e))?;
+
+        let deadline = Instant::now() + timeout;
+        let mut byte = [0];
+        while Instant::now() < deadline {
+            match self.port.read(&mut byte) {
+                Ok(1) => {
+                    if let Some((status, reply)) = self.framer.push(byte[0]) {
+                        let status = boot::Status::from_u8(status)
+                            .ok_or_else(|| format!("Unknown reply status {}", status))?;
+                        return Ok((status, reply.to_vec()));
+                    }
+                },
+                Ok(_) => (),
+                Err(e) if e.kind() == std::io::ErrorKind::TimedOut => (),
+                Err(e) => return Err(format!("Error reading: {}", e)),
+            }
+        }
+        Err("Timed out waiting for bootloader".to_string())
+    }
+
+    /// Send a request which must succeed.
+    fn expect_ok(&mut self, id: u8, payload: &[u8], timeout: Duration) -> Result<Vec<u8>> {
+        match self.request(id, payload, timeout)? {
+            (boot::Status::Ok, reply) => Ok(reply),
+            (status, _) => Err(format!("Bootloader refused request {:#04x}: {:?}", id, status)),
+        }
+    }
+}
+
+/// Ask the running application to reset into the bootloader.
+fn reset_to_bootloader(path: &str) -> Result<()> {
+    let mut port = serialport::new(path, APP_BAUD)
+        .open()
+        .map_err(|e| format!("Error opening {}: {}", path, e))?;
+    let mut buf = [0; 4];
+    let len = command::encode(command::id::ENTER_BOOTLOADER, &[], &mut buf);
+    port.write_all(&buf[..len]).map_err(|e| format!("Error writing: {}", e))?;
+    port.flush().map_err(|e| format!("Error writing: {}", e))
+}
+
+/// Keep sending hello requests until the bootloader answers, returning the
+/// application region size.
+fn hello(link: &mut Link) -> Result<u32> {
+    let start = Instant::now();
+    let reply = loop {
+        let _ = link.port.clear(serialport::ClearBuffer::Input);
+        match link.request(boot::id::HELLO, &[], REPLY_TIMEOUT) {
+            Ok((boot::Status::Ok, reply)) if reply.len() == 12 => break reply,
+            _ if start.elapsed() > HELLO_TIMEOUT => {
+                return Err("No reply from bootloader; is the converter stopped?".to_string());
+            },
+            _ => (),
+        }
+    };
+    let version = u16::from_le_bytes([reply[0], reply[1]]);
+    let block = u16::from_le_bytes([reply[2], reply[3]]);
+    let app_size = u32::from_le_bytes([reply[8], reply[9], reply[10], reply[11]]);
+    if version != boot::VERSION || block as usize != boot::BLOCK_SIZE {
+        return Err(format!("Bootloader protocol version {} is not supported (expected {})",
+                           version, boot::VERSION));
+    }
+    Ok(app_size)
+}
+
+fn update(path: &str, image: &[u8]) -> Result<()> {
+    reset_to_bootloader(path)?;
+    let mut link = Link::open(path)?;
+    let app_size = hello(&mut link)?;
+    if image.len() > app_size as usize {
+        return Err(format!("Image is {} bytes but only {} are available", image.len(), app_size));
+    }
+
+    let crc = Crc32::new().bytes(image).finish();
+    println!("Writing {} bytes, CRC {:08X}", image.len(), crc);
+    let mut begin = (image.len() as u32).to_le_bytes().to_vec();
+    begin.extend_from_slice(&crc.to_le_bytes());
+    link.expect_ok(boot::id::BEGIN, &begin, BEGIN_TIMEOUT)?;
+
+    for (idx, block) in image.chunks(boot::BLOCK_SIZE).enumerate() {
+        let offset = idx * boot::BLOCK_SIZE;
+        let mut write = (offset as u32).to_le_bytes().to_vec();
+        write.extend_from_slice(block);
+        link.expect_ok(boot::id::WRITE, &write, REPLY_TIMEOUT)?;
+        print!("\r{:3}%", 100 * (offset + block.len()) / image.len());
+        std::io::stdout().flush().ok();
+    }
+    println!();
+
+    link.expect_ok(boot::id::COMMIT, &[], REPLY_TIMEOUT)?;
+    println!("Image verified, starting application");
+    link.expect_ok(boot::id::BOOT, &[], REPLY_TIMEOUT)?;
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() != 3 {
+        eprintln!("Usage: {} PORT IMAGE", args[0]);
+        std::process::exit(1);
+    }
+
+    let mut image = match std::fs::read(&args[2]) {
+        Ok(image) => image,
+        Err(e) => {
+            eprintln!("Error reading {}: {}", args[2], e);
+            std::process::exit(1);
+        },
+    };
+    // Images are written in whole words, padded as erased flash
+    while image.len() % 4 != 0 {
+        image.push(0xFF);
+    }
+
+    if let Err(e) = update(&args[1], &image) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}